@@ -1,9 +1,19 @@
-use std::{pin::{Pin, pin}, task::{Poll, Waker}, sync::{Arc, Mutex}, thread};
+use std::{pin::{Pin, pin}, task::{Poll, Waker}, sync::{Arc, Mutex}, thread, collections::VecDeque};
 use pin_project::pinned_drop;
 use tokio::time::Instant;
 use tokio_stream::Stream;
 
-use crate::{virtio::{guest_driver::GuestDriver, virtqueue::DescriptorCell}, poller::PollableQueue};
+use crate::{virtio::{guest_driver::GuestDriver, interrupt::InterruptCause}, poller::PollableQueue};
+
+/// What the guest interrupt handler found once it read the interrupt status
+pub enum DriverEvent {
+    UsedBuffer {
+        queue: u16,
+        head: u16,
+        length: u32,
+    },
+    ConfigChange,
+}
 
 pub struct SharedState {
     complete: bool,
@@ -12,35 +22,46 @@ pub struct SharedState {
 
 #[pin_project::pin_project(PinnedDrop)]
 pub struct DriverPoller<'a, const S: usize, P: PollableQueue + Clone + Send> {
-    driver: &'a mut GuestDriver<S, P>,
+    // Every queue of one device, they share a transport and so an interrupt status
+    drivers: Vec<&'a mut GuestDriver<S, P>>,
     last_update: Instant,
-    shared_state: Arc<Mutex<SharedState>>
+    shared_state: Arc<Mutex<SharedState>>,
+    pending: VecDeque<DriverEvent>,
 }
 
 impl <'a, const S: usize, P: PollableQueue + Clone + Send + 'static> DriverPoller<'a, S, P> {
     pub fn new(driver: &'a mut GuestDriver<S, P>) -> Self {
+        Self::with_queues(vec![driver])
+    }
+
+    pub fn with_queues(drivers: Vec<&'a mut GuestDriver<S, P>>) -> Self {
         Self {
-            driver,
+            drivers,
             last_update: Instant::now(),
             shared_state : Arc::new(Mutex::new(SharedState {
                 complete: false,
                 waker: None
             })),
+            pending: VecDeque::new(),
         }
     }
 
     pub unsafe fn get_driver(&self) -> *mut GuestDriver<S, P> {
-        let const_ptr = self.driver as *const GuestDriver<S, P>;
+        self.get_queue_driver(0)
+    }
+
+    pub unsafe fn get_queue_driver(&self, queue: u16) -> *mut GuestDriver<S, P> {
+        let const_ptr = &*self.drivers[queue as usize] as *const GuestDriver<S, P>;
         const_ptr as *mut GuestDriver<S, P>
     }
 
     pub unsafe fn get_driver_ref(&mut self) ->&mut GuestDriver<S, P> {
-        self.driver
+        self.drivers[0]
     }
 
     pub fn delayed_poller(&self) -> () {
         let shared_state = self.shared_state.clone();
-        let poll_item = self.driver.poll_interface.clone();
+        let poll_item = self.drivers[0].poll_interface.clone();
 
         thread::spawn(move || {
             loop {
@@ -63,21 +84,37 @@ impl <'a, const S: usize, P: PollableQueue + Clone + Send + 'static> DriverPolle
 }
 
 impl <'a, const S: usize, P: PollableQueue + Clone + Send> Stream for DriverPoller<'a, S, P> {
-    type Item = (*mut DescriptorCell, u16);
+    type Item = DriverEvent;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.project();
 
-        unsafe {
-            if let Some(found) = this.driver.check_used_queue() {
-                Poll::Ready(Some(found))
-            } else {
-                let mut state = this.shared_state.lock().unwrap();
-                state.waker = Some(cx.waker().clone());
+        if let Some(event) = this.pending.pop_front() {
+            return Poll::Ready(Some(event));
+        }
 
-                Poll::Pending
+        // Register before reading the status so an interrupt landing in between still wakes us
+        this.shared_state.lock().unwrap().waker = Some(cx.waker().clone());
+
+        for cause in this.drivers[0].take_interrupts() {
+            match cause {
+                InterruptCause::UsedBuffer(queue) => unsafe {
+                    let Some(driver) = this.drivers.iter_mut().find(|driver| driver.queue_index() == queue) else {
+                        continue;
+                    };
+
+                    while let Some((_, head, length)) = driver.check_used_queue() {
+                        this.pending.push_back(DriverEvent::UsedBuffer { queue, head, length });
+                    }
+                },
+                InterruptCause::ConfigChange => this.pending.push_back(DriverEvent::ConfigChange),
             }
         }
+
+        match this.pending.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
     }
 }
 
@@ -11,6 +11,10 @@ pub enum Messages {
     GlobalMessages(String),
     FileWrite(String, String),
     FileRead(String),
+    FileContents(String, String),
+    ConsoleOutput(String),
+    // Balloon target, actual size and the guest's memory, all in bytes
+    BalloonSize(u64, u64, u64),
 }
 
 pub struct CommsLink {
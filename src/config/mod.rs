@@ -0,0 +1,176 @@
+// Command line options, everything is optional and the simulator runs with its defaults when
+// nothing is passed.
+
+use std::env;
+
+use crate::virtio::transport::{TransportMode, TransportOptions};
+use crate::virtio_net::{unix_socket::SocketSpec, DEFAULT_QUEUE_PAIRS, MAX_QUEUE_PAIRS};
+use crate::virtio_vsock::DEFAULT_GUEST_CID;
+
+pub const DEFAULT_DISK_PATH: &str = "disk.img";
+pub const DEFAULT_DISK_SIZE: u64 = 16 << 20;
+pub const DEFAULT_NET_GUESTS: usize = 1;
+pub const DEFAULT_NET_BASE: u8 = 1;
+pub const DEFAULT_VSOCK_PATH: &str = "vsock.sock";
+pub const DEFAULT_FS_DIR: &str = "shared";
+pub const DEFAULT_FS_TAG: &str = "playground";
+pub const DEFAULT_FS_DAX_MIB: u64 = 8;
+pub const DEFAULT_9P_TAG: &str = "playground9p";
+pub const DEFAULT_MEMORY_MIB: u64 = 64;
+
+#[derive(Debug)]
+pub struct Config {
+    /// Write the device tree for the configured devices here before starting
+    pub dtb_path: Option<String>,
+    /// The same device tree as DTS source
+    pub dts_path: Option<String>,
+
+    /// What every device sits behind, legacy is there to test transitional drivers
+    pub transport: TransportOptions,
+
+    /// Raw image backing the block device, created sparse when it doesn't exist
+    pub disk_path: String,
+    pub disk_size: u64,
+    /// Run the block device off a RAM disk of this many bytes instead of an image
+    pub disk_memory: Option<u64>,
+    /// Run the disk as a read-only base with its writes and snapshots kept in this directory
+    pub overlay_dir: Option<String>,
+
+    /// Named console ports as name and backend, e.g. `agent` and `socket:/tmp/agent.sock`
+    pub console_ports: Vec<(String, String)>,
+
+    /// Seed the entropy device for reproducible runs instead of using getrandom
+    pub rng_seed: Option<u64>,
+    /// Bytes per second the entropy device hands out, unlimited when unset
+    pub rng_rate: Option<u64>,
+
+    /// Extra simulated guests on the virtual switch, each answers pings on its own address
+    pub net_guests: usize,
+    /// Receive and transmit queue pairs each NIC offers, multiqueue needs more than one
+    pub net_queue_pairs: u16,
+    /// The last byte of the first NIC's MAC and IP address, the others count up from it.
+    /// Simulators linked together need ranges that don't overlap.
+    pub net_base: u8,
+    /// Links the virtual switch to another simulator or a test harness over a Unix socket
+    pub net_link: Option<SocketSpec>,
+    /// NICs to capture from the start as name and pcapng path, e.g. `net0` and `/tmp/net0.pcapng`
+    pub net_captures: Vec<(String, String)>,
+
+    /// The guest's vsock context id, 0 to 2 are taken by the hypervisor and the host
+    pub vsock_cid: u64,
+    /// Host programs reach the guest through this Unix socket, guest connections to host port P
+    /// go to `<path>_P`
+    pub vsock_path: String,
+
+    /// The host directory shared with the guest over virtio-fs, created when it doesn't exist
+    pub fs_dir: String,
+    /// What the guest mounts the share by, up to 36 bytes
+    pub fs_tag: String,
+    /// MiB of DAX window the guest can map shared files into, 0 for none
+    pub fs_dax_mib: u64,
+    /// The host directory shared over virtio-9p, the virtio-fs one when unset
+    pub nine_p_dir: Option<String>,
+    pub nine_p_tag: String,
+
+    /// The guest's RAM in MiB, what the balloon takes pages out of
+    pub memory_mib: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dtb_path: None,
+            dts_path: None,
+            transport: TransportOptions::default(),
+            disk_path: DEFAULT_DISK_PATH.to_string(),
+            disk_size: DEFAULT_DISK_SIZE,
+            disk_memory: None,
+            overlay_dir: None,
+            console_ports: Vec::new(),
+            rng_seed: None,
+            rng_rate: None,
+            net_guests: DEFAULT_NET_GUESTS,
+            net_queue_pairs: DEFAULT_QUEUE_PAIRS,
+            net_base: DEFAULT_NET_BASE,
+            net_link: None,
+            net_captures: Vec::new(),
+            vsock_cid: DEFAULT_GUEST_CID,
+            vsock_path: DEFAULT_VSOCK_PATH.to_string(),
+            fs_dir: DEFAULT_FS_DIR.to_string(),
+            fs_tag: DEFAULT_FS_TAG.to_string(),
+            fs_dax_mib: DEFAULT_FS_DAX_MIB,
+            nine_p_dir: None,
+            nine_p_tag: DEFAULT_9P_TAG.to_string(),
+            memory_mib: DEFAULT_MEMORY_MIB,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self, String> {
+        Self::parse(env::args().skip(1))
+    }
+
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut config = Self::default();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} expects a value"));
+
+            match arg.as_str() {
+                "--dtb" => config.dtb_path = Some(value()?),
+                "--dts" => config.dts_path = Some(value()?),
+                "--legacy" => config.transport.mode = TransportMode::Legacy,
+                "--msix" => config.transport.msix_vectors = value()?.parse().ok().filter(|vectors| *vectors < 0x800).ok_or(format!("{arg} expects 0 to 2047 vectors"))?,
+                "--disk" => config.disk_path = value()?,
+                "--overlay" => config.overlay_dir = Some(value()?),
+                "--port" => {
+                    let port = value()?;
+                    let (name, backend) = port.split_once('=').ok_or(format!("{arg} expects name=socket:<path>, name=pty or name=file:<path>"))?;
+
+                    config.console_ports.push((name.to_string(), backend.to_string()));
+                },
+                "--rng-seed" => config.rng_seed = Some(value()?.parse().map_err(|_| format!("{arg} expects a number"))?),
+                "--rng-rate" => config.rng_rate = Some(value()?.parse().ok().filter(|rate| *rate > 0).ok_or(format!("{arg} expects bytes per second"))?),
+                "--net-guests" => config.net_guests = value()?.parse().map_err(|_| format!("{arg} expects a number of guests"))?,
+                "--net-queues" => config.net_queue_pairs = value()?.parse().ok().filter(|pairs| (1..=MAX_QUEUE_PAIRS).contains(pairs)).ok_or(format!("{arg} expects 1 to {MAX_QUEUE_PAIRS} queue pairs"))?,
+                "--net-base" => config.net_base = value()?.parse().ok().filter(|base| *base > 0).ok_or(format!("{arg} expects 1 to 254"))?,
+                "--net-link" => config.net_link = Some(SocketSpec::parse(&value()?)?),
+                "--net-capture" => {
+                    let capture = value()?;
+                    let (name, path) = capture.split_once('=').filter(|(_, path)| !path.is_empty()).ok_or(format!("{arg} expects net<n>=<path>"))?;
+
+                    config.net_captures.push((name.to_string(), path.to_string()));
+                },
+                "--vsock-cid" => config.vsock_cid = value()?.parse().ok().filter(|cid| (3..u32::MAX as u64).contains(cid)).ok_or(format!("{arg} expects a context id from 3 up"))?,
+                "--vsock" => config.vsock_path = value()?,
+                "--fs" => config.fs_dir = value()?,
+                "--fs-tag" => config.fs_tag = value()?,
+                "--fs-dax" => config.fs_dax_mib = value()?.parse().ok().filter(|size| *size <= 1 << 14).ok_or(format!("{arg} expects 0 to 16384 MiB"))?,
+                "--9p" => config.nine_p_dir = Some(value()?),
+                "--9p-tag" => config.nine_p_tag = value()?,
+                "--memory" => config.memory_mib = value()?.parse().ok().filter(|size| (1..=1 << 14).contains(size)).ok_or(format!("{arg} expects 1 to 16384 MiB"))?,
+                "--disk-size" => config.disk_size = value()?.parse().map_err(|_| format!("{arg} expects a size in bytes"))?,
+                "--disk-memory" => config.disk_memory = Some(value()?.parse().ok().filter(|size| *size > 0).ok_or(format!("{arg} expects a size in bytes"))?),
+                _ => return Err(format!("Unknown argument {arg}")),
+            }
+        }
+
+        // Only the modern register layout has the vector registers
+        if config.transport.mode == TransportMode::Legacy && config.transport.msix_vectors > 0 {
+            return Err("--msix needs the modern transport, it can't be used with --legacy".to_string());
+        }
+
+        // A RAM disk has no image for the overlays to sit on
+        if config.disk_memory.is_some() && config.overlay_dir.is_some() {
+            return Err("--disk-memory has no image to overlay, it can't be used with --overlay".to_string());
+        }
+
+        // Every NIC needs an address short of the broadcast one
+        if config.net_base as usize + config.net_guests > 254 {
+            return Err(format!("--net-base {} leaves no room for {} guests", config.net_base, config.net_guests));
+        }
+
+        Ok(config)
+    }
+}
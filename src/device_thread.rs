@@ -1,78 +1,278 @@
-use std::{mem::ManuallyDrop, slice};
+use std::{fs, sync::{Arc, Mutex, mpsc::{channel, Receiver, Sender as CommandSender}}};
 
 use tokio::sync::mpsc::Sender;
 
-use crate::{comms::Messages, virtio::{device_driver::DeviceDriver, virtqueue::DescriptorCell}, faux_blk::{self, FILE_STATE_FLAG, STATE_SUCCESS, FILE_READ}, poller::PollableQueue};
+use crate::{comms::Messages, virtio::{device_driver::DeviceDriver, device::{VirtioDevice, DeviceContext}, transport::TransportEvent}, poller::PollableQueue};
 
-unsafe fn read_string_from_cell(cell: &DescriptorCell) -> ManuallyDrop<String> {
-    ManuallyDrop::new(String::from_raw_parts(cell.addr as *mut u8, cell.length as usize, cell.length as usize))
+/// Lets other threads hand the device thread commands, or any other input the device model
+/// picks up itself. Sending wakes the thread the same way a queue notification would.
+pub struct DeviceControl<T = String> {
+    commands: CommandSender<T>,
+    waker: Arc<Mutex<Box<dyn PollableQueue + Send>>>,
 }
 
-unsafe fn write_file_to_cell<const S: usize, P: PollableQueue + Clone>(driver: &mut DeviceDriver<S, P>, cell: &mut DescriptorCell) {
-    let data_slice = slice::from_raw_parts_mut(cell.addr as *mut u8, cell.length as usize);
+impl<T> DeviceControl<T> {
+    /// `waker` has to be the guest side poller, its events are the ones the device waits on
+    pub fn new<P: PollableQueue + Send + 'static>(waker: P) -> (Self, Receiver<T>) {
+        let (commands, receiver) = channel();
 
-    driver.read_to_slice(data_slice, cell.length as u64).unwrap()
-}
+        (Self { commands, waker: Arc::new(Mutex::new(Box::new(waker))) }, receiver)
+    }
 
-unsafe fn write_success_to_cell(cell: &mut DescriptorCell) {
-    (&mut cell.flags as *mut u16).write_volatile(FILE_STATE_FLAG | STATE_SUCCESS);
+    pub fn send(&self, command: T) {
+        if self.commands.send(command).is_ok() {
+            self.waker.lock().unwrap().submit_event();
+        }
+    }
 }
 
-unsafe fn read_message<const S: usize, P: PollableQueue + Clone>(comms: &Sender<Messages>, driver: &mut DeviceDriver<S, P>, cell: &mut DescriptorCell, idx: u16) {
-    if cell.flags & faux_blk::FILE_OPEN_FLAG > 0{
-        let file_name = read_string_from_cell(cell);
-
-        let result = driver.open_file(&file_name);
-
-        let message = format!("Submitted file open it was success: {}", result.is_ok());
-        comms.blocking_send(Messages::DriverMessage(message)).unwrap();
+// Derived Clone would want T: Clone
+impl<T> Clone for DeviceControl<T> {
+    fn clone(&self) -> Self {
+        Self { commands: self.commands.clone(), waker: self.waker.clone() }
+    }
+}
 
-        write_success_to_cell(cell);
-    } else if cell.flags & faux_blk::FILE_WRITE_CONTENTS_FLAG > 0 {
-        if cell.flags & faux_blk::FILE_WRITE > 0 {
-            let file_contents = read_string_from_cell(cell);
+/// Pushes the device's view of itself into the transport so the driver can find it
+pub fn publish_device<const S: usize, P: PollableQueue + Clone, D: VirtioDevice + ?Sized>(device: &D, drivers: &[DeviceDriver<S, P>]) {
+    if let Some(driver) = drivers.first() {
+        let mut transport = driver.transport().lock().unwrap();
 
-            let result = driver.write_to_file(&file_contents);
+        transport.set_config_space(device.config_space());
+        transport.set_config_selections(device.config_selections());
+    }
+}
 
-            let message = format!("Submitted file write it was success: {}", result.is_ok());
-            comms.blocking_send(Messages::DriverMessage(message)).unwrap();
+unsafe fn handle_transport_events<const S: usize, P: PollableQueue + Clone, D: VirtioDevice + ?Sized>(comms: &Sender<Messages>, device: &mut D, drivers: &mut [DeviceDriver<S, P>]) {
+    let events = drivers[0].transport().lock().unwrap().take_events();
 
-            write_success_to_cell(cell);
-        } else if cell.flags & faux_blk::FILE_READ > 0 {
-            write_file_to_cell(driver, cell);
+    for event in events {
+        match event {
+            TransportEvent::Reset => {
+                device.reset();
+                drivers.iter_mut().for_each(|driver| driver.reset());
+                publish_device(device, drivers);
 
-            let message = format!("Recieved read request");
-            comms.blocking_send(Messages::DriverMessage(message)).unwrap();
+                comms.blocking_send(Messages::DriverMessage("Device reset by the driver".to_string())).unwrap();
+            },
+            TransportEvent::QueueReady(queue) => {
+                if let Some(driver) = drivers.get_mut(queue as usize) {
+                    driver.attach();
+                }
+            },
+            TransportEvent::DriverOk => {
+                let features = drivers[0].transport().lock().unwrap().negotiated_features();
+                device.activate(features);
 
-            (&mut cell.flags as *mut u16).write_volatile(FILE_READ | STATE_SUCCESS);
+                comms.blocking_send(Messages::DriverMessage(format!("Device activated with features {features:x}"))).unwrap();
+            },
+            _ => {}
         }
-    } else if cell.flags & faux_blk::FILE_CLOSE_FLAG > 0 {
-        driver.close_file();
+    }
+}
 
-        let message = format!("Submitted file close");
-        comms.blocking_send(Messages::DriverMessage(message)).unwrap();
+/// Writes the model's snapshot to `path`, every device understands `state save <file>`
+pub fn save_state<D: VirtioDevice + ?Sized>(device: &D, path: &str) -> Result<String, String> {
+    let state = device.snapshot().map_err(|err| format!("Couldn't save the device state: {err}"))?;
+    fs::write(path, &state).map_err(|err| format!("Couldn't write {path}: {err}"))?;
 
-        write_success_to_cell(cell);
-    } else {
-        let message = format!("Unknown flag of {}", cell.flags);
-        comms.blocking_send(Messages::DriverMessage(message)).unwrap();
-    }
+    Ok(format!("Saved {} bytes of device state to {path}", state.len()))
+}
+
+/// Hands a snapshot from `save_state` back to the model, its config space may have moved with it
+pub fn load_state<D: VirtioDevice + ?Sized>(device: &mut D, ctx: &mut DeviceContext, path: &str) -> Result<String, String> {
+    let state = fs::read(path).map_err(|err| format!("Couldn't read {path}: {err}"))?;
+    device.restore(&state).map_err(|err| format!("Couldn't load the device state: {err}"))?;
+    ctx.config_changed();
 
-    driver.submit_to_used_queue(idx);
+    Ok(format!("Loaded the device state from {path}"))
 }
 
-pub unsafe fn create_device_thread<const S: usize, P: PollableQueue + Clone>(ui_comms: Sender<Messages>, mut driver: DeviceDriver<S, P>) {
+/// Runs a device model until the process exits. Every queue of the device shares one poller,
+/// so any notification wakes the thread and all the queues get checked.
+pub unsafe fn create_device_thread<const S: usize, P: PollableQueue + Clone, D: VirtioDevice>(ui_comms: Sender<Messages>, mut device: D, mut drivers: Vec<DeviceDriver<S, P>>, commands: Receiver<String>) {
     ui_comms.blocking_send(Messages::DriverMessage("Hardware device booted!".to_string())).unwrap();
 
+    publish_device(&device, &drivers);
+
     loop {
-        while let Some((cell, idx)) = driver.poll_available_queue() {
-            let found_data = cell.as_mut().unwrap();
+        handle_transport_events(&ui_comms, &mut device, &mut drivers);
+
+        let transport = drivers[0].transport().clone();
+        let mut ctx = DeviceContext::new(&ui_comms, transport.clone());
 
-            read_message(&ui_comms, &mut driver, found_data, idx)
+        while let Ok(command) = commands.try_recv() {
+            let reply = match command.split_whitespace().collect::<Vec<_>>()[..] {
+                ["state", "save", path] => save_state(&device, path),
+                ["state", "load", path] => load_state(&mut device, &mut ctx, path),
+                _ => device.command(&mut ctx, &command),
+            };
+
+            ui_comms.blocking_send(Messages::DriverMessage(reply.unwrap_or_else(|err| err))).unwrap();
+        }
+
+        for (queue, driver) in drivers.iter_mut().enumerate() {
+            while let Some(idx) = driver.poll_available_queue() {
+                let chain = driver.chain(idx);
+
+                if let Some(length) = device.process_request(&mut ctx, queue as u16, chain) {
+                    driver.submit_to_used_queue(idx, length);
+                }
+            }
+        }
+
+        device.poll(&mut ctx);
+
+        for completion in ctx.completions.drain(..) {
+            drivers[completion.queue as usize].submit_to_used_queue(completion.head, completion.length);
+        }
+
+        if ctx.config_changed {
+            transport.lock().unwrap().set_config_space(device.config_space());
+            drivers[0].notify_config_change();
+        }
+
+        drop(ctx);
+
+        // Anything the driver queues meanwhile is picked up on the next lap either way
+        if device.wait_for_completions() {
+            continue;
         }
 
         ui_comms.blocking_send(Messages::DriverMessage("Waiting for epoll event".to_string())).unwrap();
-        driver.wait_for_event();
+
+        match device.completion_fd() {
+            Some(fd) => drivers[0].wait_for_event_or(fd),
+            None => drivers[0].wait_for_event(),
+        }
+
         ui_comms.blocking_send(Messages::DriverMessage("Epoll event Recieved".to_string())).unwrap();
     }
 }
+
+#[test]
+pub fn test_legacy_device_runs_from_the_transport() {
+    use std::thread;
+
+    use tokio::sync::mpsc::channel as ui_channel;
+
+    use crate::{mmio_trap::TrappedRegion, os_thread::{initialise_device, queue_rings, post_buffers}, virtio::{create_epoll_queues, transport::{TransportMode, TransportOptions}}, virtio_rng::VirtioRng};
+
+    let rng = VirtioRng::seeded(7);
+    let (mut guest_drivers, device_drivers) = create_epoll_queues::<8>(&rng, TransportOptions { mode: TransportMode::Legacy, ..Default::default() });
+    let (_control, commands) = DeviceControl::<String>::new(guest_drivers[0].poll_interface);
+
+    let (ui, mut messages) = ui_channel(16);
+    thread::spawn(move || while messages.blocking_recv().is_some() {});
+    thread::spawn(move || unsafe { create_device_thread(ui, rng, device_drivers, commands) });
+
+    let registers = TrappedRegion::new(guest_drivers[0].transport().clone()).unwrap();
+    let guest = &mut guest_drivers[0];
+
+    unsafe {
+        initialise_device(&registers, &queue_rings(std::slice::from_ref(guest))).unwrap();
+
+        // The queue only exists where the PFN says, the device has to have found it there
+        let ring = guest.vring();
+        assert_eq!(guest.transport().lock().unwrap().queue_vring(0).unwrap().used, ring.used);
+
+        post_buffers(guest, 1, 16);
+
+        let length = loop {
+            guest.poll_interface.wait_for_event();
+            guest.take_interrupts();
+
+            if let Some((_, _, length)) = guest.check_used_queue() {
+                break length;
+            }
+        };
+
+        assert_eq!(length, 16);
+    }
+}
+
+#[test]
+pub fn test_state_saves_and_loads() {
+    use tokio::sync::mpsc::channel as ui_channel;
+
+    use crate::{virtio::transport::{MmioTransport, TransportMode}, virtio_rng::VirtioRng};
+
+    let (tx, _rx) = ui_channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, 4, 0, &[8]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport);
+
+    let path = std::env::temp_dir().join(format!("virtio-state-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let saved = VirtioRng::seeded(7);
+    save_state(&saved, path).unwrap();
+
+    let mut loaded = VirtioRng::seeded(9);
+    load_state(&mut loaded, &mut ctx, path).unwrap();
+
+    assert_eq!(loaded.snapshot().unwrap(), saved.snapshot().unwrap());
+    assert!(ctx.config_changed);
+
+    // An unseeded rng has nothing to carry the seeded state over into
+    assert!(load_state(&mut VirtioRng::new(), &mut ctx, path).is_err());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+pub fn test_block_io_wakes_the_device_thread() {
+    use std::thread;
+
+    use tokio::sync::mpsc::channel as ui_channel;
+
+    use crate::{mmio_trap::TrappedRegion, os_thread::{initialise_device, queue_rings}, virtio::{create_epoll_queues, create_io_uring_queues, guest_driver::GuestDriver, transport::TransportOptions}, virtio_blk::{RequestHeader, VirtioBlk, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT}};
+
+    // The write and the read both go through the device's io_uring, the thread only sees them
+    // finish if it sleeps on the completions as well as the queue
+    unsafe fn run<P: PollableQueue + Clone + Send + 'static>(device: VirtioBlk, mut guest_drivers: Vec<GuestDriver<8, P>>, device_drivers: Vec<DeviceDriver<8, P>>) {
+        let (_control, commands) = DeviceControl::<String>::new(guest_drivers[0].poll_interface.clone());
+
+        let (ui, mut messages) = ui_channel(16);
+        thread::spawn(move || while messages.blocking_recv().is_some() {});
+        thread::spawn(move || create_device_thread(ui, device, device_drivers, commands));
+
+        let registers = TrappedRegion::new(guest_drivers[0].transport().clone()).unwrap();
+        let guest = &mut guest_drivers[0];
+        initialise_device(&registers, &queue_rings(std::slice::from_ref(guest))).unwrap();
+
+        let mut request = |request_type, data: Box<[u8]>| {
+            let header = RequestHeader { request_type, sector: 4 }.to_bytes();
+            let buffers = vec![(Box::from(header), false), (data, request_type == VIRTIO_BLK_T_IN), (Box::from([0xffu8]), true)];
+
+            guest.submit_chain(buffers).unwrap();
+
+            loop {
+                guest.poll_interface.wait_for_event();
+                guest.take_interrupts();
+
+                if let Some((head, index, length)) = guest.check_used_queue() {
+                    let data = &*head.sub(index as usize).add((*head).next as usize);
+                    break (length, std::slice::from_raw_parts(data.addr as *const u8, data.length as usize).to_vec());
+                }
+            }
+        };
+
+        assert_eq!(request(VIRTIO_BLK_T_OUT, Box::from([5u8; 512])).0, 1);
+        assert_eq!(request(VIRTIO_BLK_T_IN, Box::from([0u8; 512])), (513, vec![5u8; 512]));
+    }
+
+    let path = std::env::temp_dir().join(format!("virtio-blk-wake-{}.img", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    unsafe {
+        let device = VirtioBlk::open(path, 1 << 20, false).unwrap();
+        let (guest_drivers, device_drivers) = create_epoll_queues::<8>(&device, TransportOptions::default());
+        run(device, guest_drivers, device_drivers);
+
+        let device = VirtioBlk::open(path, 1 << 20, false).unwrap();
+        let (guest_drivers, device_drivers) = create_io_uring_queues::<8>(&device, TransportOptions::default());
+        run(device, guest_drivers, device_drivers);
+    }
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,290 @@
+// Flattened device tree generation for the virtio-mmio devices we expose. The tree is built in
+// memory first so the same description can be written out as a DTB or as DTS text for diffing.
+
+use std::collections::HashMap;
+
+use crate::virtio::{device_register::{CONFIG_SPACE, DEVICE_ID}, transport::MmioTransport};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+const HEADER_SIZE: usize = 40;
+const MEM_RSVMAP_SIZE: usize = 16;
+
+const INTC_PHANDLE: u32 = 1;
+
+// QEMU hands every virtio-mmio device a 0x200 window, we do the same unless the config space
+// needs more room.
+pub const MIN_WINDOW_SIZE: u64 = 0x200;
+
+pub const DEFAULT_MMIO_BASE: u64 = 0x0a00_0000;
+pub const DEFAULT_FIRST_IRQ: u32 = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropValue {
+    Empty,
+    Cells(Vec<u32>),
+    String(String),
+}
+
+impl PropValue {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            PropValue::Empty => Vec::new(),
+            PropValue::Cells(cells) => cells.iter().flat_map(|cell| cell.to_be_bytes()).collect(),
+            PropValue::String(value) => [value.as_bytes(), &[0]].concat(),
+        }
+    }
+
+    fn to_dts(&self) -> String {
+        match self {
+            PropValue::Empty => String::new(),
+            PropValue::Cells(cells) => {
+                let cells: Vec<String> = cells.iter().map(|cell| format!("0x{cell:x}")).collect();
+                format!(" = <{}>", cells.join(" "))
+            },
+            PropValue::String(value) => format!(" = \"{value}\""),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<(String, PropValue)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), ..Default::default() }
+    }
+
+    pub fn property(mut self, name: &str, value: PropValue) -> Self {
+        self.properties.push((name.to_string(), value));
+        self
+    }
+
+    pub fn child(mut self, node: Node) -> Self {
+        self.children.push(node);
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MmioDeviceLayout {
+    pub base: u64,
+    pub size: u64,
+    pub irq: u32,
+    pub device_id: u32,
+}
+
+/// Hands out addresses and interrupt lines to each device in the order they're added
+pub struct MmioTopology {
+    next_base: u64,
+    next_irq: u32,
+    pub devices: Vec<MmioDeviceLayout>,
+}
+
+impl Default for MmioTopology {
+    fn default() -> Self {
+        Self::new(DEFAULT_MMIO_BASE, DEFAULT_FIRST_IRQ)
+    }
+}
+
+impl MmioTopology {
+    pub fn new(base: u64, first_irq: u32) -> Self {
+        Self { next_base: base, next_irq: first_irq, devices: Vec::new() }
+    }
+
+    pub fn window_size(transport: &MmioTransport) -> u64 {
+        let used = CONFIG_SPACE as u64 + transport.config_len() as u64;
+        used.next_power_of_two().max(MIN_WINDOW_SIZE)
+    }
+
+    pub fn add(&mut self, transport: &MmioTransport) -> MmioDeviceLayout {
+        let size = Self::window_size(transport);
+
+        let layout = MmioDeviceLayout {
+            base: self.next_base,
+            size,
+            irq: self.next_irq,
+            device_id: transport.registers.get(DEVICE_ID),
+        };
+
+        self.next_base += size;
+        self.next_irq += 1;
+        self.devices.push(layout);
+
+        layout
+    }
+
+    pub fn to_tree(&self) -> Node {
+        let intc = Node::new("intc")
+            .property("compatible", PropValue::String("virtio-playground,intc".to_string()))
+            .property("interrupt-controller", PropValue::Empty)
+            .property("#interrupt-cells", PropValue::Cells(vec![1]))
+            .property("phandle", PropValue::Cells(vec![INTC_PHANDLE]));
+
+        let mut root = Node::new("")
+            .property("compatible", PropValue::String("virtio-playground".to_string()))
+            .property("#address-cells", PropValue::Cells(vec![2]))
+            .property("#size-cells", PropValue::Cells(vec![2]))
+            .property("interrupt-parent", PropValue::Cells(vec![INTC_PHANDLE]))
+            .child(intc);
+
+        for device in self.devices.iter() {
+            let node = Node::new(&format!("virtio_mmio@{:x}", device.base))
+                .property("compatible", PropValue::String("virtio,mmio".to_string()))
+                .property("reg", PropValue::Cells(vec![
+                    (device.base >> 32) as u32, device.base as u32,
+                    (device.size >> 32) as u32, device.size as u32,
+                ]))
+                .property("interrupts", PropValue::Cells(vec![device.irq]))
+                .property("dma-coherent", PropValue::Empty);
+
+            root = root.child(node);
+        }
+
+        root
+    }
+}
+
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl FdtWriter {
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+
+        offset
+    }
+
+    fn write_node(&mut self, node: &Node) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(node.name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+
+        for (name, value) in node.properties.iter() {
+            let bytes = value.to_bytes();
+            let name_offset = self.string_offset(name);
+
+            self.push_u32(FDT_PROP);
+            self.push_u32(bytes.len() as u32);
+            self.push_u32(name_offset);
+            self.structure.extend_from_slice(&bytes);
+            self.pad();
+        }
+
+        for child in node.children.iter() {
+            self.write_node(child);
+        }
+
+        self.push_u32(FDT_END_NODE);
+    }
+}
+
+pub fn to_dtb(root: &Node) -> Vec<u8> {
+    let mut writer = FdtWriter { structure: Vec::new(), strings: Vec::new(), string_offsets: HashMap::new() };
+
+    writer.write_node(root);
+    writer.push_u32(FDT_END);
+
+    let off_mem_rsvmap = HEADER_SIZE;
+    let off_dt_struct = off_mem_rsvmap + MEM_RSVMAP_SIZE;
+    let off_dt_strings = off_dt_struct + writer.structure.len();
+    let total_size = off_dt_strings + writer.strings.len();
+
+    let header = [
+        FDT_MAGIC,
+        total_size as u32,
+        off_dt_struct as u32,
+        off_dt_strings as u32,
+        off_mem_rsvmap as u32,
+        FDT_VERSION,
+        FDT_LAST_COMP_VERSION,
+        0,
+        writer.strings.len() as u32,
+        writer.structure.len() as u32,
+    ];
+
+    let mut blob: Vec<u8> = header.iter().flat_map(|value| value.to_be_bytes()).collect();
+    blob.extend_from_slice(&[0; MEM_RSVMAP_SIZE]);
+    blob.extend_from_slice(&writer.structure);
+    blob.extend_from_slice(&writer.strings);
+
+    blob
+}
+
+pub fn to_dts(root: &Node) -> String {
+    fn write_node(node: &Node, depth: usize, out: &mut String) {
+        let indent = "\t".repeat(depth);
+        let name = if node.name.is_empty() { "/" } else { &node.name };
+
+        out.push_str(&format!("{indent}{name} {{\n"));
+
+        for (name, value) in node.properties.iter() {
+            out.push_str(&format!("{indent}\t{name}{};\n", value.to_dts()));
+        }
+
+        for child in node.children.iter() {
+            out.push('\n');
+            write_node(child, depth + 1, out);
+        }
+
+        out.push_str(&format!("{indent}}};\n"));
+    }
+
+    let mut out = String::from("/dts-v1/;\n\n");
+    write_node(root, 0, &mut out);
+
+    out
+}
+
+#[test]
+pub fn test_generate_dtb() {
+    use crate::virtio::transport::TransportMode;
+
+    let mut topology = MmioTopology::default();
+    topology.add(&MmioTransport::new(TransportMode::Modern, 2, 0, &[64]));
+    topology.add(&MmioTransport::new(TransportMode::Modern, 3, 0, &[64, 64]));
+
+    assert_eq!(topology.devices[1].base, DEFAULT_MMIO_BASE + MIN_WINDOW_SIZE);
+    assert_eq!(topology.devices[1].irq, DEFAULT_FIRST_IRQ + 1);
+
+    let blob = to_dtb(&topology.to_tree());
+
+    assert_eq!(&blob[0..4], &FDT_MAGIC.to_be_bytes());
+    assert_eq!(u32::from_be_bytes(blob[4..8].try_into().unwrap()) as usize, blob.len());
+
+    let dts = to_dts(&topology.to_tree());
+    assert!(dts.contains("virtio_mmio@a000200 {"));
+    assert!(dts.contains("compatible = \"virtio,mmio\";"));
+}
@@ -1,4 +1,4 @@
-use libc::{c_int, epoll_event, EPOLLIN, epoll_create1, epoll_ctl, EPOLL_CTL_ADD, epoll_wait, c_void};
+use libc::{c_int, epoll_event, EPOLLIN, epoll_create1, epoll_ctl, EPOLL_CTL_ADD, EPOLL_CTL_DEL, epoll_wait, c_void};
 use crate::{comms::GLOBAL_COMMS, poller::PollableQueue};
 
 #[derive(Copy, Clone, Debug)]
@@ -30,6 +30,30 @@ impl PollableQueue for Epoll {
     fn submit_event(&self) {
         unsafe { notify_epoll_fd(self.publish_fd) }
     }
+
+    // The extra fd is only in the set for the one wait, tagged 1 so it can't be taken for a notification
+    fn wait_for_event_or(&self, fd: c_int) {
+        let mut events = [epoll_event { events: 0, u64: 0}; 10];
+        let mut other = epoll_event { events: EPOLLIN as u32, u64: 1 };
+
+        unsafe {
+            epoll_ctl(self.event_fd, EPOLL_CTL_ADD, fd, &mut other);
+
+            loop {
+                let n = epoll_wait(self.event_fd, events.as_mut_ptr(), events.len() as i32, -1);
+
+                if n > 0 {
+                    if events[..n as usize].iter().any(|event| event.u64 == 0) {
+                        read_buffer(self.listener_fd);
+                    }
+
+                    break;
+                }
+            }
+
+            epoll_ctl(self.event_fd, EPOLL_CTL_DEL, fd, &mut other);
+        }
+    }
 }
 
 unsafe impl Send for Epoll {}
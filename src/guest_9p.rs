@@ -0,0 +1,299 @@
+// A guest 9P2000.L client for virtio-9p, with the same file commands as the virtio-fs client so
+// the two shares can be compared on the same workload. Requests go one at a time and each waits
+// for its reply. The root is attached as fid 0 at startup, every command walks its own fids from
+// there and clunks them again when it's done.
+
+use futures::StreamExt;
+
+use crate::{async_driver::{DriverEvent, DriverPoller}, mmio_trap::TrappedRegion, os_thread::initialise_device, poller::PollableQueue};
+use crate::virtio::{device_register::CONFIG_SPACE, guest_driver::GuestDriver, vring::Vring};
+use crate::guest_fs::{errno_name, split_path};
+use crate::virtio_9p::protocol::*;
+
+// What we offer at Tversion, the device may settle on less
+const MSIZE: u32 = 16 * 1024;
+
+const ROOT_FID: u32 = 0;
+
+// What `cat` shows at most
+const MAX_CAT: usize = 64 * 1024;
+
+/// The mount tag, its length comes first in the config space
+unsafe fn read_mount_tag(registers: &TrappedRegion) -> String {
+    let mut config = registers.register(CONFIG_SPACE).read_volatile().to_le_bytes().to_vec();
+    let length = u16::from_le_bytes([config[0], config[1]]) as usize;
+
+    for offset in (4..2 + length).step_by(4) {
+        config.extend_from_slice(&registers.register(CONFIG_SPACE + offset as u32).read_volatile().to_le_bytes());
+    }
+
+    String::from_utf8_lossy(&config[2..2 + length]).into_owned()
+}
+
+pub struct NinePClient<'a, const S: usize, P: PollableQueue + Clone + Send> {
+    poller: DriverPoller<'a, S, P>,
+    driver: *mut GuestDriver<S, P>,
+    msize: u32,
+    tag: u16,
+    next_fid: u32,
+    // Fids walked for the command in progress
+    walked: Vec<u32>,
+}
+
+impl<'a, const S: usize, P: PollableQueue + Clone + Send + 'static> NinePClient<'a, S, P> {
+    pub fn new(driver: &'a mut GuestDriver<S, P>) -> Self {
+        let poller = DriverPoller::new(driver);
+        let driver = unsafe { poller.get_driver() };
+
+        poller.delayed_poller();
+
+        Self { poller, driver, msize: MSIZE, tag: 0, next_fid: ROOT_FID + 1, walked: Vec::new() }
+    }
+
+    fn message(&mut self, kind: u8) -> Writer {
+        self.tag = self.tag.wrapping_add(1);
+        Writer::new(kind, self.tag)
+    }
+
+    /// Sends one T-message and waits for its R-message, an error is the errno the device sent
+    async fn call(&mut self, request: Writer) -> Result<Vec<u8>, String> {
+        let request = request.finish();
+        let driver = unsafe { self.driver.as_mut().unwrap() };
+
+        let Some(head) = (unsafe { driver.submit_chain(vec![(request.into_boxed_slice(), false), (vec![0u8; self.msize as usize].into_boxed_slice(), true)]) }) else {
+            return Err("the request queue is full".to_string());
+        };
+
+        while let Some(event) = self.poller.next().await {
+            let DriverEvent::UsedBuffer { head: used, length, .. } = event else {
+                continue;
+            };
+
+            let buffers = unsafe { driver.release_chain(used) };
+
+            if used != head {
+                continue;
+            }
+
+            let reply = &buffers[1][..(length as usize).min(buffers[1].len())];
+
+            return match parse_message(reply) {
+                Some((RLERROR, _, body)) => Err(errno_name(Reader::new(body).u32().unwrap_or(libc::EIO as u32) as i32)),
+                Some((_, _, body)) => Ok(body.to_vec()),
+                None => Err("the device sent a short reply".to_string()),
+            };
+        }
+
+        Err("the device went away".to_string())
+    }
+
+    /// Brings the device up and attaches the root
+    pub async fn start(&mut self, registers: &TrappedRegion, rings: &[Vring]) -> Result<String, String> {
+        let device_id = unsafe { initialise_device(registers, rings)? };
+        let version = self.attach().await.map_err(|reason| format!("it refused to attach: {reason}"))?;
+
+        Ok(format!("with id {device_id}, {version} tagged {}", unsafe { read_mount_tag(registers) }))
+    }
+
+    /// Agrees on the version and message size, then attaches the root
+    async fn attach(&mut self) -> Result<String, String> {
+        let version = self.message(TVERSION).u32(MSIZE).string(VERSION);
+        let reply = self.call(version).await?;
+        let mut reader = Reader::new(&reply);
+        let (msize, version) = (reader.u32().unwrap_or(0), reader.string().unwrap_or(""));
+
+        if version != VERSION {
+            return Err(format!("the device speaks {version}"));
+        }
+
+        self.msize = msize.min(MSIZE);
+
+        let attach = self.message(TATTACH).u32(ROOT_FID).u32(NOFID).string("root").string("").u32(0);
+        self.call(attach).await?;
+
+        Ok(format!("{VERSION} with msize {}", self.msize))
+    }
+
+    /// Walks a new fid to `names` under the root
+    async fn walk(&mut self, names: &[&str]) -> Result<u32, String> {
+        let fid = self.next_fid;
+        self.next_fid = self.next_fid.checked_add(1).filter(|fid| *fid != NOFID).unwrap_or(ROOT_FID + 1);
+
+        let walk = names.iter().fold(self.message(TWALK).u32(ROOT_FID).u32(fid).u16(names.len() as u16), |walk, name| walk.string(name));
+        let reply = self.call(walk).await?;
+
+        // Part of the way means the rest wasn't there, and the fid wasn't bound
+        if Reader::new(&reply).u16() != Some(names.len() as u16) {
+            return Err(errno_name(libc::ENOENT));
+        }
+
+        self.walked.push(fid);
+        Ok(fid)
+    }
+
+    /// Walks to the directory `path` is in, returns its fid and the name after it
+    async fn walk_parent<'p>(&mut self, path: &'p str) -> Result<(u32, Option<&'p str>), String> {
+        let (directories, last) = split_path(path);
+        Ok((self.walk(&directories).await?, last))
+    }
+
+    async fn walk_path(&mut self, path: &str) -> Result<u32, String> {
+        let (mut names, last) = split_path(path);
+        names.extend(last);
+
+        self.walk(&names).await
+    }
+
+    /// Clunks every fid the last command walked
+    async fn clunk_all(&mut self) {
+        for fid in std::mem::take(&mut self.walked) {
+            let clunk = self.message(TCLUNK).u32(fid);
+            let _ = self.call(clunk).await;
+        }
+    }
+
+    fn io_size(&self) -> u32 {
+        self.msize - HEADER_SIZE as u32 - 4
+    }
+
+    async fn list(&mut self, path: &str) -> Result<String, String> {
+        let fid = self.walk_path(path).await?;
+        let open = self.message(TLOPEN).u32(fid).u32((libc::O_RDONLY | libc::O_DIRECTORY) as u32);
+        self.call(open).await?;
+
+        let mut names = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let readdir = self.message(TREADDIR).u32(fid).u64(offset).u32(self.io_size());
+            let reply = self.call(readdir).await?;
+            let entries = parse_dirents(&reply[4.min(reply.len())..]);
+
+            let Some((last, ..)) = entries.last() else {
+                break;
+            };
+
+            offset = *last;
+            names.extend(entries.into_iter().filter(|(_, _, name)| name != "." && name != "..").map(|(_, kind, name)| match kind {
+                libc::DT_DIR => format!("{name}/"),
+                _ => name,
+            }));
+        }
+
+        names.sort();
+        Ok(names.into_iter().map(|name| format!("{name}\n")).collect())
+    }
+
+    async fn cat(&mut self, path: &str) -> Result<String, String> {
+        let fid = self.walk_path(path).await?;
+        let open = self.message(TLOPEN).u32(fid).u32(libc::O_RDONLY as u32);
+        self.call(open).await?;
+
+        let mut contents = Vec::new();
+
+        while contents.len() < MAX_CAT {
+            let read = self.message(TREAD).u32(fid).u64(contents.len() as u64).u32(self.io_size());
+            let reply = self.call(read).await?;
+            let mut reader = Reader::new(&reply);
+            let count = reader.u32().unwrap_or(0) as usize;
+
+            match reader.bytes(count) {
+                Some(data) if !data.is_empty() => contents.extend_from_slice(data),
+                _ => break,
+            }
+        }
+
+        let mut text = String::from_utf8_lossy(&contents).into_owned();
+
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+
+        Ok(text)
+    }
+
+    async fn put(&mut self, path: &str, text: &str) -> Result<String, String> {
+        let (fid, Some(name)) = self.walk_parent(path).await? else {
+            return Err("expected a file name".to_string());
+        };
+
+        // The directory's fid becomes the new file's
+        let create = self.message(TLCREATE).u32(fid).string(name).u32((libc::O_WRONLY | libc::O_TRUNC) as u32).u32(0o644).u32(0);
+        self.call(create).await?;
+
+        let data = format!("{text}\n");
+        let write = self.message(TWRITE).u32(fid).u64(0).u32(data.len() as u32).bytes(data.as_bytes());
+        let written = Reader::new(&self.call(write).await?).u32().unwrap_or(0);
+
+        Ok(format!("Wrote {written} bytes to {path}\n"))
+    }
+
+    async fn remove(&mut self, path: &str, flags: u32) -> Result<String, String> {
+        let (fid, Some(name)) = self.walk_parent(path).await? else {
+            return Err("can't remove the root".to_string());
+        };
+
+        let unlink = self.message(TUNLINKAT).u32(fid).string(name).u32(flags);
+        self.call(unlink).await?;
+
+        Ok(String::new())
+    }
+
+    async fn make_directory(&mut self, path: &str) -> Result<String, String> {
+        let (fid, Some(name)) = self.walk_parent(path).await? else {
+            return Err("the root is already there".to_string());
+        };
+
+        let mkdir = self.message(TMKDIR).u32(fid).string(name).u32(0o755).u32(0);
+        self.call(mkdir).await?;
+
+        Ok(String::new())
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> Result<String, String> {
+        let (from_fid, Some(from_name)) = self.walk_parent(from).await? else {
+            return Err("expected two file names".to_string());
+        };
+
+        let (to_fid, Some(to_name)) = self.walk_parent(to).await? else {
+            return Err("expected two file names".to_string());
+        };
+
+        let rename = self.message(TRENAMEAT).u32(from_fid).string(from_name).u32(to_fid).string(to_name);
+        self.call(rename).await?;
+
+        Ok(String::new())
+    }
+
+    async fn statfs(&mut self) -> Result<String, String> {
+        let statfs = self.message(TSTATFS).u32(ROOT_FID);
+        let reply = self.call(statfs).await?;
+        let mut reader = Reader::new(&reply);
+        let (_kind, block_size) = (reader.u32(), reader.u32().unwrap_or(0) as u64);
+        let (blocks, free, available) = (reader.u64().unwrap_or(0), reader.u64().unwrap_or(0), reader.u64().unwrap_or(0));
+
+        Ok(format!("{} KiB, {} KiB free, {} KiB available\n", blocks * block_size / 1024, free * block_size / 1024, available * block_size / 1024))
+    }
+
+    /// Runs one of the shell's file commands, what comes back is shown on the console
+    pub async fn run(&mut self, command: &str, arguments: &str) -> String {
+        let mut arguments = arguments.split_whitespace();
+        let (first, second) = (arguments.next().unwrap_or(""), arguments.collect::<Vec<_>>().join(" "));
+
+        let result = match command {
+            "ls" => self.list(first).await,
+            "cat" => self.cat(first).await,
+            "put" => self.put(first, &second).await,
+            "rm" => self.remove(first, 0).await,
+            "rmdir" => self.remove(first, AT_REMOVEDIR).await,
+            "mkdir" => self.make_directory(first).await,
+            "mv" => self.rename(first, &second).await,
+            "df" => self.statfs().await,
+            _ => Err("not a file command".to_string()),
+        };
+
+        self.clunk_all().await;
+
+        result.unwrap_or_else(|reason| format!("{command}: {reason}\n"))
+    }
+}
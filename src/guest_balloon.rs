@@ -0,0 +1,268 @@
+// The guest side of the balloon, a page allocator over the guest's RAM that gives pages up to
+// the device when the host asks for them. Programs allocate from the bottom of memory and the
+// balloon inflates from the top. Inflating and deflating go one batch at a time, after each the
+// driver updates `actual` and looks at the target again. Freed pages are reported to the device
+// in aligned runs, and the stats queue always has the latest numbers waiting.
+
+use std::sync::Arc;
+
+use crate::{mmio_trap::TrappedRegion, os_thread::initialise_device, poller::PollableQueue};
+use crate::virtio::{device_register::CONFIG_SPACE, guest_driver::GuestDriver, guest_memory::GuestMemory, vring::Vring};
+use crate::virtio_balloon::{DEFLATEQ, INFLATEQ, REPORTINGQ, STATSQ, BALLOON_PAGE_SIZE, stats::*};
+
+// The most page frame numbers one inflate or deflate buffer carries, same as Linux
+const BATCH_PAGES: usize = 256;
+
+// Free pages are reported in aligned runs of this many, smaller holes aren't worth the trouble
+const REPORT_PAGES: u32 = 64;
+const MAX_REPORT_RANGES: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Page {
+    Free,
+    // Free and the host already has it back, through reporting or a deflate
+    Reported,
+    Reporting,
+    Used,
+    Inflating,
+    Ballooned,
+    Deflating,
+}
+
+fn mib(pages: usize) -> u64 {
+    (pages as u64 * BALLOON_PAGE_SIZE) >> 20
+}
+
+pub struct GuestBalloon {
+    registers: TrappedRegion,
+    memory: Arc<GuestMemory>,
+    pages: Vec<Page>,
+    // The inflate or deflate batch the device has
+    batch: Option<(u16, Vec<u32>)>,
+    reporting: Option<(u16, Vec<(u32, u32)>)>,
+    faults: u64,
+}
+
+impl GuestBalloon {
+    fn new(registers: TrappedRegion, memory: Arc<GuestMemory>) -> Self {
+        // Nothing has touched memory yet, so the host isn't holding any of it
+        let pages = vec![Page::Reported; memory.page_count() as usize];
+
+        Self { registers, memory, pages, batch: None, reporting: None, faults: 0 }
+    }
+
+    fn count(&self, state: Page) -> usize {
+        self.pages.iter().filter(|page| **page == state).count()
+    }
+
+    fn is_free(page: Page) -> bool {
+        page == Page::Free || page == Page::Reported
+    }
+
+    fn stats(&self) -> Vec<(u16, u64)> {
+        let free = (self.count(Page::Free) + self.count(Page::Reported) + self.count(Page::Reporting)) as u64 * BALLOON_PAGE_SIZE;
+        let ballooned = (self.count(Page::Ballooned) + self.count(Page::Inflating)) as u64 * BALLOON_PAGE_SIZE;
+
+        vec![
+            (VIRTIO_BALLOON_S_SWAP_IN, 0),
+            (VIRTIO_BALLOON_S_SWAP_OUT, 0),
+            (VIRTIO_BALLOON_S_MAJFLT, 0),
+            (VIRTIO_BALLOON_S_MINFLT, self.faults),
+            (VIRTIO_BALLOON_S_MEMFREE, free),
+            (VIRTIO_BALLOON_S_MEMTOT, self.memory.size() - ballooned),
+            (VIRTIO_BALLOON_S_AVAIL, free),
+        ]
+    }
+
+    /// Brings the device up, gives it the stats buffer and catches up with whatever target is
+    /// already set
+    pub unsafe fn start<const S: usize, P: PollableQueue + Clone>(registers: TrappedRegion, rings: &[Vring], memory: Arc<GuestMemory>, queue: impl Fn(u16) -> *mut GuestDriver<S, P>) -> Result<(Self, String), String> {
+        let device_id = initialise_device(&registers, rings)?;
+        let size = memory.size() >> 20;
+
+        let mut balloon = Self::new(registers, memory);
+        balloon.post_stats(&queue);
+        balloon.step(&queue);
+
+        Ok((balloon, format!("with id {device_id}, {size} MiB of memory")))
+    }
+
+    unsafe fn post_stats<const S: usize, P: PollableQueue + Clone>(&self, queue: &impl Fn(u16) -> *mut GuestDriver<S, P>) {
+        let _ = queue(STATSQ).as_mut().unwrap().submit_chain(vec![(to_bytes(&self.stats()).into_boxed_slice(), false)]);
+    }
+
+    /// Sends the next batch towards the target if none is out, returns false once it's there
+    pub unsafe fn step<const S: usize, P: PollableQueue + Clone>(&mut self, queue: &impl Fn(u16) -> *mut GuestDriver<S, P>) -> bool {
+        if self.batch.is_some() {
+            return true;
+        }
+
+        let target = self.registers.register(CONFIG_SPACE).read_volatile() as usize;
+        let ballooned = self.count(Page::Ballooned);
+
+        let (balloon_queue, state, pfns): (_, _, Vec<u32>) = if target > ballooned {
+            let pfns = (0..self.pages.len() as u32).rev().filter(|pfn| Self::is_free(self.pages[*pfn as usize])).take((target - ballooned).min(BATCH_PAGES)).collect();
+            (INFLATEQ, Page::Inflating, pfns)
+        } else {
+            let pfns = (0..self.pages.len() as u32).filter(|pfn| self.pages[*pfn as usize] == Page::Ballooned).take((ballooned - target).min(BATCH_PAGES)).collect();
+            (DEFLATEQ, Page::Deflating, pfns)
+        };
+
+        // Out of free pages to give is as far as the balloon goes
+        if pfns.is_empty() {
+            return false;
+        }
+
+        let buffer: Vec<u8> = pfns.iter().flat_map(|pfn| pfn.to_le_bytes()).collect();
+
+        if let Some(head) = queue(balloon_queue).as_mut().unwrap().submit_chain(vec![(buffer.into_boxed_slice(), false)]) {
+            for pfn in pfns.iter() {
+                self.pages[*pfn as usize] = state;
+            }
+
+            self.batch = Some((head, pfns));
+        }
+
+        true
+    }
+
+    /// Tells the device about aligned runs of free pages it doesn't have yet
+    unsafe fn report<const S: usize, P: PollableQueue + Clone>(&mut self, queue: &impl Fn(u16) -> *mut GuestDriver<S, P>) {
+        if self.reporting.is_some() {
+            return;
+        }
+
+        let ranges: Vec<(u32, u32)> = (0..self.pages.len() as u32).step_by(REPORT_PAGES as usize)
+            .filter(|pfn| self.pages[*pfn as usize..].iter().take(REPORT_PAGES as usize).all(|page| *page == Page::Free))
+            .filter(|pfn| pfn + REPORT_PAGES <= self.pages.len() as u32)
+            .take(MAX_REPORT_RANGES)
+            .map(|pfn| (pfn, REPORT_PAGES))
+            .collect();
+
+        if ranges.is_empty() {
+            return;
+        }
+
+        let memory = ranges.iter().map(|(pfn, count)| (self.memory.page_address(*pfn).unwrap(), count * BALLOON_PAGE_SIZE as u32, true)).collect();
+
+        if let Some(head) = queue(REPORTINGQ).as_mut().unwrap().submit_memory(memory) {
+            for (pfn, count) in ranges.iter() {
+                self.pages[*pfn as usize..(pfn + count) as usize].fill(Page::Reporting);
+            }
+
+            self.reporting = Some((head, ranges));
+        }
+    }
+
+    /// The device is done with a buffer, returns anything worth telling the user
+    pub unsafe fn used<const S: usize, P: PollableQueue + Clone>(&mut self, queue: impl Fn(u16) -> *mut GuestDriver<S, P>, used: u16, head: u16) -> Option<String> {
+        match used {
+            INFLATEQ | DEFLATEQ => {
+                queue(used).as_mut().unwrap().release_chain(head);
+
+                let (_, pfns) = self.batch.take()?;
+                let state = if used == INFLATEQ { Page::Ballooned } else { Page::Reported };
+
+                for pfn in pfns {
+                    self.pages[pfn as usize] = state;
+                }
+
+                let actual = self.count(Page::Ballooned) as u32;
+                self.registers.register(CONFIG_SPACE + 4).write_volatile(actual);
+
+                if !self.step(&queue) {
+                    let target = self.registers.register(CONFIG_SPACE).read_volatile();
+                    return (target != actual).then(|| format!("The balloon stopped at {} MiB, the guest has no more free memory", mib(actual as usize)));
+                }
+
+                None
+            },
+            STATSQ => {
+                queue(STATSQ).as_mut().unwrap().release_chain(head);
+                self.post_stats(&queue);
+                None
+            },
+            _ => {
+                queue(REPORTINGQ).as_mut().unwrap().release_memory(head);
+
+                for (pfn, count) in self.reporting.take()?.1 {
+                    self.pages[pfn as usize..(pfn + count) as usize].fill(Page::Reported);
+                }
+
+                self.report(&queue);
+                None
+            },
+        }
+    }
+
+    /// The target may have moved
+    pub unsafe fn config_changed<const S: usize, P: PollableQueue + Clone>(&mut self, queue: impl Fn(u16) -> *mut GuestDriver<S, P>) -> String {
+        let target = self.registers.register(CONFIG_SPACE).read_volatile() as usize;
+        self.step(&queue);
+
+        format!("The balloon target is now {} MiB", mib(target))
+    }
+
+    fn allocate(&mut self, pages: usize) -> usize {
+        let mut allocated = 0;
+
+        for pfn in 0..self.pages.len() {
+            if allocated == pages {
+                break;
+            }
+
+            if Self::is_free(self.pages[pfn]) {
+                self.memory.touch(pfn as u32);
+                self.pages[pfn] = Page::Used;
+                self.faults += 1;
+                allocated += 1;
+            }
+        }
+
+        allocated
+    }
+
+    /// Frees the most recently allocated pages, they stay resident until they're reported
+    fn free(&mut self, pages: usize) -> usize {
+        let used: Vec<usize> = (0..self.pages.len()).rev().filter(|pfn| self.pages[*pfn] == Page::Used).take(pages).collect();
+
+        for pfn in used.iter() {
+            self.pages[*pfn] = Page::Free;
+        }
+
+        used.len()
+    }
+
+    /// The shell's memory commands
+    pub unsafe fn run<const S: usize, P: PollableQueue + Clone>(&mut self, queue: impl Fn(u16) -> *mut GuestDriver<S, P>, command: &str, arguments: &str) -> String {
+        let pages = arguments.trim().parse::<u64>().ok().map(|size| (size * (1 << 20) / BALLOON_PAGE_SIZE) as usize);
+
+        let output = match (command, pages) {
+            ("mem", _) => {
+                let free = self.count(Page::Free) + self.count(Page::Reported) + self.count(Page::Reporting);
+                let ballooned = self.count(Page::Ballooned) + self.count(Page::Inflating) + self.count(Page::Deflating);
+
+                format!(
+                    "{} MiB: {} MiB used, {} MiB ballooned, {} MiB free of which {} MiB reported\n",
+                    mib(self.pages.len()), mib(self.count(Page::Used)), mib(ballooned), mib(free), mib(self.count(Page::Reported)),
+                )
+            },
+            ("alloc", Some(pages)) => {
+                let allocated = self.allocate(pages);
+
+                match allocated < pages {
+                    true => format!("alloc: out of memory after {} MiB\n", mib(allocated)),
+                    false => format!("Allocated {} MiB\n", mib(allocated)),
+                }
+            },
+            ("free", Some(pages)) => format!("Freed {} MiB\n", mib(self.free(pages))),
+            (command, _) => format!("{command}: expected a size in MiB\n"),
+        };
+
+        // Freed memory may make whole runs, and may let a stalled balloon carry on
+        self.report(&queue);
+        self.step(&queue);
+
+        output
+    }
+}
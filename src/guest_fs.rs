@@ -0,0 +1,371 @@
+// A guest FUSE client for virtio-fs, enough for the console shell to list, read and write files
+// in the shared directory. Requests go one at a time: each is put on the request queue and the
+// client waits for its reply before the next, the way a single threaded program would see it.
+// Everything it looks up it forgets again on the hiprio queue once the command is done.
+
+use futures::StreamExt;
+
+use crate::{async_driver::{DriverEvent, DriverPoller}, mmio_trap::TrappedRegion, os_thread::initialise_device, poller::PollableQueue};
+use crate::virtio::{device_register::{CONFIG_SPACE, SHM_SEL, SHM_LEN_LOW, SHM_LEN_HIGH}, guest_driver::GuestDriver, vring::Vring};
+use crate::virtio_fs::{HIPRIO_QUEUE, REQUEST_QUEUE, TAG_SIZE, DAX_WINDOW, fuse::*};
+
+// The biggest reply the shell waits for, a directory listing or a chunk of a file
+const REPLY_SIZE: usize = 16 * 1024;
+const MAX_READ: u32 = (REPLY_SIZE - OUT_HEADER_SIZE) as u32;
+
+// What `cat` shows at most
+const MAX_CAT: usize = 64 * 1024;
+
+pub(crate) fn errno_name(errno: i32) -> String {
+    match errno {
+        libc::ENOENT => "no such file or directory".to_string(),
+        libc::EEXIST => "already exists".to_string(),
+        libc::ENOTDIR => "not a directory".to_string(),
+        libc::EISDIR => "is a directory".to_string(),
+        libc::ENOTEMPTY => "directory not empty".to_string(),
+        libc::EINVAL => "invalid argument".to_string(),
+        libc::EACCES | libc::EPERM => "permission denied".to_string(),
+        libc::ELOOP => "is a symlink".to_string(),
+        errno => format!("error {errno}"),
+    }
+}
+
+fn name_bytes(name: &str) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.push(0);
+
+    bytes
+}
+
+/// Splits a shell path into its directories and last name, `/` and empty parts are ignored
+pub(crate) fn split_path(path: &str) -> (Vec<&str>, Option<&str>) {
+    let mut parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    let last = parts.pop();
+
+    (parts, last)
+}
+
+/// The tag the share is mounted by, the start of the config space
+unsafe fn read_tag(registers: &TrappedRegion) -> String {
+    let mut tag = Vec::with_capacity(TAG_SIZE);
+
+    for offset in (0..TAG_SIZE).step_by(4) {
+        tag.extend_from_slice(&registers.register(CONFIG_SPACE + offset as u32).read_volatile().to_le_bytes());
+    }
+
+    String::from_utf8_lossy(&tag).trim_end_matches('\0').to_string()
+}
+
+/// How much DAX window the device offers, a region that isn't there reads back a length of -1
+unsafe fn read_dax_window(registers: &TrappedRegion) -> String {
+    registers.register(SHM_SEL).write_volatile(DAX_WINDOW as u32);
+
+    let low = registers.register(SHM_LEN_LOW).read_volatile() as u64;
+    let high = registers.register(SHM_LEN_HIGH).read_volatile() as u64;
+
+    match high << 32 | low {
+        0 | u64::MAX => "no DAX window".to_string(),
+        length => format!("a {} KiB DAX window", length / 1024),
+    }
+}
+
+pub struct FsClient<'a, const S: usize, P: PollableQueue + Clone + Send> {
+    poller: DriverPoller<'a, S, P>,
+    hiprio: *mut GuestDriver<S, P>,
+    requests: *mut GuestDriver<S, P>,
+    unique: u64,
+    // Nodes looked up for the command in progress
+    looked_up: Vec<u64>,
+}
+
+impl<'a, const S: usize, P: PollableQueue + Clone + Send + 'static> FsClient<'a, S, P> {
+    pub fn new(drivers: Vec<&'a mut GuestDriver<S, P>>) -> Self {
+        let poller = DriverPoller::with_queues(drivers);
+        let (hiprio, requests) = unsafe { (poller.get_queue_driver(HIPRIO_QUEUE), poller.get_queue_driver(REQUEST_QUEUE)) };
+
+        poller.delayed_poller();
+
+        Self { poller, hiprio, requests, unique: 0, looked_up: Vec::new() }
+    }
+
+    fn request(&mut self, opcode: u32, nodeid: u64, body: &[u8]) -> Vec<u8> {
+        self.unique += 1;
+
+        let header = InHeader { len: (IN_HEADER_SIZE + body.len()) as u32, opcode, unique: self.unique, nodeid, ..InHeader::default() };
+        let mut request = header.to_bytes().to_vec();
+        request.extend_from_slice(body);
+
+        request
+    }
+
+    /// Sends one request and waits for its reply, an error is the errno the device sent
+    async fn call(&mut self, opcode: u32, nodeid: u64, body: &[u8], reply_size: usize) -> Result<Vec<u8>, String> {
+        let request = self.request(opcode, nodeid, body);
+        let requests = unsafe { self.requests.as_mut().unwrap() };
+
+        let Some(head) = (unsafe { requests.submit_chain(vec![(request.into_boxed_slice(), false), (vec![0u8; OUT_HEADER_SIZE + reply_size].into_boxed_slice(), true)]) }) else {
+            return Err("the request queue is full".to_string());
+        };
+
+        while let Some(event) = self.poller.next().await {
+            let DriverEvent::UsedBuffer { queue, head: used, length, .. } = event else {
+                continue;
+            };
+
+            let driver = match queue {
+                HIPRIO_QUEUE => self.hiprio,
+                _ => self.requests,
+            };
+
+            let buffers = unsafe { driver.as_mut().unwrap().release_chain(used) };
+
+            if queue != REQUEST_QUEUE || used != head {
+                continue;
+            }
+
+            let reply = &buffers[1][..(length as usize).min(buffers[1].len())];
+
+            return match parse_reply(reply) {
+                Some((_, 0, body)) => Ok(body.to_vec()),
+                Some((_, error, _)) => Err(errno_name(-error)),
+                None => Err("the device sent a short reply".to_string()),
+            };
+        }
+
+        Err("the device went away".to_string())
+    }
+
+    /// Brings the device up and agrees on a FUSE version with it
+    pub async fn start(&mut self, registers: &TrappedRegion, rings: &[Vring]) -> Result<String, String> {
+        let device_id = unsafe { initialise_device(registers, rings)? };
+        let version = self.init().await.map_err(|reason| format!("it refused FUSE INIT: {reason}"))?;
+
+        Ok(format!("with id {device_id}, {version} tagged {} with {}", unsafe { read_tag(registers) }, unsafe { read_dax_window(registers) }))
+    }
+
+    async fn init(&mut self) -> Result<String, String> {
+        let mut body = Vec::new();
+
+        for value in [FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION, 0, FUSE_ASYNC_READ | FUSE_BIG_WRITES] {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+
+        body.resize(64, 0);
+
+        let reply = self.call(FUSE_INIT, 0, &body, INIT_OUT_SIZE).await?;
+        let mut reader = Reader::new(&reply);
+        let (major, minor) = (reader.u32().unwrap_or(0), reader.u32().unwrap_or(0));
+
+        Ok(format!("FUSE {major}.{minor}"))
+    }
+
+    async fn lookup(&mut self, parent: u64, name: &str) -> Result<(u64, u32), String> {
+        let reply = self.call(FUSE_LOOKUP, parent, &name_bytes(name), ENTRY_OUT_SIZE).await?;
+        let nodeid = Reader::new(&reply).u64().ok_or("the device sent a short entry")?;
+        self.looked_up.push(nodeid);
+
+        // The mode is 60 bytes into the attributes
+        let mode = Reader::new(&reply[40 + 60..]).u32().unwrap_or(0);
+
+        Ok((nodeid, mode))
+    }
+
+    /// Walks the directories of `path`, returns the node of the last one and the name after it
+    async fn resolve_parent<'p>(&mut self, path: &'p str) -> Result<(u64, Option<&'p str>), String> {
+        let (directories, last) = split_path(path);
+        let mut nodeid = FUSE_ROOT_ID;
+
+        for directory in directories {
+            nodeid = self.lookup(nodeid, directory).await?.0;
+        }
+
+        Ok((nodeid, last))
+    }
+
+    async fn resolve(&mut self, path: &str) -> Result<(u64, u32), String> {
+        match self.resolve_parent(path).await? {
+            (parent, Some(name)) => self.lookup(parent, name).await,
+            (root, None) => Ok((root, libc::S_IFDIR)),
+        }
+    }
+
+    /// Gives back every lookup the last command made, the device can let go of those nodes
+    unsafe fn forget_all(&mut self) {
+        let hiprio = self.hiprio.as_mut().unwrap();
+
+        for nodeid in std::mem::take(&mut self.looked_up) {
+            let request = self.request(FUSE_FORGET, nodeid, &1u64.to_le_bytes());
+            hiprio.submit_chain(vec![(request.into_boxed_slice(), false)]);
+        }
+    }
+
+    async fn list(&mut self, path: &str) -> Result<String, String> {
+        let (nodeid, _) = self.resolve(path).await?;
+        let fh = Reader::new(&self.call(FUSE_OPENDIR, nodeid, &[0; 8], OPEN_OUT_SIZE).await?).u64().unwrap_or(0);
+
+        let mut names = Vec::new();
+        let mut offset = 0u64;
+
+        loop {
+            let mut body = Vec::new();
+            body.extend_from_slice(&fh.to_le_bytes());
+            body.extend_from_slice(&offset.to_le_bytes());
+            body.extend_from_slice(&MAX_READ.to_le_bytes());
+            body.resize(READ_IN_SIZE, 0);
+
+            let entries = parse_dirents(&self.call(FUSE_READDIR, nodeid, &body, MAX_READ as usize).await?);
+
+            let Some((last, ..)) = entries.last() else {
+                break;
+            };
+
+            offset = *last;
+            names.extend(entries.into_iter().filter(|(_, _, name)| name != "." && name != "..").map(|(_, kind, name)| match kind {
+                kind if kind == libc::DT_DIR as u32 => format!("{name}/"),
+                _ => name,
+            }));
+        }
+
+        self.call(FUSE_RELEASEDIR, nodeid, &[fh.to_le_bytes().as_slice(), &[0; 16]].concat(), 0).await?;
+        names.sort();
+
+        Ok(names.into_iter().map(|name| format!("{name}\n")).collect())
+    }
+
+    async fn cat(&mut self, path: &str) -> Result<String, String> {
+        let (nodeid, _) = self.resolve(path).await?;
+        let fh = Reader::new(&self.call(FUSE_OPEN, nodeid, &[0; 8], OPEN_OUT_SIZE).await?).u64().unwrap_or(0);
+
+        let mut contents = Vec::new();
+
+        while contents.len() < MAX_CAT {
+            let mut body = Vec::new();
+            body.extend_from_slice(&fh.to_le_bytes());
+            body.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            body.extend_from_slice(&MAX_READ.to_le_bytes());
+            body.resize(READ_IN_SIZE, 0);
+
+            let data = self.call(FUSE_READ, nodeid, &body, MAX_READ as usize).await?;
+
+            if data.is_empty() {
+                break;
+            }
+
+            contents.extend_from_slice(&data);
+        }
+
+        self.call(FUSE_RELEASE, nodeid, &[fh.to_le_bytes().as_slice(), &[0; 16]].concat(), 0).await?;
+
+        let mut text = String::from_utf8_lossy(&contents).into_owned();
+
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+
+        Ok(text)
+    }
+
+    async fn put(&mut self, path: &str, text: &str) -> Result<String, String> {
+        let (parent, Some(name)) = self.resolve_parent(path).await? else {
+            return Err("expected a file name".to_string());
+        };
+
+        let mut body = Vec::new();
+
+        for value in [(libc::O_WRONLY | libc::O_TRUNC) as u32, libc::S_IFREG | 0o644, 0o022, 0] {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+
+        body.extend_from_slice(&name_bytes(name));
+
+        let reply = self.call(FUSE_CREATE, parent, &body, ENTRY_OUT_SIZE + OPEN_OUT_SIZE).await?;
+        let nodeid = Reader::new(&reply).u64().unwrap_or(0);
+        let fh = Reader::new(&reply[ENTRY_OUT_SIZE..]).u64().unwrap_or(0);
+        self.looked_up.push(nodeid);
+
+        let data = format!("{text}\n");
+        let mut body = Vec::new();
+        body.extend_from_slice(&fh.to_le_bytes());
+        body.extend_from_slice(&0u64.to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.resize(WRITE_IN_SIZE, 0);
+        body.extend_from_slice(data.as_bytes());
+
+        let written = Reader::new(&self.call(FUSE_WRITE, nodeid, &body, 8).await?).u32().unwrap_or(0);
+        self.call(FUSE_RELEASE, nodeid, &[fh.to_le_bytes().as_slice(), &[0; 16]].concat(), 0).await?;
+
+        Ok(format!("Wrote {written} bytes to {path}\n"))
+    }
+
+    async fn remove(&mut self, path: &str, opcode: u32) -> Result<String, String> {
+        let (parent, Some(name)) = self.resolve_parent(path).await? else {
+            return Err("can't remove the root".to_string());
+        };
+
+        self.call(opcode, parent, &name_bytes(name), 0).await?;
+        Ok(String::new())
+    }
+
+    async fn make_directory(&mut self, path: &str) -> Result<String, String> {
+        let (parent, Some(name)) = self.resolve_parent(path).await? else {
+            return Err("the root is already there".to_string());
+        };
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&0o755u32.to_le_bytes());
+        body.extend_from_slice(&0o022u32.to_le_bytes());
+        body.extend_from_slice(&name_bytes(name));
+
+        let nodeid = Reader::new(&self.call(FUSE_MKDIR, parent, &body, ENTRY_OUT_SIZE).await?).u64().unwrap_or(0);
+        self.looked_up.push(nodeid);
+
+        Ok(String::new())
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> Result<String, String> {
+        let (Some(from_name), Some(to_name)) = (split_path(from).1, split_path(to).1) else {
+            return Err("expected two file names".to_string());
+        };
+
+        let (from_parent, _) = self.resolve_parent(from).await?;
+        let (to_parent, _) = self.resolve_parent(to).await?;
+
+        let mut body = to_parent.to_le_bytes().to_vec();
+        body.extend_from_slice(&name_bytes(from_name));
+        body.extend_from_slice(&name_bytes(to_name));
+
+        self.call(FUSE_RENAME, from_parent, &body, 0).await?;
+        Ok(String::new())
+    }
+
+    async fn statfs(&mut self) -> Result<String, String> {
+        let reply = self.call(FUSE_STATFS, FUSE_ROOT_ID, &[], 80).await?;
+        let mut reader = Reader::new(&reply);
+        let (blocks, free, available) = (reader.u64().unwrap_or(0), reader.u64().unwrap_or(0), reader.u64().unwrap_or(0));
+        let (_files, _free_files, block_size) = (reader.u64(), reader.u64(), reader.u32().unwrap_or(0) as u64);
+
+        Ok(format!("{} KiB, {} KiB free, {} KiB available\n", blocks * block_size / 1024, free * block_size / 1024, available * block_size / 1024))
+    }
+
+    /// Runs one of the shell's file commands, what comes back is shown on the console
+    pub async fn run(&mut self, command: &str, arguments: &str) -> String {
+        let mut arguments = arguments.split_whitespace();
+        let (first, second) = (arguments.next().unwrap_or(""), arguments.collect::<Vec<_>>().join(" "));
+
+        let result = match command {
+            "ls" => self.list(first).await,
+            "cat" => self.cat(first).await,
+            "put" => self.put(first, &second).await,
+            "rm" => self.remove(first, FUSE_UNLINK).await,
+            "rmdir" => self.remove(first, FUSE_RMDIR).await,
+            "mkdir" => self.make_directory(first).await,
+            "mv" => self.rename(first, &second).await,
+            "df" => self.statfs().await,
+            _ => Err("not a file command".to_string()),
+        };
+
+        unsafe { self.forget_all() };
+
+        result.unwrap_or_else(|reason| format!("{command}: {reason}\n"))
+    }
+}
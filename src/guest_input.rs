@@ -0,0 +1,117 @@
+// The guest end of virtio-input, an evtest of sorts. At startup it pages through the config
+// space the way the Linux driver does to find out what the device is, then every report the
+// device sends, everything up to a SYN_REPORT, shows up as one line. The lock keys toggle their
+// LEDs on the status queue like a keyboard driver would.
+
+use crate::{mmio_trap::TrappedRegion, os_thread::{initialise_device, post_buffers}, poller::PollableQueue};
+use crate::virtio::{device_register::CONFIG_SPACE, guest_driver::GuestDriver, vring::Vring};
+use crate::virtio_input::{evdev::*, CONFIG_DATA_OFFSET, EVENTQ, VIRTIO_INPUT_CFG_ABS_INFO, VIRTIO_INPUT_CFG_EV_BITS, VIRTIO_INPUT_CFG_ID_NAME};
+
+const EVENT_BUFFERS: usize = 32;
+
+/// Selects one page of the config space and reads the answer back
+pub unsafe fn read_config(registers: &TrappedRegion, select: u8, subsel: u8) -> Vec<u8> {
+    registers.register(CONFIG_SPACE).write_volatile(select as u32 | (subsel as u32) << 8);
+
+    let size = (registers.register(CONFIG_SPACE).read_volatile() >> 16 & 0xff) as usize;
+    let mut answer = Vec::with_capacity(size.next_multiple_of(4));
+
+    for offset in (0..size).step_by(4) {
+        answer.extend_from_slice(&registers.register(CONFIG_SPACE + (CONFIG_DATA_OFFSET + offset) as u32).read_volatile().to_le_bytes());
+    }
+
+    answer.truncate(size);
+    answer
+}
+
+fn count_bits(bits: &[u8]) -> u32 {
+    bits.iter().map(|byte| byte.count_ones()).sum()
+}
+
+/// What the device says it is, as a line for the OS messages
+unsafe fn probe(registers: &TrappedRegion) -> String {
+    let name = String::from_utf8_lossy(&read_config(registers, VIRTIO_INPUT_CFG_ID_NAME, 0)).into_owned();
+    let keys = count_bits(&read_config(registers, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8));
+    let relative = count_bits(&read_config(registers, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8));
+    let leds = count_bits(&read_config(registers, VIRTIO_INPUT_CFG_EV_BITS, EV_LED as u8));
+
+    let absolute = read_config(registers, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8);
+    let range = match read_config(registers, VIRTIO_INPUT_CFG_ABS_INFO, ABS_X as u8) {
+        info if info.len() >= 8 => format!(" from {} to {}", u32::from_le_bytes(info[0..4].try_into().unwrap()), u32::from_le_bytes(info[4..8].try_into().unwrap())),
+        _ => String::new(),
+    };
+
+    format!("{name}: {keys} keys and buttons, {relative} relative and {} absolute axes{range}, {leds} LEDs", count_bits(&absolute))
+}
+
+pub struct InputAgent {
+    report: Vec<InputEvent>,
+    leds: u32,
+}
+
+impl InputAgent {
+    fn new() -> Self {
+        Self { report: Vec::new(), leds: 0 }
+    }
+
+    /// Brings the device up, finds out what it is and stocks the event queue
+    pub unsafe fn start<const S: usize, P: PollableQueue + Clone>(registers: &TrappedRegion, rings: &[Vring], queue: impl Fn(u16) -> *mut GuestDriver<S, P>) -> Result<(Self, String), String> {
+        let device_id = initialise_device(registers, rings)?;
+        let details = format!("with id {device_id}, {}", probe(registers));
+
+        post_buffers(queue(EVENTQ).as_mut().unwrap(), EVENT_BUFFERS, EVENT_SIZE);
+
+        Ok((Self::new(), details))
+    }
+
+    fn describe(event: &InputEvent) -> Option<String> {
+        let description = match (event.kind, event.code, event.value) {
+            (EV_KEY, code, 0) => format!("{} up", key_name(code)),
+            (EV_KEY, code, 1) => format!("{} down", key_name(code)),
+            (EV_KEY, code, _) => format!("{} repeat", key_name(code)),
+            (EV_ABS, ABS_X, x) => format!("x {x}"),
+            (EV_ABS, ABS_Y, y) => format!("y {y}"),
+            (EV_REL, REL_WHEEL, value) => format!("wheel {}", if value as i32 > 0 { "up" } else { "down" }),
+            (EV_REL, REL_HWHEEL, value) => format!("wheel {}", if value as i32 > 0 { "right" } else { "left" }),
+            (EV_SYN, ..) => return None,
+            (kind, code, value) => format!("event {kind}:{code} {value}"),
+        };
+
+        Some(description)
+    }
+
+    /// Takes one event off the queue. A finished report comes back as a line to show, along
+    /// with any LED changes for the status queue.
+    pub fn received(&mut self, bytes: &[u8]) -> (Option<String>, Vec<u8>) {
+        let Some(event) = InputEvent::parse(bytes) else {
+            return (None, Vec::new());
+        };
+
+        if event.kind != EV_SYN || event.code != SYN_REPORT {
+            self.report.push(event);
+            return (None, Vec::new());
+        }
+
+        let report = std::mem::take(&mut self.report);
+        let mut status = Vec::new();
+
+        for event in report.iter().filter(|event| event.kind == EV_KEY && event.value == 1) {
+            let led = match event.code {
+                KEY_CAPSLOCK => LED_CAPSL,
+                KEY_NUMLOCK => LED_NUML,
+                KEY_SCROLLLOCK => LED_SCROLLL,
+                _ => continue,
+            };
+
+            self.leds ^= 1 << led;
+            status.extend_from_slice(&InputEvent::new(EV_LED, led, self.leds >> led & 1).to_bytes());
+        }
+
+        if !status.is_empty() {
+            status.extend_from_slice(&InputEvent::syn().to_bytes());
+        }
+
+        let described: Vec<String> = report.iter().filter_map(Self::describe).collect();
+        (Some(format!("input: {}", described.join(", "))), status)
+    }
+}
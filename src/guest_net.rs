@@ -0,0 +1,538 @@
+// A very small guest network stack, ARP, ICMP echo and UDP echo and nothing else. It's enough for
+// the simulated guests on the virtual switch to find each other and answer pings, which exercises
+// the virtio-net queues from both ends. Big UDP datagrams go to the device whole and it does the
+// checksum and the fragmenting.
+
+use std::{collections::HashMap, time::Instant};
+
+use futures::StreamExt;
+use tokio::{runtime, sync::mpsc::Sender};
+
+use crate::{async_driver::{DriverPoller, DriverEvent}, comms::Messages, mmio_trap::TrappedRegion, os_thread::{initialise_device, post_buffers, queue_rings, transmit}, poller::PollableQueue};
+use crate::virtio::{device_register::CONFIG_SPACE, guest_driver::GuestDriver, vring::Vring};
+use crate::virtio_net::{control::*, internet_checksum, offload::{self, VIRTIO_NET_HDR_F_NEEDS_CSUM}, receive_queue, switch::MacAddress, NetHeader, DEFAULT_MTU, MAX_GSO_FRAME_SIZE, NET_HEADER_SIZE, RECEIVEQ, TRANSMITQ};
+
+pub type Ipv4Address = [u8; 4];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const IP_PROTOCOL_ICMP: u8 = 1;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_OFFSET_MASK: u16 = 0x1fff;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+const BROADCAST: MacAddress = [0xff; 6];
+const MIN_FRAME_SIZE: usize = 60;
+
+const PING_ID: u16 = 0x7670;
+const PING_PAYLOAD: &[u8] = b"virtio-playground ping";
+
+const UDP_ECHO_PORT: u16 = 7;
+const UDP_SOURCE_PORT: u16 = 0x7671;
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+const RECEIVE_BUFFERS: usize = 16;
+
+pub fn parse_ipv4(text: &str) -> Option<Ipv4Address> {
+    let octets: Vec<u8> = text.split('.').map(|octet| octet.parse().ok()).collect::<Option<_>>()?;
+    octets.try_into().ok()
+}
+
+pub fn format_ipv4(ip: &Ipv4Address) -> String {
+    ip.iter().map(|octet| octet.to_string()).collect::<Vec<_>>().join(".")
+}
+
+/// The simulated network hands out addresses by MAC, like a DHCP server with a static lease
+/// for every guest
+pub fn address_for(mac: &MacAddress) -> Ipv4Address {
+    [10, 0, 0, mac[5]]
+}
+
+fn word(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+// Where a fragment goes in its datagram, what's in it and whether more follow
+type Fragment = (usize, Vec<u8>, bool);
+
+pub struct NetStack {
+    pub mac: MacAddress,
+    pub ip: Ipv4Address,
+
+    neighbours: HashMap<Ipv4Address, MacAddress>,
+    // Pings waiting on ARP to find out where to go
+    unresolved: Vec<Ipv4Address>,
+
+    sequence: u16,
+    sent: HashMap<u16, Instant>,
+
+    ip_id: u16,
+    // Fragments by sender and IP id
+    fragments: HashMap<(Ipv4Address, u16), Vec<Fragment>>,
+}
+
+impl NetStack {
+    pub fn new(mac: MacAddress, ip: Ipv4Address) -> Self {
+        Self {
+            mac,
+            ip,
+
+            neighbours: HashMap::new(),
+            unresolved: Vec::new(),
+
+            sequence: 0,
+            sent: HashMap::new(),
+
+            ip_id: 0,
+            fragments: HashMap::new(),
+        }
+    }
+
+    fn ethernet(&self, destination: MacAddress, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(MIN_FRAME_SIZE.max(14 + payload.len()));
+        frame.extend_from_slice(&destination);
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.resize(frame.len().max(MIN_FRAME_SIZE), 0);
+
+        frame
+    }
+
+    fn arp(&self, operation: u16, target_mac: MacAddress, target_ip: Ipv4Address) -> Vec<u8> {
+        let mut arp = Vec::with_capacity(28);
+        arp.extend_from_slice(&[0, 1, 8, 0, 6, 4]);
+        arp.extend_from_slice(&operation.to_be_bytes());
+        arp.extend_from_slice(&self.mac);
+        arp.extend_from_slice(&self.ip);
+        arp.extend_from_slice(&target_mac);
+        arp.extend_from_slice(&target_ip);
+
+        let destination = if operation == ARP_REQUEST { BROADCAST } else { target_mac };
+        self.ethernet(destination, ETHERTYPE_ARP, &arp)
+    }
+
+    fn ipv4(&mut self, destination: MacAddress, destination_ip: Ipv4Address, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        self.ip_id = self.ip_id.wrapping_add(1);
+
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&self.ip_id.to_be_bytes());
+        packet.extend_from_slice(&[0x40, 0, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&self.ip);
+        packet.extend_from_slice(&destination_ip);
+
+        let checksum = internet_checksum(&packet);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        packet.extend_from_slice(payload);
+        self.ethernet(destination, ETHERTYPE_IPV4, &packet)
+    }
+
+    fn echo(kind: u8, id: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+        let mut icmp = vec![kind, 0, 0, 0];
+        icmp.extend_from_slice(&id.to_be_bytes());
+        icmp.extend_from_slice(&sequence.to_be_bytes());
+        icmp.extend_from_slice(payload);
+
+        let checksum = internet_checksum(&icmp);
+        icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        icmp
+    }
+
+    fn udp_datagram(&self, source_port: u16, destination_ip: Ipv4Address, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+        let length = (8 + payload.len()) as u16;
+
+        let mut udp = Vec::with_capacity(length as usize);
+        udp.extend_from_slice(&source_port.to_be_bytes());
+        udp.extend_from_slice(&destination_port.to_be_bytes());
+        udp.extend_from_slice(&length.to_be_bytes());
+        udp.extend_from_slice(&[0, 0]);
+        udp.extend_from_slice(payload);
+
+        let checksum = match udp_checksum(&self.ip, &destination_ip, &udp) {
+            0 => 0xffff,
+            checksum => checksum,
+        };
+
+        udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+        udp
+    }
+
+    fn echo_request(&mut self, destination: MacAddress, destination_ip: Ipv4Address) -> Vec<u8> {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sent.insert(self.sequence, Instant::now());
+
+        let icmp = Self::echo(ICMP_ECHO_REQUEST, PING_ID, self.sequence, PING_PAYLOAD);
+        self.ipv4(destination, destination_ip, IP_PROTOCOL_ICMP, &icmp)
+    }
+
+    /// The frames that send a ping, an ARP request first when we don't know where `ip` is
+    pub fn ping(&mut self, ip: Ipv4Address) -> Vec<Vec<u8>> {
+        match self.neighbours.get(&ip).copied() {
+            Some(mac) => vec![self.echo_request(mac, ip)],
+            None => {
+                self.unresolved.push(ip);
+                vec![self.arp(ARP_REQUEST, [0; 6], ip)]
+            },
+        }
+    }
+
+    /// A datagram of `length` bytes for the UDP echo service at `ip`, which has to have been
+    /// pinged already so we know where it is
+    pub fn udp(&mut self, ip: Ipv4Address, length: usize) -> Result<Vec<u8>, String> {
+        if length > MAX_DATAGRAM_SIZE {
+            return Err(format!("{length} bytes won't fit in a datagram, {MAX_DATAGRAM_SIZE} will"));
+        }
+
+        let mac = self.neighbours.get(&ip).copied().ok_or(format!("{} hasn't been found yet, ping it first", format_ipv4(&ip)))?;
+
+        let payload: Vec<u8> = (0..length).map(|byte| byte as u8).collect();
+        let udp = self.udp_datagram(UDP_SOURCE_PORT, ip, UDP_ECHO_PORT, &payload);
+
+        Ok(self.ipv4(mac, ip, IP_PROTOCOL_UDP, &udp))
+    }
+
+    /// Handles a frame off the wire, returns the frames to send back and anything worth saying
+    pub fn receive(&mut self, frame: &[u8]) -> (Vec<Vec<u8>>, Option<String>) {
+        if frame.len() < 14 {
+            return (Vec::new(), None);
+        }
+
+        let payload = &frame[14..];
+
+        match word(frame, 12) {
+            ETHERTYPE_ARP => self.receive_arp(payload),
+            ETHERTYPE_IPV4 => self.receive_ipv4(&frame[6..12].try_into().unwrap(), payload),
+            _ => (Vec::new(), None),
+        }
+    }
+
+    fn receive_arp(&mut self, arp: &[u8]) -> (Vec<Vec<u8>>, Option<String>) {
+        if arp.len() < 28 {
+            return (Vec::new(), None);
+        }
+
+        let sender_mac: MacAddress = arp[8..14].try_into().unwrap();
+        let sender_ip: Ipv4Address = arp[14..18].try_into().unwrap();
+        let target_ip: Ipv4Address = arp[24..28].try_into().unwrap();
+
+        self.neighbours.insert(sender_ip, sender_mac);
+
+        let mut replies = Vec::new();
+
+        if word(arp, 6) == ARP_REQUEST && target_ip == self.ip {
+            replies.push(self.arp(ARP_REPLY, sender_mac, sender_ip));
+        }
+
+        // Now we know where they are the pings can go
+        let waiting = self.unresolved.iter().filter(|ip| **ip == sender_ip).count();
+        self.unresolved.retain(|ip| *ip != sender_ip);
+
+        for _ in 0..waiting {
+            let request = self.echo_request(sender_mac, sender_ip);
+            replies.push(request);
+        }
+
+        (replies, None)
+    }
+
+    fn receive_ipv4(&mut self, source_mac: &MacAddress, packet: &[u8]) -> (Vec<Vec<u8>>, Option<String>) {
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return (Vec::new(), None);
+        }
+
+        let header_len = (packet[0] & 0xf) as usize * 4;
+        let total_len = (word(packet, 2) as usize).min(packet.len());
+
+        let source_ip: Ipv4Address = packet[12..16].try_into().unwrap();
+        let destination_ip: Ipv4Address = packet[16..20].try_into().unwrap();
+
+        if destination_ip != self.ip || total_len < header_len + 8 {
+            return (Vec::new(), None);
+        }
+
+        let fragment = word(packet, 6);
+        let mut payload = packet[header_len..total_len].to_vec();
+
+        if fragment & (IPV4_MORE_FRAGMENTS | IPV4_OFFSET_MASK) != 0 {
+            let offset = (fragment & IPV4_OFFSET_MASK) as usize * 8;
+
+            match self.reassemble((source_ip, word(packet, 4)), offset, payload, fragment & IPV4_MORE_FRAGMENTS != 0) {
+                Some(whole) => payload = whole,
+                None => return (Vec::new(), None),
+            }
+        }
+
+        match packet[9] {
+            IP_PROTOCOL_ICMP => self.receive_icmp(source_mac, source_ip, &payload),
+            IP_PROTOCOL_UDP => self.receive_udp(source_mac, source_ip, &payload),
+            _ => (Vec::new(), None),
+        }
+    }
+
+    /// Holds on to a fragment, returns the whole payload once every piece is in
+    fn reassemble(&mut self, key: (Ipv4Address, u16), offset: usize, data: Vec<u8>, more: bool) -> Option<Vec<u8>> {
+        let pieces = self.fragments.entry(key).or_default();
+        pieces.retain(|(other, _, _)| *other != offset);
+        pieces.push((offset, data, more));
+        pieces.sort_by_key(|(offset, _, _)| *offset);
+
+        let mut whole = Vec::new();
+
+        for (offset, data, more) in pieces.iter() {
+            if *offset != whole.len() {
+                return None;
+            }
+
+            whole.extend_from_slice(data);
+
+            if !more {
+                self.fragments.remove(&key);
+                return Some(whole);
+            }
+        }
+
+        None
+    }
+
+    fn receive_udp(&mut self, source_mac: &MacAddress, source_ip: Ipv4Address, udp: &[u8]) -> (Vec<Vec<u8>>, Option<String>) {
+        let length = (word(udp, 4) as usize).clamp(8, udp.len());
+        let udp = &udp[..length];
+        let (source_port, destination_port) = (word(udp, 0), word(udp, 2));
+
+        if word(udp, 6) != 0 && udp_checksum(&source_ip, &self.ip, udp) != 0 {
+            return (Vec::new(), Some(format!("Dropped a {} byte datagram from {} with a bad checksum", udp.len() - 8, format_ipv4(&source_ip))));
+        }
+
+        match destination_port {
+            UDP_ECHO_PORT => {
+                let reply = self.udp_datagram(UDP_ECHO_PORT, source_ip, source_port, &udp[8..]);
+                (vec![self.ipv4(*source_mac, source_ip, IP_PROTOCOL_UDP, &reply)], None)
+            },
+            UDP_SOURCE_PORT if source_port == UDP_ECHO_PORT => {
+                let intact = udp[8..].iter().enumerate().all(|(index, byte)| *byte == index as u8);
+                let state = if intact { "intact" } else { "corrupted" };
+
+                (Vec::new(), Some(format!("{} bytes echoed by {}, checksum ok, data {state}", udp.len() - 8, format_ipv4(&source_ip))))
+            },
+            _ => (Vec::new(), None),
+        }
+    }
+
+    fn receive_icmp(&mut self, source_mac: &MacAddress, source_ip: Ipv4Address, icmp: &[u8]) -> (Vec<Vec<u8>>, Option<String>) {
+        if internet_checksum(icmp) != 0 {
+            return (Vec::new(), Some(format!("Dropped an ICMP packet from {} with a bad checksum", format_ipv4(&source_ip))));
+        }
+
+        let (id, sequence) = (word(icmp, 4), word(icmp, 6));
+
+        match icmp[0] {
+            ICMP_ECHO_REQUEST => {
+                let reply = Self::echo(ICMP_ECHO_REPLY, id, sequence, &icmp[8..]);
+                (vec![self.ipv4(*source_mac, source_ip, IP_PROTOCOL_ICMP, &reply)], None)
+            },
+            ICMP_ECHO_REPLY if id == PING_ID => {
+                let time = self.sent.remove(&sequence).map(|sent| sent.elapsed().as_secs_f64() * 1000.0).unwrap_or(0.0);
+                (Vec::new(), Some(format!("{} bytes from {}: seq={sequence} time={time:.2}ms", icmp.len(), format_ipv4(&source_ip))))
+            },
+            _ => (Vec::new(), None),
+        }
+    }
+}
+
+/// The UDP checksum over the pseudo header and `udp`, zero when a received datagram checks out
+fn udp_checksum(source: &Ipv4Address, destination: &Ipv4Address, udp: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(12 + udp.len());
+    pseudo.extend_from_slice(source);
+    pseudo.extend_from_slice(destination);
+    pseudo.extend_from_slice(&[0, IP_PROTOCOL_UDP]);
+    pseudo.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+    pseudo.extend_from_slice(udp);
+
+    internet_checksum(&pseudo)
+}
+
+/// Queues a frame on the transmit queue. The guests take every offload the device has, so TCP
+/// and UDP checksums are left to it and so is cutting up anything bigger than the MTU.
+pub unsafe fn send_frame<const S: usize, P: PollableQueue + Clone>(driver: &mut GuestDriver<S, P>, frame: &[u8]) {
+    let mut frame = frame.to_vec();
+    let header = offload::prepare_transmit(&mut frame, DEFAULT_MTU as usize);
+
+    let mut packet = header.to_bytes().to_vec();
+    packet.extend_from_slice(&frame);
+
+    transmit(driver, &packet);
+}
+
+/// Takes the frame out of a filled receive buffer, finishing off the checksum if the device
+/// left it to us
+pub fn receive_frame(buffer: &[u8]) -> Vec<u8> {
+    let mut frame = buffer.get(NET_HEADER_SIZE..).unwrap_or_default().to_vec();
+
+    if let Some(header) = NetHeader::parse(buffer).filter(|header| header.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0) {
+        // A frame the checksum can't be done for isn't any use, the stack drops it
+        if offload::complete_checksum(&mut frame, header.csum_start as usize, header.csum_offset as usize).is_err() {
+            frame.clear();
+        }
+    }
+
+    frame
+}
+
+/// Receive buffers are big enough for whatever the device coalesces, as the guest offloads need
+pub unsafe fn post_receive_buffers<const S: usize, P: PollableQueue + Clone>(driver: &mut GuestDriver<S, P>, count: usize) {
+    post_buffers(driver, count, NET_HEADER_SIZE + MAX_GSO_FRAME_SIZE);
+}
+
+/// The MAC is the first six bytes of the net config space
+pub unsafe fn read_mac(registers: &TrappedRegion) -> MacAddress {
+    let low = registers.register(CONFIG_SPACE).read_volatile().to_le_bytes();
+    let high = registers.register(CONFIG_SPACE + 4).read_volatile().to_le_bytes();
+
+    [low[0], low[1], low[2], low[3], high[0], high[1]]
+}
+
+/// The most queue pairs the device will let us use, it's always 1 without multiqueue
+unsafe fn read_queue_pairs(registers: &TrappedRegion) -> u16 {
+    (registers.register(CONFIG_SPACE + 8).read_volatile() & 0xffff) as u16
+}
+
+/// Queues a command on the control queue, the device acks it in the last byte of the chain
+pub unsafe fn control_command<const S: usize, P: PollableQueue + Clone>(driver: &mut GuestDriver<S, P>, class: u8, command: u8, data: &[u8]) {
+    let mut request = vec![class, command];
+    request.extend_from_slice(data);
+
+    driver.submit_chain(vec![(request.into_boxed_slice(), false), (vec![0xff].into_boxed_slice(), true)]);
+}
+
+/// Brings the OS's own NIC up: every queue pair it offers turned on, nothing let through but our
+/// address and broadcasts, and each receive queue stocked. The control queue is the last one.
+/// Comes back with the stack and how many pairs there are.
+pub unsafe fn start<const S: usize, P: PollableQueue + Clone>(registers: &TrappedRegion, rings: &[Vring], queue: impl Fn(u16) -> *mut GuestDriver<S, P>) -> Result<((NetStack, u16), String), String> {
+    let device_id = initialise_device(registers, rings)?;
+    let control = queue(rings.len() as u16 - 1).as_mut().unwrap();
+
+    let mac = read_mac(registers);
+    let stack = NetStack::new(mac, address_for(&mac));
+    let pairs = read_queue_pairs(registers).max(1);
+
+    if pairs > 1 {
+        control_command(control, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, &pairs.to_le_bytes());
+    }
+
+    control_command(control, VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET, &[0; 8]);
+    control_command(control, VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI, &[0]);
+
+    for pair in 0..pairs {
+        post_receive_buffers(queue(receive_queue(pair)).as_mut().unwrap(), RECEIVE_BUFFERS);
+    }
+
+    let details = format!("with id {device_id}, address {}", format_ipv4(&stack.ip));
+    Ok(((stack, pairs), details))
+}
+
+/// A guest that does nothing but sit on the network answering ARP, pings and UDP echo
+pub fn create_net_guest<const S: usize, P: PollableQueue + Clone + Send + 'static>(ui: Sender<Messages>, mut drivers: Vec<GuestDriver<S, P>>) {
+    let rt = runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+    let registers = TrappedRegion::new(drivers[0].transport().clone()).unwrap();
+    let rings = queue_rings(&drivers);
+
+    let mut poller = DriverPoller::with_queues(drivers.iter_mut().collect());
+    let (receive, transmit) = unsafe { (poller.get_queue_driver(RECEIVEQ), poller.get_queue_driver(TRANSMITQ)) };
+
+    poller.delayed_poller();
+
+    rt.block_on(async {
+        let mac = match unsafe { initialise_device(&registers, &rings) } {
+            Ok(_) => unsafe { read_mac(&registers) },
+            Err(reason) => {
+                ui.send(Messages::OSMessage(format!("A guest couldn't bring up its network: {reason}"))).await.unwrap();
+                return;
+            },
+        };
+
+        let ip = address_for(&mac);
+        let mut stack = NetStack::new(mac, ip);
+        unsafe { post_receive_buffers(receive.as_mut().unwrap(), RECEIVE_BUFFERS) };
+
+        while let Some(event) = poller.next().await {
+            let DriverEvent::UsedBuffer { queue, head, length, .. } = event else {
+                continue;
+            };
+
+            unsafe {
+                if queue != RECEIVEQ {
+                    transmit.as_mut().unwrap().release_chain(head);
+                    continue;
+                }
+
+                let buffers = receive.as_mut().unwrap().release_chain(head);
+                let frame = receive_frame(&buffers[0][..length as usize]);
+                let (replies, message) = stack.receive(&frame);
+
+                for reply in replies {
+                    send_frame(transmit.as_mut().unwrap(), &reply);
+                }
+
+                post_receive_buffers(receive.as_mut().unwrap(), 1);
+
+                if let Some(message) = message {
+                    ui.send(Messages::OSMessage(format!("Guest {}: {message}", format_ipv4(&ip)))).await.unwrap();
+                }
+            }
+        }
+    });
+}
+
+#[test]
+pub fn test_stacks_ping_each_other() {
+    let mut first = NetStack::new([2, 0, 0, 0, 0, 1], [10, 0, 0, 1]);
+    let mut second = NetStack::new([2, 0, 0, 0, 0, 2], [10, 0, 0, 2]);
+
+    // Nothing is known yet so the ping starts as an ARP request, answered by an ARP reply
+    let request = first.ping([10, 0, 0, 2]);
+    let (arp_reply, _) = second.receive(&request[0]);
+
+    // Which lets the echo request out, and the reply comes straight back
+    let (echo_request, _) = first.receive(&arp_reply[0]);
+    let (echo_reply, _) = second.receive(&echo_request[0]);
+    let (nothing, message) = first.receive(&echo_reply[0]);
+
+    assert!(nothing.is_empty());
+    assert!(message.unwrap().starts_with("30 bytes from 10.0.0.2: seq=1"));
+
+    // The second ping goes straight out
+    assert_eq!(first.ping([10, 0, 0, 2]).len(), 1);
+    assert_eq!(parse_ipv4("10.0.0.2"), Some([10, 0, 0, 2]));
+
+    // A datagram too big for one frame goes out in fragments, however they get cut up
+    let mut datagram = first.udp([10, 0, 0, 2], 3000).unwrap();
+    assert!(second.udp([10, 0, 0, 3], 10).is_err());
+
+    let total = datagram.len() - 14;
+    datagram[20] |= 0x20;
+    datagram[16..18].copy_from_slice(&1500u16.to_be_bytes());
+
+    let mut rest = datagram[..34].to_vec();
+    rest.extend_from_slice(&datagram[34 + 1480..]);
+    rest[16..18].copy_from_slice(&((total - 1480) as u16).to_be_bytes());
+    rest[20..22].copy_from_slice(&(1480u16 / 8).to_be_bytes());
+
+    assert!(second.receive(&rest).0.is_empty());
+    let (echo, _) = second.receive(&datagram[..14 + 1500]);
+
+    let (_, message) = first.receive(&echo[0]);
+    assert_eq!(message.unwrap(), "3000 bytes echoed by 10.0.0.2, checksum ok, data intact");
+    assert_eq!(parse_ipv4("10.0.0"), None);
+}
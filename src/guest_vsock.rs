@@ -0,0 +1,279 @@
+// The guest end of vsock, stream sockets to the host and nothing else. An agent listens on
+// AGENT_PORT and sends back whatever a host program writes to it, and the shell can connect out
+// to a host port and send a line. Both sides keep to the credit the other gives them.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::{mmio_trap::TrappedRegion, os_thread::{initialise_device, post_buffers, transmit}, poller::PollableQueue};
+use crate::virtio::{device_register::CONFIG_SPACE, guest_driver::GuestDriver, vring::Vring};
+use crate::virtio_vsock::{EVENTQ, RECEIVEQ, VMADDR_CID_HOST, packet::{Packet, PacketHeader, HEADER_SIZE, VIRTIO_VSOCK_OP_CREDIT_REQUEST, VIRTIO_VSOCK_OP_CREDIT_UPDATE, VIRTIO_VSOCK_OP_REQUEST, VIRTIO_VSOCK_OP_RESPONSE, VIRTIO_VSOCK_OP_RST, VIRTIO_VSOCK_OP_RW, VIRTIO_VSOCK_OP_SHUTDOWN, VIRTIO_VSOCK_TYPE_STREAM}};
+
+pub const AGENT_PORT: u32 = 52;
+
+// Where the shell's connections get their local ports, like Linux's ephemeral range
+const FIRST_LOCAL_PORT: u32 = 49152;
+
+const BUFFER_SIZE: u32 = 64 * 1024;
+const MAX_PACKET_DATA: usize = 4096;
+
+pub const RECEIVE_BUFFER_SIZE: usize = HEADER_SIZE + MAX_PACKET_DATA;
+pub const EVENT_BUFFER_SIZE: usize = 4;
+
+const RECEIVE_BUFFERS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Connecting,
+    Established,
+}
+
+struct GuestConnection {
+    state: State,
+    /// Accepted by the agent rather than opened by the shell
+    agent: bool,
+    /// Waiting for the connection or for the host to have room
+    outgoing: VecDeque<u8>,
+
+    fwd_cnt: u32,
+    reported_fwd_cnt: u32,
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl GuestConnection {
+    fn new(state: State, agent: bool) -> Self {
+        Self { state, agent, outgoing: VecDeque::new(), fwd_cnt: 0, reported_fwd_cnt: 0, tx_cnt: 0, peer_buf_alloc: 0, peer_fwd_cnt: 0 }
+    }
+
+    fn peer_free(&self) -> u32 {
+        self.peer_buf_alloc.saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+}
+
+pub struct VsockStack {
+    pub cid: u64,
+
+    // Known by local port and host port
+    connections: HashMap<(u32, u32), GuestConnection>,
+    next_port: u32,
+}
+
+impl VsockStack {
+    pub fn new(cid: u64) -> Self {
+        Self { cid, connections: HashMap::new(), next_port: FIRST_LOCAL_PORT }
+    }
+
+    fn packet(&mut self, key: (u32, u32), op: u16, data: Vec<u8>) -> Packet {
+        let (local_port, peer_port) = key;
+        let fwd_cnt = self.connections.get_mut(&key).map_or(0, |connection| {
+            connection.reported_fwd_cnt = connection.fwd_cnt;
+            connection.fwd_cnt
+        });
+
+        let header = PacketHeader {
+            src_cid: self.cid,
+            dst_cid: VMADDR_CID_HOST,
+            src_port: local_port,
+            dst_port: peer_port,
+            len: data.len() as u32,
+            kind: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: BUFFER_SIZE,
+            fwd_cnt,
+            ..PacketHeader::default()
+        };
+
+        Packet { header, data }
+    }
+
+    /// Sends what's waiting on a connection as far as the host's credit goes. Running out can
+    /// ask the host for an update, but not in answer to one or the two would go back and forth.
+    fn flush(&mut self, key: (u32, u32), request_credit: bool) -> Vec<Packet> {
+        let mut packets = Vec::new();
+
+        loop {
+            let Some(connection) = self.connections.get_mut(&key).filter(|connection| connection.state == State::Established) else {
+                return packets;
+            };
+
+            let count = connection.outgoing.len().min(connection.peer_free() as usize).min(MAX_PACKET_DATA);
+
+            if count == 0 {
+                // Out of credit with more to send, ask the host for an update
+                if request_credit && !connection.outgoing.is_empty() && packets.is_empty() {
+                    packets.push(self.packet(key, VIRTIO_VSOCK_OP_CREDIT_REQUEST, Vec::new()));
+                }
+
+                return packets;
+            }
+
+            let data: Vec<u8> = connection.outgoing.drain(..count).collect();
+            connection.tx_cnt = connection.tx_cnt.wrapping_add(count as u32);
+
+            packets.push(self.packet(key, VIRTIO_VSOCK_OP_RW, data));
+        }
+    }
+
+    /// Sends `data` to a host port, connecting first unless the shell already has a connection
+    pub fn connect(&mut self, port: u32, data: &[u8]) -> Vec<Packet> {
+        let existing = self.connections.iter().find(|((_, peer), connection)| *peer == port && !connection.agent).map(|(key, _)| *key);
+
+        if let Some(key) = existing {
+            self.connections.get_mut(&key).unwrap().outgoing.extend(data);
+            return self.flush(key, true);
+        }
+
+        let key = (self.next_port, port);
+        self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_LOCAL_PORT);
+
+        let mut connection = GuestConnection::new(State::Connecting, false);
+        connection.outgoing.extend(data);
+        self.connections.insert(key, connection);
+
+        vec![self.packet(key, VIRTIO_VSOCK_OP_REQUEST, Vec::new())]
+    }
+
+    /// Handles a packet from the host, returns what to send back and anything worth showing
+    pub fn receive(&mut self, bytes: &[u8]) -> (Vec<Packet>, Option<String>) {
+        let Some(header) = PacketHeader::parse(bytes).filter(|header| header.dst_cid == self.cid) else {
+            return (Vec::new(), None);
+        };
+
+        let data = &bytes[HEADER_SIZE..(HEADER_SIZE + header.len as usize).min(bytes.len())];
+        let key = (header.dst_port, header.src_port);
+
+        if header.op == VIRTIO_VSOCK_OP_REQUEST {
+            if header.dst_port != AGENT_PORT || self.connections.contains_key(&key) {
+                return (vec![self.packet(key, VIRTIO_VSOCK_OP_RST, Vec::new())], None);
+            }
+
+            let mut connection = GuestConnection::new(State::Established, true);
+            connection.peer_buf_alloc = header.buf_alloc;
+            connection.peer_fwd_cnt = header.fwd_cnt;
+            self.connections.insert(key, connection);
+
+            return (vec![self.packet(key, VIRTIO_VSOCK_OP_RESPONSE, Vec::new())], Some(format!("vsock agent accepted host port {}", header.src_port)));
+        }
+
+        let Some(connection) = self.connections.get_mut(&key) else {
+            return match header.op {
+                VIRTIO_VSOCK_OP_RST => (Vec::new(), None),
+                _ => (vec![self.packet(key, VIRTIO_VSOCK_OP_RST, Vec::new())], None),
+            };
+        };
+
+        connection.peer_buf_alloc = header.buf_alloc;
+        connection.peer_fwd_cnt = header.fwd_cnt;
+
+        let port = header.src_port;
+
+        match header.op {
+            VIRTIO_VSOCK_OP_RESPONSE if connection.state == State::Connecting => {
+                connection.state = State::Established;
+                (self.flush(key, true), None)
+            },
+            VIRTIO_VSOCK_OP_RW => {
+                // Everything is used as soon as it arrives, so it's forwarded straight away
+                connection.fwd_cnt = connection.fwd_cnt.wrapping_add(data.len() as u32);
+
+                let message = match connection.agent {
+                    true => {
+                        connection.outgoing.extend(data);
+                        None
+                    },
+                    false => Some(format!("vsock {port}: {}", String::from_utf8_lossy(data).trim_end())),
+                };
+
+                let needs_update = connection.fwd_cnt.wrapping_sub(connection.reported_fwd_cnt) >= BUFFER_SIZE / 2;
+                let mut packets = self.flush(key, true);
+
+                if packets.is_empty() && needs_update {
+                    packets.push(self.packet(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, Vec::new()));
+                }
+
+                (packets, message)
+            },
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => (self.flush(key, false), None),
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => (vec![self.packet(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, Vec::new())], None),
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                // The host end is closing, we close too and the reset finishes it
+                let agent = connection.agent;
+                let reset = self.packet(key, VIRTIO_VSOCK_OP_RST, Vec::new());
+                self.connections.remove(&key);
+
+                (vec![reset], (!agent).then(|| format!("vsock {port}: closed by the host")))
+            },
+            VIRTIO_VSOCK_OP_RST => {
+                let connection = self.connections.remove(&key).unwrap();
+
+                let message = match (connection.agent, connection.state) {
+                    (true, _) => None,
+                    (false, State::Connecting) => Some(format!("vsock {port}: connection refused")),
+                    (false, State::Established) => Some(format!("vsock {port}: connection reset")),
+                };
+
+                (Vec::new(), message)
+            },
+            _ => (Vec::new(), None),
+        }
+    }
+
+    /// The device reset its transport, every connection is gone
+    pub fn transport_reset(&mut self) -> String {
+        let dropped = self.connections.len();
+        self.connections.clear();
+
+        format!("vsock transport reset, {dropped} connections dropped")
+    }
+}
+
+pub unsafe fn send_packet<const S: usize, P: PollableQueue + Clone>(driver: &mut GuestDriver<S, P>, packet: &Packet) {
+    transmit(driver, &packet.to_bytes());
+}
+
+/// The guest's CID is the whole of the config space
+unsafe fn read_guest_cid(registers: &TrappedRegion) -> u64 {
+    let low = registers.register(CONFIG_SPACE).read_volatile() as u64;
+    let high = registers.register(CONFIG_SPACE + 4).read_volatile() as u64;
+
+    high << 32 | low
+}
+
+/// Brings the device up with the receive queue stocked and a buffer waiting for transport resets
+pub unsafe fn start<const S: usize, P: PollableQueue + Clone>(registers: &TrappedRegion, rings: &[Vring], queue: impl Fn(u16) -> *mut GuestDriver<S, P>) -> Result<(VsockStack, String), String> {
+    let device_id = initialise_device(registers, rings)?;
+    let stack = VsockStack::new(read_guest_cid(registers));
+
+    post_buffers(queue(RECEIVEQ).as_mut().unwrap(), RECEIVE_BUFFERS, RECEIVE_BUFFER_SIZE);
+    post_buffers(queue(EVENTQ).as_mut().unwrap(), 1, EVENT_BUFFER_SIZE);
+
+    let details = format!("with id {device_id}, CID {}, agent on port {AGENT_PORT}", stack.cid);
+    Ok((stack, details))
+}
+
+#[test]
+pub fn test_agent_echoes_within_credit() {
+    let mut stack = VsockStack::new(3);
+
+    let host = |op, buf_alloc, fwd_cnt, data: &[u8]| {
+        let header = PacketHeader { src_cid: VMADDR_CID_HOST, dst_cid: 3, src_port: 1 << 30, dst_port: AGENT_PORT, len: data.len() as u32, kind: VIRTIO_VSOCK_TYPE_STREAM, op, buf_alloc, fwd_cnt, ..PacketHeader::default() };
+        Packet { header, data: data.to_vec() }.to_bytes()
+    };
+
+    let (replies, _) = stack.receive(&host(VIRTIO_VSOCK_OP_REQUEST, 4, 0, &[]));
+    assert_eq!(replies[0].header.op, VIRTIO_VSOCK_OP_RESPONSE);
+
+    // The host only has room for 4 bytes, the rest waits for it to say it's used them
+    let (replies, _) = stack.receive(&host(VIRTIO_VSOCK_OP_RW, 4, 0, b"hello"));
+    assert_eq!((replies[0].header.op, replies[0].data.as_slice()), (VIRTIO_VSOCK_OP_RW, &b"hell"[..]));
+    assert_eq!(replies[0].header.fwd_cnt, 5);
+
+    let (replies, _) = stack.receive(&host(VIRTIO_VSOCK_OP_CREDIT_UPDATE, 4, 4, &[]));
+    assert_eq!(replies[0].data, b"o");
+
+    // A port nobody listens on is refused
+    let mut refused = host(VIRTIO_VSOCK_OP_REQUEST, 4, 0, &[]);
+    refused[20..24].copy_from_slice(&53u32.to_le_bytes());
+    assert_eq!(stack.receive(&refused).0[0].header.op, VIRTIO_VSOCK_OP_RST);
+}
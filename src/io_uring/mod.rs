@@ -63,6 +63,36 @@ impl IOUring {
         self.recv_ring_ref.completion_shared().next().expect("completion queue is empty");
     }
 
+    // Both polls go in together, whichever doesn't fire is removed again so it can't complete
+    // into a later wait
+    unsafe fn poll_either_and_wait(&self, other_fd: i32) {
+        let fds = [self.listen_fd, other_fd];
+
+        for fd in fds {
+            let poll = opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as _)
+                .build()
+                .user_data(fd as _);
+
+            self.recv_ring_ref.submission_shared().push(&poll).unwrap();
+        }
+
+        self.recv_ring_ref.submit_and_wait(1).unwrap();
+
+        let fired: Vec<u64> = self.recv_ring_ref.completion_shared().map(|entry| entry.user_data()).collect();
+        let pending: Vec<u64> = fds.iter().map(|fd| *fd as u64).filter(|fd| !fired.contains(fd)).collect();
+
+        for user_data in pending.iter() {
+            let remove = opcode::PollRemove::new(*user_data).build().user_data(u64::MAX);
+            self.recv_ring_ref.submission_shared().push(&remove).unwrap();
+        }
+
+        // One completion for each remove and one for the poll it took out
+        self.recv_ring_ref.submit_and_wait(2 * pending.len()).unwrap();
+        self.recv_ring_ref.completion_shared().for_each(drop);
+
+        read_buffer(self.listen_fd);
+    }
+
     unsafe fn write_to_fd(&self) {
         let data: [u8; 1] = [0];
         libc::write(self.publish_fd, data.as_ptr() as * const c_void, 1);
@@ -79,4 +109,8 @@ impl PollableQueue for IOUring {
         unsafe { self.write_to_fd(); }
     }
 
+    fn wait_for_event_or(&self, fd: i32) {
+        unsafe { self.poll_either_and_wait(fd); }
+    }
+
 }
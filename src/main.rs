@@ -1,6 +1,20 @@
 #![feature(new_uninit)]
 
-mod faux_blk;
+mod virtio_blk;
+mod virtio_console;
+mod virtio_rng;
+mod virtio_net;
+mod virtio_vsock;
+mod virtio_fs;
+mod virtio_9p;
+mod virtio_balloon;
+mod virtio_input;
+mod guest_net;
+mod guest_vsock;
+mod guest_fs;
+mod guest_9p;
+mod guest_balloon;
+mod guest_input;
 mod comms;
 mod terminal_thread;
 mod device_thread;
@@ -10,36 +24,239 @@ mod virtio;
 mod epoll;
 mod poller;
 mod io_uring;
+mod mmio_trap;
+mod dtb;
+mod config;
 
-use std::{error::Error, thread};
+use std::{error::Error, thread, fs, sync::Arc};
 
 use comms::{CommsLink, GLOBAL_COMMS};
+use config::Config;
+use dtb::MmioTopology;
 
-use device_thread::create_device_thread;
+use device_thread::{create_device_thread, DeviceControl};
 use terminal_thread::create_terminal;
-use os_thread::create_os_thread;
-use virtio::create_epoll_queue;
-use virtio::create_io_uring_queue;
+use os_thread::{create_os_thread, GuestDrivers};
+use virtio_blk::VirtioBlk;
+use virtio_console::{VirtioConsole, DEFAULT_MAX_PORTS};
+use virtio_rng::VirtioRng;
+use virtio_net::{VirtioNet, switch::VirtualSwitch, unix_socket::UnixSocketBackend};
+use virtio_vsock::VirtioVsock;
+use virtio_fs::VirtioFs;
+use virtio_9p::Virtio9p;
+use virtio_balloon::VirtioBalloon;
+use virtio_input::{VirtioInput, DEFAULT_NAME as INPUT_NAME};
+use virtio::{create_io_uring_queues, guest_memory::GuestMemory};
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::from_args()?;
+
     let (ui_comms, os_comms) = CommsLink::new_pair();
     let driver_queue = os_comms.tx.clone();
     let global_link = os_comms.tx.clone();
 
     GLOBAL_COMMS.set_tx_value(global_link);
 
-    let (host_driver, device_driver) = create_io_uring_queue::<64>();
+    let device = match (config.disk_memory, config.overlay_dir.as_ref()) {
+        (Some(capacity), _) => VirtioBlk::in_memory(capacity),
+        (None, Some(directory)) => VirtioBlk::open_overlay(&config.disk_path, config.disk_size, directory)?,
+        (None, None) => VirtioBlk::open(&config.disk_path, config.disk_size, false)?,
+    };
+
+    let (mut host_drivers, device_drivers) = create_io_uring_queues::<64>(&device, config.transport);
+    let host_driver = host_drivers.remove(0);
+
+    let (device_control, device_commands) = DeviceControl::new(host_driver.poll_interface.clone());
+
+    let mut console = VirtioConsole::with_ports(DEFAULT_MAX_PORTS.max(config.console_ports.len() as u32 + 1));
+    let (console_guest_drivers, console_device_drivers) = create_io_uring_queues::<64>(&console, config.transport);
+
+    let (console_control, console_commands) = DeviceControl::new(console_guest_drivers[0].poll_interface.clone());
+    let (console_input, console_input_receiver) = DeviceControl::new(console_guest_drivers[0].poll_interface.clone());
+    let (port_events, port_event_receiver) = DeviceControl::new(console_guest_drivers[0].poll_interface.clone());
+    console.set_input(console_input_receiver);
+    console.set_host_events(port_events, port_event_receiver);
+
+    for (name, backend) in config.console_ports.iter() {
+        console.add_port(name, backend)?;
+    }
+
+    let mut rng = match config.rng_seed {
+        Some(seed) => VirtioRng::seeded(seed),
+        None => VirtioRng::new(),
+    };
+
+    if let Some(rate) = config.rng_rate {
+        rng = rng.with_rate_limit(rate);
+    }
+
+    let (mut rng_guest_drivers, rng_device_drivers) = create_io_uring_queues::<64>(&rng, config.transport);
+    let rng_guest_driver = rng_guest_drivers.remove(0);
+    let (rng_control, rng_commands) = DeviceControl::new(rng_guest_driver.poll_interface.clone());
+
+    if let Some((name, _)) = config.net_captures.iter().find(|(name, _)| !(0..=config.net_guests).any(|nic| *name == format!("net{nic}"))) {
+        return Err(format!("There's no NIC called {name} to capture from").into());
+    }
+
+    // NIC 0 belongs to the OS thread, the rest to guests that only answer pings
+    let switch = VirtualSwitch::new();
+    let mut nics = Vec::new();
+
+    for nic in 0..=config.net_guests {
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, config.net_base + nic as u8];
+
+        let mut net = VirtioNet::new(mac).with_queue_pairs(config.net_queue_pairs);
+        let (guest_drivers, device_drivers) = create_io_uring_queues::<64>(&net, config.transport);
+        net.set_backend(Box::new(switch.connect(guest_drivers[0].poll_interface.clone())));
+
+        for (_, path) in config.net_captures.iter().filter(|(name, _)| *name == format!("net{nic}")) {
+            net.start_capture(path)?;
+        }
+
+        let (control, commands) = DeviceControl::new(guest_drivers[0].poll_interface.clone());
+        nics.push((net, guest_drivers, device_drivers, control, commands));
+    }
+
+    if let Some(spec) = config.net_link.clone() {
+        switch.bridge(|waker| UnixSocketBackend::open(spec, waker))?;
+    }
+
+    let mut vsock = VirtioVsock::new(config.vsock_cid, &config.vsock_path);
+    let (vsock_guest_drivers, vsock_device_drivers) = create_io_uring_queues::<64>(&vsock, config.transport);
+
+    let (vsock_control, vsock_commands) = DeviceControl::new(vsock_guest_drivers[0].poll_interface.clone());
+    let (vsock_events, vsock_event_receiver) = DeviceControl::new(vsock_guest_drivers[0].poll_interface.clone());
+    vsock.listen(vsock_events, vsock_event_receiver)?;
+
+    fs::create_dir_all(&config.fs_dir)?;
+    let shared = VirtioFs::new(&config.fs_tag, &fs::canonicalize(&config.fs_dir)?, config.fs_dax_mib << 20)?;
+    let (fs_guest_drivers, fs_device_drivers) = create_io_uring_queues::<64>(&shared, config.transport);
+
+    let (fs_control, fs_commands) = DeviceControl::new(fs_guest_drivers[0].poll_interface.clone());
+
+    // Both shares serve the same directory unless told otherwise, so they can be compared
+    let nine_p_dir = config.nine_p_dir.as_ref().unwrap_or(&config.fs_dir);
+    fs::create_dir_all(nine_p_dir)?;
+    let nine_p = Virtio9p::new(&config.nine_p_tag, &fs::canonicalize(nine_p_dir)?)?;
+    let (mut nine_p_guest_drivers, nine_p_device_drivers) = create_io_uring_queues::<64>(&nine_p, config.transport);
+    let nine_p_guest_driver = nine_p_guest_drivers.remove(0);
+
+    let (nine_p_control, nine_p_commands) = DeviceControl::new(nine_p_guest_driver.poll_interface.clone());
+
+    let guest_memory = Arc::new(GuestMemory::new(config.memory_mib << 20)?);
+    let balloon = VirtioBalloon::new(guest_memory.clone());
+    let (balloon_guest_drivers, balloon_device_drivers) = create_io_uring_queues::<64>(&balloon, config.transport);
+
+    let (balloon_control, balloon_commands) = DeviceControl::new(balloon_guest_drivers[0].poll_interface.clone());
+
+    let mut input = VirtioInput::new(INPUT_NAME);
+    let (input_guest_drivers, input_device_drivers) = create_io_uring_queues::<64>(&input, config.transport);
+
+    let (input_control, input_commands) = DeviceControl::new(input_guest_drivers[0].poll_interface.clone());
+    let (input_events, input_event_receiver) = DeviceControl::new(input_guest_drivers[0].poll_interface.clone());
+    input.set_input(input_event_receiver);
+
+    let mut topology = MmioTopology::default();
+    topology.add(&host_driver.transport().lock().unwrap());
+    topology.add(&console_guest_drivers[0].transport().lock().unwrap());
+    topology.add(&rng_guest_driver.transport().lock().unwrap());
+
+    for (_, guest_drivers, ..) in nics.iter() {
+        topology.add(&guest_drivers[0].transport().lock().unwrap());
+    }
+
+    topology.add(&vsock_guest_drivers[0].transport().lock().unwrap());
+    topology.add(&fs_guest_drivers[0].transport().lock().unwrap());
+    topology.add(&nine_p_guest_driver.transport().lock().unwrap());
+    topology.add(&balloon_guest_drivers[0].transport().lock().unwrap());
+    topology.add(&input_guest_drivers[0].transport().lock().unwrap());
+
+    if let Some(path) = config.dtb_path.as_ref() {
+        fs::write(path, dtb::to_dtb(&topology.to_tree()))?;
+    }
+
+    if let Some(path) = config.dts_path.as_ref() {
+        fs::write(path, dtb::to_dts(&topology.to_tree()))?;
+    }
+
+    let mut devices = vec![("blk".to_string(), device_control), ("console".to_string(), console_control), ("rng".to_string(), rng_control), ("vsock".to_string(), vsock_control), ("fs".to_string(), fs_control), ("9p".to_string(), nine_p_control), ("balloon".to_string(), balloon_control), ("input".to_string(), input_control)];
+    let mut net_guest_drivers = Vec::new();
+
+    for (nic, (net, guest_drivers, device_drivers, control, commands)) in nics.into_iter().enumerate() {
+        devices.push((format!("net{nic}"), control));
+
+        match nic {
+            0 => net_guest_drivers = guest_drivers,
+            _ => {
+                let guest_queue = driver_queue.clone();
+                thread::spawn(move || guest_net::create_net_guest(guest_queue, guest_drivers));
+            },
+        }
+
+        let net_queue = driver_queue.clone();
+        thread::spawn(move || unsafe {
+            create_device_thread(net_queue, net, device_drivers, commands);
+        });
+    }
+
+    let guest_drivers = GuestDrivers {
+        block: host_driver,
+        console: console_guest_drivers,
+        rng: rng_guest_driver,
+        net: net_guest_drivers,
+        vsock: vsock_guest_drivers,
+        fs: fs_guest_drivers,
+        nine_p: nine_p_guest_driver,
+        balloon: balloon_guest_drivers,
+        input: input_guest_drivers,
+    };
 
     let _os_thread = thread::spawn(move || {
-        create_os_thread(os_comms, host_driver);
+        create_os_thread(os_comms, guest_drivers, guest_memory);
     });
 
     let ui_thread = thread::spawn(|| {
-        create_terminal(ui_comms).unwrap();
+        create_terminal(ui_comms, devices, console_input, input_events).unwrap();
     });
 
+    let console_queue = driver_queue.clone();
+    let rng_queue = driver_queue.clone();
+    let vsock_queue = driver_queue.clone();
+    let fs_queue = driver_queue.clone();
+    let nine_p_queue = driver_queue.clone();
+    let balloon_queue = driver_queue.clone();
+    let input_queue = driver_queue.clone();
+
     let _driver_thread = thread::spawn(move || unsafe {
-        create_device_thread(driver_queue, device_driver);
+        create_device_thread(driver_queue, device, device_drivers, device_commands);
+    });
+
+    let _console_thread = thread::spawn(move || unsafe {
+        create_device_thread(console_queue, console, console_device_drivers, console_commands);
+    });
+
+    let _rng_thread = thread::spawn(move || unsafe {
+        create_device_thread(rng_queue, rng, rng_device_drivers, rng_commands);
+    });
+
+    let _vsock_thread = thread::spawn(move || unsafe {
+        create_device_thread(vsock_queue, vsock, vsock_device_drivers, vsock_commands);
+    });
+
+    let _fs_thread = thread::spawn(move || unsafe {
+        create_device_thread(fs_queue, shared, fs_device_drivers, fs_commands);
+    });
+
+    let _nine_p_thread = thread::spawn(move || unsafe {
+        create_device_thread(nine_p_queue, nine_p, nine_p_device_drivers, nine_p_commands);
+    });
+
+    let _balloon_thread = thread::spawn(move || unsafe {
+        create_device_thread(balloon_queue, balloon, balloon_device_drivers, balloon_commands);
+    });
+
+    let _input_thread = thread::spawn(move || unsafe {
+        create_device_thread(input_queue, input, input_device_drivers, input_commands);
     });
 
 
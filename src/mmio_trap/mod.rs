@@ -0,0 +1,502 @@
+// Trap and emulate MMIO, the register window is a PROT_NONE page so every load or store the
+// guest does against it faults. The SIGSEGV handler decodes the faulting mov, has the access
+// performed against the transport and steps over the instruction as if the hardware had done it.
+//
+// Nothing the handler runs may take a lock, the faulting thread could be holding it. Regions are
+// found through a fixed table of atomic base addresses and the access itself is written down a
+// pipe to a worker thread, the handler sleeps on a futex until the worker has done it.
+
+use std::{fs::File, io::Read, mem, os::unix::io::{FromRawFd, RawFd}, ptr, sync::{atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering}, Mutex, OnceLock}, thread};
+
+use lazy_static::lazy_static;
+use libc::{c_int, c_void, mmap, munmap, sigaction, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_NONE};
+
+use crate::virtio::{device_register::CONFIG_SPACE, transport::SharedTransport};
+
+pub const MMIO_WINDOW_SIZE: usize = 0x1000;
+
+// More register windows than the playground ever has devices
+const MAX_REGIONS: usize = 64;
+
+// Zero for a free slot, read by the signal handler
+static REGION_BASES: [AtomicUsize; MAX_REGIONS] = [const { AtomicUsize::new(0) }; MAX_REGIONS];
+
+static TRAP: OnceLock<Trap> = OnceLock::new();
+
+lazy_static! {
+    // Only the worker and region setup touch these, never the handler
+    static ref TRANSPORTS: Mutex<Vec<Option<SharedTransport>>> = Mutex::new(vec![None; MAX_REGIONS]);
+}
+
+struct Trap {
+    previous: sigaction,
+    requests: RawFd,
+}
+
+/// One faulting access, sent to the worker in a single pipe write so concurrent faults can't
+/// interleave
+#[repr(C)]
+struct Request {
+    slot: usize,
+    offset: u32,
+    size: usize,
+    is_store: bool,
+    value: u64,
+    reply: *const Reply,
+}
+
+// Lives on the faulting thread's stack until `done` is set, to DONE or REJECTED
+struct Reply {
+    done: AtomicU32,
+    value: AtomicU64,
+}
+
+const DONE: u32 = 1;
+const REJECTED: u32 = 2;
+
+/// A register window the guest can touch through plain pointers
+pub struct TrappedRegion {
+    base: *mut u8,
+    length: usize,
+    slot: usize,
+}
+
+impl TrappedRegion {
+    pub fn new(transport: SharedTransport) -> std::io::Result<Self> {
+        install_handler()?;
+
+        let mut transports = TRANSPORTS.lock().unwrap();
+
+        let Some(slot) = transports.iter().position(Option::is_none) else {
+            return Err(std::io::Error::other("Out of trapped MMIO regions"));
+        };
+
+        let base = unsafe { mmap(ptr::null_mut(), MMIO_WINDOW_SIZE, PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+
+        if base == MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        transports[slot] = Some(transport);
+        REGION_BASES[slot].store(base as usize, Ordering::Release);
+
+        Ok(Self { base: base as *mut u8, length: MMIO_WINDOW_SIZE, slot })
+    }
+
+    #[cfg(test)]
+    pub fn base(&self) -> *mut u8 {
+        self.base
+    }
+
+    pub fn register(&self, offset: u32) -> *mut u32 {
+        unsafe { self.base.add(offset as usize) as *mut u32 }
+    }
+}
+
+impl Drop for TrappedRegion {
+    fn drop(&mut self) {
+        REGION_BASES[self.slot].store(0, Ordering::Release);
+        TRANSPORTS.lock().unwrap()[self.slot] = None;
+
+        unsafe { munmap(self.base as *mut c_void, self.length); }
+    }
+}
+
+unsafe impl Send for TrappedRegion {}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn install_handler() -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Trapped MMIO decodes x86-64 instructions only"))
+}
+
+#[cfg(target_arch = "x86_64")]
+fn install_handler() -> std::io::Result<()> {
+    static INSTALL: Mutex<()> = Mutex::new(());
+
+    let _guard = INSTALL.lock().unwrap();
+
+    if TRAP.get().is_some() {
+        return Ok(());
+    }
+
+    unsafe {
+        let mut fds = [0; 2];
+
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let worker = File::from_raw_fd(fds[0]);
+        thread::Builder::new().name("mmio-trap".into()).spawn(move || serve_requests(worker))?;
+
+        // The previous handler has to be known before ours can run and pass faults on to it
+        let mut previous: sigaction = mem::zeroed();
+        libc::sigaction(libc::SIGSEGV, ptr::null(), &mut previous);
+
+        let _ = TRAP.set(Trap { previous, requests: fds[1] });
+
+        let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = handle_segv;
+
+        // SA_ONSTACK keeps the handler, and std's stack overflow handler behind it, working when
+        // the fault is a thread running off the end of its stack
+        let mut action: sigaction = mem::zeroed();
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        if libc::sigaction(libc::SIGSEGV, &action, ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+// The worker side of the pipe, this is where the locks get taken
+fn serve_requests(mut requests: File) {
+    let mut buffer = [0u8; mem::size_of::<Request>()];
+
+    while requests.read_exact(&mut buffer).is_ok() {
+        let request = unsafe { ptr::read_unaligned(buffer.as_ptr() as *const Request) };
+
+        let transport = TRANSPORTS.lock().unwrap()[request.slot].clone();
+
+        let (value, done) = match transport {
+            Some(transport) if request.is_store => {
+                let accepted = emulate_write(&transport, request.offset, request.size, request.value);
+                (0, if accepted { DONE } else { REJECTED })
+            },
+            Some(transport) => (emulate_read(&transport, request.offset, request.size), DONE),
+            None => (0, DONE),
+        };
+
+        unsafe {
+            let reply = &*request.reply;
+            reply.value.store(value, Ordering::Relaxed);
+            reply.done.store(done, Ordering::Release);
+
+            libc::syscall(libc::SYS_futex, &reply.done as *const AtomicU32, libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG, 1);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operand {
+    Register { number: u8, high_byte: bool },
+    Immediate(u64),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Access {
+    pub is_store: bool,
+    pub size: usize,
+    pub operand: Operand,
+    pub zero_extend: bool,
+    pub length: usize,
+}
+
+#[cfg(target_arch = "x86_64")]
+/// Decodes the handful of x86-64 moves a compiler emits for volatile register accesses:
+/// `mov r/m, r` (88, 89), `mov r, r/m` (8a, 8b), `mov r/m, imm` (c6, c7) and `movzx` (0f b6, 0f b7)
+pub unsafe fn decode(instruction: *const u8) -> Option<Access> {
+    let mut cursor = 0;
+    let mut operand_16 = false;
+    let mut rex = 0u8;
+
+    loop {
+        match *instruction.add(cursor) {
+            0x66 => operand_16 = true,
+            byte @ 0x40..=0x4f => rex = byte,
+            _ => break,
+        }
+
+        cursor += 1;
+    }
+
+    let rex_w = rex & 0x8 > 0;
+    let rex_r = (rex & 0x4) << 1;
+
+    let word_size = if rex_w { 8 } else if operand_16 { 2 } else { 4 };
+
+    let opcode = *instruction.add(cursor);
+    cursor += 1;
+
+    let (is_store, size, immediate, zero_extend) = match opcode {
+        0x88 => (true, 1, 0, false),
+        0x89 => (true, word_size, 0, false),
+        0x8a => (false, 1, 0, false),
+        0x8b => (false, word_size, 0, word_size == 4),
+        0xc6 => (true, 1, 1, false),
+        0xc7 => (true, word_size, word_size.min(4), false),
+        0x0f => {
+            let second = *instruction.add(cursor);
+            cursor += 1;
+
+            match second {
+                0xb6 => (false, 1, 0, true),
+                0xb7 => (false, 2, 0, true),
+                _ => return None,
+            }
+        },
+        _ => return None,
+    };
+
+    let modrm = *instruction.add(cursor);
+    cursor += 1;
+
+    let mode = modrm >> 6;
+    let reg = ((modrm >> 3) & 0x7) | rex_r;
+    let rm = modrm & 0x7;
+
+    if mode == 3 {
+        return None;
+    }
+
+    if rm == 4 {
+        let sib = *instruction.add(cursor);
+        cursor += 1;
+
+        if mode == 0 && sib & 0x7 == 5 {
+            cursor += 4;
+        }
+    }
+
+    cursor += match (mode, rm) {
+        (0, 5) => 4,
+        (1, _) => 1,
+        (2, _) => 4,
+        _ => 0,
+    };
+
+    let operand = if immediate > 0 {
+        let mut value = 0u64;
+
+        for idx in 0..immediate {
+            value |= (*instruction.add(cursor + idx) as u64) << (8 * idx);
+        }
+
+        cursor += immediate;
+
+        // A 64 bit store of an imm32 sign extends it
+        if size == 8 && value & 0x8000_0000 > 0 {
+            value |= 0xffff_ffff_0000_0000;
+        }
+
+        Operand::Immediate(value)
+    } else {
+        let high_byte = size == 1 && !zero_extend && rex == 0 && reg >= 4;
+        let number = if high_byte { reg - 4 } else { reg };
+
+        Operand::Register { number, high_byte }
+    };
+
+    Some(Access { is_store, size, operand, zero_extend, length: cursor })
+}
+
+// Maps the x86 register encoding onto the gregs slots in the signal context
+#[cfg(target_arch = "x86_64")]
+fn greg_index(number: u8) -> usize {
+    let index = match number {
+        0 => libc::REG_RAX,
+        1 => libc::REG_RCX,
+        2 => libc::REG_RDX,
+        3 => libc::REG_RBX,
+        4 => libc::REG_RSP,
+        5 => libc::REG_RBP,
+        6 => libc::REG_RSI,
+        7 => libc::REG_RDI,
+        number => libc::REG_R8 + (number as c_int - 8),
+    };
+
+    index as usize
+}
+
+fn size_mask(size: usize) -> u64 {
+    if size == 8 { u64::MAX } else { (1u64 << (size * 8)) - 1 }
+}
+
+/// Performs the access against the transport, register space is only ever touched as whole
+/// 32 bit words, narrower accesses are shifted out of the word they live in.
+pub fn emulate_read(transport: &SharedTransport, offset: u32, size: usize) -> u64 {
+    let transport = transport.lock().unwrap();
+
+    if offset >= CONFIG_SPACE {
+        let mut data = [0u8; 8];
+        transport.read_config(offset - CONFIG_SPACE, &mut data[..size]);
+
+        return u64::from_le_bytes(data);
+    }
+
+    let word = transport.read(offset & !3) as u64;
+    (word >> ((offset & 3) * 8)) & size_mask(size)
+}
+
+/// Returns false for a store the registers can't take, a 64 bit one would lose its upper half
+pub fn emulate_write(transport: &SharedTransport, offset: u32, size: usize, value: u64) -> bool {
+    let mut transport = transport.lock().unwrap();
+
+    if offset >= CONFIG_SPACE {
+        let data = value.to_le_bytes();
+        transport.write_config(offset - CONFIG_SPACE, &data[..size]);
+
+        return true;
+    }
+
+    if size > 4 {
+        return false;
+    }
+
+    // Narrow or unaligned stores land in their lanes of the word, the rest of it is kept
+    let shift = (offset & 3) * 8;
+    let mask = (size_mask(size) << shift) as u32;
+    let word = (transport.registers.get(offset & !3) & !mask) | ((value << shift) as u32 & mask);
+
+    transport.write(offset & !3, word);
+
+    true
+}
+
+// Blocks in the kernel until the worker has done the access, everything here is async signal safe
+#[cfg(target_arch = "x86_64")]
+unsafe fn forward(trap: &Trap, slot: usize, offset: u32, size: usize, is_store: bool, value: u64) -> Option<u64> {
+    let reply = Reply { done: AtomicU32::new(0), value: AtomicU64::new(0) };
+    let request = Request { slot, offset, size, is_store, value, reply: &reply };
+
+    let written = libc::write(trap.requests, &request as *const Request as *const c_void, mem::size_of::<Request>());
+
+    if written != mem::size_of::<Request>() as isize {
+        return None;
+    }
+
+    let mut done = reply.done.load(Ordering::Acquire);
+
+    while done == 0 {
+        libc::syscall(libc::SYS_futex, &reply.done as *const AtomicU32, libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG, 0, ptr::null::<libc::timespec>());
+        done = reply.done.load(Ordering::Acquire);
+    }
+
+    // A rejected access goes on to the previous handler like any other bad one
+    (done == DONE).then(|| reply.value.load(Ordering::Relaxed))
+}
+
+#[cfg(target_arch = "x86_64")]
+extern "C" fn handle_segv(signal: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    unsafe {
+        let Some(trap) = TRAP.get() else {
+            return;
+        };
+
+        let fault_address = (*info).si_addr() as usize;
+
+        let region = REGION_BASES.iter()
+            .map(|base| base.load(Ordering::Acquire))
+            .position(|base| base != 0 && fault_address >= base && fault_address < base + MMIO_WINDOW_SIZE)
+            .map(|slot| (slot, (fault_address - REGION_BASES[slot].load(Ordering::Relaxed)) as u32));
+
+        let context = &mut *(context as *mut libc::ucontext_t);
+        let gregs = &mut context.uc_mcontext.gregs;
+        let rip = gregs[libc::REG_RIP as usize] as *const u8;
+
+        let emulated = match region.and_then(|region| Some((region, decode(rip)?))) {
+            Some(((slot, offset), access)) if access.is_store => {
+                let value = match access.operand {
+                    Operand::Immediate(value) => value,
+                    Operand::Register { number, high_byte: true } => (gregs[greg_index(number)] as u64) >> 8,
+                    Operand::Register { number, .. } => gregs[greg_index(number)] as u64,
+                };
+
+                forward(trap, slot, offset, access.size, true, value & size_mask(access.size)).map(|_| access)
+            },
+            Some(((slot, offset), access)) => {
+                forward(trap, slot, offset, access.size, false, 0).map(|value| {
+                    if let Operand::Register { number, high_byte } = access.operand {
+                        let slot = &mut gregs[greg_index(number)];
+
+                        *slot = if access.zero_extend {
+                            value as i64
+                        } else if high_byte {
+                            ((*slot as u64 & !0xff00) | (value << 8)) as i64
+                        } else {
+                            ((*slot as u64 & !size_mask(access.size)) | value) as i64
+                        };
+                    }
+
+                    access
+                })
+            },
+            None => None,
+        };
+
+        if let Some(access) = emulated {
+            gregs[libc::REG_RIP as usize] += access.length as i64;
+            return;
+        }
+
+        // Not one of ours, hand it back to whoever was there before so real crashes still crash
+        let previous = &trap.previous;
+
+        if previous.sa_sigaction == libc::SIG_DFL || previous.sa_sigaction == libc::SIG_IGN {
+            libc::sigaction(libc::SIGSEGV, previous, ptr::null_mut());
+        } else if previous.sa_flags & libc::SA_SIGINFO > 0 {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = mem::transmute(previous.sa_sigaction);
+            handler(signal, info, context as *mut libc::ucontext_t as *mut c_void);
+        } else {
+            let handler: extern "C" fn(c_int) = mem::transmute(previous.sa_sigaction);
+            handler(signal);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+pub fn test_trapped_register_access() {
+    use crate::virtio::{device_register::*, transport::{MmioTransport, TransportMode, STATUS_ACKNOWLEDGE}};
+
+    let transport = MmioTransport::new(TransportMode::Modern, 2, 0, &[16]).into_shared();
+    transport.lock().unwrap().set_config_space(vec![0xaa, 0xbb, 0xcc, 0xdd]);
+
+    let region = TrappedRegion::new(transport.clone()).unwrap();
+
+    unsafe {
+        assert_eq!(region.register(MAGIC_VALUE).read_volatile(), MAGIC);
+        assert_eq!(region.register(DEVICE_ID).read_volatile(), 2);
+
+        region.register(STATUS).write_volatile(STATUS_ACKNOWLEDGE);
+        assert_eq!(transport.lock().unwrap().status(), STATUS_ACKNOWLEDGE);
+
+        assert_eq!(region.base().add(CONFIG_SPACE as usize + 1).read_volatile(), 0xbb);
+        assert_eq!((region.base().add(CONFIG_SPACE as usize) as *const u16).read_volatile(), 0xbbaa);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+pub fn test_narrow_writes_keep_the_rest_of_the_word() {
+    use crate::virtio::{device_register::*, transport::{MmioTransport, TransportMode}};
+
+    let transport = MmioTransport::new(TransportMode::Modern, 2, 0, &[16, 16]).into_shared();
+    let region = TrappedRegion::new(transport.clone()).unwrap();
+
+    unsafe {
+        region.register(QUEUE_DESC_LOW).write_volatile(0x1122_3344);
+        region.base().add(QUEUE_DESC_LOW as usize + 1).write_volatile(0x55);
+
+        assert_eq!(transport.lock().unwrap().registers.get(QUEUE_DESC_LOW), 0x1122_5544);
+    }
+}
+
+#[test]
+pub fn test_wide_register_stores_are_rejected() {
+    use crate::virtio::{device_register::*, transport::{MmioTransport, TransportMode}};
+
+    let transport = MmioTransport::new(TransportMode::Modern, 2, 0, &[16]).into_shared();
+    transport.lock().unwrap().set_config_space(vec![0; 8]);
+
+    assert!(!emulate_write(&transport, QUEUE_DESC_LOW, 8, 0x5566_7788_1122_3344));
+    assert_eq!(transport.lock().unwrap().registers.get(QUEUE_DESC_LOW), 0);
+    assert_eq!(transport.lock().unwrap().registers.get(QUEUE_DESC_HIGH), 0);
+
+    assert!(emulate_write(&transport, QUEUE_DESC_LOW, 4, 0x1122_3344));
+    assert!(emulate_write(&transport, CONFIG_SPACE, 8, 0x0102_0304_0506_0708));
+    assert_eq!(emulate_read(&transport, CONFIG_SPACE, 8), 0x0102_0304_0506_0708);
+}
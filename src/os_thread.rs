@@ -1,142 +1,856 @@
 // A fake OS thread this will act as a virtual os to handle the file writes and interacting with
 // the virtio thread
 
-use std::mem::ManuallyDrop;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
 
 use tokio_stream::StreamExt;
 use futures::FutureExt;
 
-use tokio::runtime;
+use tokio::{runtime, time::Instant};
 
-use crate::faux_blk;
-use crate::async_driver::DriverPoller;
+use crate::async_driver::{DriverPoller, DriverEvent};
 
+use crate::mmio_trap::TrappedRegion;
 use crate::poller::PollableQueue;
+use crate::virtio::device_register::*;
+use crate::virtio::transport::{STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_FEATURES_OK, STATUS_DRIVER_OK, STATUS_FAILED};
+use crate::virtio_blk::{RequestHeader, SECTOR_SIZE, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_S_OK};
+use crate::virtio_console::{ControlMessage, CONTROL_MESSAGE_SIZE, CONTROL_RECEIVEQ, CONTROL_TRANSMITQ, RECEIVEQ, TRANSMITQ, VIRTIO_CONSOLE_DEVICE_ADD, VIRTIO_CONSOLE_DEVICE_READY, VIRTIO_CONSOLE_DEVICE_REMOVE, VIRTIO_CONSOLE_PORT_NAME, VIRTIO_CONSOLE_PORT_OPEN, VIRTIO_CONSOLE_PORT_READY, queue_port, receive_queue, transmit_queue};
 use crate::{comms::{CommsLink, Messages}, virtio::guest_driver::GuestDriver};
+use crate::guest_net::{self, control_command, format_ipv4, parse_ipv4, post_receive_buffers, receive_frame, send_frame, Ipv4Address};
+use crate::virtio_net::transmit_queue as net_transmit_queue;
+use crate::virtio_net::control::*;
+use crate::guest_vsock::{self, send_packet, EVENT_BUFFER_SIZE, RECEIVE_BUFFER_SIZE};
+use crate::guest_fs::FsClient;
+use crate::guest_9p::NinePClient;
+use crate::guest_balloon::GuestBalloon;
+use crate::guest_input::InputAgent;
+use crate::virtio_input::{evdev::EVENT_SIZE, EVENTQ as INPUT_EVENTQ, STATUSQ as INPUT_STATUSQ};
+use crate::virtio::guest_memory::GuestMemory;
+use crate::virtio::legacy::{pfn_of, LEGACY_GUEST_PAGE_SIZE, LEGACY_QUEUE_ALIGN};
+use crate::virtio::vring::Vring;
+use crate::virtio_vsock::{RECEIVEQ as VSOCK_RECEIVEQ, TRANSMITQ as VSOCK_TRANSMITQ, EVENTQ as VSOCK_EVENTQ, VIRTIO_VSOCK_EVENT_TRANSPORT_RESET};
+
+// Our "filesystem" gives every file a fixed slot on the disk picked by hashing its name. The
+// first sector of a slot holds the name and length, the contents follow. Two names that hash
+// to the same slot overwrite each other, it's a fake OS after all.
+const SLOT_SECTORS: u64 = 64;
+const MAX_FILE_NAME: usize = SECTOR_SIZE as usize - 6;
+const MAX_FILE_SIZE: usize = ((SLOT_SECTORS - 1) * SECTOR_SIZE) as usize;
+
+enum BlockRequest {
+    Write(String),
+    Read(String),
+}
 
-unsafe fn write_string_to_queue<const S: usize, P: PollableQueue + Clone>(driver: &mut GuestDriver<S, P>, message: &str, flag: u16) -> bool {
-    let (cell_ptr, idx) = match driver.get_descriptor_cell() {
-        Some(res) => res,
-        None => return false,
-    };
+struct SlotFs {
+    slot_count: u64,
+    pending: HashMap<u16, BlockRequest>,
+}
+
+impl SlotFs {
+    fn new(capacity_sectors: u64) -> Self {
+        Self {
+            slot_count: capacity_sectors / SLOT_SECTORS,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn slot_sector(&self, file_name: &str) -> Option<u64> {
+        if self.slot_count == 0 {
+            return None;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        file_name.hash(&mut hasher);
+
+        Some(hasher.finish() % self.slot_count * SLOT_SECTORS)
+    }
+
+    unsafe fn write_file<const S: usize, P: PollableQueue + Clone>(&mut self, driver: &mut GuestDriver<S, P>, file_name: &str, file_contents: &str) -> Result<(), String> {
+        let sector = self.slot_sector(file_name).ok_or("The disk is too small for any files")?;
+
+        if file_name.len() > MAX_FILE_NAME || file_contents.len() > MAX_FILE_SIZE {
+            return Err(format!("{file_name} is too big to store"));
+        }
+
+        let mut data = Vec::with_capacity(SECTOR_SIZE as usize + file_contents.len());
+        data.extend_from_slice(&(file_name.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(file_contents.len() as u32).to_le_bytes());
+        data.extend_from_slice(file_name.as_bytes());
+        data.resize(SECTOR_SIZE as usize, 0);
+        data.extend_from_slice(file_contents.as_bytes());
+        data.resize(data.len().next_multiple_of(SECTOR_SIZE as usize), 0);
 
-    let cell = cell_ptr.as_mut().unwrap();
-    let mut message = message.to_string();
-    message.shrink_to_fit();
+        let header = RequestHeader { request_type: VIRTIO_BLK_T_OUT, sector };
 
-    let device_address = ManuallyDrop::new(message);
-    let length = device_address.capacity();
+        let head = driver.submit_chain(vec![
+            (Box::new(header.to_bytes()), false),
+            (data.into_boxed_slice(), false),
+            (Box::new([0xff]), true),
+        ]).ok_or("The queue is full")?;
 
-    cell.addr = device_address.as_ptr() as u64;
-    cell.length = length as u32;
-    cell.flags = flag;
-    cell.next = 0;
+        self.pending.insert(head, BlockRequest::Write(file_name.to_string()));
+        Ok(())
+    }
+
+    unsafe fn read_file<const S: usize, P: PollableQueue + Clone>(&mut self, driver: &mut GuestDriver<S, P>, file_name: &str) -> Result<(), String> {
+        let sector = self.slot_sector(file_name).ok_or("The disk is too small for any files")?;
+        let header = RequestHeader { request_type: VIRTIO_BLK_T_IN, sector };
 
-    driver.submit_to_avail_queue(idx);
+        let head = driver.submit_chain(vec![
+            (Box::new(header.to_bytes()), false),
+            (vec![0u8; (SLOT_SECTORS * SECTOR_SIZE) as usize].into_boxed_slice(), true),
+            (Box::new([0xff]), true),
+        ]).ok_or("The queue is full")?;
 
-    return true
+        self.pending.insert(head, BlockRequest::Read(file_name.to_string()));
+        Ok(())
+    }
+
+    /// Works out what a finished request meant, reads hand back the file contents
+    fn complete(&mut self, head: u16, buffers: Vec<Box<[u8]>>) -> Messages {
+        let status = buffers.last().map(|status| status[0]).unwrap_or(0xff);
+
+        match self.pending.remove(&head) {
+            Some(BlockRequest::Write(file_name)) => {
+                Messages::OSMessage(format!("Wrote {file_name} to disk, status {status}"))
+            },
+            Some(BlockRequest::Read(file_name)) if status == VIRTIO_BLK_S_OK => {
+                let slot = &buffers[1];
+                let name_len = u16::from_le_bytes([slot[0], slot[1]]) as usize;
+                let contents_len = (u32::from_le_bytes(slot[2..6].try_into().unwrap()) as usize).min(MAX_FILE_SIZE);
+
+                if name_len > MAX_FILE_NAME || &slot[6..6 + name_len] != file_name.as_bytes() {
+                    return Messages::OSMessage(format!("{file_name} does not exist"));
+                }
+
+                let contents = &slot[SECTOR_SIZE as usize..SECTOR_SIZE as usize + contents_len];
+                Messages::FileContents(file_name, String::from_utf8_lossy(contents).into_owned())
+            },
+            Some(BlockRequest::Read(file_name)) => {
+                Messages::OSMessage(format!("Reading {file_name} failed with status {status}"))
+            },
+            None => Messages::OSMessage(format!("Completion for unknown request {head}")),
+        }
+    }
 }
 
-unsafe fn read_request_to_queue<const S: usize, P: PollableQueue + Clone>(driver: &mut GuestDriver<S, P>, flag: u16) -> bool {
-    let (cell_ptr, idx) = match driver.get_descriptor_cell() {
-        Some(res) => res,
-        None => return false,
-    };
+const CONSOLE_BUFFERS: usize = 8;
+const CONSOLE_BUFFER_SIZE: usize = 64;
 
-    let cell = cell_ptr.as_mut().unwrap();
-    let storage_blob = Vec::<u8>::with_capacity(2048);
-    let values = ManuallyDrop::new(storage_blob);
+const CONTROL_BUFFERS: usize = 8;
+const CONTROL_BUFFER_SIZE: usize = 128;
 
-    let length = values.capacity();
+const PROMPT: &str = "$ ";
 
-    cell.addr = values.as_ptr() as u64;
-    cell.length = length as u32;
-    cell.flags = flag;
-    cell.next = 0;
+const ENTROPY_REQUEST_SIZE: usize = 16;
 
-    driver.submit_to_avail_queue(idx);
+/// The guest end of every device the OS drives, a driver per queue for the ones with several
+pub struct GuestDrivers<const S: usize, P: PollableQueue + Clone> {
+    pub block: GuestDriver<S, P>,
+    pub console: Vec<GuestDriver<S, P>>,
+    pub rng: GuestDriver<S, P>,
+    pub net: Vec<GuestDriver<S, P>>,
+    pub vsock: Vec<GuestDriver<S, P>>,
+    pub fs: Vec<GuestDriver<S, P>>,
+    pub nine_p: GuestDriver<S, P>,
+    pub balloon: Vec<GuestDriver<S, P>>,
+    pub input: Vec<GuestDriver<S, P>>,
+}
 
-    true
+/// Output is dropped when the transmit queue is full, same as a real console would
+pub(crate) unsafe fn transmit<const S: usize, P: PollableQueue + Clone>(driver: &mut GuestDriver<S, P>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        let _ = driver.submit_chain(vec![(bytes.to_vec().into_boxed_slice(), false)]);
+    }
 }
 
-unsafe fn write_file_contents<const S: usize, P: PollableQueue + Clone>(driver_ptr: *mut GuestDriver<S, P>, file_name: &str, file_contents: &str) -> bool {
-    let driver = driver_ptr.as_mut().unwrap();
+pub(crate) unsafe fn post_buffers<const S: usize, P: PollableQueue + Clone>(driver: &mut GuestDriver<S, P>, count: usize, size: usize) {
+    for _ in 0..count {
+        if driver.submit_chain(vec![(vec![0u8; size].into_boxed_slice(), true)]).is_none() {
+            return;
+        }
+    }
+}
 
-    const OPEN_FILE_FLAG: u16 = faux_blk::FILE_WRITE | faux_blk::FILE_OPEN_FLAG;
-    if !write_string_to_queue(driver, file_name, OPEN_FILE_FLAG) {
-        return false;
+// Which device a file command goes through, the shell has the same commands for both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Share {
+    VirtioFs,
+    NineP,
+}
+
+// A line at a time shell on the console. Keystrokes come in on the receive queue and get echoed
+// back out on the transmit queue, a finished line is run as a command.
+struct ConsoleShell {
+    line: String,
+    posted: usize,
+    booted: Instant,
+    capacity: u64,
+
+    // Addresses the user asked to ping or send datagrams to and promiscuous mode changes, the
+    // network side does them
+    pings: Vec<Ipv4Address>,
+    datagrams: Vec<(Ipv4Address, usize)>,
+    promiscuous: Option<bool>,
+    // Lines to send to host vsock ports
+    vsock_lines: Vec<(u32, String)>,
+    // A file command for one of the shares, its output and the next prompt come once the
+    // device has answered
+    fs_command: Option<(Share, String, String)>,
+    // A memory command, the balloon driver owns the page allocator
+    memory_command: Option<(String, String)>,
+}
+
+impl ConsoleShell {
+    fn new(capacity: u64) -> Self {
+        Self {
+            line: String::new(),
+            posted: 0,
+            booted: Instant::now(),
+            capacity,
+
+            pings: Vec::new(),
+            datagrams: Vec::new(),
+            promiscuous: None,
+            vsock_lines: Vec::new(),
+            fs_command: None,
+            memory_command: None,
+        }
     }
 
-    const WRITE_CONTENTS: u16 = faux_blk::FILE_WRITE | faux_blk::FILE_WRITE_CONTENTS_FLAG;
-    if !write_string_to_queue(driver, file_contents, WRITE_CONTENTS) {
-        return false;
+    /// Keeps the device stocked with somewhere to put what's typed
+    unsafe fn post_receive_buffers<const S: usize, P: PollableQueue + Clone>(&mut self, driver: &mut GuestDriver<S, P>) {
+        while self.posted < CONSOLE_BUFFERS {
+            if driver.submit_chain(vec![(vec![0u8; CONSOLE_BUFFER_SIZE].into_boxed_slice(), true)]).is_none() {
+                return;
+            }
+
+            self.posted += 1;
+        }
     }
 
-    const CLOSE_FILE_FLAG: u16 = faux_blk::FILE_WRITE | faux_blk::FILE_CLOSE_FLAG;
-    if !write_string_to_queue(driver, "", CLOSE_FILE_FLAG) {
-        return false;
+    /// Takes a filled receive buffer back, returns what to echo
+    fn received(&mut self, bytes: &[u8]) -> String {
+        self.posted -= 1;
+
+        let mut output = String::new();
+
+        for byte in bytes {
+            match byte {
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+
+                    output.push('\n');
+                    output.push_str(&self.run(line.trim()));
+
+                    if self.fs_command.is_none() && self.memory_command.is_none() {
+                        output.push_str(PROMPT);
+                    }
+                },
+                // Backspace on an empty line falls through to the catch all and does nothing
+                0x08 | 0x7f if self.line.pop().is_some() => output.push('\x08'),
+                byte if byte.is_ascii_graphic() || *byte == b' ' => {
+                    self.line.push(*byte as char);
+                    output.push(*byte as char);
+                },
+                _ => {},
+            }
+        }
+
+        output
     }
 
-    true
+    fn run(&mut self, line: &str) -> String {
+        match line.split_once(' ').unwrap_or((line, "")) {
+            ("", _) => String::new(),
+            ("help", _) => "Commands: help, echo <text>, uptime, disk, ping <address>, udp <address> <bytes>, vsock <port> <text>, promisc on|off, ls [dir], cat <file>, put <file> <text>, rm <file>, mkdir <dir>, rmdir <dir>, mv <from> <to>, df, 9p <file command> for the same over virtio-9p, mem, alloc <MiB>, free <MiB>\n".to_string(),
+            ("echo", text) => format!("{text}\n"),
+            ("uptime", _) => format!("Up for {} seconds\n", self.booted.elapsed().as_secs()),
+            ("disk", _) => format!("The disk has {} sectors\n", self.capacity),
+            ("ping", address) => match parse_ipv4(address.trim()) {
+                Some(ip) => {
+                    self.pings.push(ip);
+                    format!("PING {}\n", format_ipv4(&ip))
+                },
+                None => format!("ping: {address} isn't an address\n"),
+            },
+            ("udp", arguments) => match arguments.split_once(' ').map(|(address, length)| (parse_ipv4(address), length.trim().parse())) {
+                Some((Some(ip), Ok(length))) => {
+                    self.datagrams.push((ip, length));
+                    format!("Sending {length} bytes to {} port 7\n", format_ipv4(&ip))
+                },
+                _ => "udp: expected an address and a size in bytes\n".to_string(),
+            },
+            ("vsock", arguments) => match arguments.split_once(' ').map(|(port, text)| (port.parse::<u32>(), text)) {
+                Some((Ok(port), text)) => {
+                    self.vsock_lines.push((port, format!("{text}\n")));
+                    format!("Sending to host port {port}\n")
+                },
+                _ => "vsock: expected a port and some text\n".to_string(),
+            },
+            (command @ ("ls" | "cat" | "put" | "rm" | "mkdir" | "rmdir" | "mv" | "df"), arguments) => {
+                self.fs_command = Some((Share::VirtioFs, command.to_string(), arguments.to_string()));
+                String::new()
+            },
+            ("9p", line) => match line.split_once(' ').unwrap_or((line, "")) {
+                (command @ ("ls" | "cat" | "put" | "rm" | "mkdir" | "rmdir" | "mv" | "df"), arguments) => {
+                    self.fs_command = Some((Share::NineP, command.to_string(), arguments.to_string()));
+                    String::new()
+                },
+                _ => "9p: expected a file command, like 9p ls\n".to_string(),
+            },
+            (command @ ("mem" | "alloc" | "free"), arguments) => {
+                self.memory_command = Some((command.to_string(), arguments.to_string()));
+                String::new()
+            },
+            ("promisc", mode @ ("on" | "off")) => {
+                self.promiscuous = Some(mode == "on");
+                format!("Turning promiscuous mode {mode}\n")
+            },
+            (command, _) => format!("{command}: command not found\n"),
+        }
+    }
 }
 
-unsafe fn read_file_contents<const S: usize, P: PollableQueue + Clone>(driver_ptr: *mut GuestDriver<S, P>, file_name: &str) -> bool {
-    let driver = driver_ptr.as_mut().unwrap();
+// The guest end of a multiport console. Every named port gets a tiny agent that sends back
+// whatever the host writes to it, enough to check a channel works end to end.
+struct ConsolePorts {
+    names: HashMap<u32, String>,
+}
+
+impl ConsolePorts {
+    fn new() -> Self {
+        Self { names: HashMap::new() }
+    }
+
+    unsafe fn start<const S: usize, P: PollableQueue + Clone>(&mut self, queue: impl Fn(u16) -> *mut GuestDriver<S, P>) {
+        post_buffers(queue(CONTROL_RECEIVEQ).as_mut().unwrap(), CONTROL_BUFFERS, CONTROL_BUFFER_SIZE);
+        self.send(&queue, 0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+    }
 
-    const OPEN_FILE_FLAG: u16 = faux_blk::FILE_READ | faux_blk::FILE_OPEN_FLAG;
-    if !write_string_to_queue(driver, file_name, OPEN_FILE_FLAG) {
-        return false;
+    unsafe fn send<const S: usize, P: PollableQueue + Clone>(&self, queue: &impl Fn(u16) -> *mut GuestDriver<S, P>, id: u32, event: u16, value: u16) {
+        transmit(queue(CONTROL_TRANSMITQ).as_mut().unwrap(), &ControlMessage { id, event, value }.to_bytes());
     }
 
-    const READ_CONTENTS: u16 = faux_blk::FILE_READ | faux_blk::FILE_WRITE_CONTENTS_FLAG;
-    if !read_request_to_queue(driver, READ_CONTENTS) {
-        return false;
+    /// Handles a message on the control queue, returns anything worth telling the user
+    unsafe fn control<const S: usize, P: PollableQueue + Clone>(&mut self, queue: impl Fn(u16) -> *mut GuestDriver<S, P>, bytes: &[u8]) -> Option<String> {
+        let message = ControlMessage::parse(bytes)?;
+        let id = message.id;
+
+        match message.event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                // Port 0 is the shell's and already has its buffers
+                if id != 0 {
+                    post_buffers(queue(receive_queue(id)).as_mut().unwrap(), CONSOLE_BUFFERS, CONSOLE_BUFFER_SIZE);
+                }
+
+                self.names.insert(id, String::new());
+                self.send(&queue, id, VIRTIO_CONSOLE_PORT_READY, 1);
+
+                Some(format!("Console port {id} added"))
+            },
+            VIRTIO_CONSOLE_DEVICE_REMOVE => {
+                self.names.remove(&id);
+                Some(format!("Console port {id} removed"))
+            },
+            VIRTIO_CONSOLE_PORT_NAME => {
+                let name = String::from_utf8_lossy(&bytes[CONTROL_MESSAGE_SIZE..]).into_owned();
+                self.names.insert(id, name.clone());
+
+                // The agent has the port open as soon as it knows what it is
+                self.send(&queue, id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+
+                Some(format!("Console port {id} is {name}"))
+            },
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                let state = if message.value == 1 { "connected to" } else { "disconnected from" };
+                Some(format!("The host {state} console port {id}"))
+            },
+            _ => None,
+        }
     }
 
-    const CLOSE_FILE_FLAG: u16 = faux_blk::FILE_READ | faux_blk::FILE_CLOSE_FLAG;
-    if !write_string_to_queue(driver, "", CLOSE_FILE_FLAG) {
-        return false;
+    /// Bytes for a named port's agent. Buffers of ports that have gone aren't handed back.
+    unsafe fn received<const S: usize, P: PollableQueue + Clone>(&mut self, queue: impl Fn(u16) -> *mut GuestDriver<S, P>, port: u32, bytes: &[u8]) {
+        if !self.names.contains_key(&port) {
+            return;
+        }
+
+        transmit(queue(transmit_queue(port)).as_mut().unwrap(), bytes);
+        post_buffers(queue(receive_queue(port)).as_mut().unwrap(), 1, CONSOLE_BUFFER_SIZE);
     }
+}
 
-    true
+/// Where each of a device's queues lives, read before the pollers take the drivers
+pub(crate) fn queue_rings<const S: usize, P: PollableQueue + Clone>(drivers: &[GuestDriver<S, P>]) -> Vec<Vring> {
+    drivers.iter().map(|driver| driver.vring()).collect()
 }
 
-pub fn create_os_thread<const S: usize, P: PollableQueue +  Clone + Send + 'static>(mut ui_comms: CommsLink, mut driver: GuestDriver<S, P>) {
+// Brings the device up the way a kernel driver would, every access here is a plain pointer
+// into the trapped register window.
+pub(crate) unsafe fn initialise_device(registers: &TrappedRegion, queues: &[Vring]) -> Result<u32, String> {
+    let magic = registers.register(MAGIC_VALUE).read_volatile();
+    let version = registers.register(VERSION).read_volatile();
+
+    if magic != MAGIC {
+        return Err(format!("Bad magic value {magic:x}"));
+    }
+
+    registers.register(STATUS).write_volatile(0);
+    registers.register(STATUS).write_volatile(STATUS_ACKNOWLEDGE);
+    registers.register(STATUS).write_volatile(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+
+    for word in 0..2 {
+        registers.register(DEVICE_FEATURES_SEL).write_volatile(word);
+        let offered = registers.register(DEVICE_FEATURES).read_volatile();
+
+        registers.register(DRIVER_FEATURES_SEL).write_volatile(word);
+        registers.register(DRIVER_FEATURES).write_volatile(offered);
+    }
+
+    if version != LEGACY_VERSION {
+        status |= STATUS_FEATURES_OK;
+        registers.register(STATUS).write_volatile(status);
+
+        if registers.register(STATUS).read_volatile() & STATUS_FEATURES_OK == 0 {
+            registers.register(STATUS).write_volatile(status | STATUS_FAILED);
+            return Err("Device rejected our features".to_string());
+        }
+    }
+
+    if version == LEGACY_VERSION {
+        registers.register(GUEST_PAGE_SIZE).write_volatile(LEGACY_GUEST_PAGE_SIZE);
+    }
+
+    // With vectors on offer config changes get the first and the queues share out the rest.
+    // One the device won't take reads back as NO_VECTOR and that cause stays on the line.
+    let vectors = registers.register(MSIX_VECTOR_COUNT).read_volatile();
+
+    if vectors > 0 {
+        registers.register(MSIX_CONFIG_VECTOR).write_volatile(0);
+    }
+
+    for (queue, ring) in queues.iter().enumerate() {
+        registers.register(QUEUE_SEL).write_volatile(queue as u32);
+        registers.register(QUEUE_NUM).write_volatile(ring.size as u32);
+
+        if vectors > 0 {
+            let vector = if vectors == 1 { 0 } else { 1 + queue as u32 % (vectors - 1) };
+            registers.register(MSIX_QUEUE_VECTOR).write_volatile(vector);
+        }
+
+        let ready = if version == LEGACY_VERSION {
+            registers.register(QUEUE_ALIGN).write_volatile(LEGACY_QUEUE_ALIGN);
+            registers.register(QUEUE_PFN).write_volatile(pfn_of(ring.desc));
+
+            registers.register(QUEUE_PFN).read_volatile() != 0
+        } else {
+            registers.register(QUEUE_DESC_LOW).write_volatile(ring.desc as u32);
+            registers.register(QUEUE_DESC_HIGH).write_volatile((ring.desc >> 32) as u32);
+            registers.register(QUEUE_DRIVER_LOW).write_volatile(ring.avail as u32);
+            registers.register(QUEUE_DRIVER_HIGH).write_volatile((ring.avail >> 32) as u32);
+            registers.register(QUEUE_DEVICE_LOW).write_volatile(ring.used as u32);
+            registers.register(QUEUE_DEVICE_HIGH).write_volatile((ring.used >> 32) as u32);
+            registers.register(QUEUE_READY).write_volatile(1);
+
+            registers.register(QUEUE_READY).read_volatile() == 1
+        };
+
+        if !ready {
+            registers.register(STATUS).write_volatile(status | STATUS_FAILED);
+            return Err(format!("Device refused queue {queue}"));
+        }
+    }
+
+    registers.register(STATUS).write_volatile(status | STATUS_DRIVER_OK);
+
+    Ok(registers.register(DEVICE_ID).read_volatile())
+}
+
+/// Tells the user how bringing a device up went, only one that came up gets used
+async fn announce<T>(ui_comms: &CommsLink, device: &str, started: Result<(T, String), String>) -> Option<T> {
+    let (device, message) = match started {
+        Ok((started, details)) => (Some(started), format!("Initialised virtio {device} {details}")),
+        Err(reason) => (None, format!("Failed to initialise the virtio {device}: {reason}")),
+    };
+
+    ui_comms.tx.send(Messages::OSMessage(message)).await.unwrap();
+    device
+}
+
+pub fn create_os_thread<const S: usize, P: PollableQueue +  Clone + Send + 'static>(mut ui_comms: CommsLink, drivers: GuestDrivers<S, P>, memory: Arc<GuestMemory>) {
     let rt = runtime::Builder::new_current_thread().enable_all().build().unwrap();
 
+    let GuestDrivers { block: mut driver, console: mut console_drivers, rng: mut rng_driver, net: mut net_drivers, vsock: mut vsock_drivers, fs: mut fs_drivers, nine_p: mut nine_p_driver, balloon: mut balloon_drivers, input: mut input_drivers } = drivers;
+
+    let registers = TrappedRegion::new(driver.transport().clone()).unwrap();
+    let console_registers = TrappedRegion::new(console_drivers[0].transport().clone()).unwrap();
+    let rng_registers = TrappedRegion::new(rng_driver.transport().clone()).unwrap();
+    let net_registers = TrappedRegion::new(net_drivers[0].transport().clone()).unwrap();
+    let vsock_registers = TrappedRegion::new(vsock_drivers[0].transport().clone()).unwrap();
+    let fs_registers = TrappedRegion::new(fs_drivers[0].transport().clone()).unwrap();
+    let nine_p_registers = TrappedRegion::new(nine_p_driver.transport().clone()).unwrap();
+    let balloon_registers = TrappedRegion::new(balloon_drivers[0].transport().clone()).unwrap();
+    let input_registers = TrappedRegion::new(input_drivers[0].transport().clone()).unwrap();
+
+    let rings = queue_rings(std::slice::from_ref(&driver));
+    let console_rings = queue_rings(&console_drivers);
+    let rng_rings = queue_rings(std::slice::from_ref(&rng_driver));
+    let net_rings = queue_rings(&net_drivers);
+    let vsock_rings = queue_rings(&vsock_drivers);
+    let fs_rings = queue_rings(&fs_drivers);
+    let nine_p_rings = queue_rings(std::slice::from_ref(&nine_p_driver));
+    let balloon_rings = queue_rings(&balloon_drivers);
+    let input_rings = queue_rings(&input_drivers);
+
     let mut poller = DriverPoller::new(&mut driver);
     let driver_ptr = unsafe { poller.get_driver() };
 
     poller.delayed_poller();
 
+    let console_queues = console_drivers.len();
+    let mut console_poller = DriverPoller::with_queues(console_drivers.iter_mut().collect());
+    let console_ptrs: Vec<_> = (0..console_queues).map(|queue| unsafe { console_poller.get_queue_driver(queue as u16) }).collect();
+    let console_queue = |queue: u16| console_ptrs[queue as usize];
+
+    console_poller.delayed_poller();
+
+    let mut rng_poller = DriverPoller::new(&mut rng_driver);
+    rng_poller.delayed_poller();
+
+    // The control queue is always the last one
+    let net_control = net_drivers.len() as u16 - 1;
+    let mut net_poller = DriverPoller::with_queues(net_drivers.iter_mut().collect());
+    let net_ptrs: Vec<_> = (0..=net_control).map(|queue| unsafe { net_poller.get_queue_driver(queue) }).collect();
+    let net_queue = |queue: u16| net_ptrs[queue as usize];
+
+    // Pings go out on each pair in turn so the replies get steered across all of them
+    let mut next_pair = 0;
+
+    net_poller.delayed_poller();
+
+    let mut vsock_poller = DriverPoller::with_queues(vsock_drivers.iter_mut().collect());
+    let vsock_ptrs: Vec<_> = (0..=VSOCK_EVENTQ).map(|queue| unsafe { vsock_poller.get_queue_driver(queue) }).collect();
+    let vsock_queue = |queue: u16| vsock_ptrs[queue as usize];
+
+    vsock_poller.delayed_poller();
+
+    let balloon_queues = balloon_drivers.len();
+    let mut balloon_poller = DriverPoller::with_queues(balloon_drivers.iter_mut().collect());
+    let balloon_ptrs: Vec<_> = (0..balloon_queues).map(|queue| unsafe { balloon_poller.get_queue_driver(queue as u16) }).collect();
+    let balloon_queue = |queue: u16| balloon_ptrs[queue as usize];
+
+    balloon_poller.delayed_poller();
+
+    let mut input_poller = DriverPoller::with_queues(input_drivers.iter_mut().collect());
+    let input_ptrs: Vec<_> = (0..=INPUT_STATUSQ).map(|queue| unsafe { input_poller.get_queue_driver(queue) }).collect();
+    let input_queue = |queue: u16| input_ptrs[queue as usize];
+
+    input_poller.delayed_poller();
+
+    // Only ever waited on while a file command runs, so it stays out of the select loop
+    let mut fs_client = FsClient::new(fs_drivers.iter_mut().collect());
+    let mut nine_p_client = NinePClient::new(&mut nine_p_driver);
+
     rt.block_on(async {
         let start_message = Messages::OSMessage(format!("The os thread has booted!"));
         ui_comms.tx.send(start_message).await.unwrap();
 
+        let init_message = match unsafe { initialise_device(&registers, &rings) } {
+            Ok(device_id) => format!("Initialised virtio device with id {device_id} through MMIO"),
+            Err(reason) => format!("Failed to initialise the virtio device: {reason}"),
+        };
+        ui_comms.tx.send(Messages::OSMessage(init_message)).await.unwrap();
+
+        // Capacity is the first field of the block config space
+        let capacity = unsafe {
+            let low = registers.register(CONFIG_SPACE).read_volatile() as u64;
+            let high = registers.register(CONFIG_SPACE + 4).read_volatile() as u64;
+
+            high << 32 | low
+        };
+
+        let mut filesystem = SlotFs::new(capacity);
+        ui_comms.tx.send(Messages::OSMessage(format!("Disk has {capacity} sectors"))).await.unwrap();
+
+        let mut shell = ConsoleShell::new(capacity);
+        let mut ports = ConsolePorts::new();
+
+        let console_message = match unsafe { initialise_device(&console_registers, &console_rings) } {
+            Ok(device_id) => format!("Initialised virtio console with id {device_id} through MMIO"),
+            Err(reason) => format!("Failed to initialise the virtio console: {reason}"),
+        };
+        ui_comms.tx.send(Messages::OSMessage(console_message)).await.unwrap();
+
+        unsafe {
+            shell.post_receive_buffers(console_queue(RECEIVEQ).as_mut().unwrap());
+            transmit(console_queue(TRANSMITQ).as_mut().unwrap(), format!("Fake OS console, type help for commands\n{PROMPT}").as_bytes());
+
+            // More queues than the one pair means the device offered ports, which we always take
+            if console_queues > 2 {
+                ports.start(console_queue);
+            }
+        }
+
+        let rng_message = match unsafe { initialise_device(&rng_registers, &rng_rings) } {
+            Ok(device_id) => format!("Initialised virtio rng with id {device_id} through MMIO"),
+            Err(reason) => format!("Failed to initialise the virtio rng: {reason}"),
+        };
+        ui_comms.tx.send(Messages::OSMessage(rng_message)).await.unwrap();
+
+        // Seeds the kernel's pool the way a real boot would
+        unsafe { post_buffers(rng_poller.get_driver_ref(), 1, ENTROPY_REQUEST_SIZE) };
+
+        let (mut network, net_pairs) = match announce(&ui_comms, "net", unsafe { guest_net::start(&net_registers, &net_rings, net_queue) }).await {
+            Some((stack, pairs)) => (Some(stack), pairs),
+            None => (None, 1),
+        };
+
+        let mut vsock = announce(&ui_comms, "vsock", unsafe { guest_vsock::start(&vsock_registers, &vsock_rings, vsock_queue) }).await;
+
+        let started = fs_client.start(&fs_registers, &fs_rings).await;
+        let mut fs = announce(&ui_comms, "fs", started.map(|details| (&mut fs_client, details))).await;
+
+        let started = nine_p_client.start(&nine_p_registers, &nine_p_rings).await;
+        let mut nine_p = announce(&ui_comms, "9p", started.map(|details| (&mut nine_p_client, details))).await;
+
+        let mut balloon = announce(&ui_comms, "balloon", unsafe { GuestBalloon::start(balloon_registers, &balloon_rings, memory, balloon_queue) }).await;
+
+        let mut input = announce(&ui_comms, "input", unsafe { InputAgent::start(&input_registers, &input_rings, input_queue) }).await;
+
         loop {
             let ui_comms_link = ui_comms.rx.recv().fuse();
             let poller_loop = poller.next().fuse();
+            let console_loop = console_poller.next().fuse();
+            let rng_loop = rng_poller.next().fuse();
+            let net_loop = net_poller.next().fuse();
+            let vsock_loop = vsock_poller.next().fuse();
+            let balloon_loop = balloon_poller.next().fuse();
+            let input_loop = input_poller.next().fuse();
 
             tokio::select! {
                 Some(res) = ui_comms_link => {
                     let ack_message = Messages::OSMessage(format!("The os thread acknowledged the message"));
                     ui_comms.tx.send(ack_message).await.unwrap();
 
-                    if let Messages::FileWrite(file_name, file_contents) = res {
-                        let result = unsafe { write_file_contents(driver_ptr, &file_name, &file_contents) };
-                        let write_message = Messages::OSMessage(format!("Writing to the driver was successful: {result}"));
+                    let driver = unsafe { driver_ptr.as_mut().unwrap() };
+
+                    let result = match res {
+                        Messages::FileWrite(file_name, file_contents) => unsafe { filesystem.write_file(driver, &file_name, &file_contents) },
+                        Messages::FileRead(file_name) => unsafe { filesystem.read_file(driver, &file_name) },
+                        _ => Ok(()),
+                    };
+
+                    let write_message = Messages::OSMessage(format!("Writing to the driver was successful: {}", result.is_ok()));
+                    ui_comms.tx.send(write_message).await.unwrap();
+
+                    if let Err(reason) = result {
+                        ui_comms.tx.send(Messages::OSMessage(reason)).await.unwrap();
+                    }
+                },
+                Some(event) = poller_loop => {
+                    match event {
+                        DriverEvent::UsedBuffer { head, .. } => {
+                            let buffers = unsafe { poller.get_driver_ref().release_chain(head) };
+                            ui_comms.tx.send(filesystem.complete(head, buffers)).await.unwrap();
+                        },
+                        DriverEvent::ConfigChange => {
+                            ui_comms.tx.send(Messages::OSMessage("The device configuration changed".to_string())).await.unwrap();
+                        }
+                    }
+                },
+                Some(event) = console_loop => unsafe {
+                    match event {
+                        DriverEvent::UsedBuffer { queue: RECEIVEQ, head, length, .. } => {
+                            let receive = console_queue(RECEIVEQ).as_mut().unwrap();
+                            let buffers = receive.release_chain(head);
+                            let echo = shell.received(&buffers[0][..length as usize]);
+
+                            transmit(console_queue(TRANSMITQ).as_mut().unwrap(), echo.as_bytes());
+                            shell.post_receive_buffers(receive);
+
+                            for ip in shell.pings.drain(..) {
+                                let Some(stack) = network.as_mut() else {
+                                    transmit(console_queue(TRANSMITQ).as_mut().unwrap(), b"ping: the network is down\n");
+                                    continue;
+                                };
+
+                                let transmit = net_queue(net_transmit_queue(next_pair)).as_mut().unwrap();
+                                stack.ping(ip).iter().for_each(|frame| send_frame(transmit, frame));
+
+                                next_pair = (next_pair + 1) % net_pairs;
+                            }
+
+                            for (ip, length) in shell.datagrams.drain(..) {
+                                let datagram = match network.as_mut() {
+                                    Some(stack) => stack.udp(ip, length),
+                                    None => Err("the network is down".to_string()),
+                                };
+
+                                match datagram {
+                                    Ok(frame) => send_frame(net_queue(net_transmit_queue(next_pair)).as_mut().unwrap(), &frame),
+                                    Err(reason) => transmit(console_queue(TRANSMITQ).as_mut().unwrap(), format!("udp: {reason}\n").as_bytes()),
+                                }
+                            }
+
+                            for (port, line) in shell.vsock_lines.drain(..) {
+                                let Some(stack) = vsock.as_mut() else {
+                                    transmit(console_queue(TRANSMITQ).as_mut().unwrap(), b"vsock: the device is down\n");
+                                    continue;
+                                };
+
+                                for packet in stack.connect(port, line.as_bytes()) {
+                                    send_packet(vsock_queue(VSOCK_TRANSMITQ).as_mut().unwrap(), &packet);
+                                }
+                            }
+
+                            if let Some((share, command, arguments)) = shell.fs_command.take() {
+                                let output = match (share, fs.as_mut(), nine_p.as_mut()) {
+                                    (Share::VirtioFs, Some(client), _) => client.run(&command, &arguments).await,
+                                    (Share::NineP, _, Some(client)) => client.run(&command, &arguments).await,
+                                    _ => format!("{command}: the shared directory is down\n"),
+                                };
+
+                                transmit(console_queue(TRANSMITQ).as_mut().unwrap(), format!("{output}{PROMPT}").as_bytes());
+                            }
+
+                            if let Some((command, arguments)) = shell.memory_command.take() {
+                                let output = match balloon.as_mut() {
+                                    Some(balloon) => balloon.run(balloon_queue, &command, &arguments),
+                                    None => format!("{command}: the balloon is down\n"),
+                                };
+
+                                transmit(console_queue(TRANSMITQ).as_mut().unwrap(), format!("{output}{PROMPT}").as_bytes());
+                            }
+
+                            if let Some(on) = shell.promiscuous.take().filter(|_| network.is_some()) {
+                                control_command(net_queue(net_control).as_mut().unwrap(), VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC, &[on as u8]);
+                            }
+                        },
+                        DriverEvent::UsedBuffer { queue: CONTROL_RECEIVEQ, head, length, .. } => {
+                            let buffers = console_queue(CONTROL_RECEIVEQ).as_mut().unwrap().release_chain(head);
+
+                            if let Some(message) = ports.control(console_queue, &buffers[0][..length as usize]) {
+                                ui_comms.tx.send(Messages::OSMessage(message)).await.unwrap();
+                            }
+
+                            post_buffers(console_queue(CONTROL_RECEIVEQ).as_mut().unwrap(), 1, CONTROL_BUFFER_SIZE);
+                        },
+                        DriverEvent::UsedBuffer { queue, head, length, .. } if queue != CONTROL_TRANSMITQ && queue == receive_queue(queue_port(queue).unwrap()) => {
+                            let buffers = console_queue(queue).as_mut().unwrap().release_chain(head);
+                            ports.received(console_queue, queue_port(queue).unwrap(), &buffers[0][..length as usize]);
+                        },
+                        DriverEvent::UsedBuffer { queue, head, .. } => {
+                            console_queue(queue).as_mut().unwrap().release_chain(head);
+                        },
+                        DriverEvent::ConfigChange => {
+                            ui_comms.tx.send(Messages::OSMessage("The console configuration changed".to_string())).await.unwrap();
+                        }
+                    }
+                },
+                Some(DriverEvent::UsedBuffer { head, length, .. }) = rng_loop => {
+                    let buffers = unsafe { rng_poller.get_driver_ref().release_chain(head) };
+                    let entropy: String = buffers[0][..length as usize].iter().map(|byte| format!("{byte:02x}")).collect();
+
+                    ui_comms.tx.send(Messages::OSMessage(format!("Got {length} bytes of entropy: {entropy}"))).await.unwrap();
+                },
+                Some(DriverEvent::UsedBuffer { queue, head, length, .. }) = net_loop => unsafe {
+                    let buffers = net_queue(queue).as_mut().unwrap().release_chain(head);
+
+                    if queue == net_control {
+                        if buffers[1][0] != VIRTIO_NET_OK {
+                            ui_comms.tx.send(Messages::OSMessage(format!("The net device refused control command {} {}", buffers[0][0], buffers[0][1]))).await.unwrap();
+                        }
+
+                        continue;
+                    }
+
+                    if queue % 2 == 1 {
+                        continue;
+                    }
+
+                    if let Some(stack) = network.as_mut() {
+                        let (replies, message) = stack.receive(&receive_frame(&buffers[0][..length as usize]));
 
-                        ui_comms.tx.send(write_message).await.unwrap();
-                    } else if let Messages::FileRead(file_name) = res {
-                        let result = unsafe { read_file_contents(driver_ptr, &file_name) };
-                        let write_message = Messages::OSMessage(format!("Writing to the driver was successful: {result}"));
+                        // Answers go out on the pair the frame came in on
+                        for reply in replies {
+                            send_frame(net_queue(queue + 1).as_mut().unwrap(), &reply);
+                        }
 
-                        ui_comms.tx.send(write_message).await.unwrap();
+                        // Ping replies show up on the console like they would in a real shell
+                        if let Some(message) = message {
+                            transmit(console_queue(TRANSMITQ).as_mut().unwrap(), format!("{message}\n").as_bytes());
+                        }
+                    }
+
+                    post_receive_buffers(net_queue(queue).as_mut().unwrap(), 1);
+                },
+                Some(DriverEvent::UsedBuffer { queue, head, length, .. }) = vsock_loop => unsafe {
+                    let buffers = vsock_queue(queue).as_mut().unwrap().release_chain(head);
+
+                    let Some(stack) = vsock.as_mut() else {
+                        continue;
+                    };
+
+                    match queue {
+                        VSOCK_RECEIVEQ => {
+                            let (replies, message) = stack.receive(&buffers[0][..length as usize]);
+
+                            for reply in replies {
+                                send_packet(vsock_queue(VSOCK_TRANSMITQ).as_mut().unwrap(), &reply);
+                            }
+
+                            if let Some(message) = message {
+                                transmit(console_queue(TRANSMITQ).as_mut().unwrap(), format!("{message}\n").as_bytes());
+                            }
+
+                            post_buffers(vsock_queue(VSOCK_RECEIVEQ).as_mut().unwrap(), 1, RECEIVE_BUFFER_SIZE);
+                        },
+                        VSOCK_EVENTQ => {
+                            if length >= 4 && u32::from_le_bytes(buffers[0][..4].try_into().unwrap()) == VIRTIO_VSOCK_EVENT_TRANSPORT_RESET {
+                                ui_comms.tx.send(Messages::OSMessage(stack.transport_reset())).await.unwrap();
+                            }
+
+                            post_buffers(vsock_queue(VSOCK_EVENTQ).as_mut().unwrap(), 1, EVENT_BUFFER_SIZE);
+                        },
+                        _ => {},
+                    }
+                },
+                Some(event) = balloon_loop => unsafe {
+                    let Some(balloon) = balloon.as_mut() else {
+                        continue;
+                    };
+
+                    let message = match event {
+                        DriverEvent::UsedBuffer { queue, head, .. } => balloon.used(balloon_queue, queue, head),
+                        DriverEvent::ConfigChange => Some(balloon.config_changed(balloon_queue)),
+                    };
+
+                    if let Some(message) = message {
+                        ui_comms.tx.send(Messages::OSMessage(message)).await.unwrap();
                     }
                 },
-                Some((cell, idx)) = poller_loop => {
-                    unsafe { poller.get_driver_ref().release_back_to_pool(cell, idx) }
-                    ui_comms.tx.send(Messages::OSMessage(format!("We got a notification from our device driver!"))).await.unwrap();
+                Some(DriverEvent::UsedBuffer { queue, head, length, .. }) = input_loop => unsafe {
+                    let buffers = input_queue(queue).as_mut().unwrap().release_chain(head);
+
+                    let Some(agent) = input.as_mut().filter(|_| queue == INPUT_EVENTQ) else {
+                        continue;
+                    };
+
+                    let (report, status) = agent.received(&buffers[0][..length as usize]);
+                    transmit(input_queue(INPUT_STATUSQ).as_mut().unwrap(), &status);
+                    post_buffers(input_queue(INPUT_EVENTQ).as_mut().unwrap(), 1, EVENT_SIZE);
+
+                    if let Some(report) = report {
+                        ui_comms.tx.send(Messages::OSMessage(report)).await.unwrap();
+                    }
                 }
             }
         }
@@ -1,5 +1,13 @@
+use std::os::unix::io::RawFd;
+
 pub trait PollableQueue {
     fn wait_for_event(&self);
 
     fn submit_event(&self);
+
+    /// Waits for a notification or for `fd` to turn readable, whichever comes first. Pollers
+    /// that can't watch a second fd only wait for the notification.
+    fn wait_for_event_or(&self, _fd: RawFd) {
+        self.wait_for_event()
+    }
 }
@@ -24,7 +24,9 @@ use ratatui::{prelude::*, widgets::*};
 use tokio::{runtime, select};
 use tokio_stream::StreamExt;
 
-use crate::comms::{CommsLink, Messages};
+use crate::{comms::{CommsLink, Messages}, device_thread::DeviceControl};
+
+const MAX_CONSOLE_OUTPUT: usize = 16 * 1024;
 
 #[derive(PartialEq, Eq)]
 enum InputMode {
@@ -32,6 +34,9 @@ enum InputMode {
     ReadMode,
     FileName,
     Messages,
+    Command,
+    Console,
+    Input,
 }
 
 impl InputMode {
@@ -89,6 +94,19 @@ struct App {
     /// Out comms to the *os* thread
     comms: CommsLink,
 
+    /// Commands for the devices themselves by name, they skip the os thread entirely
+    devices: Vec<(String, DeviceControl)>,
+
+    /// Keystrokes typed into the console pane, straight to the virtio console
+    console_input: DeviceControl<Vec<u8>>,
+    console_output: String,
+
+    /// Keys and mouse events for the virtio-input device while in input mode
+    input_events: DeviceControl<Event>,
+
+    /// The balloon's target and actual size and the guest's memory in bytes, once it's reported
+    balloon: Option<(u64, u64, u64)>,
+
     file_name: String,
     file_contents: String,
 
@@ -98,7 +116,7 @@ struct App {
 
 
 impl App {
-    fn new(comms: CommsLink) -> App {
+    fn new(comms: CommsLink, devices: Vec<(String, DeviceControl)>, console_input: DeviceControl<Vec<u8>>, input_events: DeviceControl<Event>) -> App {
         App {
             input: String::new(),
             input_mode: InputMode::Normal,
@@ -106,6 +124,11 @@ impl App {
             messages: Vec::new(),
             cursor_position: 0,
             comms,
+            devices,
+            console_input,
+            console_output: String::new(),
+            input_events,
+            balloon: None,
             file_name: String::new(),
             file_contents: String::new(),
             list_state: ListState::default(),
@@ -134,6 +157,60 @@ impl App {
         self.file_contents = String::new();
     }
 
+    fn enter_command(&mut self) {
+        self.input_mode = InputMode::Command;
+        self.input.clear();
+        self.reset_cursor();
+    }
+
+    fn enter_console(&mut self) {
+        self.input_mode = InputMode::Console;
+    }
+
+    fn enter_input(&mut self) {
+        self.input_mode = InputMode::Input;
+    }
+
+    fn send_input(&mut self, event: Event) {
+        self.input_events.send(event);
+    }
+
+    fn send_console(&mut self, bytes: &[u8]) {
+        self.console_input.send(bytes.to_vec());
+    }
+
+    fn console_output(&mut self, output: &str) {
+        for c in output.chars() {
+            match c {
+                '\x08' => { self.console_output.pop(); },
+                '\r' => {},
+                c => self.console_output.push(c),
+            }
+        }
+
+        // Only the tail is ever drawn
+        if self.console_output.len() > MAX_CONSOLE_OUTPUT {
+            let cut = self.console_output.len() - MAX_CONSOLE_OUTPUT;
+            let cut = (cut..self.console_output.len()).find(|idx| self.console_output.is_char_boundary(*idx)).unwrap();
+            self.console_output.drain(..cut);
+        }
+    }
+
+    /// Commands starting with a device name go to that device, anything else to the first one
+    fn send_command(&mut self, command: &str) {
+        let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+
+        let (idx, command) = match self.devices.iter().position(|(device, _)| device == name) {
+            Some(idx) => (idx, rest.to_string()),
+            None => (0, command.to_string()),
+        };
+
+        let (name, control) = &self.devices[idx];
+        control.send(command.clone());
+
+        self.os_message.push(OsMessageTypes::Os(format!("Sent \"{command}\" to the {name} device")));
+    }
+
     fn move_cursor_left(&mut self) {
         let cursor_moved_left = self.cursor_position.saturating_sub(1);
         self.cursor_position = self.clamp_cursor(cursor_moved_left);
@@ -211,7 +288,11 @@ impl App {
     }
 
     async fn submit_message(&mut self) {
-        if self.input_mode == InputMode::ReadMode {
+        if self.input_mode == InputMode::Command {
+            let command = self.input.clone();
+            self.send_command(&command);
+            self.input_mode = InputMode::Normal;
+        } else if self.input_mode == InputMode::ReadMode {
             self.file_name = self.input.clone();
             self.messages = vec![format!("Reading a file now!")];
 
@@ -254,7 +335,7 @@ impl App {
     }
 }
 
-pub fn create_terminal(comms: CommsLink) -> Result<(), Box<dyn Error>> {
+pub fn create_terminal(comms: CommsLink, devices: Vec<(String, DeviceControl)>, console_input: DeviceControl<Vec<u8>>, input_events: DeviceControl<Event>) -> Result<(), Box<dyn Error>> {
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -264,7 +345,7 @@ pub fn create_terminal(comms: CommsLink) -> Result<(), Box<dyn Error>> {
 
     let rt = runtime::Builder::new_current_thread().enable_all().build().unwrap();
 
-    let app = App::new(comms);
+    let app = App::new(comms, devices, console_input, input_events);
     rt.block_on(async {
         run_app(&mut terminal, app).await.unwrap();
     });
@@ -291,7 +372,18 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Re
 
         select! {
             maybe_event = event => {
-                if let Some(Ok(Event::Key(key))) = maybe_event {
+                // Everything but Esc is the guest's, the mouse included
+                if app.input_mode == InputMode::Input {
+                    match maybe_event {
+                        Some(Ok(Event::Key(key))) if key.code == KeyCode::Esc => {
+                            app.input_mode = InputMode::Normal;
+                        }
+                        Some(Ok(event @ (Event::Key(_) | Event::Mouse(_)))) => {
+                            app.send_input(event);
+                        }
+                        _ => {}
+                    }
+                } else if let Some(Ok(Event::Key(key))) = maybe_event {
                     match app.input_mode {
                         InputMode::Normal => match key.code {
                             KeyCode::Char('e') => {
@@ -300,6 +392,18 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Re
                             KeyCode::Char('r') => {
                                 app.enter_read();
                             }
+                            KeyCode::Char(':') => {
+                                app.enter_command();
+                            }
+                            KeyCode::Char('c') => {
+                                app.enter_console();
+                            }
+                            KeyCode::Char('i') => {
+                                app.enter_input();
+                            }
+                            KeyCode::Char('p') => {
+                                app.send_command("net0 capture");
+                            }
                             KeyCode::Char('q') => {
                                 return Ok(());
                             }
@@ -311,6 +415,21 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Re
                             }
                             _ => {}
                         },
+                        InputMode::Console if key.kind == KeyEventKind::Press => match key.code {
+                            KeyCode::Char(typed) => {
+                                app.send_console(typed.to_string().as_bytes());
+                            }
+                            KeyCode::Enter => {
+                                app.send_console(b"\n");
+                            }
+                            KeyCode::Backspace => {
+                                app.send_console(&[0x7f]);
+                            }
+                            KeyCode::Esc => {
+                                app.input_mode = InputMode::Normal;
+                            }
+                            _ => {}
+                        },
                         _ if key.kind == KeyEventKind::Press => match key.code {
                             KeyCode::Enter => {
                                 app.submit_message().await;
@@ -347,6 +466,16 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Re
                     Messages::GlobalMessages(str) => {
                         app.os_message.push(OsMessageTypes::Global(str))
                     }
+                    Messages::FileContents(file_name, contents) => {
+                        app.messages = contents.lines().map(|line| line.to_string()).collect();
+                        app.os_message.push(OsMessageTypes::Os(format!("Read {file_name} from the disk")))
+                    }
+                    Messages::ConsoleOutput(output) => {
+                        app.console_output(&output);
+                    }
+                    Messages::BalloonSize(target, actual, total) => {
+                        app.balloon = Some((target, actual, total));
+                    }
                     _ => {}
                 }
             }
@@ -370,8 +499,9 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     let inner_types = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
-            Constraint::Percentage(50),
-            Constraint::Percentage(50)
+            Constraint::Percentage(35),
+            Constraint::Percentage(30),
+            Constraint::Percentage(35)
         ])
         .split(chunks[2]);
 
@@ -384,7 +514,15 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
                 "e".bold(),
                 " to start editing, ".bold(),
                 "r".bold(),
-                " to start reading.".bold(),
+                " to start reading, ".bold(),
+                "c".bold(),
+                " to type into the console, ".bold(),
+                "i".bold(),
+                " to send keys and the mouse to the input device, ".bold(),
+                "p".bold(),
+                " to start or stop capturing net0, ".bold(),
+                ":".bold(),
+                " to send a device a command.".bold(),
             ],
             Style::default().add_modifier(Modifier::RAPID_BLINK),
         ),
@@ -418,23 +556,50 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
             ],
             Style::default(),
         ),
+        InputMode::Command => (
+            vec![
+                "Press ".into(),
+                "Esc".bold(),
+                " to cancel, ".into(),
+                "Enter".bold(),
+                " to send the command, e.g. blk snapshot take clean, console resize 100 30 or rng state save rng.state".into(),
+            ],
+            Style::default(),
+        ),
+        InputMode::Console => (
+            vec![
+                "Typing goes to the guest console, press ".into(),
+                "Esc".bold(),
+                " to stop".into(),
+            ],
+            Style::default(),
+        ),
+        InputMode::Input => (
+            vec![
+                "Keys and the mouse go to the virtio input device, press ".into(),
+                "Esc".bold(),
+                " to stop".into(),
+            ],
+            Style::default(),
+        ),
     };
     let mut text = Text::from(Line::from(msg));
     text.patch_style(style);
     let help_message = Paragraph::new(text);
     f.render_widget(help_message, chunks[0]);
 
-    if app.input_mode.is_writing() {
+    if app.input_mode.is_writing() && app.input_mode != InputMode::Console && app.input_mode != InputMode::Input {
         let input = Paragraph::new(app.input.as_str())
             .style(match app.input_mode {
-                InputMode::Normal => Style::default(),
+                InputMode::Normal | InputMode::Console | InputMode::Input => Style::default(),
                 InputMode::FileName | InputMode::ReadMode => Style::default().fg(Color::Green),
+                InputMode::Command => Style::default().fg(Color::Cyan),
                 InputMode::Messages => Style::default().fg(Color::Yellow),
             })
             .block(Block::default().borders(Borders::ALL).title("Input"));
         f.render_widget(input, chunks[1]);
         match app.input_mode {
-            InputMode::Normal =>
+            InputMode::Normal | InputMode::Console | InputMode::Input =>
                 // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
                 {}
 
@@ -450,6 +615,14 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
                 )
             }
         }
+    } else if let Some((target, actual, total)) = app.balloon {
+        // The input box's space shows how much of the guest's memory the balloon holds
+        let balloon = Gauge::default()
+            .block(Block::default().borders(Borders::ALL).title("Balloon"))
+            .gauge_style(Style::default().fg(Color::Magenta))
+            .ratio((actual as f64 / total.max(1) as f64).min(1.0))
+            .label(format!("{} MiB of {} MiB ballooned, target {} MiB", actual >> 20, total >> 20, target >> 20));
+        f.render_widget(balloon, chunks[1]);
     }
 
     let messages: Vec<ListItem> = app
@@ -468,6 +641,19 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         .highlight_symbol(">>");
     f.render_stateful_widget(messages, inner_types[0], &mut app.list_state);
 
+    let console_height = inner_types[1].height.saturating_sub(2) as usize;
+    let console_lines: Vec<&str> = app.console_output.split('\n').collect();
+    let console_tail = console_lines[console_lines.len().saturating_sub(console_height)..].join("\n");
+
+    let console_style = match app.input_mode {
+        InputMode::Console => Style::default().fg(Color::Yellow),
+        _ => Style::default(),
+    };
+
+    let console = Paragraph::new(console_tail)
+        .block(Block::default().borders(Borders::ALL).title("Console").border_style(console_style));
+    f.render_widget(console, inner_types[1]);
+
     let os_messages: Vec<ListItem> = app
         .os_message
         .iter()
@@ -482,5 +668,5 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         List::new(os_messages).block(Block::default().borders(Borders::ALL).title("OS Messages"))
         .highlight_style(Style::default().add_modifier(Modifier::ITALIC))
         .highlight_symbol(">>");
-    f.render_stateful_widget(os_messages, inner_types[1], &mut app.os_list_state);
+    f.render_stateful_widget(os_messages, inner_types[2], &mut app.os_list_state);
 }
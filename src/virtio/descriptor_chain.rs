@@ -0,0 +1,130 @@
+// A request as the device sees it, the head descriptor plus everything linked from it through
+// VIRTQ_DESC_F_NEXT. Device readable buffers come first, then the device writable ones.
+
+use std::{ptr, slice};
+
+use super::{virtqueue::DescriptorCell, vring::{Vring, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE}};
+
+#[derive(Clone, Copy, Debug)]
+pub struct DescriptorChain {
+    vring: Vring,
+    head: u16,
+}
+
+impl DescriptorChain {
+    /// A chain in a modern descriptor table that holds `size` descriptors
+    #[cfg(test)]
+    pub unsafe fn new(table: *mut DescriptorCell, size: u16, head: u16) -> Self {
+        Self::from_vring(Vring::new(table as u64, 0, 0, size, super::vring::RingEndian::Little), head)
+    }
+
+    /// A chain in the rings the transport handed over, read in whatever endian they use
+    pub unsafe fn from_vring(vring: Vring, head: u16) -> Self {
+        Self { vring, head }
+    }
+
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// Walks the chain, a malformed chain that loops is cut off after one lap of the table
+    pub unsafe fn cells(&self) -> Vec<DescriptorCell> {
+        let mut cells = Vec::new();
+        let mut idx = self.head;
+
+        while cells.len() < self.vring.size as usize {
+            let cell = self.vring.descriptor(idx);
+            let has_next = cell.flags & VIRTQ_DESC_F_NEXT > 0;
+            idx = cell.next;
+
+            cells.push(cell);
+
+            if !has_next {
+                break;
+            }
+        }
+
+        cells
+    }
+
+    pub unsafe fn readable(&self) -> Vec<&[u8]> {
+        self.cells().into_iter()
+            .filter(|cell| cell.flags & VIRTQ_DESC_F_WRITE == 0)
+            .map(|cell| slice::from_raw_parts(cell.addr as *const u8, cell.length as usize))
+            .collect()
+    }
+
+    // Left as pointers, only `write_at` stores through them
+    unsafe fn writable(&self) -> Vec<(*mut u8, usize)> {
+        self.cells().into_iter()
+            .filter(|cell| cell.flags & VIRTQ_DESC_F_WRITE > 0)
+            .map(|cell| (cell.addr as *mut u8, cell.length as usize))
+            .collect()
+    }
+
+    pub unsafe fn readable_len(&self) -> usize {
+        self.readable().iter().map(|buffer| buffer.len()).sum()
+    }
+
+    pub unsafe fn writable_len(&self) -> usize {
+        self.writable().iter().map(|(_, length)| length).sum()
+    }
+
+    /// Gathers every device readable buffer into one contiguous copy
+    pub unsafe fn read_all(&self) -> Vec<u8> {
+        self.readable().concat()
+    }
+
+    /// Copies `data` into the device writable buffers starting `offset` bytes in, returns how
+    /// much actually fit.
+    pub unsafe fn write_at(&self, mut offset: usize, data: &[u8]) -> usize {
+        let mut written = 0;
+
+        for (buffer, length) in self.writable() {
+            if offset >= length {
+                offset -= length;
+                continue;
+            }
+
+            let count = (length - offset).min(data.len() - written);
+            ptr::copy_nonoverlapping(data.as_ptr().add(written), buffer.add(offset), count);
+
+            written += count;
+            offset = 0;
+
+            if written == data.len() {
+                break;
+            }
+        }
+
+        written
+    }
+}
+
+unsafe impl Send for DescriptorChain {}
+
+#[test]
+pub fn test_chain_gather_scatter() {
+    let mut header = *b"head";
+    let mut first = [0u8; 2];
+    let mut second = [0u8; 4];
+
+    let mut table = [
+        DescriptorCell { addr: header.as_mut_ptr() as u64, length: 4, flags: VIRTQ_DESC_F_NEXT, next: 2 },
+        DescriptorCell::default(),
+        DescriptorCell { addr: first.as_mut_ptr() as u64, length: 2, flags: VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, next: 3 },
+        DescriptorCell { addr: second.as_mut_ptr() as u64, length: 4, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+    ];
+
+    unsafe {
+        let chain = DescriptorChain::new(table.as_mut_ptr(), 4, 0);
+
+        assert_eq!(chain.cells().len(), 3);
+        assert_eq!(chain.read_all(), b"head");
+        assert_eq!(chain.writable_len(), 6);
+        assert_eq!(chain.write_at(1, b"abcdefg"), 5);
+    }
+
+    assert_eq!(&first, b"\0a");
+    assert_eq!(&second, b"bcde");
+}
@@ -0,0 +1,131 @@
+// The interface every device model implements. The device thread owns the queues and the
+// transport, a model only ever sees requests and talks back through the `DeviceContext`.
+
+use std::{io::{Error, ErrorKind, Result}, os::unix::io::RawFd};
+
+use tokio::sync::mpsc::Sender;
+
+use crate::comms::Messages;
+
+use super::{descriptor_chain::DescriptorChain, shared_memory::SharedMemoryRegion, transport::{ConfigSelections, SharedTransport}};
+
+pub trait VirtioDevice: Send {
+    fn device_id(&self) -> u32;
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    /// The device specific configuration space as the driver should see it
+    fn config_space(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Answers for a config space the driver pages through by writing selector bytes
+    fn config_selections(&self) -> Option<ConfigSelections> {
+        None
+    }
+
+    fn queue_count(&self) -> usize;
+
+    /// Shared memory regions to expose as id and length. The transport reserves them and the
+    /// device maps into them through `DeviceContext::shared_memory`.
+    fn shared_memory_regions(&self) -> Vec<(u8, u64)> {
+        Vec::new()
+    }
+
+    /// Called once the driver sets DRIVER_OK, `features` is what was negotiated
+    fn activate(&mut self, _features: u64) {}
+
+    /// Handles one descriptor chain. Returns the number of bytes written into the device
+    /// writable buffers, or None when the device holds on to the chain to complete it later
+    /// through `DeviceContext::complete`.
+    fn process_request(&mut self, ctx: &mut DeviceContext, queue: u16, chain: DescriptorChain) -> Option<u32>;
+
+    /// Gives the device a chance to make progress on work that didn't come from the driver,
+    /// called every time the device thread wakes up.
+    fn poll(&mut self, _ctx: &mut DeviceContext) {}
+
+    /// Blocks until some of the device's own outstanding work finishes. Returns false when
+    /// there's nothing outstanding, the device thread then waits on the driver instead.
+    fn wait_for_completions(&mut self) -> bool {
+        false
+    }
+
+    /// A fd that turns readable when some of the device's outstanding work finishes, None with
+    /// nothing outstanding. The device thread sleeps on it and the driver's notifications at once.
+    fn completion_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn reset(&mut self);
+
+    /// A command typed at the simulator rather than sent by the driver, returns what to report
+    /// back to the user
+    fn command(&mut self, _ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        Err(format!("The device doesn't understand {command}"))
+    }
+
+    /// The model's own state, enough for `restore` to carry on from. Saved and loaded through
+    /// the `state save|load <file>` command.
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Err(Error::new(ErrorKind::Unsupported, "the device has no state that can be saved"))
+    }
+
+    fn restore(&mut self, _snapshot: &[u8]) -> Result<()> {
+        Err(Error::new(ErrorKind::Unsupported, "the device has no state that can be loaded"))
+    }
+}
+
+pub struct Completion {
+    pub queue: u16,
+    pub head: u16,
+    pub length: u32,
+}
+
+pub struct DeviceContext<'a> {
+    comms: &'a Sender<Messages>,
+    transport: SharedTransport,
+
+    pub(crate) completions: Vec<Completion>,
+    pub(crate) config_changed: bool,
+}
+
+impl<'a> DeviceContext<'a> {
+    pub fn new(comms: &'a Sender<Messages>, transport: SharedTransport) -> Self {
+        Self {
+            comms,
+            transport,
+            completions: Vec::new(),
+            config_changed: false,
+        }
+    }
+
+    /// Hands back a chain the device held on to from an earlier `process_request`
+    pub fn complete(&mut self, queue: u16, chain: DescriptorChain, length: u32) {
+        self.completions.push(Completion { queue, head: chain.head(), length });
+    }
+
+    /// The device changed its config space, the driver gets a config change interrupt
+    pub fn config_changed(&mut self) {
+        self.config_changed = true;
+    }
+
+    /// Reads what the driver currently sees in the config space, including its own writes
+    pub fn read_config(&self, offset: u32, data: &mut [u8]) {
+        self.transport.lock().unwrap().read_config(offset, data)
+    }
+
+    /// Runs `map` against the shared memory region `id`, None when the device has no such region
+    pub fn shared_memory<T>(&self, id: u8, map: impl FnOnce(&mut SharedMemoryRegion) -> T) -> Option<T> {
+        self.transport.lock().unwrap().shared_memory_region_mut(id).map(map)
+    }
+
+    pub fn send_message(&self, message: String) {
+        self.send(Messages::DriverMessage(message));
+    }
+
+    pub fn send(&self, message: Messages) {
+        self.comms.blocking_send(message).unwrap();
+    }
+}
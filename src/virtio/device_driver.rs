@@ -1,116 +1,159 @@
-use std::{sync::atomic::{fence, Ordering::{Release, Acquire}}, fs::File, io::{Result, BufWriter, Write, Read}, ffi::c_int};
+use std::{sync::atomic::{fence, Ordering::Acquire}, ffi::c_int};
 
-use crate::{epoll::Epoll, poller::PollableQueue};
+use crate::poller::PollableQueue;
+#[cfg(test)]
+use crate::epoll::Epoll;
 
-use super::virtqueue::{VirtQueue, DescriptorCell};
+use super::{vring::Vring, transport::SharedTransport, interrupt::InterruptCause, descriptor_chain::DescriptorChain};
 
 pub struct DeviceDriver<const S: usize, P: PollableQueue + Clone> {
-    queue: *mut VirtQueue<S>,
+    // Only known once the driver has told the transport where the queue is
+    vring: Option<Vring>,
 
     available_index: u16,
-    free_index: u16,
-
-    file: Option<File>,
 
     poller: P,
+
+    transport: SharedTransport,
+    queue_index: u16,
 }
 
+#[cfg(test)]
 impl <const S: usize> DeviceDriver<S, Epoll> {
-    pub fn new_epoll(queue: *mut VirtQueue<S>, listen_fd: c_int, send_fs: c_int) -> Self {
-        Self::new(queue, Epoll::new(listen_fd, send_fs))
+    pub fn new_epoll(listen_fd: c_int, send_fs: c_int, transport: SharedTransport, queue_index: u16) -> Self {
+        Self::new(Epoll::new(listen_fd, send_fs), transport, queue_index)
     }
 }
 
 impl<const S: usize, P: PollableQueue + Clone> DeviceDriver<S, P> {
 
-    pub fn new(queue: *mut VirtQueue<S>, poller: P) -> Self {
+    pub fn new(poller: P, transport: SharedTransport, queue_index: u16) -> Self {
         Self {
-            queue,
+            vring: None,
             available_index: 0,
-            free_index: 0,
 
-            file:  None,
+            poller,
 
-            poller
+            transport,
+            queue_index,
         }
     }
 
-
-    pub fn open_file(&mut self, file_name: &str) -> Result<()> {
-        let file_opened = File::create(file_name)?;
-        self.file = Some(file_opened);
-
-        Ok(())
+    pub fn transport(&self) -> &SharedTransport {
+        &self.transport
     }
 
-    pub fn write_to_file(&mut self, contents: &str) -> Result<()> {
-        if let Some(file) = self.file.as_mut() {
-            let mut buf_writer = BufWriter::new(file);
-            buf_writer.write_all(contents.as_bytes())?;
-            buf_writer.flush()?;
-        }
+    pub unsafe fn notify_poller(&mut self) {
+        self.raise_interrupt(InterruptCause::UsedBuffer(self.queue_index));
+    }
 
-        Ok(())
+    pub fn raise_interrupt(&mut self, cause: InterruptCause) {
+        self.transport.lock().unwrap().raise_interrupt(cause);
+        self.poller.submit_event();
     }
 
-    pub fn read_to_slice(&mut self, buffer: &mut [u8], length: u64) -> Result<()> {
-        if let Some(file) = self.file.as_ref() {
-            let mut handle = file.take(length);
+    pub fn notify_config_change(&mut self) {
+        self.transport.lock().unwrap().notify_config_change();
+        self.poller.submit_event();
+    }
 
-            handle.read(buffer)?;
-        }
+    pub unsafe fn wait_for_event(&mut self) {
+        self.poller.wait_for_event()
+    }
 
-        Ok(())
+    pub unsafe fn wait_for_event_or(&mut self, fd: c_int) {
+        self.poller.wait_for_event_or(fd)
     }
 
-    pub fn close_file(&mut self) {
-        self.file = None;
+    /// Forgets the rings and where we were in them, used when the driver resets the device
+    pub fn reset(&mut self) {
+        self.vring = None;
+        self.available_index = 0;
     }
 
-    pub unsafe fn notify_poller(&mut self) {
-        self.poller.submit_event();
+    /// Picks up the rings the driver just made ready, wherever the transport says they are
+    pub fn attach(&mut self) {
+        self.vring = self.transport.lock().unwrap().queue_vring(self.queue_index as u32);
+        self.available_index = 0;
     }
 
-    pub unsafe fn wait_for_event(&mut self) {
-        self.poller.wait_for_event()
+    pub unsafe fn chain(&self, head: u16) -> DescriptorChain {
+        DescriptorChain::from_vring(self.vring.unwrap(), head)
     }
 
-    pub unsafe fn poll_available_queue(&mut self) -> Option<(*mut DescriptorCell, u16)>{
-        let queue = self.queue.as_mut().unwrap();
-        let available_ring = queue.available.as_mut().unwrap();
+    /// The head of the next chain the driver made available
+    pub unsafe fn poll_available_queue(&mut self) -> Option<u16> {
+        let vring = self.vring.as_ref()?;
+
+        if self.available_index == vring.avail_idx() {
+            return None;
+        }
 
         fence(Acquire);
 
-        let ring_idx = available_ring.get_idx();
+        let head = vring.avail_entry(self.available_index);
+        self.available_index = self.available_index.wrapping_add(1);
 
-        if self.available_index == ring_idx {
-            return None;
-        }
+        Some(head)
+    }
+
+    pub unsafe fn submit_to_used_queue(&mut self, head: u16, length: u32) {
+        let Some(vring) = self.vring.as_ref() else {
+            return;
+        };
+
+        // Chains can finish out of order so the slot is wherever the used ring is up to
+        vring.push_used(head, length);
+
+        self.notify_poller();
+    }
+}
 
-        let loading_idx = self.available_index;
-        let available_ring_pos = available_ring.get_ring_from_idx(loading_idx).read_volatile();
+unsafe impl<const S: usize, P: PollableQueue + Clone> Send for DeviceDriver<S, P> {}
 
-        self.available_index += 1;
+#[test]
+pub fn test_ring_indices_wrap() {
+    use super::{device_register::*, virtqueue::VirtQueue, vring::RingEndian, transport::{MmioTransport, TransportMode}};
 
-        Some((queue.get_descriptor_from_idx(available_ring_pos), available_ring_pos))
+    #[derive(Clone)]
+    struct Quiet;
+
+    impl PollableQueue for Quiet {
+        fn wait_for_event(&self) {}
+        fn submit_event(&self) {}
     }
 
-    pub unsafe fn submit_to_used_queue(&mut self, cell_pos: u16) {
-        let queue = self.queue.as_mut().unwrap();
-        let used_ring = queue.used.as_mut().unwrap();
+    let transport = MmioTransport::new(TransportMode::Modern, 4, 0, &[8]).into_shared();
+    let ring = VirtQueue::<4>::new_with_size().vring(RingEndian::Little);
+
+    // The driver asks for less than the queue can take, the device has to go by what it asked for
+    {
+        let mut transport = transport.lock().unwrap();
+        transport.write(QUEUE_NUM, 4);
+        transport.write(QUEUE_DESC_LOW, ring.desc as u32);
+        transport.write(QUEUE_DESC_HIGH, (ring.desc >> 32) as u32);
+        transport.write(QUEUE_DRIVER_LOW, ring.avail as u32);
+        transport.write(QUEUE_DRIVER_HIGH, (ring.avail >> 32) as u32);
+        transport.write(QUEUE_DEVICE_LOW, ring.used as u32);
+        transport.write(QUEUE_DEVICE_HIGH, (ring.used >> 32) as u32);
+        transport.write(QUEUE_READY, 1);
+    }
 
-        let ring_cell = used_ring.get_ring_from_idx(self.available_index).as_mut().unwrap();
-        (&mut ring_cell.id as *mut u16).write_volatile(cell_pos);
+    let mut driver = DeviceDriver::<8, Quiet>::new(Quiet, transport, 0);
+    driver.attach();
 
-        used_ring.increment_idx(S as u16);
+    unsafe {
+        for round in 0..0x10010u32 {
+            let head = (round % 4) as u16;
+            ring.push_avail(head);
 
-        fence(Release);
+            assert_eq!(driver.poll_available_queue(), Some(head));
+            assert_eq!(driver.poll_available_queue(), None);
 
-        self.free_index += 1;
-        self.free_index &= (S as u16) - 1;
+            driver.submit_to_used_queue(head, round);
+        }
 
-        self.notify_poller();
+        assert_eq!(ring.avail_idx(), 0x10);
+        assert_eq!(ring.used_idx(), 0x10);
     }
 }
-
-unsafe impl<const S: usize, P: PollableQueue + Clone> Send for DeviceDriver<S, P> {}
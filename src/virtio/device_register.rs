@@ -1,5 +1,52 @@
 use packed_struct::prelude::*;
 
+use super::interrupt::NO_VECTOR;
+
+pub const MAGIC_VALUE: u32 = 0x000;
+pub const VERSION: u32 = 0x004;
+pub const DEVICE_ID: u32 = 0x008;
+pub const VENDOR_ID: u32 = 0x00c;
+pub const DEVICE_FEATURES: u32 = 0x010;
+pub const DEVICE_FEATURES_SEL: u32 = 0x014;
+pub const DRIVER_FEATURES: u32 = 0x020;
+pub const DRIVER_FEATURES_SEL: u32 = 0x024;
+pub const GUEST_PAGE_SIZE: u32 = 0x028;
+pub const QUEUE_SEL: u32 = 0x030;
+pub const QUEUE_NUM_MAX: u32 = 0x034;
+pub const QUEUE_NUM: u32 = 0x038;
+pub const QUEUE_ALIGN: u32 = 0x03c;
+pub const QUEUE_PFN: u32 = 0x040;
+pub const QUEUE_READY: u32 = 0x044;
+pub const QUEUE_NOTIFY: u32 = 0x050;
+pub const INTERRUPT_STATUS: u32 = 0x060;
+pub const INTERRUPT_ACK: u32 = 0x064;
+pub const STATUS: u32 = 0x070;
+pub const QUEUE_DESC_LOW: u32 = 0x080;
+pub const QUEUE_DESC_HIGH: u32 = 0x084;
+pub const QUEUE_DRIVER_LOW: u32 = 0x090;
+pub const QUEUE_DRIVER_HIGH: u32 = 0x094;
+pub const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+pub const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+pub const SHM_SEL: u32 = 0x0ac;
+pub const SHM_LEN_LOW: u32 = 0x0b0;
+pub const SHM_LEN_HIGH: u32 = 0x0b4;
+pub const SHM_BASE_LOW: u32 = 0x0b8;
+pub const SHM_BASE_HIGH: u32 = 0x0bc;
+// Not part of virtio-mmio, these borrow virtio-pci's MSI-X vector fields so a driver can steer
+// causes to their own vectors. The queue vector applies to the queue picked by QueueSel.
+pub const MSIX_VECTOR_COUNT: u32 = 0x0c0;
+pub const MSIX_CONFIG_VECTOR: u32 = 0x0c4;
+pub const MSIX_QUEUE_VECTOR: u32 = 0x0c8;
+pub const CONFIG_GENERATION: u32 = 0x0fc;
+
+// Device specific configuration space starts straight after the register block
+pub const CONFIG_SPACE: u32 = 0x100;
+
+pub const MAGIC: u32 = 0x74726976;
+
+pub const LEGACY_VERSION: u32 = 1;
+pub const MODERN_VERSION: u32 = 2;
+
 #[derive(PackedStruct)]
 #[packed_struct(endian="lsb", bit_numbering="msb0")]
 pub struct DeviceRegister {
@@ -18,8 +65,21 @@ pub struct DeviceRegister {
     #[packed_field(bytes="0x10..=0x13")]
     device_features: Integer<u32, packed_bits::Bits::<32>>,
 
+    #[packed_field(bytes="0x14..=0x17")]
+    device_features_sel: Integer<u32, packed_bits::Bits::<32>>,
+
     // We have a gap here
 
+    #[packed_field(bytes="0x20..=0x23")]
+    driver_features: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x24..=0x27")]
+    driver_features_sel: Integer<u32, packed_bits::Bits::<32>>,
+
+    // Legacy only
+    #[packed_field(bytes="0x28..=0x2b")]
+    guest_page_size: Integer<u32, packed_bits::Bits::<32>>,
+
     #[packed_field(bytes="0x30..=0x33")]
     queue_sel: Integer<u32, packed_bits::Bits::<32>>,
 
@@ -29,10 +89,18 @@ pub struct DeviceRegister {
     #[packed_field(bytes="0x38..=0x3b")]
     queue_size: Integer<u32, packed_bits::Bits::<32>>,
 
+    // Legacy only
     #[packed_field(bytes="0x3c..=0x3f")]
-    queue_ready: Integer<u32, packed_bits::Bits::<32>>,
+    queue_align: Integer<u32, packed_bits::Bits::<32>>,
 
+    // Legacy only
     #[packed_field(bytes="0x40..=0x43")]
+    queue_pfn: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x44..=0x47")]
+    queue_ready: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x50..=0x53")]
     queue_notify: Integer<u32, packed_bits::Bits::<32>>,
 
 
@@ -43,33 +111,270 @@ pub struct DeviceRegister {
 
     #[packed_field(bytes="0x64..=0x67")]
     interupt_ack: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x70..=0x73")]
+    status: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x80..=0x83")]
+    queue_desc_low: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x84..=0x87")]
+    queue_desc_high: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x90..=0x93")]
+    queue_driver_low: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x94..=0x97")]
+    queue_driver_high: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xa0..=0xa3")]
+    queue_device_low: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xa4..=0xa7")]
+    queue_device_high: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xac..=0xaf")]
+    shm_sel: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xb0..=0xb3")]
+    shm_len_low: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xb4..=0xb7")]
+    shm_len_high: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xb8..=0xbb")]
+    shm_base_low: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xbc..=0xbf")]
+    shm_base_high: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xc0..=0xc3")]
+    msix_vector_count: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xc4..=0xc7")]
+    msix_config_vector: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xc8..=0xcb")]
+    msix_queue_vector: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0xfc..=0xff")]
+    config_generation: Integer<u32, packed_bits::Bits::<32>>,
 }
 
 impl Default for DeviceRegister {
     fn default() -> Self {
+        Self::with_version(MODERN_VERSION)
+    }
+}
+
+impl DeviceRegister {
+    pub fn legacy() -> Self {
+        Self::with_version(LEGACY_VERSION)
+    }
+
+    pub fn with_version(version: u32) -> Self {
         Self {
-            magic_number: 0x74726976.into(),
-            version: 2.into(),
+            magic_number: MAGIC.into(),
+            version: version.into(),
             device_id: 2.into(),
             vendor_id: 0.into(),
             device_features: 0.into(),
+            device_features_sel: 0.into(),
+
+            driver_features: 0.into(),
+            driver_features_sel: 0.into(),
+            guest_page_size: 0.into(),
 
             queue_sel: 0.into(),
             queue_max_size: 0.into(),
             queue_size: 0.into(),
+            queue_align: 0.into(),
+            queue_pfn: 0.into(),
             queue_ready: 0.into(),
             queue_notify: 0.into(),
 
             interupt_state: 0.into(),
-            interupt_ack: 0.into()
+            interupt_ack: 0.into(),
+            status: 0.into(),
+
+            queue_desc_low: 0.into(),
+            queue_desc_high: 0.into(),
+            queue_driver_low: 0.into(),
+            queue_driver_high: 0.into(),
+            queue_device_low: 0.into(),
+            queue_device_high: 0.into(),
+
+            shm_sel: 0.into(),
+            shm_len_low: 0.into(),
+            shm_len_high: 0.into(),
+            shm_base_low: 0.into(),
+            shm_base_high: 0.into(),
+
+            msix_vector_count: 0.into(),
+            msix_config_vector: (NO_VECTOR as u32).into(),
+            msix_queue_vector: (NO_VECTOR as u32).into(),
+
+            config_generation: 0.into(),
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        *self.version == LEGACY_VERSION
+    }
+
+    /// A guest (driver side) read of the register at `offset`. Registers that don't exist in the
+    /// current transport version read back as zero.
+    pub fn read(&self, offset: u32) -> u32 {
+        if !self.is_readable(offset) {
+            return 0;
         }
+
+        self.get(offset)
+    }
+
+    /// A guest (driver side) write of the register at `offset`. Returns false when the register
+    /// is read only or doesn't exist for the current transport version, the write is dropped.
+    pub fn write(&mut self, offset: u32, value: u32) -> bool {
+        if !self.is_writable(offset) {
+            return false;
+        }
+
+        self.set(offset, value);
+        true
+    }
+
+    /// Device side access, this ignores the driver access rules.
+    pub fn get(&self, offset: u32) -> u32 {
+        match self.field(offset) {
+            Some(field) => **field,
+            None => 0,
+        }
+    }
+
+    /// Device side update, this ignores the driver access rules.
+    pub fn set(&mut self, offset: u32, value: u32) {
+        if let Some(field) = self.field_mut(offset) {
+            *field = value.into();
+        }
+    }
+
+    fn is_readable(&self, offset: u32) -> bool {
+        let legacy = self.is_legacy();
+
+        match offset {
+            MAGIC_VALUE | VERSION | DEVICE_ID | VENDOR_ID | DEVICE_FEATURES
+                | QUEUE_NUM_MAX | INTERRUPT_STATUS | STATUS => true,
+            QUEUE_PFN => legacy,
+            QUEUE_READY | CONFIG_GENERATION | SHM_LEN_LOW | SHM_LEN_HIGH
+                | SHM_BASE_LOW | SHM_BASE_HIGH | MSIX_VECTOR_COUNT | MSIX_CONFIG_VECTOR
+                | MSIX_QUEUE_VECTOR => !legacy,
+            _ => false,
+        }
+    }
+
+    fn is_writable(&self, offset: u32) -> bool {
+        let legacy = self.is_legacy();
+
+        match offset {
+            DEVICE_FEATURES_SEL | DRIVER_FEATURES | DRIVER_FEATURES_SEL | QUEUE_SEL
+                | QUEUE_NUM | QUEUE_NOTIFY | INTERRUPT_ACK | STATUS => true,
+            GUEST_PAGE_SIZE | QUEUE_ALIGN | QUEUE_PFN => legacy,
+            QUEUE_READY | QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW
+                | QUEUE_DRIVER_HIGH | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH | SHM_SEL
+                | MSIX_CONFIG_VECTOR | MSIX_QUEUE_VECTOR => !legacy,
+            _ => false,
+        }
+    }
+
+    fn field(&self, offset: u32) -> Option<&Integer<u32, packed_bits::Bits::<32>>> {
+        let field = match offset {
+            MAGIC_VALUE => &self.magic_number,
+            VERSION => &self.version,
+            DEVICE_ID => &self.device_id,
+            VENDOR_ID => &self.vendor_id,
+            DEVICE_FEATURES => &self.device_features,
+            DEVICE_FEATURES_SEL => &self.device_features_sel,
+            DRIVER_FEATURES => &self.driver_features,
+            DRIVER_FEATURES_SEL => &self.driver_features_sel,
+            GUEST_PAGE_SIZE => &self.guest_page_size,
+            QUEUE_SEL => &self.queue_sel,
+            QUEUE_NUM_MAX => &self.queue_max_size,
+            QUEUE_NUM => &self.queue_size,
+            QUEUE_ALIGN => &self.queue_align,
+            QUEUE_PFN => &self.queue_pfn,
+            QUEUE_READY => &self.queue_ready,
+            QUEUE_NOTIFY => &self.queue_notify,
+            INTERRUPT_STATUS => &self.interupt_state,
+            INTERRUPT_ACK => &self.interupt_ack,
+            STATUS => &self.status,
+            QUEUE_DESC_LOW => &self.queue_desc_low,
+            QUEUE_DESC_HIGH => &self.queue_desc_high,
+            QUEUE_DRIVER_LOW => &self.queue_driver_low,
+            QUEUE_DRIVER_HIGH => &self.queue_driver_high,
+            QUEUE_DEVICE_LOW => &self.queue_device_low,
+            QUEUE_DEVICE_HIGH => &self.queue_device_high,
+            SHM_SEL => &self.shm_sel,
+            SHM_LEN_LOW => &self.shm_len_low,
+            SHM_LEN_HIGH => &self.shm_len_high,
+            SHM_BASE_LOW => &self.shm_base_low,
+            SHM_BASE_HIGH => &self.shm_base_high,
+            MSIX_VECTOR_COUNT => &self.msix_vector_count,
+            MSIX_CONFIG_VECTOR => &self.msix_config_vector,
+            MSIX_QUEUE_VECTOR => &self.msix_queue_vector,
+            CONFIG_GENERATION => &self.config_generation,
+            _ => return None,
+        };
+
+        Some(field)
+    }
+
+    fn field_mut(&mut self, offset: u32) -> Option<&mut Integer<u32, packed_bits::Bits::<32>>> {
+        let field = match offset {
+            MAGIC_VALUE => &mut self.magic_number,
+            VERSION => &mut self.version,
+            DEVICE_ID => &mut self.device_id,
+            VENDOR_ID => &mut self.vendor_id,
+            DEVICE_FEATURES => &mut self.device_features,
+            DEVICE_FEATURES_SEL => &mut self.device_features_sel,
+            DRIVER_FEATURES => &mut self.driver_features,
+            DRIVER_FEATURES_SEL => &mut self.driver_features_sel,
+            GUEST_PAGE_SIZE => &mut self.guest_page_size,
+            QUEUE_SEL => &mut self.queue_sel,
+            QUEUE_NUM_MAX => &mut self.queue_max_size,
+            QUEUE_NUM => &mut self.queue_size,
+            QUEUE_ALIGN => &mut self.queue_align,
+            QUEUE_PFN => &mut self.queue_pfn,
+            QUEUE_READY => &mut self.queue_ready,
+            QUEUE_NOTIFY => &mut self.queue_notify,
+            INTERRUPT_STATUS => &mut self.interupt_state,
+            INTERRUPT_ACK => &mut self.interupt_ack,
+            STATUS => &mut self.status,
+            QUEUE_DESC_LOW => &mut self.queue_desc_low,
+            QUEUE_DESC_HIGH => &mut self.queue_desc_high,
+            QUEUE_DRIVER_LOW => &mut self.queue_driver_low,
+            QUEUE_DRIVER_HIGH => &mut self.queue_driver_high,
+            QUEUE_DEVICE_LOW => &mut self.queue_device_low,
+            QUEUE_DEVICE_HIGH => &mut self.queue_device_high,
+            SHM_SEL => &mut self.shm_sel,
+            SHM_LEN_LOW => &mut self.shm_len_low,
+            SHM_LEN_HIGH => &mut self.shm_len_high,
+            SHM_BASE_LOW => &mut self.shm_base_low,
+            SHM_BASE_HIGH => &mut self.shm_base_high,
+            MSIX_VECTOR_COUNT => &mut self.msix_vector_count,
+            MSIX_CONFIG_VECTOR => &mut self.msix_config_vector,
+            MSIX_QUEUE_VECTOR => &mut self.msix_queue_vector,
+            CONFIG_GENERATION => &mut self.config_generation,
+            _ => return None,
+        };
+
+        Some(field)
     }
 }
 
 #[test]
 pub fn test_create_register() {
     let register = DeviceRegister::default();
-    let packed: [u8; 104] = register.pack().unwrap();
+    let packed: [u8; 256] = register.pack().unwrap();
 
     for (row, value) in packed.chunks_exact(4).enumerate() {
         let byte_arr: [u8;4] = [value[0], value[1], value[2], value[3]];
@@ -77,3 +382,16 @@ pub fn test_create_register() {
         println!("Cell {row}: {:x}", result);
     }
 }
+
+#[test]
+pub fn test_legacy_register_access() {
+    let mut register = DeviceRegister::legacy();
+
+    assert_eq!(register.read(VERSION), LEGACY_VERSION);
+    assert!(register.write(QUEUE_PFN, 0x10));
+    assert!(!register.write(QUEUE_READY, 1));
+    assert_eq!(register.read(QUEUE_READY), 0);
+
+    let packed: [u8; 256] = register.pack().unwrap();
+    assert_eq!(&packed[QUEUE_PFN as usize..QUEUE_PFN as usize + 4], &0x10u32.to_le_bytes());
+}
@@ -1,8 +1,10 @@
-use std::{sync::atomic::{fence, Ordering::Release}, ffi::c_int};
+use std::{sync::atomic::{fence, Ordering::Release}, ptr};
 
-use crate::{epoll::Epoll, poller::PollableQueue};
+use crate::poller::PollableQueue;
+#[cfg(test)]
+use crate::epoll::Epoll;
 
-use super::virtqueue::{VirtQueue, DescriptorCell};
+use super::{virtqueue::{VirtQueue, DescriptorCell}, transport::SharedTransport, interrupt::InterruptCause, device_register::QUEUE_NOTIFY, vring::{Vring, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE}};
 
 pub struct GuestDriver<const S: usize, P: PollableQueue + Clone> {
     queue: *mut VirtQueue<S>,
@@ -14,17 +16,21 @@ pub struct GuestDriver<const S: usize, P: PollableQueue + Clone> {
     free_descriptor_cells: [u16; S],
 
     pub poll_interface: P,
+
+    transport: SharedTransport,
+    queue_index: u16,
 }
 
+#[cfg(test)]
 impl<const S: usize> GuestDriver<S, Epoll> {
-    pub fn new_epoll(queue: *mut VirtQueue<S>, listen_fd: c_int, send_fs: c_int) -> Self {
-        Self::new(queue, Epoll::new(listen_fd, send_fs))
+    pub fn new_epoll(queue: *mut VirtQueue<S>, listen_fd: std::ffi::c_int, send_fs: std::ffi::c_int, transport: SharedTransport, queue_index: u16) -> Self {
+        Self::new(queue, Epoll::new(listen_fd, send_fs), transport, queue_index)
     }
 }
 
 impl<const S: usize, P: PollableQueue + Clone> GuestDriver<S, P> {
 
-    pub fn new(queue: *mut VirtQueue<S>, poller: P) -> Self {
+    pub fn new(queue: *mut VirtQueue<S>, poller: P, transport: SharedTransport, queue_index: u16) -> Self {
         let mut free_cells = [0; S];
 
         for (idx, cell) in free_cells.iter_mut().enumerate() {
@@ -40,14 +46,38 @@ impl<const S: usize, P: PollableQueue + Clone> GuestDriver<S, P> {
             descriptor_item_index: S,
             free_descriptor_cells: free_cells,
 
-            poll_interface: poller
+            poll_interface: poller,
+
+            transport,
+            queue_index,
         }
     }
 
+    pub fn transport(&self) -> &SharedTransport {
+        &self.transport
+    }
+
+    pub fn queue_index(&self) -> u16 {
+        self.queue_index
+    }
+
+    /// Where the queue lives, what the driver programs into the transport during bring up
+    pub fn vring(&self) -> Vring {
+        let endian = self.transport.lock().unwrap().ring_endian();
+
+        unsafe { self.queue.as_ref().unwrap().vring(endian) }
+    }
+
     pub unsafe fn notify_poller(&self) {
+        self.transport.lock().unwrap().write(QUEUE_NOTIFY, self.queue_index as u32);
         self.poll_interface.submit_event()
     }
 
+    /// Reads and acknowledges the interrupt status, the caller dispatches on the causes
+    pub fn take_interrupts(&self) -> Vec<InterruptCause> {
+        self.transport.lock().unwrap().take_interrupts()
+    }
+
     pub unsafe fn get_descriptor_cell(&mut self) -> Option<(*mut DescriptorCell, u16)> {
         if self.descriptor_item_index == 0 {
             return None;
@@ -65,20 +95,20 @@ impl<const S: usize, P: PollableQueue + Clone> GuestDriver<S, P> {
         let queue = self.queue.as_mut().unwrap();
         let available_ring = queue.available.as_mut().unwrap();
 
-        let ring_cell = available_ring.get_ring_from_idx(self.available_index);
+        let ring_cell = available_ring.get_ring_from_idx(self.available_index % S as u16);
         *ring_cell = idx;
 
-        available_ring.increment_idx(S as u16);
-
         fence(Release);
 
-        self.available_index += 1;
-        self.available_index &= (S as u16) - 1;
+        available_ring.increment_idx();
+
+        self.available_index = self.available_index.wrapping_add(1);
 
         self.notify_poller();
     }
 
-    pub unsafe fn check_used_queue(&mut self) -> Option<(*mut DescriptorCell, u16)> {
+    /// The next finished chain: its head cell, head index and how much the device wrote into it
+    pub unsafe fn check_used_queue(&mut self) -> Option<(*mut DescriptorCell, u16, u32)> {
         let queue = self.queue.as_mut().unwrap();
         let used = queue.used.as_mut().unwrap();
 
@@ -89,19 +119,97 @@ impl<const S: usize, P: PollableQueue + Clone> GuestDriver<S, P> {
             return None;
         }
 
-        let freed_item = used.get_ring_from_idx(self.free_index).as_ref().unwrap();
-        self.free_index += 1;
+        let freed_item = used.get_ring_from_idx(self.free_index % S as u16).as_ref().unwrap();
+        let head = freed_item.id as u16;
+        self.free_index = self.free_index.wrapping_add(1);
+
+        Some((queue.get_descriptor_from_idx(head) as *mut DescriptorCell, head, freed_item.len))
+    }
+
+    /// Links the buffers into one descriptor chain and publishes it, the buffers are owned by
+    /// the queue until `release_chain` hands them back. `true` marks a device writable buffer.
+    pub unsafe fn submit_chain(&mut self, buffers: Vec<(Box<[u8]>, bool)>) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.descriptor_item_index {
+            return None;
+        }
+
+        self.link_chain(buffers.into_iter().map(|(buffer, writable)| (buffer.len() as u32, Box::into_raw(buffer) as *mut u8 as u64, writable)).collect())
+    }
+
+    /// Publishes a chain over memory the queue doesn't own, like guest pages being reported to
+    /// the device, as (address, length, writable). `release_memory` hands it back.
+    pub unsafe fn submit_memory(&mut self, ranges: Vec<(u64, u32, bool)>) -> Option<u16> {
+        if ranges.is_empty() || ranges.len() > self.descriptor_item_index {
+            return None;
+        }
+
+        self.link_chain(ranges.into_iter().map(|(address, length, writable)| (length, address, writable)).collect())
+    }
+
+    unsafe fn link_chain(&mut self, buffers: Vec<(u32, u64, bool)>) -> Option<u16> {
+        let count = buffers.len();
+        let mut cells = Vec::with_capacity(count);
+
+        for (length, address, writable) in buffers {
+            let (cell_ptr, idx) = self.get_descriptor_cell().unwrap();
+            let cell = cell_ptr.as_mut().unwrap();
+
+            cell.length = length;
+            cell.addr = address;
+            cell.flags = if writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            cell.next = 0;
+
+            cells.push((cell, idx));
+        }
+
+        for link in 1..count {
+            let next = cells[link].1;
+            let cell = &mut cells[link - 1].0;
+
+            cell.flags |= VIRTQ_DESC_F_NEXT;
+            cell.next = next;
+        }
+
+        let head = cells[0].1;
+        self.submit_to_avail_queue(head);
+
+        Some(head)
+    }
+
+    /// Returns every descriptor of a finished chain to the pool along with its buffers
+    pub unsafe fn release_chain(&mut self, head: u16) -> Vec<Box<[u8]>> {
+        self.unlink_chain(head).into_iter()
+            .map(|(address, length)| Box::from_raw(ptr::slice_from_raw_parts_mut(address as *mut u8, length as usize)))
+            .collect()
+    }
 
-        Some((queue.get_descriptor_from_idx(freed_item.id) as *mut DescriptorCell, freed_item.id))
+    /// Returns the descriptors of a chain from `submit_memory`, the ranges are left alone
+    pub unsafe fn release_memory(&mut self, head: u16) -> Vec<(u64, u32)> {
+        self.unlink_chain(head)
     }
 
-    pub unsafe fn release_back_to_pool(&mut self, cell: *mut DescriptorCell, idx: u16) {
-        self.free_descriptor_cells[self.descriptor_item_index] = idx;
-        self.descriptor_item_index += 1;
+    unsafe fn unlink_chain(&mut self, head: u16) -> Vec<(u64, u32)> {
+        let queue = self.queue.as_mut().unwrap();
+        let mut ranges = Vec::new();
+        let mut idx = head;
 
-        let cell_ref = cell.as_ref().unwrap();
+        loop {
+            let cell = queue.get_descriptor_from_idx(idx);
+            let has_next = cell.flags & VIRTQ_DESC_F_NEXT > 0;
+
+            ranges.push((cell.addr, cell.length));
+
+            self.free_descriptor_cells[self.descriptor_item_index] = idx;
+            self.descriptor_item_index += 1;
+
+            if !has_next {
+                break;
+            }
+
+            idx = cell.next;
+        }
 
-        drop(Vec::from_raw_parts(cell_ref.addr as *mut u8, cell_ref.length as usize, cell_ref.length as usize));
+        ranges
     }
 }
 
@@ -0,0 +1,115 @@
+// The guest's RAM, one anonymous mapping the guest allocates its pages from and devices can
+// reach into. Page frame numbers count 4 KiB pages from the start of it, which is what the
+// balloon talks in. Nothing is backed until it's touched, and a discarded page goes back to the
+// host until the next touch brings it back as zeroes.
+
+use std::{io::{Error, Result}, ptr};
+
+use libc::{c_void, madvise, mincore, mmap, munmap, MADV_DONTNEED, MAP_ANONYMOUS, MAP_FAILED, MAP_NORESERVE, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+use super::shared_memory::PAGE_SIZE;
+
+pub struct GuestMemory {
+    base: *mut u8,
+    pages: u32,
+}
+
+impl GuestMemory {
+    pub fn new(size: u64) -> Result<Self> {
+        let pages = (size / PAGE_SIZE) as u32;
+
+        let base = unsafe {
+            mmap(ptr::null_mut(), pages as usize * PAGE_SIZE as usize, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0)
+        };
+
+        if base == MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(Self { base: base as *mut u8, pages })
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.pages
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pages as u64 * PAGE_SIZE
+    }
+
+    /// Where a page is, as a descriptor would point at it
+    pub fn page_address(&self, pfn: u32) -> Option<u64> {
+        (pfn < self.pages).then(|| self.base as u64 + pfn as u64 * PAGE_SIZE)
+    }
+
+    /// The pages a guest address range covers, None if it isn't whole pages of guest memory
+    pub fn pages_of(&self, address: u64, length: u64) -> Option<(u32, u32)> {
+        let offset = address.checked_sub(self.base as u64)?;
+
+        if !offset.is_multiple_of(PAGE_SIZE) || !length.is_multiple_of(PAGE_SIZE) || offset + length > self.size() {
+            return None;
+        }
+
+        Some(((offset / PAGE_SIZE) as u32, (length / PAGE_SIZE) as u32))
+    }
+
+    /// Writes to a page the way a program using it would, which makes it resident
+    pub fn touch(&self, pfn: u32) {
+        if let Some(address) = self.page_address(pfn) {
+            unsafe { (address as *mut u64).write_volatile(pfn as u64) };
+        }
+    }
+
+    /// Hands pages back to the host, they read as zeroes afterwards
+    pub fn discard(&self, pfn: u32, count: u32) -> Result<()> {
+        if pfn.checked_add(count).is_none_or(|end| end > self.pages) {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let address = unsafe { self.base.add(pfn as usize * PAGE_SIZE as usize) };
+
+        if unsafe { madvise(address as *mut c_void, count as usize * PAGE_SIZE as usize, MADV_DONTNEED) } != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// How many pages the host is really holding for the guest
+    pub fn resident_pages(&self) -> Result<u32> {
+        let mut residency = vec![0u8; self.pages as usize];
+
+        if unsafe { mincore(self.base as *mut c_void, self.size() as usize, residency.as_mut_ptr()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(residency.iter().filter(|page| *page & 1 != 0).count() as u32)
+    }
+}
+
+impl Drop for GuestMemory {
+    fn drop(&mut self) {
+        unsafe { munmap(self.base as *mut c_void, self.size() as usize); }
+    }
+}
+
+unsafe impl Send for GuestMemory {}
+unsafe impl Sync for GuestMemory {}
+
+#[test]
+pub fn test_discarded_pages_leave() {
+    let memory = GuestMemory::new(64 * PAGE_SIZE).unwrap();
+
+    for pfn in 0..16 {
+        memory.touch(pfn);
+    }
+
+    assert_eq!(memory.resident_pages().unwrap(), 16);
+
+    memory.discard(4, 8).unwrap();
+    assert_eq!(memory.resident_pages().unwrap(), 8);
+    assert_eq!(unsafe { (memory.page_address(4).unwrap() as *const u64).read_volatile() }, 0);
+
+    assert_eq!(memory.pages_of(memory.page_address(2).unwrap(), 3 * PAGE_SIZE), Some((2, 3)));
+    assert!(memory.discard(60, 8).is_err());
+}
@@ -0,0 +1,107 @@
+// Interrupt emulation, the device raises a cause and the transport either latches it into
+// InterruptStatus (the plain virtio-mmio line) or posts it to an MSI-X style vector.
+
+pub const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+pub const NO_VECTOR: u16 = 0xffff;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptCause {
+    UsedBuffer(u16),
+    ConfigChange,
+}
+
+impl InterruptCause {
+    pub fn status_bit(&self) -> u32 {
+        match self {
+            InterruptCause::UsedBuffer(_) => INTERRUPT_USED_BUFFER,
+            InterruptCause::ConfigChange => INTERRUPT_CONFIG_CHANGE,
+        }
+    }
+}
+
+pub struct MsixTable {
+    config_vector: u16,
+    queue_vectors: Vec<u16>,
+
+    vector_count: u16,
+    pending: Vec<bool>,
+}
+
+impl MsixTable {
+    pub fn new(vector_count: u16, queue_count: usize) -> Self {
+        Self {
+            config_vector: NO_VECTOR,
+            queue_vectors: vec![NO_VECTOR; queue_count],
+            vector_count,
+            pending: vec![false; vector_count as usize],
+        }
+    }
+
+    pub fn vector_count(&self) -> u16 {
+        self.vector_count
+    }
+
+    /// Mirrors the PCI behaviour, asking for a vector that doesn't exist reads back as NO_VECTOR
+    pub fn set_config_vector(&mut self, vector: u16) -> u16 {
+        self.config_vector = self.checked_vector(vector);
+        self.config_vector
+    }
+
+    pub fn set_queue_vector(&mut self, queue: u16, vector: u16) -> u16 {
+        let vector = self.checked_vector(vector);
+
+        match self.queue_vectors.get_mut(queue as usize) {
+            Some(slot) => {
+                *slot = vector;
+                vector
+            },
+            None => NO_VECTOR,
+        }
+    }
+
+    fn checked_vector(&self, vector: u16) -> u16 {
+        if vector < self.vector_count { vector } else { NO_VECTOR }
+    }
+
+    pub fn vector_for(&self, cause: InterruptCause) -> u16 {
+        match cause {
+            InterruptCause::UsedBuffer(queue) => *self.queue_vectors.get(queue as usize).unwrap_or(&NO_VECTOR),
+            InterruptCause::ConfigChange => self.config_vector,
+        }
+    }
+
+    /// Returns false when the cause has no vector assigned, the caller falls back to the line.
+    pub fn post(&mut self, cause: InterruptCause) -> bool {
+        let vector = self.vector_for(cause);
+
+        if vector == NO_VECTOR {
+            return false;
+        }
+
+        self.pending[vector as usize] = true;
+        true
+    }
+
+    /// Drains every pending vector and works out which causes share it
+    pub fn take_pending(&mut self) -> Vec<InterruptCause> {
+        let mut causes = Vec::new();
+
+        for vector in 0..self.vector_count {
+            if !std::mem::take(&mut self.pending[vector as usize]) {
+                continue;
+            }
+
+            if self.config_vector == vector {
+                causes.push(InterruptCause::ConfigChange);
+            }
+
+            for (queue, _) in self.queue_vectors.iter().enumerate().filter(|(_, v)| **v == vector) {
+                causes.push(InterruptCause::UsedBuffer(queue as u16));
+            }
+        }
+
+        causes
+    }
+}
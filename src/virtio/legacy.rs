@@ -0,0 +1,82 @@
+// Legacy (version 1) virtio-mmio queues live in one physically contiguous block, the driver
+// only tells the device where it starts through QueuePFN and the device works out the rest.
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+use super::vring::{Vring, RingEndian, DESCRIPTOR_SIZE};
+
+// Our "guest physical" addresses are host pointers, a 64KiB guest page keeps every user space
+// address within the 32 bits of QueuePFN.
+pub const LEGACY_GUEST_PAGE_SIZE: u32 = 1 << 16;
+pub const LEGACY_QUEUE_ALIGN: u32 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LegacyVringLayout {
+    pub desc: u64,
+    pub avail: u64,
+    pub used: u64,
+    pub size: u16,
+}
+
+/// The page frame number a legacy driver writes to QueuePFN for a queue starting at `address`
+pub fn pfn_of(address: u64) -> u32 {
+    (address / LEGACY_GUEST_PAGE_SIZE as u64) as u32
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+impl LegacyVringLayout {
+    pub fn new(base: u64, size: u16, align: u32) -> Self {
+        let desc = base;
+        let avail = desc + DESCRIPTOR_SIZE as u64 * size as u64;
+        let used = align_up(avail + Vring::avail_bytes(size) as u64, align as u64);
+
+        Self { desc, avail, used, size }
+    }
+
+    pub fn total_bytes(size: u16, align: u32) -> usize {
+        let layout = Self::new(0, size, align);
+
+        align_up(layout.used + Vring::used_bytes(size) as u64, align as u64) as usize
+    }
+
+    pub fn vring(&self) -> Vring {
+        Vring::new(self.desc, self.avail, self.used, self.size, RingEndian::Native)
+    }
+}
+
+/// Guest side allocation of a legacy queue, aligned to the guest page size so it can be handed
+/// to the device as a page frame number.
+pub struct LegacyVringMemory {
+    memory: *mut u8,
+    layout: Layout,
+
+    pub size: u16,
+    pub align: u32,
+}
+
+impl LegacyVringMemory {
+    pub fn allocate(size: u16, align: u32, page_size: u32) -> Self {
+        let bytes = LegacyVringLayout::total_bytes(size, align);
+        let layout = Layout::from_size_align(bytes, page_size as usize).unwrap();
+
+        let memory = unsafe { alloc_zeroed(layout) };
+        assert!(!memory.is_null(), "failed to allocate legacy vring");
+
+        Self { memory, layout, size, align }
+    }
+
+    pub fn layout(&self) -> LegacyVringLayout {
+        LegacyVringLayout::new(self.memory as u64, self.size, self.align)
+    }
+}
+
+impl Drop for LegacyVringMemory {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.memory, self.layout) }
+    }
+}
+
+unsafe impl Send for LegacyVringMemory {}
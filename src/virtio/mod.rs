@@ -1,18 +1,59 @@
-use std::mem::ManuallyDrop;
+use crate::io_uring::{IOUring, create_rings};
+#[cfg(test)]
+use crate::epoll::Epoll;
 
-use crate::{epoll::Epoll, io_uring::{IOUring, create_rings}};
-
-use self::{virtqueue::VirtQueue, guest_driver::GuestDriver, device_driver::DeviceDriver};
+use self::{virtqueue::VirtQueue, guest_driver::GuestDriver, device_driver::DeviceDriver, transport::{MmioTransport, TransportMode, TransportOptions, SharedTransport}, shared_memory::SharedMemoryRegion, device::VirtioDevice};
+#[cfg(test)]
 use libc::{pipe2, O_NONBLOCK};
 
 pub mod device_register;
 pub mod virtqueue;
 pub mod guest_driver;
 pub mod device_driver;
+pub mod vring;
+pub mod legacy;
+pub mod transport;
+pub mod interrupt;
+pub mod shared_memory;
+pub mod guest_memory;
+pub mod descriptor_chain;
+pub mod device;
+
+
+fn create_transport(device: &dyn VirtioDevice, options: TransportOptions, queue_size: u16) -> SharedTransport {
+    let queue_sizes = vec![queue_size; device.queue_count()];
+
+    let mut transport = MmioTransport::new(options.mode, device.device_id(), device.device_features(), &queue_sizes);
+
+    if options.msix_vectors > 0 {
+        transport.enable_msix(options.msix_vectors);
+    }
+
+    transport.set_config_space(device.config_space());
+    transport.set_config_selections(device.config_selections());
 
+    for (id, length) in device.shared_memory_regions() {
+        let region = SharedMemoryRegion::new(id, length).expect("Couldn't reserve address space for a shared memory region");
+        transport.add_shared_memory_region(region);
+    }
+
+    transport.into_shared()
+}
+
+// The guest's half of a queue, the device only finds it once the driver programs the transport
+fn create_queue<const S: usize>(mode: TransportMode) -> *mut VirtQueue<S> {
+    let queue = match mode {
+        TransportMode::Legacy => VirtQueue::<S>::new_legacy(),
+        TransportMode::Modern => VirtQueue::<S>::new_with_size(),
+    };
+
+    Box::into_raw(Box::new(queue))
+}
 
-pub fn create_epoll_queue<const S: usize>() -> (GuestDriver<S, Epoll>, DeviceDriver<S, Epoll>) {
-    let mut core_virt_queue = ManuallyDrop::new(Box::new(VirtQueue::<S>::new_with_size()));
+// The queues the tests run over pipes, the simulator itself only uses io_uring
+#[cfg(test)]
+pub fn create_epoll_queues<const S: usize>(device: &dyn VirtioDevice, options: TransportOptions) -> (Vec<GuestDriver<S, Epoll>>, Vec<DeviceDriver<S, Epoll>>) {
+    let transport = create_transport(device, options, S as u16);
 
     let mut guest_to_device = [-1; 2];
     let mut device_to_guest = [-1; 2];
@@ -22,19 +63,23 @@ pub fn create_epoll_queue<const S: usize>() -> (GuestDriver<S, Epoll>, DeviceDri
         pipe2(device_to_guest.as_mut_ptr(), O_NONBLOCK);
     }
 
-    (
-        GuestDriver::new_epoll(core_virt_queue.as_mut(), device_to_guest[0], guest_to_device[1]),
-        DeviceDriver::new_epoll(core_virt_queue.as_mut(), guest_to_device[0], device_to_guest[1])
-    )
+    (0..device.queue_count()).map(|queue| {
+        (
+            GuestDriver::new_epoll(create_queue(options.mode), device_to_guest[0], guest_to_device[1], transport.clone(), queue as u16),
+            DeviceDriver::new_epoll(guest_to_device[0], device_to_guest[1], transport.clone(), queue as u16)
+        )
+    }).unzip()
 }
 
-pub fn create_io_uring_queue<const S: usize>() -> (GuestDriver<S, IOUring>, DeviceDriver<S, IOUring>) {
-    let mut core_virt_queue = ManuallyDrop::new(Box::new(VirtQueue::<S>::new_with_size()));
+pub fn create_io_uring_queues<const S: usize>(device: &dyn VirtioDevice, options: TransportOptions) -> (Vec<GuestDriver<S, IOUring>>, Vec<DeviceDriver<S, IOUring>>) {
+    let transport = create_transport(device, options, S as u16);
 
     let (guest_poller, device_poller) = create_rings(12);
 
-    (
-        GuestDriver::new(core_virt_queue.as_mut(), guest_poller),
-        DeviceDriver::new(core_virt_queue.as_mut(), device_poller)
-    )
+    (0..device.queue_count()).map(|queue| {
+        (
+            GuestDriver::new(create_queue(options.mode), guest_poller.clone(), transport.clone(), queue as u16),
+            DeviceDriver::new(device_poller.clone(), transport.clone(), queue as u16)
+        )
+    }).unzip()
 }
@@ -0,0 +1,85 @@
+// Virtio 1.2 shared memory regions. A region is a window of address space owned by the device,
+// the guest finds it through SHMSel/SHMLen/SHMBase and accesses it directly. Device models ask
+// for their regions through `VirtioDevice::shared_memory_regions` and map host files into them
+// DAX style as they need to.
+
+use std::{fs::File, io::{Error, Result}, os::fd::AsRawFd, ptr};
+
+use libc::{c_void, mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED, MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, PROT_NONE, PROT_READ, PROT_WRITE};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+pub struct SharedMemoryRegion {
+    id: u8,
+    base: *mut u8,
+    length: u64,
+}
+
+impl SharedMemoryRegion {
+    /// Reserves `length` bytes of address space, nothing is accessible until it is mapped
+    pub fn new(id: u8, length: u64) -> Result<Self> {
+        let base = unsafe {
+            mmap(ptr::null_mut(), length as usize, PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0)
+        };
+
+        if base == MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(Self { id, base: base as *mut u8, length })
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base as u64
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    fn check_range(&self, offset: u64, length: u64) -> Result<*mut c_void> {
+        if !offset.is_multiple_of(PAGE_SIZE) || !length.is_multiple_of(PAGE_SIZE) || offset + length > self.length {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(unsafe { self.base.add(offset as usize) as *mut c_void })
+    }
+
+    fn map_fixed(&mut self, offset: u64, length: u64, protection: i32, flags: i32, fd: i32, file_offset: u64) -> Result<*mut u8> {
+        let target = self.check_range(offset, length)?;
+
+        let mapped = unsafe {
+            mmap(target, length as usize, protection, flags | MAP_FIXED, fd, file_offset as libc::off_t)
+        };
+
+        if mapped == MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(mapped as *mut u8)
+    }
+
+    /// Maps part of a host file into the window, writes from the guest land straight in the file
+    pub fn map_file(&mut self, offset: u64, file: &File, file_offset: u64, length: u64, writable: bool) -> Result<*mut u8> {
+        let protection = if writable { PROT_READ | PROT_WRITE } else { PROT_READ };
+
+        self.map_fixed(offset, length, protection, MAP_SHARED, file.as_raw_fd(), file_offset)
+    }
+
+    /// Takes whatever was mapped out of part of the window, it goes back to being inaccessible
+    pub fn unmap(&mut self, offset: u64, length: u64) -> Result<()> {
+        self.map_fixed(offset, length, PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0).map(|_| ())
+    }
+}
+
+impl Drop for SharedMemoryRegion {
+    fn drop(&mut self) {
+        unsafe { munmap(self.base as *mut c_void, self.length as usize); }
+    }
+}
+
+unsafe impl Send for SharedMemoryRegion {}
@@ -0,0 +1,689 @@
+// The virtio-mmio transport, this sits behind the register window and reacts to what the
+// driver writes. A transitional device runs the same device model behind either a legacy
+// (version 1) or a modern (version 2) transport.
+
+use std::{sync::{Arc, Mutex}, collections::{HashMap, VecDeque}};
+
+use super::device_register::*;
+use super::interrupt::{InterruptCause, MsixTable, INTERRUPT_CONFIG_CHANGE, INTERRUPT_USED_BUFFER, NO_VECTOR};
+use super::legacy::LegacyVringLayout;
+use super::shared_memory::SharedMemoryRegion;
+use super::vring::{Vring, RingEndian};
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+pub const STATUS_DRIVER: u32 = 2;
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_FEATURES_OK: u32 = 8;
+pub const STATUS_FAILED: u32 = 128;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransportMode {
+    Legacy,
+    Modern,
+}
+
+/// How every transport gets built
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TransportOptions {
+    pub mode: TransportMode,
+    /// MSI-X style vectors the driver can spread causes over, 0 keeps everything on the line
+    pub msix_vectors: u16,
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        Self { mode: TransportMode::Modern, msix_vectors: 0 }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct QueueConfig {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+
+    pub desc_addr: u64,
+    pub driver_addr: u64,
+    pub device_addr: u64,
+
+    // Legacy only
+    pub align: u32,
+    pub pfn: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransportEvent {
+    QueueNotify(u32),
+    QueueReady(u32),
+    FeaturesAccepted(u64),
+    FeaturesRejected(u64),
+    DriverOk,
+    Reset,
+}
+
+pub type SharedTransport = Arc<Mutex<MmioTransport>>;
+
+/// A config space the driver pages through, like virtio-input's. It writes the two selector
+/// bytes at the start and reads the answer straight back, sooner than the device thread could
+/// get a look in, so the device hands the transport every answer up front.
+#[derive(Clone, Default, Debug)]
+pub struct ConfigSelections {
+    /// Where the answer's length byte goes
+    pub size_offset: usize,
+    /// Where the answer goes, the rest of the space up to the end is zeroed
+    pub data_offset: usize,
+    pub answers: HashMap<(u8, u8), Vec<u8>>,
+}
+
+pub struct MmioTransport {
+    pub registers: DeviceRegister,
+
+    mode: TransportMode,
+
+    device_features: u64,
+    driver_features: u64,
+
+    queues: Vec<QueueConfig>,
+
+    msix: Option<MsixTable>,
+
+    config: Vec<u8>,
+    selections: Option<ConfigSelections>,
+
+    shm_regions: Vec<SharedMemoryRegion>,
+
+    // Lifecycle events the device thread still has to act on
+    events: VecDeque<TransportEvent>,
+}
+
+impl MmioTransport {
+    pub fn new(mode: TransportMode, device_id: u32, device_features: u64, queue_sizes: &[u16]) -> Self {
+        let mut registers = match mode {
+            TransportMode::Legacy => DeviceRegister::legacy(),
+            TransportMode::Modern => DeviceRegister::default(),
+        };
+
+        registers.set(DEVICE_ID, device_id);
+
+        // Legacy drivers must never see VERSION_1, modern drivers refuse devices without it
+        let device_features = match mode {
+            TransportMode::Legacy => device_features & !VIRTIO_F_VERSION_1,
+            TransportMode::Modern => device_features | VIRTIO_F_VERSION_1,
+        };
+
+        let queues = queue_sizes.iter()
+            .map(|size| QueueConfig { max_size: *size, ..Default::default() })
+            .collect();
+
+        let mut transport = Self {
+            registers,
+            mode,
+            device_features,
+            driver_features: 0,
+            queues,
+            msix: None,
+            config: Vec::new(),
+            selections: None,
+            shm_regions: Vec::new(),
+            events: VecDeque::new(),
+        };
+
+        transport.select_features(0);
+        transport.select_queue(0);
+        transport.select_shared_memory(0);
+
+        transport
+    }
+
+    pub fn ring_endian(&self) -> RingEndian {
+        match self.mode {
+            TransportMode::Legacy => RingEndian::Native,
+            TransportMode::Modern => RingEndian::Little,
+        }
+    }
+
+    pub fn status(&self) -> u32 {
+        self.registers.get(STATUS)
+    }
+
+    pub fn negotiated_features(&self) -> u64 {
+        self.driver_features & self.device_features
+    }
+
+    pub fn queue(&self, idx: u32) -> Option<&QueueConfig> {
+        self.queues.get(idx as usize)
+    }
+
+    pub fn queue_vring(&self, idx: u32) -> Option<Vring> {
+        let queue = self.queue(idx)?;
+
+        if !queue.ready {
+            return None;
+        }
+
+        Some(Vring::new(queue.desc_addr, queue.driver_addr, queue.device_addr, queue.size, self.ring_endian()))
+    }
+
+    pub fn into_shared(self) -> SharedTransport {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn enable_msix(&mut self, vector_count: u16) {
+        self.msix = Some(MsixTable::new(vector_count, self.queues.len()));
+        self.registers.set(MSIX_VECTOR_COUNT, vector_count as u32);
+    }
+
+    /// Device side, latch an interrupt for the driver. Causes with an MSI-X vector assigned skip
+    /// InterruptStatus entirely, the same as a PCI device does once MSI-X is switched on.
+    pub fn raise_interrupt(&mut self, cause: InterruptCause) {
+        if let Some(msix) = self.msix.as_mut() {
+            if msix.post(cause) {
+                return;
+            }
+        }
+
+        let state = self.registers.get(INTERRUPT_STATUS);
+        self.registers.set(INTERRUPT_STATUS, state | cause.status_bit());
+    }
+
+    /// Device side, bump the generation so drivers re-read the config space and let them know
+    pub fn notify_config_change(&mut self) {
+        let generation = self.registers.get(CONFIG_GENERATION);
+        self.registers.set(CONFIG_GENERATION, generation.wrapping_add(1));
+
+        self.raise_interrupt(InterruptCause::ConfigChange);
+    }
+
+    /// Driver side interrupt handler entry, works out what fired and acknowledges it. The shared
+    /// line doesn't say which queue had buffers used so every queue on it is reported.
+    pub fn take_interrupts(&mut self) -> Vec<InterruptCause> {
+        let mut causes = match self.msix.as_mut() {
+            Some(msix) => msix.take_pending(),
+            None => Vec::new(),
+        };
+
+        let status = self.read(INTERRUPT_STATUS);
+
+        if status == 0 {
+            return causes;
+        }
+
+        self.write(INTERRUPT_ACK, status);
+
+        if status & INTERRUPT_CONFIG_CHANGE > 0 {
+            causes.push(InterruptCause::ConfigChange);
+        }
+
+        // Queues with a vector of their own never use the line
+        if status & INTERRUPT_USED_BUFFER > 0 {
+            let on_line = |cause: &InterruptCause| self.msix.as_ref().is_none_or(|msix| msix.vector_for(*cause) == NO_VECTOR);
+
+            causes.extend((0..self.queues.len()).map(|queue| InterruptCause::UsedBuffer(queue as u16)).filter(on_line));
+        }
+
+        causes
+    }
+
+    /// Device side, expose a shared memory region to the driver. Regions are looked up by id so
+    /// adding one with an id already in use replaces it.
+    pub fn add_shared_memory_region(&mut self, region: SharedMemoryRegion) {
+        self.shm_regions.retain(|existing| existing.id() != region.id());
+        self.shm_regions.push(region);
+
+        let selected = self.registers.get(SHM_SEL);
+        self.select_shared_memory(selected);
+    }
+
+    pub fn shared_memory_region_mut(&mut self, id: u8) -> Option<&mut SharedMemoryRegion> {
+        self.shm_regions.iter_mut().find(|region| region.id() == id)
+    }
+
+    /// Device side, replace the device specific configuration space
+    pub fn set_config_space(&mut self, config: Vec<u8>) {
+        self.config = config;
+        self.select_config();
+    }
+
+    /// Device side, answer selector writes from now on
+    pub fn set_config_selections(&mut self, selections: Option<ConfigSelections>) {
+        self.selections = selections;
+        self.select_config();
+    }
+
+    fn select_config(&mut self) {
+        let Some(selections) = self.selections.as_ref() else {
+            return;
+        };
+
+        if self.config.len() <= selections.data_offset {
+            return;
+        }
+
+        let selector = (self.config[0], self.config[1]);
+        let answer = selections.answers.get(&selector).map_or(&[][..], |answer| answer.as_slice());
+        let data = &mut self.config[selections.data_offset..];
+        let length = answer.len().min(data.len());
+
+        data.fill(0);
+        data[..length].copy_from_slice(&answer[..length]);
+        self.config[selections.size_offset] = length as u8;
+    }
+
+    pub fn config_len(&self) -> usize {
+        self.config.len()
+    }
+
+    /// Driver side config access, `offset` is relative to the start of the config space.
+    /// Anything past the end of the space reads as zero.
+    pub fn read_config(&self, offset: u32, data: &mut [u8]) {
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = *self.config.get(offset as usize + idx).unwrap_or(&0);
+        }
+    }
+
+    pub fn write_config(&mut self, offset: u32, data: &[u8]) {
+        for (idx, byte) in data.iter().enumerate() {
+            if let Some(slot) = self.config.get_mut(offset as usize + idx) {
+                *slot = *byte;
+            }
+        }
+
+        if offset < 2 {
+            self.select_config();
+        }
+    }
+
+    pub fn read(&self, offset: u32) -> u32 {
+        self.registers.read(offset)
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) -> Option<TransportEvent> {
+        let event = self.write_register(offset, value);
+
+        // Notifications already wake the device through its poller, everything else queues up
+        if let Some(event) = event {
+            if !matches!(event, TransportEvent::QueueNotify(_)) {
+                self.events.push_back(event);
+            }
+        }
+
+        event
+    }
+
+    /// Device side, drains the lifecycle events raised by driver writes
+    pub fn take_events(&mut self) -> Vec<TransportEvent> {
+        self.events.drain(..).collect()
+    }
+
+    fn write_register(&mut self, offset: u32, value: u32) -> Option<TransportEvent> {
+        let previous_status = self.registers.get(STATUS);
+
+        if !self.registers.write(offset, value) {
+            return None;
+        }
+
+        match offset {
+            DEVICE_FEATURES_SEL => self.select_features(value),
+            DRIVER_FEATURES => self.write_driver_features(value),
+            QUEUE_SEL => self.select_queue(value),
+            SHM_SEL => self.select_shared_memory(value),
+            QUEUE_NUM => {
+                // A size the rings can't be laid out with counts as no size, so the queue
+                // can't be made ready until the driver picks a usable one
+                if let Some(queue) = self.selected_queue_mut() {
+                    let usable = value.is_power_of_two() && value <= queue.max_size as u32;
+                    queue.size = if usable { value as u16 } else { 0 };
+                }
+            },
+            QUEUE_ALIGN => {
+                if let Some(queue) = self.selected_queue_mut() {
+                    queue.align = value;
+                }
+            },
+            MSIX_CONFIG_VECTOR => self.write_config_vector(value),
+            MSIX_QUEUE_VECTOR => self.write_queue_vector(value),
+            QUEUE_PFN => return self.write_queue_pfn(value),
+            QUEUE_READY => return self.write_queue_ready(value),
+            QUEUE_NOTIFY => return Some(TransportEvent::QueueNotify(value)),
+            INTERRUPT_ACK => {
+                let state = self.registers.get(INTERRUPT_STATUS);
+                self.registers.set(INTERRUPT_STATUS, state & !value);
+            },
+            STATUS => return self.write_status(previous_status, value),
+            _ => {}
+        }
+
+        None
+    }
+
+    fn selected_queue_mut(&mut self) -> Option<&mut QueueConfig> {
+        let selected = self.registers.get(QUEUE_SEL) as usize;
+        self.queues.get_mut(selected)
+    }
+
+    fn select_features(&mut self, selected: u32) {
+        let word = match (self.mode, selected) {
+            (_, 0) => self.device_features as u32,
+            (TransportMode::Modern, 1) => (self.device_features >> 32) as u32,
+            _ => 0,
+        };
+
+        self.registers.set(DEVICE_FEATURES, word);
+    }
+
+    fn write_driver_features(&mut self, value: u32) {
+        let selected = self.registers.get(DRIVER_FEATURES_SEL);
+
+        self.driver_features = match (self.mode, selected) {
+            (_, 0) => (self.driver_features & !0xffff_ffff) | value as u64,
+            (TransportMode::Modern, 1) => (self.driver_features & 0xffff_ffff) | ((value as u64) << 32),
+            _ => self.driver_features,
+        };
+    }
+
+    fn select_queue(&mut self, selected: u32) {
+        let (max_size, ready, pfn) = match self.queues.get(selected as usize) {
+            Some(queue) => (queue.max_size as u32, queue.ready as u32, queue.pfn),
+            None => (0, 0, 0),
+        };
+
+        let vector = match self.msix.as_ref() {
+            Some(msix) => msix.vector_for(InterruptCause::UsedBuffer(selected as u16)),
+            None => NO_VECTOR,
+        };
+
+        self.registers.set(QUEUE_NUM_MAX, max_size);
+        self.registers.set(QUEUE_READY, ready);
+        self.registers.set(QUEUE_PFN, pfn);
+        self.registers.set(MSIX_QUEUE_VECTOR, vector as u32);
+    }
+
+    // Like PCI, a vector that can't be used reads back as NO_VECTOR so the driver can tell
+    fn write_config_vector(&mut self, value: u32) {
+        let vector = match self.msix.as_mut() {
+            Some(msix) => msix.set_config_vector(u16::try_from(value).unwrap_or(NO_VECTOR)),
+            None => NO_VECTOR,
+        };
+
+        self.registers.set(MSIX_CONFIG_VECTOR, vector as u32);
+    }
+
+    fn write_queue_vector(&mut self, value: u32) {
+        let selected = self.registers.get(QUEUE_SEL) as u16;
+
+        let vector = match self.msix.as_mut() {
+            Some(msix) => msix.set_queue_vector(selected, u16::try_from(value).unwrap_or(NO_VECTOR)),
+            None => NO_VECTOR,
+        };
+
+        self.registers.set(MSIX_QUEUE_VECTOR, vector as u32);
+    }
+
+    // Selecting a region that doesn't exist reads back a length of -1
+    fn select_shared_memory(&mut self, selected: u32) {
+        let (base, length) = match self.shm_regions.iter().find(|region| region.id() as u32 == selected) {
+            Some(region) => (region.base(), region.len()),
+            None => (0, u64::MAX),
+        };
+
+        self.registers.set(SHM_LEN_LOW, length as u32);
+        self.registers.set(SHM_LEN_HIGH, (length >> 32) as u32);
+        self.registers.set(SHM_BASE_LOW, base as u32);
+        self.registers.set(SHM_BASE_HIGH, (base >> 32) as u32);
+    }
+
+    fn write_queue_pfn(&mut self, pfn: u32) -> Option<TransportEvent> {
+        let page_size = self.registers.get(GUEST_PAGE_SIZE);
+        let selected = self.registers.get(QUEUE_SEL);
+        let queue = self.selected_queue_mut()?;
+
+        // The layout can't be worked out without a size and a sane alignment, a driver that
+        // skipped QueueNum or QueueAlign reads back a PFN of 0 and the queue stays off
+        let usable = queue.size != 0 && queue.align.is_power_of_two() && page_size.is_power_of_two();
+
+        queue.pfn = if usable { pfn } else { 0 };
+        queue.ready = queue.pfn != 0;
+
+        if !queue.ready {
+            self.registers.set(QUEUE_PFN, 0);
+            return None;
+        }
+
+        let layout = LegacyVringLayout::new(pfn as u64 * page_size as u64, queue.size, queue.align);
+        queue.desc_addr = layout.desc;
+        queue.driver_addr = layout.avail;
+        queue.device_addr = layout.used;
+
+        Some(TransportEvent::QueueReady(selected))
+    }
+
+    fn write_queue_ready(&mut self, ready: u32) -> Option<TransportEvent> {
+        let desc = join_u32(self.registers.get(QUEUE_DESC_LOW), self.registers.get(QUEUE_DESC_HIGH));
+        let driver = join_u32(self.registers.get(QUEUE_DRIVER_LOW), self.registers.get(QUEUE_DRIVER_HIGH));
+        let device = join_u32(self.registers.get(QUEUE_DEVICE_LOW), self.registers.get(QUEUE_DEVICE_HIGH));
+
+        let selected = self.registers.get(QUEUE_SEL);
+        let queue = self.selected_queue_mut()?;
+
+        // Same as legacy, there's no ring to run without a size
+        queue.ready = ready & 1 == 1 && queue.size != 0;
+        queue.desc_addr = desc;
+        queue.driver_addr = driver;
+        queue.device_addr = device;
+
+        if queue.ready {
+            Some(TransportEvent::QueueReady(selected))
+        } else {
+            None
+        }
+    }
+
+    fn write_status(&mut self, previous: u32, status: u32) -> Option<TransportEvent> {
+        if status == 0 {
+            self.reset();
+            return Some(TransportEvent::Reset);
+        }
+
+        let newly_set = status & !previous;
+
+        if self.mode == TransportMode::Modern && newly_set & STATUS_FEATURES_OK > 0 {
+            let unsupported = self.driver_features & !self.device_features;
+
+            if unsupported != 0 || self.driver_features & VIRTIO_F_VERSION_1 == 0 {
+                self.registers.set(STATUS, status & !STATUS_FEATURES_OK);
+                return Some(TransportEvent::FeaturesRejected(self.driver_features));
+            }
+
+            return Some(TransportEvent::FeaturesAccepted(self.driver_features));
+        }
+
+        if newly_set & STATUS_DRIVER_OK > 0 {
+            // Legacy drivers have no FEATURES_OK step, whatever they wrote is final once they
+            // go live and the device quietly drops anything it never offered.
+            if self.mode == TransportMode::Legacy {
+                self.driver_features &= self.device_features;
+            }
+
+            return Some(TransportEvent::DriverOk);
+        }
+
+        None
+    }
+
+    pub fn reset(&mut self) {
+        self.driver_features = 0;
+
+        for queue in self.queues.iter_mut() {
+            *queue = QueueConfig { max_size: queue.max_size, ..Default::default() };
+        }
+
+        if let Some(msix) = self.msix.as_mut() {
+            *msix = MsixTable::new(msix.vector_count(), self.queues.len());
+        }
+
+        // The windows stay reserved but nothing the driver had mapped survives a reset
+        for region in self.shm_regions.iter_mut() {
+            let _ = region.unmap(0, region.len());
+        }
+
+        self.registers.set(STATUS, 0);
+        self.registers.set(INTERRUPT_STATUS, 0);
+        self.registers.set(MSIX_CONFIG_VECTOR, NO_VECTOR as u32);
+        self.registers.set(QUEUE_SEL, 0);
+        self.select_queue(0);
+    }
+}
+
+fn join_u32(low: u32, high: u32) -> u64 {
+    (high as u64) << 32 | low as u64
+}
+
+#[test]
+pub fn test_legacy_handshake() {
+    use super::legacy::{pfn_of, LegacyVringMemory, LEGACY_GUEST_PAGE_SIZE, LEGACY_QUEUE_ALIGN};
+
+    let mut transport = MmioTransport::new(TransportMode::Legacy, 2, VIRTIO_F_VERSION_1 | 0b101, &[16]);
+
+    assert_eq!(transport.read(VERSION), LEGACY_VERSION);
+    transport.write(DEVICE_FEATURES_SEL, 1);
+    assert_eq!(transport.read(DEVICE_FEATURES), 0);
+
+    transport.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    transport.write(DRIVER_FEATURES, 0b111);
+
+    let memory = LegacyVringMemory::allocate(16, LEGACY_QUEUE_ALIGN, LEGACY_GUEST_PAGE_SIZE);
+    transport.write(GUEST_PAGE_SIZE, LEGACY_GUEST_PAGE_SIZE);
+    transport.write(QUEUE_SEL, 0);
+    transport.write(QUEUE_NUM, 16);
+    transport.write(QUEUE_ALIGN, LEGACY_QUEUE_ALIGN);
+    assert_eq!(transport.write(QUEUE_PFN, pfn_of(memory.layout().desc)), Some(TransportEvent::QueueReady(0)));
+
+    assert_eq!(transport.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK), Some(TransportEvent::DriverOk));
+    assert_eq!(transport.negotiated_features(), 0b101);
+
+    let vring = transport.queue_vring(0).unwrap();
+    assert_eq!(vring.used, memory.layout().used);
+
+    unsafe {
+        memory.layout().vring().push_avail(3);
+        assert_eq!(vring.avail_idx(), 1);
+        assert_eq!(vring.avail_entry(0), 3);
+    }
+}
+
+#[test]
+pub fn test_legacy_pfn_needs_size_and_align() {
+    use super::legacy::{pfn_of, LegacyVringMemory, LEGACY_GUEST_PAGE_SIZE, LEGACY_QUEUE_ALIGN};
+
+    let mut transport = MmioTransport::new(TransportMode::Legacy, 2, 0, &[16]);
+    let memory = LegacyVringMemory::allocate(16, LEGACY_QUEUE_ALIGN, LEGACY_GUEST_PAGE_SIZE);
+
+    transport.write(GUEST_PAGE_SIZE, LEGACY_GUEST_PAGE_SIZE);
+    transport.write(QUEUE_SEL, 0);
+
+    // No QueueNum
+    transport.write(QUEUE_ALIGN, LEGACY_QUEUE_ALIGN);
+    assert_eq!(transport.write(QUEUE_PFN, pfn_of(memory.layout().desc)), None);
+    assert_eq!(transport.read(QUEUE_PFN), 0);
+    assert!(transport.queue_vring(0).is_none());
+
+    // Sizes that aren't a power of two or are bigger than QueueNumMax
+    transport.write(QUEUE_ALIGN, LEGACY_QUEUE_ALIGN);
+    transport.write(QUEUE_NUM, 12);
+    assert_eq!(transport.write(QUEUE_PFN, pfn_of(memory.layout().desc)), None);
+    transport.write(QUEUE_NUM, 32);
+    assert_eq!(transport.write(QUEUE_PFN, pfn_of(memory.layout().desc)), None);
+
+    // No QueueAlign, then one that isn't a power of two
+    transport.write(QUEUE_NUM, 16);
+    transport.write(QUEUE_ALIGN, 0);
+    assert_eq!(transport.write(QUEUE_PFN, pfn_of(memory.layout().desc)), None);
+    transport.write(QUEUE_ALIGN, 3000);
+    assert_eq!(transport.write(QUEUE_PFN, pfn_of(memory.layout().desc)), None);
+
+    transport.write(QUEUE_ALIGN, LEGACY_QUEUE_ALIGN);
+    assert_eq!(transport.write(QUEUE_PFN, pfn_of(memory.layout().desc)), Some(TransportEvent::QueueReady(0)));
+    assert_eq!(transport.read(QUEUE_PFN), pfn_of(memory.layout().desc));
+}
+
+#[test]
+pub fn test_modern_rejects_missing_version_1() {
+    let mut transport = MmioTransport::new(TransportMode::Modern, 2, 0, &[16]);
+
+    transport.write(DEVICE_FEATURES_SEL, 1);
+    assert_eq!(transport.read(DEVICE_FEATURES), 1);
+
+    transport.write(DRIVER_FEATURES_SEL, 0);
+    transport.write(DRIVER_FEATURES, 0);
+
+    let event = transport.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+    assert_eq!(event, Some(TransportEvent::FeaturesRejected(0)));
+    assert_eq!(transport.status() & STATUS_FEATURES_OK, 0);
+}
+
+#[test]
+pub fn test_interrupt_causes() {
+    let mut transport = MmioTransport::new(TransportMode::Modern, 2, 0, &[16, 16]);
+
+    transport.raise_interrupt(InterruptCause::UsedBuffer(1));
+    transport.notify_config_change();
+    assert_eq!(transport.read(INTERRUPT_STATUS), INTERRUPT_USED_BUFFER | INTERRUPT_CONFIG_CHANGE);
+
+    let causes = transport.take_interrupts();
+    assert!(causes.contains(&InterruptCause::ConfigChange));
+    assert!(causes.contains(&InterruptCause::UsedBuffer(1)));
+    assert_eq!(transport.read(INTERRUPT_STATUS), 0);
+    assert_eq!(transport.read(CONFIG_GENERATION), 1);
+
+    transport.enable_msix(2);
+    assert_eq!(transport.read(MSIX_VECTOR_COUNT), 2);
+
+    transport.write(QUEUE_SEL, 1);
+    transport.write(MSIX_QUEUE_VECTOR, 1);
+    assert_eq!(transport.read(MSIX_QUEUE_VECTOR), 1);
+
+    // There's no vector 2, and queue 0 never had one
+    transport.write(MSIX_CONFIG_VECTOR, 2);
+    assert_eq!(transport.read(MSIX_CONFIG_VECTOR), NO_VECTOR as u32);
+    transport.write(QUEUE_SEL, 0);
+    assert_eq!(transport.read(MSIX_QUEUE_VECTOR), NO_VECTOR as u32);
+
+    transport.raise_interrupt(InterruptCause::UsedBuffer(1));
+    transport.raise_interrupt(InterruptCause::UsedBuffer(0));
+
+    // Queue 0 has no vector so it still goes down the shared line, queue 1 only shows up once
+    assert_eq!(transport.read(INTERRUPT_STATUS), INTERRUPT_USED_BUFFER);
+    assert_eq!(transport.take_interrupts(), vec![InterruptCause::UsedBuffer(1), InterruptCause::UsedBuffer(0)]);
+}
+
+#[test]
+pub fn test_shared_memory_window() {
+    use std::{fs::OpenOptions, io::Read};
+
+    let mut transport = MmioTransport::new(TransportMode::Modern, 26, 0, &[16]);
+
+    transport.write(SHM_SEL, 0);
+    assert_eq!(transport.read(SHM_LEN_LOW), u32::MAX);
+    assert_eq!(transport.read(SHM_LEN_HIGH), u32::MAX);
+
+    let path = std::env::temp_dir().join(format!("virtio-shm-{}", std::process::id()));
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    file.set_len(4096).unwrap();
+
+    let mut region = SharedMemoryRegion::new(0, 1 << 20).unwrap();
+    region.map_file(0, &file, 0, 4096, true).unwrap();
+    transport.add_shared_memory_region(region);
+
+    let base = join_u32(transport.read(SHM_BASE_LOW), transport.read(SHM_BASE_HIGH));
+    assert_eq!(join_u32(transport.read(SHM_LEN_LOW), transport.read(SHM_LEN_HIGH)), 1 << 20);
+
+    unsafe { std::ptr::copy_nonoverlapping(b"dax".as_ptr(), base as *mut u8, 3); }
+
+    let mut contents = [0u8; 3];
+    file.read_exact(&mut contents).unwrap();
+    assert_eq!(&contents, b"dax");
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -1,6 +1,9 @@
-use std::{mem::{size_of, ManuallyDrop, MaybeUninit}, sync::atomic::{AtomicU16, Ordering::SeqCst}, ptr};
+use std::mem::{size_of, ManuallyDrop};
+
+use super::{legacy::{LegacyVringMemory, LEGACY_GUEST_PAGE_SIZE, LEGACY_QUEUE_ALIGN}, vring::{Vring, RingEndian}};
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct DescriptorCell {
     pub addr: u64,
     pub length: u32,
@@ -15,32 +18,29 @@ impl Default for DescriptorCell {
     }
 }
 
+// The ring follows straight on from the header, the same as it does in the spec
 #[repr(C)]
 pub struct Available {
     flags: u16,
     idx: u16,
-    ring: *mut u16
 }
 
 #[repr(C)]
+#[derive(Default)]
 pub struct UsedCell {
-    pub id: u16,
+    pub id: u32,
     pub len: u32
 }
 
-impl Default for UsedCell {
-    fn default() -> Self {
-        Self { id: 0, len: 0 }
-    }
-}
-
 #[repr(C)]
 pub struct Used {
     pub flags: u16,
     pub idx: u16,
-    pub ring: *mut UsedCell
 }
 
+/// Where the three parts of a split virtqueue live. They can be anywhere, either allocated on
+/// their own or carved out of one legacy block.
+#[derive(Clone, Copy)]
 pub struct VirtQueue<const S: usize> {
     pub descriptor_cell: *mut DescriptorCell,
     pub available: *mut Available,
@@ -48,21 +48,22 @@ pub struct VirtQueue<const S: usize> {
     pub size: u16,
 }
 
-type MemoryRange      = ManuallyDrop<Box<[UsedCell]>>;
+type MemoryUsed       = ManuallyDrop<Box<[u32]>>;
 type MemoryDescriptor = ManuallyDrop<Box<[DescriptorCell]>>;
 type MemoryAvailable  = ManuallyDrop<Box<[u16]>>;
 
 impl Available {
     pub unsafe fn get_ring_from_idx(&mut self, idx: u16) -> *mut u16 {
-        self.ring.add(idx as usize)
+        (self as *mut Self).add(1).cast::<u16>().add(idx as usize)
     }
 
     pub unsafe fn get_idx(&mut self) -> u16 {
         (&self.idx as *const u16).read_volatile()
     }
 
-    pub unsafe fn increment_idx(&mut self, max_size: u16) {
-        let new_idx = (self.get_idx() + 1) & max_size - 1;
+    // The index runs freely and wraps at 2^16, only the ring slot is taken modulo the size
+    pub unsafe fn increment_idx(&mut self) {
+        let new_idx = self.get_idx().wrapping_add(1);
 
         (&mut self.idx as *mut u16).write_volatile(new_idx);
     }
@@ -70,54 +71,54 @@ impl Available {
 
 impl Used {
     pub unsafe fn get_ring_from_idx(&mut self, idx: u16) -> *mut UsedCell {
-        self.ring.add(idx as usize)
+        (self as *mut Self).add(1).cast::<UsedCell>().add(idx as usize)
     }
 
     pub unsafe fn get_idx(&mut self) -> u16 {
         (&self.idx as *const u16).read_volatile()
     }
-
-    pub unsafe fn increment_idx(&mut self, max_size: u16) {
-        let new_idx = (self.get_idx() + 1) & max_size - 1;
-
-        (&mut self.idx as *mut u16).write_volatile(new_idx);
-    }
 }
 
 impl<const S: usize> VirtQueue<S> {
+    /// The modern layout, every part is its own allocation
     pub fn new_with_size() -> Self {
-        let mut used_list: MemoryRange = ManuallyDrop::new(
-            Vec::from_iter(
-                (0..S).map(|_| Default::default())
-            ).into_boxed_slice()
-        );
-        let mut available_list: MemoryAvailable = ManuallyDrop::new(Vec::from_iter(0..S as u16).into_boxed_slice());
+        let mut used_list: MemoryUsed = ManuallyDrop::new(vec![0; Vring::used_bytes(S as u16).div_ceil(size_of::<u32>())].into_boxed_slice());
+        let mut available_list: MemoryAvailable = ManuallyDrop::new(vec![0; Vring::avail_bytes(S as u16) / size_of::<u16>()].into_boxed_slice());
         let mut descriptor_table: MemoryDescriptor = ManuallyDrop::new(
             Vec::from_iter(
                 (0..S).map(|_| Default::default())
             ).into_boxed_slice()
         );
 
-        let mut used = ManuallyDrop::new(Box::new(Used {
-            flags: 0,
-            idx: 0,
-            ring: used_list.as_mut_ptr()
-        }));
-
-        let mut available = ManuallyDrop::new(Box::new(Available {
-            flags: 0,
-            idx: 0,
-            ring: available_list.as_mut_ptr()
-        }));
-
         Self {
             descriptor_cell: descriptor_table.as_mut_ptr(),
-            available: available.as_mut(),
-            used: used.as_mut(),
+            available: available_list.as_mut_ptr().cast(),
+            used: used_list.as_mut_ptr().cast(),
             size: S as u16,
         }
     }
 
+    /// The legacy layout, one block the driver can hand over as a page frame number
+    pub fn new_legacy() -> Self {
+        let memory = ManuallyDrop::new(LegacyVringMemory::allocate(S as u16, LEGACY_QUEUE_ALIGN, LEGACY_GUEST_PAGE_SIZE));
+
+        Self::from_vring(&memory.layout().vring())
+    }
+
+    /// The queue the driver programmed into the transport
+    pub fn from_vring(vring: &Vring) -> Self {
+        Self {
+            descriptor_cell: vring.desc as *mut DescriptorCell,
+            available: vring.avail as *mut Available,
+            used: vring.used as *mut Used,
+            size: vring.size,
+        }
+    }
+
+    pub fn vring(&self, endian: RingEndian) -> Vring {
+        Vring::new(self.descriptor_cell as u64, self.available as u64, self.used as u64, self.size, endian)
+    }
+
     pub unsafe fn get_descriptor_from_idx(&self, idx: u16) -> &mut DescriptorCell {
         self.descriptor_cell.add(idx as usize).as_mut().unwrap()
     }
@@ -133,3 +134,21 @@ pub fn test_sizes() {
     println!("Size of used cell: {}", size_of::<UsedCell>());
     println!("Size of used: {}", size_of::<Used>());
 }
+
+#[test]
+pub fn test_legacy_queue_matches_vring() {
+    let queue = VirtQueue::<16>::new_legacy();
+    let vring = queue.vring(RingEndian::Native);
+
+    unsafe {
+        *queue.available.as_mut().unwrap().get_ring_from_idx(0) = 5;
+        queue.available.as_mut().unwrap().increment_idx();
+
+        assert_eq!(vring.avail_idx(), 1);
+        assert_eq!(vring.avail_entry(0), 5);
+
+        vring.push_used(5, 12);
+        let cell = queue.used.as_mut().unwrap().get_ring_from_idx(0).as_ref().unwrap();
+        assert_eq!((cell.id, cell.len), (5, 12));
+    }
+}
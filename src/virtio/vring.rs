@@ -0,0 +1,144 @@
+// Accessors for split virtqueues laid out the way the spec describes them, the addresses come
+// from the transport registers rather than from `VirtQueue`s own pointers. The device side reads
+// and writes its rings through these. Modern (version 2) devices keep their rings little endian,
+// legacy devices use the guests native endian.
+
+use std::mem::size_of;
+
+use super::virtqueue::DescriptorCell;
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+pub const DESCRIPTOR_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RingEndian {
+    Little,
+    Native,
+}
+
+impl RingEndian {
+    pub fn u16_to_cpu(self, value: u16) -> u16 {
+        match self {
+            RingEndian::Little => u16::from_le(value),
+            RingEndian::Native => value,
+        }
+    }
+
+    pub fn u16_from_cpu(self, value: u16) -> u16 {
+        match self {
+            RingEndian::Little => value.to_le(),
+            RingEndian::Native => value,
+        }
+    }
+
+    pub fn u32_to_cpu(self, value: u32) -> u32 {
+        match self {
+            RingEndian::Little => u32::from_le(value),
+            RingEndian::Native => value,
+        }
+    }
+
+    pub fn u64_to_cpu(self, value: u64) -> u64 {
+        match self {
+            RingEndian::Little => u64::from_le(value),
+            RingEndian::Native => value,
+        }
+    }
+
+    pub fn u32_from_cpu(self, value: u32) -> u32 {
+        match self {
+            RingEndian::Little => value.to_le(),
+            RingEndian::Native => value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Vring {
+    pub desc: u64,
+    pub avail: u64,
+    pub used: u64,
+    pub size: u16,
+    pub endian: RingEndian,
+}
+
+impl Vring {
+    pub fn new(desc: u64, avail: u64, used: u64, size: u16, endian: RingEndian) -> Self {
+        Self { desc, avail, used, size, endian }
+    }
+
+    pub fn avail_bytes(size: u16) -> usize {
+        size_of::<u16>() * (3 + size as usize)
+    }
+
+    pub fn used_bytes(size: u16) -> usize {
+        size_of::<u16>() * 3 + 8 * size as usize
+    }
+
+    unsafe fn read_u16(&self, addr: u64) -> u16 {
+        self.endian.u16_to_cpu((addr as *const u16).read_volatile())
+    }
+
+    unsafe fn read_u32(&self, addr: u64) -> u32 {
+        self.endian.u32_to_cpu((addr as *const u32).read_volatile())
+    }
+
+    unsafe fn read_u64(&self, addr: u64) -> u64 {
+        self.endian.u64_to_cpu((addr as *const u64).read_volatile())
+    }
+
+    unsafe fn write_u16(&self, addr: u64, value: u16) {
+        (addr as *mut u16).write_volatile(self.endian.u16_from_cpu(value))
+    }
+
+    unsafe fn write_u32(&self, addr: u64, value: u32) {
+        (addr as *mut u32).write_volatile(self.endian.u32_from_cpu(value))
+    }
+
+    /// A copy of the descriptor in slot `idx` with its fields in CPU order
+    pub unsafe fn descriptor(&self, idx: u16) -> DescriptorCell {
+        let base = self.desc + (idx % self.size) as u64 * DESCRIPTOR_SIZE as u64;
+
+        DescriptorCell {
+            addr: self.read_u64(base),
+            length: self.read_u32(base + 8),
+            flags: self.read_u16(base + 12),
+            next: self.read_u16(base + 14),
+        }
+    }
+
+    pub unsafe fn avail_idx(&self) -> u16 {
+        self.read_u16(self.avail + 2)
+    }
+
+    pub unsafe fn avail_entry(&self, position: u16) -> u16 {
+        self.read_u16(self.avail + 4 + (position % self.size) as u64 * 2)
+    }
+
+    /// Driver side, publish a descriptor head into the available ring
+    #[cfg(test)]
+    pub unsafe fn push_avail(&self, head: u16) {
+        let idx = self.avail_idx();
+
+        self.write_u16(self.avail + 4 + (idx % self.size) as u64 * 2, head);
+        std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+        self.write_u16(self.avail + 2, idx.wrapping_add(1));
+    }
+
+    pub unsafe fn used_idx(&self) -> u16 {
+        self.read_u16(self.used + 2)
+    }
+
+    /// Device side, hand a finished descriptor chain back to the driver
+    pub unsafe fn push_used(&self, head: u16, length: u32) {
+        let idx = self.used_idx();
+        let base = self.used + 4 + (idx % self.size) as u64 * 8;
+
+        self.write_u32(base, head as u32);
+        self.write_u32(base + 4, length);
+        std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+        self.write_u16(self.used + 2, idx.wrapping_add(1));
+    }
+}
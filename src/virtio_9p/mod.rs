@@ -0,0 +1,96 @@
+// A virtio-9p device, a host directory shared with the guest over 9P2000.L. There's one request
+// queue and each chain is a readable T-message followed by writable room for its R-message. The
+// config space names the share with a mount tag, the way virtio-fs does, so the two can serve the
+// same directory side by side.
+//
+// See `server` for how the fids map onto the directory.
+
+pub mod protocol;
+pub mod server;
+
+use std::path::Path;
+
+use crate::virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain};
+
+use self::{protocol::{parse_message, Writer, RLERROR}, server::NinePServer};
+
+pub const VIRTIO_9P_DEVICE_ID: u32 = 9;
+
+// The config space has a mount tag
+pub const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+pub const MAX_TAG_SIZE: usize = 64;
+
+pub struct Virtio9p {
+    tag: String,
+    server: NinePServer,
+
+    requests: u64,
+    errors: u64,
+}
+
+impl Virtio9p {
+    pub fn new(tag: &str, root: &Path) -> std::result::Result<Self, String> {
+        if tag.is_empty() || tag.len() > MAX_TAG_SIZE {
+            return Err(format!("The virtio-9p tag has to be 1 to {MAX_TAG_SIZE} bytes"));
+        }
+
+        Ok(Self { tag: tag.to_string(), server: NinePServer::new(root), requests: 0, errors: 0 })
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn device_id(&self) -> u32 {
+        VIRTIO_9P_DEVICE_ID
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    // The tag's length then the tag, with no NUL
+    fn config_space(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+
+        config
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, _queue: u16, chain: DescriptorChain) -> Option<u32> {
+        let request = unsafe { chain.read_all() };
+        self.requests += 1;
+
+        let Some(mut out) = self.server.handle(&request) else {
+            ctx.send_message("Dropped a 9P request too short for its header".to_string());
+            self.errors += 1;
+            return Some(0);
+        };
+
+        if out.len() > unsafe { chain.writable_len() } {
+            let tag = parse_message(&request).map_or(0, |(_, tag, _)| tag);
+            ctx.send_message(format!("A {} byte 9P reply didn't fit the driver's buffer", out.len()));
+            out = Writer::new(RLERROR, tag).u32(libc::EMSGSIZE as u32).finish();
+        }
+
+        if out[4] == RLERROR {
+            self.errors += 1;
+        }
+
+        Some(unsafe { chain.write_at(0, &out) } as u32)
+    }
+
+    fn reset(&mut self) {
+        self.server.reset();
+    }
+
+    fn command(&mut self, _ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command {
+            "stats" => Ok(format!("Sharing {} as {}: {} requests, {} errors, {}", self.server.root().display(), self.tag, self.requests, self.errors, self.server.describe())),
+            _ => Err(format!("The 9p device doesn't understand {command}, try stats")),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// The 9P2000.L wire format. Every message is size[4] type[1] tag[2] and then its fields, strings
+// are a u16 length and the bytes with no NUL. Replies are the request's type plus one and carry
+// the same tag, an error is an Rlerror with a Linux errno. Everything is little endian.
+
+use std::{fs::Metadata, os::unix::fs::MetadataExt};
+
+pub const VERSION: &str = "9P2000.L";
+
+pub const HEADER_SIZE: usize = 7;
+
+pub const NOFID: u32 = u32::MAX;
+
+// The deepest walk one Twalk can ask for
+pub const MAX_WALK: usize = 16;
+
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TSYMLINK: u8 = 16;
+pub const TRENAME: u8 = 20;
+pub const TREADLINK: u8 = 22;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TXATTRWALK: u8 = 30;
+pub const TXATTRCREATE: u8 = 32;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TLOCK: u8 = 52;
+pub const TGETLOCK: u8 = 54;
+pub const TLINK: u8 = 70;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TATTACH: u8 = 104;
+pub const TFLUSH: u8 = 108;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+pub const TREMOVE: u8 = 122;
+
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+pub const SETATTR_MODE: u32 = 1 << 0;
+pub const SETATTR_SIZE: u32 = 1 << 3;
+pub const SETATTR_ATIME: u32 = 1 << 4;
+pub const SETATTR_MTIME: u32 = 1 << 5;
+pub const SETATTR_ATIME_SET: u32 = 1 << 7;
+pub const SETATTR_MTIME_SET: u32 = 1 << 8;
+
+// Everything Rgetattr fills in, the basic stat fields
+pub const GETATTR_BASIC: u64 = 0x7ff;
+
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let kind = match metadata.file_type() {
+            kind if kind.is_dir() => QTDIR,
+            kind if kind.is_symlink() => QTSYMLINK,
+            _ => QTFILE,
+        };
+
+        Self { kind, version: metadata.mtime() as u32, path: metadata.ino() }
+    }
+}
+
+/// Reads a message's fields in order
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.offset..self.offset + count)?;
+        self.offset += count;
+
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn string(&mut self) -> Option<&'a str> {
+        let length = self.u16()? as usize;
+        std::str::from_utf8(self.bytes(length)?).ok()
+    }
+
+    pub fn qid(&mut self) -> Option<Qid> {
+        Some(Qid { kind: self.u8()?, version: self.u32()?, path: self.u64()? })
+    }
+}
+
+/// Builds a message, the size goes in when it's finished
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(kind: u8, tag: u16) -> Self {
+        let mut bytes = vec![0; 4];
+        bytes.push(kind);
+        bytes.extend_from_slice(&tag.to_le_bytes());
+
+        Self { bytes }
+    }
+
+    pub fn u8(mut self, value: u8) -> Self {
+        self.bytes.push(value);
+        self
+    }
+
+    pub fn u16(mut self, value: u16) -> Self {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn string(self, value: &str) -> Self {
+        self.u16(value.len() as u16).bytes(value.as_bytes())
+    }
+
+    pub fn qid(self, qid: Qid) -> Self {
+        self.u8(qid.kind).u32(qid.version).u64(qid.path)
+    }
+
+    pub fn bytes(mut self, value: &[u8]) -> Self {
+        self.bytes.extend_from_slice(value);
+        self
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.bytes.len() as u32;
+        self.bytes[0..4].copy_from_slice(&size.to_le_bytes());
+
+        self.bytes
+    }
+}
+
+/// Splits a message into its type, tag and fields
+pub fn parse_message(bytes: &[u8]) -> Option<(u8, u16, &[u8])> {
+    let mut reader = Reader::new(bytes);
+    let size = reader.u32()? as usize;
+    let (kind, tag) = (reader.u8()?, reader.u16()?);
+
+    Some((kind, tag, bytes.get(HEADER_SIZE..size.max(HEADER_SIZE))?))
+}
+
+/// One Rreaddir entry
+pub fn dirent(qid: Qid, offset: u64, kind: u8, name: &str) -> Vec<u8> {
+    Writer { bytes: Vec::new() }.qid(qid).u64(offset).u8(kind).string(name).bytes
+}
+
+/// Walks the entries of an Rreaddir, as (offset, type, name)
+pub fn parse_dirents(bytes: &[u8]) -> Vec<(u64, u8, String)> {
+    let mut reader = Reader::new(bytes);
+    let mut entries = Vec::new();
+
+    while let (Some(_), Some(offset), Some(kind), Some(name)) = (reader.qid(), reader.u64(), reader.u8(), reader.string()) {
+        entries.push((offset, kind, name.to_string()));
+    }
+
+    entries
+}
@@ -0,0 +1,552 @@
+// Serves 9P2000.L from a directory on the host. The guest names files by fids it picks itself:
+// Tattach binds one to the root, Twalk binds a new one to a path under an existing one and
+// Tclunk lets it go. A fid remembers its path under the shared directory and, once opened, the
+// open file or a listing of the directory. Walks only go through real directories, a fid is only
+// used as a directory if it is one rather than a symlink, and nothing is opened through a symlink,
+// so the guest can't reach outside the directory.
+
+use std::{collections::HashMap, ffi::CString, fs::{self, DirBuilder, File, OpenOptions}, io::{Error, Result}, os::unix::{ffi::OsStrExt, fs::{symlink, DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt}}, path::{Component, Path, PathBuf}};
+
+use super::protocol::*;
+
+// The biggest message we take or send, the driver's buffers are sized by it
+pub const MAX_MSIZE: u32 = 64 * 1024;
+
+// Too small to be of any use, a driver asking for less gets this much
+const MIN_MSIZE: u32 = 4096;
+
+// Room the Rread and Rreaddir headers take out of msize
+const IO_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+
+enum Open {
+    File(File),
+    // A listing taken at Tlopen, Treaddir offsets index into it
+    Directory(Vec<(Qid, u8, String)>),
+}
+
+struct Fid {
+    // Relative to the shared directory, empty for the root
+    path: PathBuf,
+    open: Option<Open>,
+}
+
+fn errno(err: Error) -> u32 {
+    err.raw_os_error().unwrap_or(libc::EIO) as u32
+}
+
+fn error(errno: i32) -> Error {
+    Error::from_raw_os_error(errno)
+}
+
+fn invalid() -> Error {
+    error(libc::EINVAL)
+}
+
+/// One name in a directory, nothing that could go anywhere else
+fn check_name(name: &str) -> Result<&str> {
+    match name {
+        "" | "." | ".." => Err(invalid()),
+        name if name.contains('/') => Err(invalid()),
+        name => Ok(name),
+    }
+}
+
+pub struct NinePServer {
+    root: PathBuf,
+    msize: u32,
+
+    fids: HashMap<u32, Fid>,
+}
+
+impl NinePServer {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf(), msize: MAX_MSIZE, fids: HashMap::new() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn describe(&self) -> String {
+        let open = self.fids.values().filter(|fid| fid.open.is_some()).count();
+        format!("{} fids, {open} open, msize {}", self.fids.len(), self.msize)
+    }
+
+    /// Every fid clunked, which is what Tversion does too
+    pub fn reset(&mut self) {
+        self.fids.clear();
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid> {
+        self.fids.get(&fid).ok_or(error(libc::EBADF))
+    }
+
+    /// `relative` under the shared directory, as long as everything before its last component is
+    /// a directory. A walk can end on a symlink, which could point anywhere
+    fn beneath(&self, relative: &Path) -> Result<PathBuf> {
+        let mut path = self.root.clone();
+        let mut components = relative.components().peekable();
+
+        while let Some(component) = components.next() {
+            path.push(component);
+
+            if components.peek().is_some() && !fs::symlink_metadata(&path)?.is_dir() {
+                return Err(error(libc::ENOTDIR));
+            }
+        }
+
+        Ok(path)
+    }
+
+    fn host_path(&self, fid: u32) -> Result<PathBuf> {
+        self.beneath(&self.fid(fid)?.path)
+    }
+
+    fn child_path(&self, fid: u32, name: &str) -> Result<(PathBuf, PathBuf)> {
+        let relative = self.fid(fid)?.path.join(check_name(name)?);
+        Ok((self.beneath(&relative)?, relative))
+    }
+
+    fn file(&self, fid: u32) -> Result<&File> {
+        match self.fid(fid)?.open.as_ref() {
+            Some(Open::File(file)) => Ok(file),
+            _ => Err(error(libc::EBADF)),
+        }
+    }
+
+    fn qid(&self, path: &Path) -> Result<Qid> {
+        Ok(Qid::from_metadata(&fs::symlink_metadata(path)?))
+    }
+
+    /// Binds a fid nobody's using, a fid can only be reused once it's clunked
+    fn add_fid(&mut self, fid: u32, path: PathBuf) -> Result<()> {
+        if self.fids.contains_key(&fid) {
+            return Err(error(libc::EBADF));
+        }
+
+        self.fids.insert(fid, Fid { path, open: None });
+        Ok(())
+    }
+
+    /// Opens with the guest's Linux open flags, never following a symlink
+    fn open_options(flags: u32) -> OpenOptions {
+        let flags = flags as i32;
+        let mut options = OpenOptions::new();
+
+        match flags & libc::O_ACCMODE {
+            libc::O_WRONLY => options.write(true),
+            libc::O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+
+        options.custom_flags(flags & (libc::O_APPEND | libc::O_TRUNC | libc::O_EXCL) | libc::O_NOFOLLOW);
+        options
+    }
+
+    /// Handles one request, the reply is an Rlerror if it failed
+    pub fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let (kind, tag, body) = parse_message(request)?;
+
+        Some(match self.dispatch(kind, tag, body) {
+            Ok(reply) => reply,
+            Err(err) => Writer::new(RLERROR, tag).u32(errno(err)).finish(),
+        })
+    }
+
+    fn dispatch(&mut self, kind: u8, tag: u16, body: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(body);
+        let reply = Writer::new(kind + 1, tag);
+
+        match kind {
+            TVERSION => {
+                let (msize, version) = (reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?);
+                self.reset();
+                self.msize = msize.clamp(MIN_MSIZE, MAX_MSIZE);
+
+                let version = match version.starts_with(VERSION) {
+                    true => VERSION,
+                    false => "unknown",
+                };
+
+                Ok(reply.u32(self.msize).string(version).finish())
+            },
+            TATTACH => {
+                let (fid, _afid) = (reader.u32().ok_or(invalid())?, reader.u32());
+                self.add_fid(fid, PathBuf::new())?;
+
+                Ok(reply.qid(self.qid(&self.root)?).finish())
+            },
+            TWALK => self.walk(reply, &mut reader),
+            TLOPEN => {
+                let (fid, flags) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                let path = self.host_path(fid)?;
+                let metadata = fs::symlink_metadata(&path)?;
+
+                let open = match metadata.is_dir() {
+                    true => Open::Directory(self.listing(&path)?),
+                    false => Open::File(Self::open_options(flags).open(&path)?),
+                };
+
+                self.fids.get_mut(&fid).unwrap().open = Some(open);
+                Ok(reply.qid(Qid::from_metadata(&metadata)).u32(self.msize - IO_HEADER_SIZE).finish())
+            },
+            TLCREATE => {
+                let (fid, name, flags, mode) = (reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                let (path, relative) = self.child_path(fid, name)?;
+                let file = Self::open_options(flags).create(true).mode(mode & 0o7777).open(&path)?;
+
+                // The fid now stands for the new file, opened
+                let qid = self.qid(&path)?;
+                *self.fids.get_mut(&fid).unwrap() = Fid { path: relative, open: Some(Open::File(file)) };
+
+                Ok(reply.qid(qid).u32(self.msize - IO_HEADER_SIZE).finish())
+            },
+            TREAD => {
+                let (fid, offset, count) = (reader.u32().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                let mut data = vec![0u8; count.min(self.msize - IO_HEADER_SIZE) as usize];
+                let count = self.file(fid)?.read_at(&mut data, offset)?;
+
+                Ok(reply.u32(count as u32).bytes(&data[..count]).finish())
+            },
+            TWRITE => {
+                let (fid, offset, count) = (reader.u32().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                let data = reader.bytes(count as usize).ok_or(invalid())?;
+                let written = self.file(fid)?.write_at(data, offset)?;
+
+                Ok(reply.u32(written as u32).finish())
+            },
+            TCLUNK => {
+                self.fids.remove(&reader.u32().ok_or(invalid())?).ok_or(error(libc::EBADF))?;
+                Ok(reply.finish())
+            },
+            TREMOVE => {
+                // The fid is clunked whether or not the remove works
+                let fid = self.fids.remove(&reader.u32().ok_or(invalid())?).ok_or(error(libc::EBADF))?;
+                let path = self.root.join(&fid.path);
+
+                match fs::symlink_metadata(&path)?.is_dir() {
+                    true => fs::remove_dir(&path)?,
+                    false => fs::remove_file(&path)?,
+                }
+
+                Ok(reply.finish())
+            },
+            TGETATTR => {
+                let metadata = fs::symlink_metadata(self.host_path(reader.u32().ok_or(invalid())?)?)?;
+
+                Ok(reply
+                    .u64(GETATTR_BASIC)
+                    .qid(Qid::from_metadata(&metadata))
+                    .u32(metadata.mode()).u32(metadata.uid()).u32(metadata.gid())
+                    .u64(metadata.nlink()).u64(metadata.rdev()).u64(metadata.size()).u64(metadata.blksize()).u64(metadata.blocks())
+                    .u64(metadata.atime() as u64).u64(metadata.atime_nsec() as u64)
+                    .u64(metadata.mtime() as u64).u64(metadata.mtime_nsec() as u64)
+                    .u64(metadata.ctime() as u64).u64(metadata.ctime_nsec() as u64)
+                    // No birth time, generation or data version
+                    .u64(0).u64(0).u64(0).u64(0)
+                    .finish())
+            },
+            TSETATTR => {
+                self.setattr(&mut reader)?;
+                Ok(reply.finish())
+            },
+            TREADDIR => {
+                let (fid, offset, count) = (reader.u32().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+
+                let Some(Open::Directory(entries)) = self.fid(fid)?.open.as_ref() else {
+                    return Err(error(libc::EBADF));
+                };
+
+                let room = count.min(self.msize - IO_HEADER_SIZE) as usize;
+                let mut data = Vec::new();
+
+                for (index, (qid, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+                    let entry = dirent(*qid, index as u64 + 1, *kind, name);
+
+                    if data.len() + entry.len() > room {
+                        break;
+                    }
+
+                    data.extend_from_slice(&entry);
+                }
+
+                Ok(reply.u32(data.len() as u32).bytes(&data).finish())
+            },
+            TSTATFS => {
+                let path = CString::new(self.host_path(reader.u32().ok_or(invalid())?)?.as_os_str().as_bytes()).map_err(|_| invalid())?;
+                let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+                if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+                    return Err(Error::last_os_error());
+                }
+
+                // V9FS_MAGIC for the type, like QEMU reports
+                Ok(reply
+                    .u32(0x01021997).u32(stat.f_bsize as u32)
+                    .u64(stat.f_blocks).u64(stat.f_bfree).u64(stat.f_bavail).u64(stat.f_files).u64(stat.f_ffree)
+                    .u64(stat.f_fsid).u32(stat.f_namemax as u32)
+                    .finish())
+            },
+            TMKDIR => {
+                let (fid, name, mode) = (reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                let (path, _) = self.child_path(fid, name)?;
+                DirBuilder::new().mode(mode & 0o7777).create(&path)?;
+
+                Ok(reply.qid(self.qid(&path)?).finish())
+            },
+            TSYMLINK => {
+                let (fid, name, target) = (reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?, reader.string().ok_or(invalid())?);
+                let (path, _) = self.child_path(fid, name)?;
+                symlink(target, &path)?;
+
+                Ok(reply.qid(self.qid(&path)?).finish())
+            },
+            TREADLINK => {
+                let target = fs::read_link(self.host_path(reader.u32().ok_or(invalid())?)?)?;
+                Ok(reply.string(&target.to_string_lossy()).finish())
+            },
+            TLINK => {
+                let (directory, fid, name) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?);
+                fs::hard_link(self.host_path(fid)?, self.child_path(directory, name)?.0)?;
+
+                Ok(reply.finish())
+            },
+            TRENAME => {
+                let (fid, directory, name) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?);
+                let old = self.fid(fid)?.path.clone();
+                let (_, new) = self.child_path(directory, name)?;
+                self.rename(&old, &new)?;
+
+                Ok(reply.finish())
+            },
+            TRENAMEAT => {
+                let (old_directory, old_name) = (reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?);
+                let (new_directory, new_name) = (reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?);
+                let (_, old) = self.child_path(old_directory, old_name)?;
+                let (_, new) = self.child_path(new_directory, new_name)?;
+                self.rename(&old, &new)?;
+
+                Ok(reply.finish())
+            },
+            TUNLINKAT => {
+                let (fid, name, flags) = (reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                let (path, _) = self.child_path(fid, name)?;
+
+                match flags & AT_REMOVEDIR != 0 {
+                    true => fs::remove_dir(&path)?,
+                    false => fs::remove_file(&path)?,
+                }
+
+                Ok(reply.finish())
+            },
+            TFSYNC => {
+                self.file(reader.u32().ok_or(invalid())?)?.sync_all()?;
+                Ok(reply.finish())
+            },
+            // Only the one guest uses the directory, every lock it asks for is granted
+            TLOCK => Ok(reply.u8(0).finish()),
+            TGETLOCK => {
+                let (_fid, _kind, start, length, proc_id, client_id) = (reader.u32(), reader.u8(), reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.string().ok_or(invalid())?);
+                Ok(reply.u8(libc::F_UNLCK as u8).u64(start).u64(length).u32(proc_id).string(client_id).finish())
+            },
+            // Everything is answered as it arrives, there's never anything to flush
+            TFLUSH => Ok(reply.finish()),
+            TXATTRWALK | TXATTRCREATE => Err(error(libc::EOPNOTSUPP)),
+            _ => Err(error(libc::ENOSYS)),
+        }
+    }
+
+    /// Walks `newfid` from `fid` a name at a time. A walk that stops part way still answers with
+    /// the qids it got through, but only a complete one binds the new fid.
+    fn walk(&mut self, reply: Writer, reader: &mut Reader) -> Result<Vec<u8>> {
+        let (fid, newfid, count) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.u16().ok_or(invalid())? as usize);
+
+        if count > MAX_WALK {
+            return Err(invalid());
+        }
+
+        let mut path = self.fid(fid)?.path.clone();
+        let mut qids = Vec::new();
+
+        for _ in 0..count {
+            let name = reader.string().ok_or(invalid())?;
+
+            let walked = match self.beneath(&path).and_then(fs::symlink_metadata) {
+                Ok(metadata) if !metadata.is_dir() => Err(error(libc::ENOTDIR)),
+                Ok(_) if name == ".." => {
+                    path.pop();
+                    self.qid(&self.root.join(&path))
+                },
+                Ok(_) => check_name(name).and_then(|name| {
+                    path.push(name);
+                    self.qid(&self.root.join(&path))
+                }),
+                Err(err) => Err(err),
+            };
+
+            match walked {
+                Ok(qid) => qids.push(qid),
+                Err(err) if qids.is_empty() => return Err(err),
+                Err(_) => break,
+            }
+        }
+
+        if qids.len() == count {
+            match newfid == fid {
+                true => self.fids.get_mut(&fid).unwrap().path = path,
+                false => self.add_fid(newfid, path)?,
+            }
+        }
+
+        let reply = reply.u16(qids.len() as u16);
+        Ok(qids.into_iter().fold(reply, |reply, qid| reply.qid(qid)).finish())
+    }
+
+    fn listing(&self, path: &Path) -> Result<Vec<(Qid, u8, String)>> {
+        let metadata = fs::symlink_metadata(path)?;
+        let mut entries = vec![(Qid::from_metadata(&metadata), libc::DT_DIR, ".".to_string()), (Qid::from_metadata(&metadata), libc::DT_DIR, "..".to_string())];
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let kind = ((metadata.mode() & libc::S_IFMT) >> 12) as u8;
+
+            entries.push((Qid::from_metadata(&metadata), kind, entry.file_name().to_string_lossy().into_owned()));
+        }
+
+        Ok(entries)
+    }
+
+    fn setattr(&mut self, reader: &mut Reader) -> Result<()> {
+        let (fid, valid, mode) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+        let (_uid, _gid) = (reader.u32(), reader.u32());
+        let (size, atime, atime_nsec, mtime, mtime_nsec) = (reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?);
+
+        let path = self.host_path(fid)?;
+
+        // chmod follows a symlink and Linux can't change a symlink's own mode anyway
+        if valid & SETATTR_MODE != 0 {
+            if fs::symlink_metadata(&path)?.is_symlink() {
+                return Err(error(libc::EOPNOTSUPP));
+            }
+
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+
+        if valid & SETATTR_SIZE != 0 {
+            match self.file(fid) {
+                Ok(file) => file.set_len(size)?,
+                Err(_) => OpenOptions::new().write(true).custom_flags(libc::O_NOFOLLOW).open(&path)?.set_len(size)?,
+            }
+        }
+
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            // Without the _SET bit a time is set to now
+            let time = |change: u32, set: u32, seconds: u64, nanoseconds: u64| match (valid & change != 0, valid & set != 0) {
+                (false, _) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+                (true, false) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+                (true, true) => libc::timespec { tv_sec: seconds as i64, tv_nsec: nanoseconds as i64 },
+            };
+
+            let times = [time(SETATTR_ATIME, SETATTR_ATIME_SET, atime, atime_nsec), time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime, mtime_nsec)];
+            let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| invalid())?;
+
+            if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Renames on the host and moves every fid at or below the old path along with it
+    fn rename(&mut self, old: &Path, new: &Path) -> Result<()> {
+        if old.components().next().is_none() || new.components().any(|component| component == Component::ParentDir) {
+            return Err(invalid());
+        }
+
+        fs::rename(self.beneath(old)?, self.beneath(new)?)?;
+
+        for fid in self.fids.values_mut() {
+            match fid.path.strip_prefix(old) {
+                Ok(below) if below.as_os_str().is_empty() => fid.path = new.to_path_buf(),
+                Ok(below) => fid.path = new.join(below),
+                Err(_) => {},
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_fids_walk_the_directory() {
+    let root = std::env::temp_dir().join(format!("virtio-9p-{}", std::process::id()));
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("sub/old.txt"), b"hello").unwrap();
+
+    let mut server = NinePServer::new(&root);
+    let mut call = |message: Writer| {
+        let reply = server.handle(&message.finish()).unwrap();
+        let (kind, _, body) = parse_message(&reply).unwrap();
+
+        (kind, body.to_vec())
+    };
+
+    let (kind, reply) = call(Writer::new(TVERSION, 0xffff).u32(1 << 20).string(VERSION));
+    assert_eq!((kind, Reader::new(&reply).u32()), (TVERSION + 1, Some(MAX_MSIZE)));
+    assert_eq!(call(Writer::new(TATTACH, 1).u32(0).u32(NOFID).string("root").string("").u32(0)).0, TATTACH + 1);
+
+    // A walk that stops part way says how far it got and binds nothing
+    let (_, reply) = call(Writer::new(TWALK, 1).u32(0).u32(1).u16(2).string("sub").string("missing"));
+    assert_eq!(Reader::new(&reply).u16(), Some(1));
+    assert_eq!(call(Writer::new(TCLUNK, 1).u32(1)).0, RLERROR);
+
+    let (_, reply) = call(Writer::new(TWALK, 1).u32(0).u32(1).u16(2).string("sub").string("old.txt"));
+    assert_eq!(Reader::new(&reply).u16(), Some(2));
+
+    // Renamed under an open fid, which follows it
+    assert_eq!(call(Writer::new(TWALK, 1).u32(0).u32(2).u16(1).string("sub")).0, TWALK + 1);
+    assert_eq!(call(Writer::new(TRENAMEAT, 1).u32(2).string("old.txt").u32(2).string("new.txt")).0, TRENAMEAT + 1);
+    assert_eq!(call(Writer::new(TLOPEN, 1).u32(1).u32(libc::O_RDONLY as u32)).0, TLOPEN + 1);
+
+    let (_, reply) = call(Writer::new(TREAD, 1).u32(1).u64(1).u32(100));
+    assert_eq!(&reply, &[&4u32.to_le_bytes()[..], b"ello"].concat());
+
+    assert_eq!(call(Writer::new(TLCREATE, 1).u32(2).string("made.txt").u32(libc::O_WRONLY as u32).u32(0o644).u32(0)).0, TLCREATE + 1);
+    assert_eq!(call(Writer::new(TWRITE, 1).u32(2).u64(0).u32(2).bytes(b"hi")).1, 2u32.to_le_bytes());
+    assert_eq!(fs::read(root.join("sub/made.txt")).unwrap(), b"hi");
+
+    assert_eq!(call(Writer::new(TWALK, 1).u32(0).u32(3).u16(1).string("sub")).0, TWALK + 1);
+    assert_eq!(call(Writer::new(TLOPEN, 1).u32(3).u32(libc::O_RDONLY as u32)).0, TLOPEN + 1);
+
+    let (_, reply) = call(Writer::new(TREADDIR, 1).u32(3).u64(0).u32(4096));
+    let mut names: Vec<String> = parse_dirents(&reply[4..]).into_iter().map(|(_, _, name)| name).collect();
+    names.sort();
+    assert_eq!(names, [".", "..", "made.txt", "new.txt"]);
+
+    // No climbing out of the shared directory
+    let (kind, reply) = call(Writer::new(TWALK, 1).u32(0).u32(4).u16(1).string(".."));
+    assert_eq!((kind, Reader::new(&reply).u16()), (TWALK + 1, Some(1)));
+    assert_eq!(call(Writer::new(TUNLINKAT, 1).u32(4).string("../escape").u32(0)).1, (libc::EINVAL as u32).to_le_bytes());
+
+    // Nor through a symlink, a walk can end on one but it's never used as a directory
+    let outside = std::env::temp_dir().join(format!("virtio-9p-outside-{}", std::process::id()));
+    fs::create_dir_all(&outside).unwrap();
+
+    assert_eq!(call(Writer::new(TSYMLINK, 1).u32(0).string("link").string(outside.to_str().unwrap()).u32(0)).0, TSYMLINK + 1);
+    assert_eq!(call(Writer::new(TWALK, 1).u32(0).u32(5).u16(1).string("link")).0, TWALK + 1);
+
+    let not_a_directory = (libc::ENOTDIR as u32).to_le_bytes();
+    assert_eq!(call(Writer::new(TLCREATE, 1).u32(5).string("planted.txt").u32(libc::O_WRONLY as u32).u32(0o644).u32(0)).1, not_a_directory);
+    assert_eq!(call(Writer::new(TMKDIR, 1).u32(5).string("planted").u32(0o755).u32(0)).1, not_a_directory);
+    assert_eq!(call(Writer::new(TUNLINKAT, 1).u32(5).string("planted.txt").u32(0)).1, not_a_directory);
+    assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+    fs::remove_dir_all(&outside).unwrap();
+
+    assert_eq!(call(Writer::new(TUNLINKAT, 1).u32(3).string("new.txt").u32(0)).0, TUNLINKAT + 1);
+    assert!(!root.join("sub/new.txt").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,316 @@
+// A virtio-balloon device, the host's way of asking the guest for memory back. The host sets a
+// target number of pages in the config space, the driver takes that many pages out of use and
+// sends their frame numbers on the inflate queue, and the device hands them back to the host
+// with MADV_DONTNEED. Deflating gives them back to the guest, which faults them in fresh. The
+// driver keeps `actual` in the config space up to date as it goes.
+//
+// The stats queue always holds one buffer from the driver, the device returns it whenever it
+// wants fresh numbers and the driver sends it back filled in. Free page reporting is the driver
+// telling the device about free pages on its own, those get discarded too but stay the guest's.
+//
+// The queues are numbered for both STATS_VQ and PAGE_REPORTING being negotiated, the driver has
+// to take both.
+
+pub mod stats;
+
+use std::{collections::BTreeSet, io::{Error, Result}, sync::Arc};
+
+use packed_struct::prelude::*;
+
+use crate::comms::Messages;
+use crate::virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain, guest_memory::GuestMemory};
+
+pub const VIRTIO_BALLOON_DEVICE_ID: u32 = 5;
+
+pub const INFLATEQ: u16 = 0;
+pub const DEFLATEQ: u16 = 1;
+pub const STATSQ: u16 = 2;
+pub const REPORTINGQ: u16 = 3;
+
+pub const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1 << 1;
+pub const VIRTIO_BALLOON_F_PAGE_REPORTING: u64 = 1 << 5;
+
+// Balloon page frame numbers are always 4 KiB pages
+pub const BALLOON_PAGE_SIZE: u64 = 4096;
+
+#[derive(PackedStruct)]
+#[packed_struct(endian="lsb", bit_numbering="msb0")]
+pub struct BalloonConfig {
+    #[packed_field(bytes="0x00..=0x03")]
+    num_pages: Integer<u32, packed_bits::Bits::<32>>,
+    #[packed_field(bytes="0x04..=0x07")]
+    actual: Integer<u32, packed_bits::Bits::<32>>,
+    #[packed_field(bytes="0x08..=0x0b")]
+    free_page_hint_cmd_id: Integer<u32, packed_bits::Bits::<32>>,
+    #[packed_field(bytes="0x0c..=0x0f")]
+    poison_val: Integer<u32, packed_bits::Bits::<32>>,
+}
+
+fn mib(pages: u64) -> u64 {
+    (pages * BALLOON_PAGE_SIZE) >> 20
+}
+
+pub struct VirtioBalloon {
+    memory: Arc<GuestMemory>,
+
+    target: u32,
+    actual: u32,
+    inflated: BTreeSet<u32>,
+
+    stats_buffer: Option<DescriptorChain>,
+    stats_requested: bool,
+    stats: Vec<(u16, u64)>,
+
+    reported_pages: u64,
+
+    // What the TUI was last told as target and actual
+    shown: Option<(u32, u32)>,
+}
+
+impl VirtioBalloon {
+    pub fn new(memory: Arc<GuestMemory>) -> Self {
+        Self {
+            memory,
+            target: 0,
+            actual: 0,
+            inflated: BTreeSet::new(),
+            stats_buffer: None,
+            stats_requested: false,
+            stats: Vec::new(),
+            reported_pages: 0,
+            shown: None,
+        }
+    }
+
+    /// Picks up the driver's latest `actual`, which it writes straight into the config space
+    fn read_actual(&mut self, ctx: &DeviceContext) {
+        let mut actual = [0u8; 4];
+        ctx.read_config(4, &mut actual);
+        self.actual = u32::from_le_bytes(actual);
+    }
+
+    fn show(&mut self, ctx: &DeviceContext) {
+        if self.shown != Some((self.target, self.actual)) {
+            self.shown = Some((self.target, self.actual));
+            ctx.send(Messages::BalloonSize(self.target as u64 * BALLOON_PAGE_SIZE, self.actual as u64 * BALLOON_PAGE_SIZE, self.memory.size()));
+        }
+    }
+
+    fn inflate(&mut self, ctx: &DeviceContext, pfns: &[u8]) {
+        for pfn in pfns.chunks_exact(4).map(|pfn| u32::from_le_bytes(pfn.try_into().unwrap())) {
+            if pfn >= self.memory.page_count() {
+                ctx.send_message(format!("The driver inflated page {pfn}, past the end of guest memory"));
+                continue;
+            }
+
+            if self.inflated.insert(pfn) {
+                if let Err(err) = self.memory.discard(pfn, 1) {
+                    ctx.send_message(format!("Couldn't release ballooned page {pfn}: {err}"));
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, ctx: &DeviceContext, chain: &DescriptorChain) {
+        for cell in unsafe { chain.cells() } {
+            let Some((pfn, count)) = self.memory.pages_of(cell.addr, cell.length as u64) else {
+                ctx.send_message(format!("The driver reported {} bytes at {:#x}, which isn't whole pages of guest memory", cell.length, cell.addr));
+                continue;
+            };
+
+            match self.memory.discard(pfn, count) {
+                Ok(()) => self.reported_pages += count as u64,
+                Err(err) => ctx.send_message(format!("Couldn't release reported pages {pfn}..{}: {err}", pfn + count)),
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_id(&self) -> u32 {
+        VIRTIO_BALLOON_DEVICE_ID
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_BALLOON_F_STATS_VQ | VIRTIO_BALLOON_F_PAGE_REPORTING
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        let config = BalloonConfig {
+            num_pages: self.target.into(),
+            actual: self.actual.into(),
+            free_page_hint_cmd_id: 0.into(),
+            poison_val: 0.into(),
+        };
+
+        config.pack().unwrap().to_vec()
+    }
+
+    fn queue_count(&self) -> usize {
+        4
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, queue: u16, chain: DescriptorChain) -> Option<u32> {
+        match queue {
+            INFLATEQ => self.inflate(ctx, &unsafe { chain.read_all() }),
+            DEFLATEQ => {
+                // The guest just uses the pages again, they fault back in as they're touched
+                for pfn in unsafe { chain.read_all() }.chunks_exact(4) {
+                    self.inflated.remove(&u32::from_le_bytes(pfn.try_into().unwrap()));
+                }
+            },
+            STATSQ => {
+                self.stats = stats::parse(&unsafe { chain.read_all() });
+
+                if std::mem::take(&mut self.stats_requested) {
+                    ctx.send_message(format!("Guest memory: {}", stats::describe(&self.stats)));
+                }
+
+                // Kept until we want the next update
+                self.stats_buffer = Some(chain);
+                return None;
+            },
+            _ => self.report(ctx, &chain),
+        }
+
+        Some(0)
+    }
+
+    fn poll(&mut self, ctx: &mut DeviceContext) {
+        self.read_actual(ctx);
+        self.show(ctx);
+    }
+
+    fn reset(&mut self) {
+        // The guest starts over with all its memory
+        self.inflated.clear();
+        self.actual = 0;
+        self.stats_buffer = None;
+        self.stats_requested = false;
+    }
+
+    fn command(&mut self, ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["target", size] => {
+                let pages = size.parse::<u64>().ok()
+                    .map(|size| size * (1 << 20) / BALLOON_PAGE_SIZE)
+                    .filter(|pages| *pages <= self.memory.page_count() as u64)
+                    .ok_or(format!("The target is in MiB, up to {} MiB", self.memory.size() >> 20))?;
+
+                // The config space is rebuilt from ours, so don't lose what the driver last wrote
+                self.read_actual(ctx);
+                self.target = pages as u32;
+                ctx.config_changed();
+                self.show(ctx);
+
+                Ok(format!("Asked the guest to balloon {size} MiB"))
+            },
+            ["stats"] => {
+                let Some(chain) = self.stats_buffer.take() else {
+                    return Err("The driver hasn't given the stats queue a buffer".to_string());
+                };
+
+                ctx.complete(STATSQ, chain, 0);
+                self.stats_requested = true;
+
+                Ok("Asked the guest for fresh statistics".to_string())
+            },
+            ["info"] => {
+                let resident = self.memory.resident_pages().map_err(|err| format!("Couldn't check residency: {err}"))?;
+
+                Ok(format!(
+                    "Target {} MiB, actual {} MiB, {} MiB reported free, {} of {} MiB resident",
+                    mib(self.target as u64), mib(self.actual as u64), mib(self.reported_pages), mib(resident as u64), self.memory.size() >> 20,
+                ))
+            },
+            _ => Err(format!("The balloon doesn't understand {command}, try target <MiB>, stats or info")),
+        }
+    }
+
+    // The target and which pages are in the balloon, they're still discarded after a restore
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        let mut snapshot = self.target.to_le_bytes().to_vec();
+
+        for pfn in self.inflated.iter() {
+            snapshot.extend_from_slice(&pfn.to_le_bytes());
+        }
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        if snapshot.len() < 4 || !snapshot.len().is_multiple_of(4) {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        self.target = u32::from_le_bytes(snapshot[..4].try_into().unwrap());
+        self.inflated = snapshot[4..].chunks_exact(4).map(|pfn| u32::from_le_bytes(pfn.try_into().unwrap())).collect();
+
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_balloon_releases_pages() {
+    use tokio::sync::mpsc::channel;
+
+    use crate::virtio::{virtqueue::DescriptorCell, vring::VIRTQ_DESC_F_WRITE, transport::{MmioTransport, TransportMode}};
+
+    let memory = Arc::new(GuestMemory::new(1024 * BALLOON_PAGE_SIZE).unwrap());
+    let (tx, mut rx) = channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, VIRTIO_BALLOON_DEVICE_ID, 0, &[8; 4]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport.clone());
+
+    let mut balloon = VirtioBalloon::new(memory.clone());
+    transport.lock().unwrap().set_config_space(balloon.config_space());
+
+    for pfn in 0..16 {
+        memory.touch(pfn);
+    }
+
+    let mut pfns: Vec<u8> = (4..8u32).flat_map(|pfn| pfn.to_le_bytes()).collect();
+    let mut stats = stats::to_bytes(&[(stats::VIRTIO_BALLOON_S_MEMFREE, 1 << 20)]);
+
+    let table = &mut [
+        DescriptorCell { addr: pfns.as_mut_ptr() as u64, length: pfns.len() as u32, flags: 0, next: 0 },
+        DescriptorCell { addr: memory.page_address(8).unwrap(), length: 4 * BALLOON_PAGE_SIZE as u32, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        DescriptorCell { addr: stats.as_mut_ptr() as u64, length: stats.len() as u32, flags: 0, next: 0 },
+    ];
+
+    let table = table.as_mut_ptr();
+    let chain = |head| unsafe { DescriptorChain::new(table, 3, head) };
+
+    // Both ballooned and reported pages go back to the host
+    assert_eq!(balloon.process_request(&mut ctx, INFLATEQ, chain(0)), Some(0));
+    assert_eq!(balloon.process_request(&mut ctx, REPORTINGQ, chain(1)), Some(0));
+    assert_eq!(memory.resident_pages().unwrap(), 8);
+    assert_eq!(balloon.inflated.len(), 4);
+
+    // The stats buffer is kept until the host asks for more
+    assert_eq!(balloon.process_request(&mut ctx, STATSQ, chain(2)), None);
+    assert!(balloon.command(&mut ctx, "stats").is_ok());
+    assert_eq!(ctx.completions.len(), 1);
+
+    assert!(balloon.command(&mut ctx, "target 1").is_ok());
+    assert!(balloon.command(&mut ctx, "target 1000").is_err());
+    assert!(ctx.config_changed);
+
+    // The driver's own `actual` is what gets shown
+    transport.lock().unwrap().write_config(4, &4u32.to_le_bytes());
+    balloon.poll(&mut ctx);
+
+    let mut sizes = Vec::new();
+
+    while let Ok(message) = rx.try_recv() {
+        if let Messages::BalloonSize(target, actual, total) = message {
+            sizes.push((target, actual, total));
+        }
+    }
+
+    assert_eq!(sizes.last(), Some(&(1 << 20, 4 * BALLOON_PAGE_SIZE, 1024 * BALLOON_PAGE_SIZE)));
+
+    let snapshot = balloon.snapshot().unwrap();
+    balloon.reset();
+    balloon.restore(&snapshot).unwrap();
+    assert_eq!((balloon.target, balloon.inflated.len()), (256, 4));
+}
@@ -0,0 +1,46 @@
+// Memory statistics as the stats queue carries them, a packed array of tag[2] value[8] pairs.
+// The driver fills in whichever tags it knows and the device keeps the latest of each.
+
+pub const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+pub const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+pub const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+pub const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+pub const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+pub const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+pub const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+pub const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+
+const STAT_SIZE: usize = 10;
+
+pub fn to_bytes(stats: &[(u16, u64)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(stats.len() * STAT_SIZE);
+
+    for (tag, value) in stats {
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    bytes
+}
+
+pub fn parse(bytes: &[u8]) -> Vec<(u16, u64)> {
+    bytes.chunks_exact(STAT_SIZE)
+        .map(|stat| (u16::from_le_bytes([stat[0], stat[1]]), u64::from_le_bytes(stat[2..].try_into().unwrap())))
+        .collect()
+}
+
+pub fn describe(stats: &[(u16, u64)]) -> String {
+    let described: Vec<String> = stats.iter().map(|(tag, value)| match *tag {
+        VIRTIO_BALLOON_S_SWAP_IN => format!("{value} pages swapped in"),
+        VIRTIO_BALLOON_S_SWAP_OUT => format!("{value} pages swapped out"),
+        VIRTIO_BALLOON_S_MAJFLT => format!("{value} major faults"),
+        VIRTIO_BALLOON_S_MINFLT => format!("{value} minor faults"),
+        VIRTIO_BALLOON_S_MEMFREE => format!("{} MiB free", value >> 20),
+        VIRTIO_BALLOON_S_MEMTOT => format!("{} MiB total", value >> 20),
+        VIRTIO_BALLOON_S_AVAIL => format!("{} MiB available", value >> 20),
+        VIRTIO_BALLOON_S_CACHES => format!("{} MiB of caches", value >> 20),
+        tag => format!("{value} for tag {tag}"),
+    }).collect();
+
+    described.join(", ")
+}
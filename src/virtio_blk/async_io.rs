@@ -0,0 +1,286 @@
+// Reads, writes and flushes of a raw image submitted through an io_uring instead of done inline.
+// The device holds on to each chain while its I/O is in flight and completes it when the ring
+// says the I/O is done, so one device thread can keep a deep queue of requests going. The ring
+// signals an eventfd as things complete, the device thread sleeps on that and the driver's
+// notifications together rather than blocking in the ring.
+
+use std::{collections::HashMap, io::{Error, Result}, os::unix::io::RawFd};
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::virtio::{descriptor_chain::DescriptorChain, device::DeviceContext};
+
+use super::{VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK};
+
+pub const DEFAULT_QUEUE_DEPTH: u32 = 128;
+
+enum Operation {
+    Read(Vec<u8>),
+    Write(Vec<u8>),
+    Flush,
+}
+
+struct InFlight {
+    queue: u16,
+    chain: DescriptorChain,
+    operation: Operation,
+
+    offset: u64,
+    // What the kernel has already done, a short read or write goes back in for the rest
+    done: usize,
+}
+
+impl InFlight {
+    fn entry(&mut self, fd: RawFd) -> squeue::Entry {
+        let offset = self.offset + self.done as u64;
+
+        match &mut self.operation {
+            Operation::Read(buffer) => {
+                let rest = &mut buffer[self.done..];
+                opcode::Read::new(types::Fd(fd), rest.as_mut_ptr(), rest.len() as u32).offset(offset).build()
+            },
+            Operation::Write(buffer) => {
+                let rest = &buffer[self.done..];
+                opcode::Write::new(types::Fd(fd), rest.as_ptr(), rest.len() as u32).offset(offset).build()
+            },
+            // Drained so the flush only starts once every write submitted before it has finished
+            Operation::Flush => opcode::Fsync::new(types::Fd(fd)).flags(types::FsyncFlags::DATASYNC).build().flags(squeue::Flags::IO_DRAIN),
+        }
+    }
+
+    fn length(&self) -> usize {
+        match &self.operation {
+            Operation::Read(buffer) | Operation::Write(buffer) => buffer.len(),
+            Operation::Flush => 0,
+        }
+    }
+}
+
+pub struct AsyncIo {
+    ring: IoUring,
+    fd: RawFd,
+    depth: usize,
+    completions: EventFd,
+
+    // Keyed by the user data of the submission, the buffers have to live until it completes
+    in_flight: HashMap<u64, InFlight>,
+    next_key: u64,
+}
+
+impl AsyncIo {
+    pub fn new(fd: RawFd, depth: u32) -> Result<Self> {
+        let ring = IoUring::new(depth)?;
+        let completions = EventFd::new()?;
+        ring.submitter().register_eventfd(completions.0)?;
+
+        Ok(Self {
+            ring,
+            fd,
+            depth: depth as usize,
+            completions,
+
+            in_flight: HashMap::new(),
+            next_key: 0,
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Readable once something has completed since the last `reap`
+    pub fn completion_fd(&self) -> RawFd {
+        self.completions.0
+    }
+
+    /// Queues the operation, false when the ring is already as deep as it goes and the caller
+    /// should do the request itself
+    fn push(&mut self, queue: u16, chain: DescriptorChain, offset: u64, operation: Operation) -> bool {
+        if self.in_flight.len() >= self.depth {
+            return false;
+        }
+
+        let key = self.next_key;
+        self.next_key += 1;
+
+        let mut request = InFlight { queue, chain, operation, offset, done: 0 };
+        let entry = request.entry(self.fd);
+
+        if !self.submit_entry(entry.user_data(key)) {
+            return false;
+        }
+
+        // Moving the request doesn't move the buffer's heap allocation the kernel was given
+        self.in_flight.insert(key, request);
+        true
+    }
+
+    fn submit_entry(&mut self, entry: squeue::Entry) -> bool {
+        unsafe {
+            if self.ring.submission().push(&entry).is_ok() {
+                return true;
+            }
+
+            // Submission queue full, hand what's there to the kernel and try once more
+            self.ring.submit().is_ok() && self.ring.submission().push(&entry).is_ok()
+        }
+    }
+
+    pub fn read(&mut self, queue: u16, chain: DescriptorChain, offset: u64, length: usize) -> bool {
+        self.push(queue, chain, offset, Operation::Read(vec![0; length]))
+    }
+
+    pub fn write(&mut self, queue: u16, chain: DescriptorChain, offset: u64, data: Vec<u8>) -> bool {
+        self.push(queue, chain, offset, Operation::Write(data))
+    }
+
+    pub fn flush(&mut self, queue: u16, chain: DescriptorChain) -> bool {
+        self.push(queue, chain, 0, Operation::Flush)
+    }
+
+    pub fn submit(&mut self) -> Result<usize> {
+        self.ring.submit()
+    }
+
+    /// Hands every finished request back to the driver through `ctx`
+    pub unsafe fn reap(&mut self, ctx: &mut DeviceContext) {
+        self.completions.clear();
+
+        let finished: Vec<(u64, i32)> = self.ring.completion().map(|entry| (entry.user_data(), entry.result())).collect();
+
+        for (key, result) in finished {
+            let Some(mut request) = self.in_flight.remove(&key) else {
+                continue;
+            };
+
+            let done = request.done + result.max(0) as usize;
+
+            // Short but still moving, the rest goes back in under the same key
+            if result > 0 && done < request.length() {
+                request.done = done;
+                let entry = request.entry(self.fd).user_data(key);
+
+                if self.submit_entry(entry) {
+                    self.in_flight.insert(key, request);
+                    continue;
+                }
+            }
+
+            let chain = request.chain;
+
+            let (status, written) = match request.operation {
+                Operation::Read(buffer) if result >= 0 && done == buffer.len() => (VIRTIO_BLK_S_OK, chain.write_at(0, &buffer)),
+                Operation::Write(buffer) if result >= 0 && done == buffer.len() => (VIRTIO_BLK_S_OK, 0),
+                Operation::Flush if result == 0 => (VIRTIO_BLK_S_OK, 0),
+                _ => (VIRTIO_BLK_S_IOERR, 0),
+            };
+
+            chain.write_at(chain.writable_len().saturating_sub(1), &[status]);
+
+            if status != VIRTIO_BLK_S_OK {
+                ctx.send_message(format!("Block request failed with status {status}"));
+            }
+
+            ctx.complete(request.queue, chain, written as u32 + 1);
+        }
+
+        // Anything resubmitted above has to reach the kernel before the thread sleeps
+        let _ = self.ring.submit();
+    }
+
+    /// Waits out everything in flight and forgets it, the driver has given the buffers up
+    pub fn drain(&mut self) {
+        while !self.in_flight.is_empty() {
+            if self.ring.submit_and_wait(1).is_err() {
+                break;
+            }
+
+            for entry in self.ring.completion() {
+                self.in_flight.remove(&entry.user_data());
+            }
+        }
+    }
+}
+
+// Nonblocking, so clearing it when nothing completed doesn't stall the device thread
+struct EventFd(RawFd);
+
+impl EventFd {
+    fn new() -> Result<Self> {
+        match unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) } {
+            -1 => Err(Error::last_os_error()),
+            fd => Ok(Self(fd)),
+        }
+    }
+
+    fn clear(&self) {
+        let mut count = 0u64;
+        unsafe { libc::read(self.0, &mut count as *mut u64 as *mut libc::c_void, 8); }
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0); }
+    }
+}
+
+#[test]
+pub fn test_async_requests_complete_later() {
+    use tokio::sync::mpsc::channel;
+
+    use crate::virtio::{device::VirtioDevice, virtqueue::DescriptorCell, vring::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE}, transport::{MmioTransport, TransportMode}};
+
+    use super::{RequestHeader, VirtioBlk, VIRTIO_BLK_DEVICE_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
+
+    let path = std::env::temp_dir().join(format!("virtio-blk-async-{}.img", std::process::id()));
+    let mut device = VirtioBlk::open(path.to_str().unwrap(), 1 << 20, false).unwrap();
+
+    let (tx, _rx) = channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, VIRTIO_BLK_DEVICE_ID, 0, &[8]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport);
+
+    let mut write_header = RequestHeader { request_type: VIRTIO_BLK_T_OUT, sector: 2 }.to_bytes();
+    let mut read_header = RequestHeader { request_type: VIRTIO_BLK_T_IN, sector: 2 }.to_bytes();
+    let mut written = [9u8; 1024];
+    let mut read = [0u8; 1024];
+    let mut statuses = [0xffu8; 2];
+
+    let mut table = [
+        DescriptorCell { addr: write_header.as_mut_ptr() as u64, length: 16, flags: VIRTQ_DESC_F_NEXT, next: 1 },
+        DescriptorCell { addr: written.as_mut_ptr() as u64, length: 1024, flags: VIRTQ_DESC_F_NEXT, next: 2 },
+        DescriptorCell { addr: statuses.as_mut_ptr() as u64, length: 1, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        DescriptorCell { addr: read_header.as_mut_ptr() as u64, length: 16, flags: VIRTQ_DESC_F_NEXT, next: 4 },
+        DescriptorCell { addr: read.as_mut_ptr() as u64, length: 1024, flags: VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, next: 5 },
+        DescriptorCell { addr: unsafe { statuses.as_mut_ptr().add(1) } as u64, length: 1, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        DescriptorCell::default(),
+        DescriptorCell::default(),
+    ];
+
+    let table = table.as_mut_ptr();
+    let chain = |head| unsafe { DescriptorChain::new(table, 8, head) };
+
+    let finish = |device: &mut VirtioBlk, ctx: &mut DeviceContext| {
+        device.poll(ctx);
+
+        while let Some(fd) = device.completion_fd() {
+            let mut ready = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            unsafe { libc::poll(&mut ready, 1, -1) };
+
+            device.poll(ctx);
+        }
+
+        ctx.completions.drain(..).map(|completion| (completion.head, completion.length)).collect::<Vec<_>>()
+    };
+
+    assert_eq!(device.process_request(&mut ctx, 0, chain(0)), None);
+    assert_eq!(finish(&mut device, &mut ctx), [(0, 1)]);
+
+    assert_eq!(device.process_request(&mut ctx, 0, chain(3)), None);
+    assert_eq!(finish(&mut device, &mut ctx), [(3, 1025)]);
+
+    assert_eq!(statuses, [VIRTIO_BLK_S_OK; 2]);
+    assert_eq!(read, written);
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,272 @@
+// Where the bytes of a block device actually live. The device model only speaks in byte
+// offsets that it has already range checked against `capacity`.
+
+use std::{fs::{File, OpenOptions}, io::{Result, Error, ErrorKind}, os::unix::{fs::FileExt, io::{AsRawFd, RawFd}}};
+
+use libc::{fallocate, EOPNOTSUPP, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE};
+
+use super::overlay::OverlayBackend;
+
+pub trait BlockBackend: Send {
+    /// Size of the disk in bytes
+    fn capacity(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8], offset: u64) -> Result<()>;
+
+    fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+
+    /// The range no longer matters to the guest, it must read back as zeroes afterwards
+    fn discard(&mut self, offset: u64, length: u64) -> Result<()>;
+
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> Result<()> {
+        write_zeroes_in_chunks(offset, length, |zeroes, offset| self.write_at(zeroes, offset))
+    }
+
+    /// The descriptor reads and writes can go straight to, for backends that are just a file
+    fn io_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Backends that can take snapshots hand themselves out here
+    fn as_overlay(&mut self) -> Option<&mut OverlayBackend> {
+        None
+    }
+}
+
+// The driver can ask for up to u32::MAX sectors in one go, far too much to allocate as zeroes
+const ZERO_CHUNK: u64 = 1 << 20;
+
+/// Writes `length` zeroes through `write` a chunk at a time, for backends with no faster way
+pub fn write_zeroes_in_chunks<F: FnMut(&[u8], u64) -> Result<()>>(offset: u64, length: u64, mut write: F) -> Result<()> {
+    let zeroes = vec![0u8; length.min(ZERO_CHUNK) as usize];
+    let mut done = 0;
+
+    while done < length {
+        let count = (length - done).min(ZERO_CHUNK);
+        write(&zeroes[..count as usize], offset + done)?;
+        done += count;
+    }
+
+    Ok(())
+}
+
+/// A disk that only exists for as long as the process does
+pub struct MemoryBackend {
+    data: Vec<u8>,
+}
+
+impl MemoryBackend {
+    pub fn new(capacity: u64) -> Self {
+        Self { data: vec![0; capacity as usize] }
+    }
+
+    fn range(&mut self, offset: u64, length: usize) -> Result<&mut [u8]> {
+        let start = offset as usize;
+
+        self.data.get_mut(start..start + length).ok_or(Error::from(ErrorKind::UnexpectedEof))
+    }
+}
+
+impl BlockBackend for MemoryBackend {
+    fn capacity(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8], offset: u64) -> Result<()> {
+        buffer.copy_from_slice(self.range(offset, buffer.len())?);
+        Ok(())
+    }
+
+    fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        self.range(offset, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> Result<()> {
+        self.range(offset, length as usize)?.fill(0);
+        Ok(())
+    }
+}
+
+/// A raw image file, discards punch holes so the file gets sparser as the guest frees blocks
+pub struct RawFileBackend {
+    file: File,
+    capacity: u64,
+}
+
+impl RawFileBackend {
+    /// Opens (or creates) a raw image, a new image is a sparse file of `capacity` bytes
+    pub fn open(path: &str, capacity: u64) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+        if file.metadata()?.len() < capacity {
+            file.set_len(capacity)?;
+        }
+
+        Self::from_file(file)
+    }
+
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        Self::from_file(File::open(path)?)
+    }
+
+    fn from_file(file: File) -> Result<Self> {
+        let capacity = file.metadata()?.len();
+
+        Ok(Self { file, capacity })
+    }
+}
+
+fn fallocate_range(file: &File, mode: i32, offset: u64, length: u64) -> Result<()> {
+    match unsafe { fallocate(file.as_raw_fd(), mode, offset as i64, length as i64) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Punching a hole hands the blocks back to the host filesystem, reads of it return zeroes
+pub(crate) fn punch_hole(file: &File, offset: u64, length: u64) -> Result<()> {
+    fallocate_range(file, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, offset, length)
+}
+
+impl BlockBackend for RawFileBackend {
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8], offset: u64) -> Result<()> {
+        self.file.read_exact_at(buffer, offset)
+    }
+
+    fn io_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+
+    fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        self.file.write_all_at(data, offset)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> Result<()> {
+        punch_hole(&self.file, offset, length)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> Result<()> {
+        match fallocate_range(&self.file, FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE, offset, length) {
+            Err(err) if err.raw_os_error() == Some(EOPNOTSUPP) => {
+                write_zeroes_in_chunks(offset, length, |zeroes, offset| self.file.write_all_at(zeroes, offset))
+            },
+            result => result,
+        }
+    }
+}
+
+/// Wraps any backend so every change the guest asks for is refused
+pub struct ReadOnlyBackend<B: BlockBackend> {
+    inner: B,
+}
+
+impl<B: BlockBackend> ReadOnlyBackend<B> {
+    pub fn new(inner: B) -> Self {
+        Self { inner }
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for ReadOnlyBackend<B> {
+    fn capacity(&self) -> u64 {
+        self.inner.capacity()
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    // Safe to hand out, the device never submits writes for a read-only backend
+    fn io_fd(&self) -> Option<RawFd> {
+        self.inner.io_fd()
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8], offset: u64) -> Result<()> {
+        self.inner.read_at(buffer, offset)
+    }
+
+    fn write_at(&mut self, _data: &[u8], _offset: u64) -> Result<()> {
+        Err(Error::from(ErrorKind::PermissionDenied))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn discard(&mut self, _offset: u64, _length: u64) -> Result<()> {
+        Err(Error::from(ErrorKind::PermissionDenied))
+    }
+
+    fn write_zeroes(&mut self, _offset: u64, _length: u64) -> Result<()> {
+        Err(Error::from(ErrorKind::PermissionDenied))
+    }
+}
+
+#[test]
+pub fn test_raw_file_discard_punches_hole() {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = std::env::temp_dir().join(format!("virtio-blk-discard-{}.img", std::process::id()));
+    let mut backend = RawFileBackend::open(path.to_str().unwrap(), 512 * 1024).unwrap();
+
+    backend.write_at(&[0xaa; 512 * 1024], 0).unwrap();
+    backend.file.sync_all().unwrap();
+    let allocated = backend.file.metadata().unwrap().blocks();
+
+    backend.discard(0, 256 * 1024).unwrap();
+    backend.write_zeroes(256 * 1024, 4096).unwrap();
+
+    assert!(backend.file.metadata().unwrap().blocks() < allocated);
+
+    let mut contents = vec![0xffu8; 260 * 1024];
+    backend.read_at(&mut contents, 0).unwrap();
+    assert!(contents.iter().all(|byte| *byte == 0));
+
+    let mut untouched = [0u8; 512];
+    backend.read_at(&mut untouched, 260 * 1024).unwrap();
+    assert_eq!(untouched, [0xaa; 512]);
+
+    let mut read_only = ReadOnlyBackend::new(RawFileBackend::open_read_only(path.to_str().unwrap()).unwrap());
+    assert_eq!(read_only.write_at(&[1], 0).unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+pub fn test_write_zeroes_spans_chunks() {
+    let mut backend = MemoryBackend::new(3 * ZERO_CHUNK);
+    backend.write_at(&vec![0xaa; 3 * ZERO_CHUNK as usize], 0).unwrap();
+
+    let mut writes = 0;
+    write_zeroes_in_chunks(512, 2 * ZERO_CHUNK + 512, |zeroes, offset| {
+        writes += 1;
+        backend.write_at(zeroes, offset)
+    }).unwrap();
+
+    assert_eq!(writes, 3);
+
+    let mut contents = vec![0u8; 3 * ZERO_CHUNK as usize];
+    backend.read_at(&mut contents, 0).unwrap();
+
+    assert!(contents[..512].iter().all(|byte| *byte == 0xaa));
+    assert!(contents[512..2 * ZERO_CHUNK as usize + 1024].iter().all(|byte| *byte == 0));
+    assert!(contents[2 * ZERO_CHUNK as usize + 1024..].iter().all(|byte| *byte == 0xaa));
+}
@@ -0,0 +1,489 @@
+// A virtio-blk device on top of a `BlockBackend`. Every request is a chain of a
+// `virtio_blk_req` header, the data buffers and a single status byte the device fills in.
+
+pub mod backend;
+pub mod qcow2;
+pub mod overlay;
+pub mod async_io;
+
+use std::{io::{Result, Error, ErrorKind}, os::unix::io::RawFd};
+
+use packed_struct::prelude::*;
+
+use crate::virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain};
+
+use self::{backend::{BlockBackend, MemoryBackend, RawFileBackend, ReadOnlyBackend}, qcow2::{is_qcow2, Qcow2Backend}, overlay::OverlayBackend, async_io::{AsyncIo, DEFAULT_QUEUE_DEPTH}};
+
+pub const VIRTIO_BLK_DEVICE_ID: u32 = 2;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+pub const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+pub const REQUEST_HEADER_SIZE: usize = 16;
+pub const DISCARD_SEGMENT_SIZE: usize = 16;
+
+const SIZE_MAX: u32 = 1 << 20;
+const SEG_MAX: u32 = 126;
+
+const MAX_DISCARD_SECTORS: u32 = u32::MAX;
+const MAX_DISCARD_SEG: u32 = 32;
+// Holes are punched in whole filesystem blocks, anything smaller just gets zeroed
+const DISCARD_SECTOR_ALIGNMENT: u32 = 8;
+
+#[derive(PackedStruct)]
+#[packed_struct(endian="lsb", bit_numbering="msb0")]
+pub struct BlkConfig {
+    #[packed_field(bytes="0x00..=0x07")]
+    capacity: Integer<u64, packed_bits::Bits::<64>>,
+
+    #[packed_field(bytes="0x08..=0x0b")]
+    size_max: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x0c..=0x0f")]
+    seg_max: Integer<u32, packed_bits::Bits::<32>>,
+
+    // Geometry lives here, we don't offer it
+
+    #[packed_field(bytes="0x14..=0x17")]
+    blk_size: Integer<u32, packed_bits::Bits::<32>>,
+
+    // Topology and writeback sit in between, also not offered
+
+    #[packed_field(bytes="0x24..=0x27")]
+    max_discard_sectors: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x28..=0x2b")]
+    max_discard_seg: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x2c..=0x2f")]
+    discard_sector_alignment: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x30..=0x33")]
+    max_write_zeroes_sectors: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x34..=0x37")]
+    max_write_zeroes_seg: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x38")]
+    write_zeroes_may_unmap: u8,
+
+    #[packed_field(bytes="0x39..=0x3b")]
+    _unused: [u8; 3],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestHeader {
+    pub request_type: u32,
+    pub sector: u64,
+}
+
+impl RequestHeader {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < REQUEST_HEADER_SIZE {
+            return None;
+        }
+
+        Some(Self {
+            request_type: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            sector: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; REQUEST_HEADER_SIZE] {
+        let mut bytes = [0; REQUEST_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.request_type.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.sector.to_le_bytes());
+
+        bytes
+    }
+}
+
+/// One range of a DISCARD or WRITE_ZEROES request, the payload is a list of these
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiscardSegment {
+    pub sector: u64,
+    pub num_sectors: u32,
+    pub flags: u32,
+}
+
+impl DiscardSegment {
+    pub fn parse_all(bytes: &[u8]) -> Option<Vec<Self>> {
+        if bytes.is_empty() || !bytes.len().is_multiple_of(DISCARD_SEGMENT_SIZE) {
+            return None;
+        }
+
+        Some(bytes.chunks_exact(DISCARD_SEGMENT_SIZE).map(|segment| Self {
+            sector: u64::from_le_bytes(segment[0..8].try_into().unwrap()),
+            num_sectors: u32::from_le_bytes(segment[8..12].try_into().unwrap()),
+            flags: u32::from_le_bytes(segment[12..16].try_into().unwrap()),
+        }).collect())
+    }
+
+    #[cfg(test)]
+    pub fn to_bytes(self) -> [u8; DISCARD_SEGMENT_SIZE] {
+        let mut bytes = [0; DISCARD_SEGMENT_SIZE];
+        bytes[0..8].copy_from_slice(&self.sector.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.num_sectors.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.flags.to_le_bytes());
+
+        bytes
+    }
+}
+
+pub struct VirtioBlk {
+    backend: Box<dyn BlockBackend>,
+    capacity: u64,
+    id: String,
+
+    // Only backends that are a plain file descriptor get their I/O done asynchronously
+    io: Option<AsyncIo>,
+}
+
+impl VirtioBlk {
+    pub fn new(backend: Box<dyn BlockBackend>, id: &str) -> Self {
+        // A trailing partial sector can't be addressed by the driver
+        let capacity = backend.capacity() / SECTOR_SIZE * SECTOR_SIZE;
+
+        let io = backend.io_fd().and_then(|fd| AsyncIo::new(fd, DEFAULT_QUEUE_DEPTH).ok());
+
+        Self {
+            backend,
+            capacity,
+            id: id.to_string(),
+
+            io,
+        }
+    }
+
+    /// Opens an existing qcow2 image, anything else is treated as raw. A missing image is
+    /// created as a sparse raw file of `capacity` bytes.
+    pub fn open(path: &str, capacity: u64, read_only: bool) -> Result<Self> {
+        let qcow2 = std::path::Path::new(path).exists() && is_qcow2(path)?;
+
+        let backend: Box<dyn BlockBackend> = match (qcow2, read_only) {
+            (true, true) => Box::new(ReadOnlyBackend::new(Qcow2Backend::open_read_only(path)?)),
+            (true, false) => Box::new(Qcow2Backend::open(path)?),
+            (false, true) => Box::new(ReadOnlyBackend::new(RawFileBackend::open_read_only(path)?)),
+            (false, false) => Box::new(RawFileBackend::open(path, capacity)?),
+        };
+
+        Ok(Self::new(backend, path.rsplit('/').next().unwrap_or(path)))
+    }
+
+    /// A RAM disk of `capacity` bytes, gone when the simulator exits
+    pub fn in_memory(capacity: u64) -> Self {
+        Self::new(Box::new(MemoryBackend::new(capacity)), "ram")
+    }
+
+    /// Leaves the image at `base_path` untouched, the guest's writes go to overlays kept in
+    /// `directory` and can be snapshotted. A missing base is created empty first.
+    pub fn open_overlay(base_path: &str, capacity: u64, directory: &str) -> Result<Self> {
+        if !std::path::Path::new(base_path).exists() {
+            RawFileBackend::open(base_path, capacity)?;
+        }
+
+        let backend = OverlayBackend::new(base_path, directory)?;
+
+        Ok(Self::new(Box::new(backend), base_path.rsplit('/').next().unwrap_or(base_path)))
+    }
+
+    pub fn capacity_sectors(&self) -> u64 {
+        self.capacity / SECTOR_SIZE
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.backend.read_only()
+    }
+
+    fn check_range(&self, sector: u64, length: usize) -> Result<u64> {
+        let offset = sector.checked_mul(SECTOR_SIZE).ok_or(Error::from(ErrorKind::InvalidInput))?;
+
+        if !(length as u64).is_multiple_of(SECTOR_SIZE) || offset.checked_add(length as u64).filter(|end| *end <= self.capacity).is_none() {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+
+        Ok(offset)
+    }
+
+    unsafe fn handle_in(&mut self, chain: &DescriptorChain, sector: u64, data_len: usize) -> Result<usize> {
+        let offset = self.check_range(sector, data_len)?;
+
+        let mut data = vec![0u8; data_len];
+        self.backend.read_at(&mut data, offset)?;
+
+        Ok(chain.write_at(0, &data))
+    }
+
+    fn handle_out(&mut self, sector: u64, data: &[u8]) -> Result<()> {
+        let offset = self.check_range(sector, data.len())?;
+        self.backend.write_at(data, offset)
+    }
+
+    fn handle_discard(&mut self, request_type: u32, payload: &[u8]) -> Result<()> {
+        let segments = DiscardSegment::parse_all(payload).ok_or(Error::from(ErrorKind::InvalidInput))?;
+
+        if segments.len() > MAX_DISCARD_SEG as usize {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+
+        for segment in segments {
+            let length = segment.num_sectors as u64 * SECTOR_SIZE;
+            let offset = self.check_range(segment.sector, length as usize)?;
+
+            match request_type {
+                VIRTIO_BLK_T_DISCARD => self.backend.discard(offset, length)?,
+                _ if segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP > 0 => self.backend.discard(offset, length)?,
+                _ => self.backend.write_zeroes(offset, length)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `snapshot take|revert|merge <name>` and `snapshot list`
+    fn snapshot_command(&mut self, args: &[&str]) -> std::result::Result<String, String> {
+        let overlay = self.backend.as_overlay().ok_or("The disk isn't running on an overlay")?;
+
+        let result = match args {
+            ["list"] => {
+                let snapshots = overlay.list_snapshots().iter()
+                    .map(|info| format!("{} ({} KiB)", info.name, info.allocated_bytes / 1024))
+                    .collect::<Vec<_>>();
+
+                return Ok(format!("Snapshots: {}", snapshots.join(", ")));
+            },
+            ["take", name] => overlay.take_snapshot(name).map(|_| format!("Took snapshot {name}")),
+            ["revert", name] => overlay.revert_to_snapshot(name).map(|_| format!("Reverted the disk to snapshot {name}")),
+            ["merge", name] => overlay.merge_snapshot(name).map(|_| format!("Merged snapshot {name}")),
+            _ => return Err("Usage: snapshot take|revert|merge <name>, snapshot list".to_string()),
+        };
+
+        result.map_err(|err| format!("Snapshot failed: {err}"))
+    }
+
+    unsafe fn handle_get_id(&self, chain: &DescriptorChain) -> usize {
+        let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+        let length = self.id.len().min(VIRTIO_BLK_ID_BYTES);
+        id[..length].copy_from_slice(&self.id.as_bytes()[..length]);
+
+        chain.write_at(0, &id)
+    }
+
+    /// Hands reads, writes and flushes to the ring, false when the request has to be done inline
+    fn submit_async(&mut self, queue: u16, chain: &DescriptorChain, header: &RequestHeader, data_in_len: usize, data_out: &[u8]) -> bool {
+        let read_only = self.read_only();
+
+        let request = match header.request_type {
+            VIRTIO_BLK_T_IN => self.check_range(header.sector, data_in_len).ok().map(|offset| (offset, None)),
+            VIRTIO_BLK_T_OUT if !read_only => self.check_range(header.sector, data_out.len()).ok().map(|offset| (offset, Some(data_out.to_vec()))),
+            VIRTIO_BLK_T_FLUSH => Some((0, None)),
+            _ => None,
+        };
+
+        match (self.io.as_mut(), request) {
+            (Some(io), Some((_, None))) if header.request_type == VIRTIO_BLK_T_FLUSH => io.flush(queue, *chain),
+            (Some(io), Some((offset, None))) => io.read(queue, *chain, offset, data_in_len),
+            (Some(io), Some((offset, Some(data)))) => io.write(queue, *chain, offset, data),
+            _ => false,
+        }
+    }
+
+    /// Returns the status byte and how many data bytes were written back to the driver, or
+    /// None when the request went to the ring and completes later
+    unsafe fn handle_request(&mut self, queue: u16, chain: &DescriptorChain) -> Option<(u8, usize)> {
+        let readable = chain.read_all();
+
+        let header = match RequestHeader::parse(&readable) {
+            Some(header) => header,
+            None => return Some((VIRTIO_BLK_S_IOERR, 0)),
+        };
+
+        // The last writable byte is always the status
+        let data_in_len = chain.writable_len().saturating_sub(1);
+        let data_out = &readable[REQUEST_HEADER_SIZE..];
+
+        if self.submit_async(queue, chain, &header, data_in_len, data_out) {
+            return None;
+        }
+
+        let result = match header.request_type {
+            VIRTIO_BLK_T_IN => self.handle_in(chain, header.sector, data_in_len),
+            VIRTIO_BLK_T_OUT => self.handle_out(header.sector, data_out).map(|_| 0),
+            VIRTIO_BLK_T_FLUSH => self.backend.flush().map(|_| 0),
+            VIRTIO_BLK_T_GET_ID => Ok(self.handle_get_id(chain)),
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => self.handle_discard(header.request_type, data_out).map(|_| 0),
+            _ => return Some((VIRTIO_BLK_S_UNSUPP, 0)),
+        };
+
+        match result {
+            Ok(written) => Some((VIRTIO_BLK_S_OK, written)),
+            Err(_) => Some((VIRTIO_BLK_S_IOERR, 0)),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        VIRTIO_BLK_DEVICE_ID
+    }
+
+    fn device_features(&self) -> u64 {
+        let features = VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+
+        if self.read_only() {
+            features | VIRTIO_BLK_F_RO
+        } else {
+            features | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES
+        }
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        let config = BlkConfig {
+            capacity: self.capacity_sectors().into(),
+            size_max: SIZE_MAX.into(),
+            seg_max: SEG_MAX.into(),
+            blk_size: (SECTOR_SIZE as u32).into(),
+            max_discard_sectors: MAX_DISCARD_SECTORS.into(),
+            max_discard_seg: MAX_DISCARD_SEG.into(),
+            discard_sector_alignment: DISCARD_SECTOR_ALIGNMENT.into(),
+            max_write_zeroes_sectors: MAX_DISCARD_SECTORS.into(),
+            max_write_zeroes_seg: MAX_DISCARD_SEG.into(),
+            write_zeroes_may_unmap: 1,
+            _unused: [0; 3],
+        };
+
+        config.pack().unwrap().to_vec()
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, queue: u16, chain: DescriptorChain) -> Option<u32> {
+        unsafe {
+            let (status, written) = self.handle_request(queue, &chain)?;
+            let status_offset = chain.writable_len().saturating_sub(1);
+
+            chain.write_at(status_offset, &[status]);
+
+            if status != VIRTIO_BLK_S_OK {
+                ctx.send_message(format!("Block request failed with status {status}"));
+            }
+
+            Some(written as u32 + 1)
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut DeviceContext) {
+        if let Some(io) = self.io.as_mut() {
+            let _ = io.submit();
+            unsafe { io.reap(ctx) };
+        }
+    }
+
+    fn completion_fd(&self) -> Option<RawFd> {
+        self.io.as_ref().filter(|io| io.in_flight() > 0).map(|io| io.completion_fd())
+    }
+
+    fn reset(&mut self) {
+        if let Some(io) = self.io.as_mut() {
+            io.drain();
+        }
+    }
+
+    // Commands run on the device thread between requests, so the disk is always quiesced
+    fn command(&mut self, _ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["snapshot", args @ ..] => self.snapshot_command(args),
+            _ => Err(format!("The block device doesn't understand {command}")),
+        }
+    }
+
+    // Everything the device knows lives in the image, flushing it is the snapshot
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(self.capacity.to_le_bytes().to_vec())
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        let capacity = snapshot.try_into().map(u64::from_le_bytes).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+
+        if capacity != self.capacity {
+            return Err(Error::new(ErrorKind::InvalidData, "snapshot is for a different sized disk"));
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_block_round_trip() {
+    use tokio::sync::mpsc::channel;
+
+    use crate::virtio::{virtqueue::DescriptorCell, vring::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE}, transport::{MmioTransport, TransportMode}};
+
+    let mut device = VirtioBlk::in_memory(8 * SECTOR_SIZE);
+
+    let (tx, _rx) = channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, VIRTIO_BLK_DEVICE_ID, 0, &[4]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport);
+
+    let mut run = |header: RequestHeader, data: &mut [u8], writable: bool, status: &mut u8| unsafe {
+        let mut header = header.to_bytes();
+        let data_flags = if writable { VIRTQ_DESC_F_WRITE } else { 0 };
+
+        let mut table = [
+            DescriptorCell { addr: header.as_mut_ptr() as u64, length: 16, flags: VIRTQ_DESC_F_NEXT, next: 1 },
+            DescriptorCell { addr: data.as_mut_ptr() as u64, length: data.len() as u32, flags: VIRTQ_DESC_F_NEXT | data_flags, next: 2 },
+            DescriptorCell { addr: status as *mut u8 as u64, length: 1, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+            DescriptorCell::default(),
+        ];
+
+        device.process_request(&mut ctx, 0, DescriptorChain::new(table.as_mut_ptr(), 4, 0))
+    };
+
+    let mut status = 0xff;
+    let mut written = [7u8; 512];
+    assert_eq!(run(RequestHeader { request_type: VIRTIO_BLK_T_OUT, sector: 3 }, &mut written, false, &mut status), Some(1));
+    assert_eq!(status, VIRTIO_BLK_S_OK);
+
+    let mut read = [0u8; 512];
+    assert_eq!(run(RequestHeader { request_type: VIRTIO_BLK_T_IN, sector: 3 }, &mut read, true, &mut status), Some(513));
+    assert_eq!(read, written);
+
+    let mut discard = DiscardSegment { sector: 3, num_sectors: 1, flags: 0 }.to_bytes();
+    run(RequestHeader { request_type: VIRTIO_BLK_T_DISCARD, sector: 0 }, &mut discard, false, &mut status);
+    assert_eq!(status, VIRTIO_BLK_S_OK);
+
+    run(RequestHeader { request_type: VIRTIO_BLK_T_IN, sector: 3 }, &mut read, true, &mut status);
+    assert_eq!(read, [0u8; 512]);
+
+    run(RequestHeader { request_type: VIRTIO_BLK_T_IN, sector: 8 }, &mut read, true, &mut status);
+    assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+    // The end of the request would wrap past u64::MAX
+    status = 0xff;
+    run(RequestHeader { request_type: VIRTIO_BLK_T_IN, sector: u64::MAX / SECTOR_SIZE }, &mut read, true, &mut status);
+    assert_eq!(status, VIRTIO_BLK_S_IOERR);
+}
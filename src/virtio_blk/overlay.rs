@@ -0,0 +1,295 @@
+// Runs a disk on top of a base image that is never written. Everything the guest changes lands
+// in a qcow2 overlay, and a snapshot is just the current overlay frozen with a fresh one stacked
+// on top of it, so taking one costs nothing however big the base is.
+//
+//   base <- snapshot-a.qcow2 <- snapshot-b.qcow2 <- active.qcow2
+//
+// A chain already in the overlay directory is picked up again on start, so the disk and its
+// snapshots outlive the process.
+
+use std::{fs, io::{Result, Error, ErrorKind}, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
+
+use super::{backend::BlockBackend, qcow2::{open_read_only_image, Qcow2Backend}};
+
+const ACTIVE_OVERLAY: &str = "active.qcow2";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    /// Space the snapshot's own changes take up on the host
+    pub allocated_bytes: u64,
+}
+
+pub struct OverlayBackend {
+    base_path: String,
+    directory: PathBuf,
+
+    snapshots: Vec<Snapshot>,
+    active: Qcow2Backend,
+
+    capacity: u64,
+}
+
+fn absolute(path: &Path) -> Result<String> {
+    Ok(fs::canonicalize(path)?.to_string_lossy().to_string())
+}
+
+fn not_found(name: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("no snapshot called {name}"))
+}
+
+/// Follows the backing names down from the active overlay, oldest snapshot first. The chain has
+/// to end on `base_path` and every layer on the way has to be one of our snapshot files.
+fn existing_snapshots(active: &Qcow2Backend, base_path: &str) -> Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    let mut backing = active.backing_path().map(str::to_string);
+
+    loop {
+        let path = match backing {
+            Some(path) if path == base_path => break,
+            Some(path) => path,
+            None => return Err(Error::new(ErrorKind::InvalidData, format!("the overlay chain doesn't end on {base_path}"))),
+        };
+
+        let name = Path::new(&path).file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("snapshot-")?.strip_suffix(".qcow2"))
+            .ok_or(Error::new(ErrorKind::InvalidData, format!("{path} isn't a snapshot of {base_path}")))?
+            .to_string();
+
+        backing = Qcow2Backend::open_read_only(&path)?.backing_path().map(str::to_string);
+        snapshots.push(Snapshot { name, path });
+    }
+
+    snapshots.reverse();
+    Ok(snapshots)
+}
+
+impl OverlayBackend {
+    pub fn new(base_path: &str, directory: &str) -> Result<Self> {
+        // Backing names are stored absolute so snapshot files can be renamed freely
+        let base_path = absolute(Path::new(base_path))?;
+        let capacity = open_read_only_image(&base_path)?.capacity();
+
+        fs::create_dir_all(directory)?;
+        let directory = PathBuf::from(absolute(Path::new(directory))?);
+
+        let active_path = directory.join(ACTIVE_OVERLAY).to_string_lossy().to_string();
+
+        let (active, snapshots) = if Path::new(&active_path).exists() {
+            let active = Qcow2Backend::open(&active_path)?;
+            let snapshots = existing_snapshots(&active, &base_path)?;
+
+            if active.capacity() != capacity {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{active_path} isn't the size of {base_path}")));
+            }
+
+            (active, snapshots)
+        } else {
+            (Qcow2Backend::create(&active_path, capacity, Some(&base_path))?, Vec::new())
+        };
+
+        Ok(Self {
+            base_path,
+            directory,
+
+            snapshots,
+            active,
+
+            capacity,
+        })
+    }
+
+    fn active_path(&self) -> String {
+        self.directory.join(ACTIVE_OVERLAY).to_string_lossy().to_string()
+    }
+
+    fn position(&self, name: &str) -> Result<usize> {
+        self.snapshots.iter().position(|snapshot| snapshot.name == name).ok_or(not_found(name))
+    }
+
+    /// Whatever the layer at `index` sits on, the base for the first snapshot
+    fn below(&self, index: usize) -> &str {
+        match index {
+            0 => &self.base_path,
+            _ => &self.snapshots[index - 1].path,
+        }
+    }
+
+    fn top(&self) -> &str {
+        self.snapshots.last().map(|snapshot| snapshot.path.as_str()).unwrap_or(&self.base_path)
+    }
+
+    /// Throws the active overlay away and starts an empty one on the newest snapshot
+    fn restart_active(&mut self) -> Result<()> {
+        self.active = Qcow2Backend::create(&self.active_path(), self.capacity, Some(self.top()))?;
+        Ok(())
+    }
+
+    pub fn take_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(Error::new(ErrorKind::InvalidInput, "snapshot names are letters, digits, - and _"));
+        }
+
+        if self.position(name).is_ok() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("snapshot {name} already exists")));
+        }
+
+        self.active.flush()?;
+
+        let path = self.directory.join(format!("snapshot-{name}.qcow2")).to_string_lossy().to_string();
+        fs::rename(self.active_path(), &path)?;
+
+        self.snapshots.push(Snapshot { name: name.to_string(), path });
+        self.restart_active()
+    }
+
+    pub fn list_snapshots(&self) -> Vec<SnapshotInfo> {
+        self.snapshots.iter().map(|snapshot| SnapshotInfo {
+            name: snapshot.name.clone(),
+            allocated_bytes: fs::metadata(&snapshot.path).map(|metadata| metadata.blocks() * 512).unwrap_or(0),
+        }).collect()
+    }
+
+    /// Puts the disk back how it was when `name` was taken. Every later snapshot goes with it.
+    pub fn revert_to_snapshot(&mut self, name: &str) -> Result<()> {
+        let index = self.position(name)?;
+
+        for snapshot in self.snapshots.drain(index + 1..) {
+            fs::remove_file(snapshot.path)?;
+        }
+
+        self.restart_active()
+    }
+
+    /// Drops `name` as a restore point without changing what the disk reads as. Its changes are
+    /// folded up into the layer above, which then sits directly on what `name` sat on.
+    pub fn merge_snapshot(&mut self, name: &str) -> Result<()> {
+        let index = self.position(name)?;
+
+        let upper_path = match self.snapshots.get(index + 1) {
+            Some(snapshot) => snapshot.path.clone(),
+            None => self.active_path(),
+        };
+
+        self.active.flush()?;
+
+        let mut merged = Qcow2Backend::open_read_only(&self.snapshots[index].path)?;
+        let mut upper = Qcow2Backend::open(&upper_path)?;
+        let mut cluster = vec![0u8; merged.cluster_size() as usize];
+
+        for offset in merged.allocated_clusters()? {
+            if upper.is_allocated(offset)? {
+                continue;
+            }
+
+            let length = (self.capacity - offset).min(cluster.len() as u64) as usize;
+            merged.read_at(&mut cluster[..length], offset)?;
+            upper.write_at(&cluster[..length], offset)?;
+        }
+
+        upper.set_backing(self.below(index))?;
+        upper.flush()?;
+
+        drop(upper);
+        drop(merged);
+
+        let snapshot = self.snapshots.remove(index);
+        fs::remove_file(snapshot.path)?;
+
+        // The active overlay still holds the old chain open
+        self.active = Qcow2Backend::open(&self.active_path())?;
+
+        Ok(())
+    }
+}
+
+impl BlockBackend for OverlayBackend {
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8], offset: u64) -> Result<()> {
+        self.active.read_at(buffer, offset)
+    }
+
+    fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        self.active.write_at(data, offset)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.active.flush()
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> Result<()> {
+        self.active.discard(offset, length)
+    }
+
+    fn as_overlay(&mut self) -> Option<&mut OverlayBackend> {
+        Some(self)
+    }
+}
+
+#[test]
+pub fn test_overlay_snapshots() {
+    let directory = std::env::temp_dir().join(format!("virtio-overlay-{}", std::process::id()));
+    let base_path = std::env::temp_dir().join(format!("virtio-overlay-base-{}.img", std::process::id()));
+    fs::write(&base_path, vec![1u8; 1 << 20]).unwrap();
+
+    let mut overlay = OverlayBackend::new(base_path.to_str().unwrap(), directory.to_str().unwrap()).unwrap();
+    let read = |overlay: &mut OverlayBackend, offset: u64| {
+        let mut byte = [0u8];
+        overlay.read_at(&mut byte, offset).unwrap();
+        byte[0]
+    };
+
+    overlay.write_at(&[2], 0).unwrap();
+    overlay.take_snapshot("first").unwrap();
+
+    overlay.write_at(&[3], 0).unwrap();
+    overlay.write_at(&[3], 1 << 19).unwrap();
+    overlay.take_snapshot("second").unwrap();
+
+    overlay.write_at(&[4], 0).unwrap();
+    overlay.take_snapshot("third").unwrap();
+    overlay.write_at(&[5], 0).unwrap();
+
+    // Folding first into second keeps what second saw
+    overlay.merge_snapshot("first").unwrap();
+    assert_eq!(overlay.list_snapshots().iter().map(|info| info.name.as_str()).collect::<Vec<_>>(), ["second", "third"]);
+    assert_eq!(read(&mut overlay, 0), 5);
+
+    overlay.revert_to_snapshot("second").unwrap();
+    assert_eq!(read(&mut overlay, 0), 3);
+    assert_eq!(read(&mut overlay, 1 << 19), 3);
+    assert_eq!(read(&mut overlay, 1), 1);
+    assert_eq!(overlay.list_snapshots().len(), 1);
+    assert!(overlay.revert_to_snapshot("third").is_err());
+
+    // Merging the last snapshot folds it into the active overlay
+    overlay.merge_snapshot("second").unwrap();
+    assert_eq!(read(&mut overlay, 1 << 19), 3);
+    assert_eq!(fs::read(&base_path).unwrap()[0], 1);
+
+    // Starting again on the same directory carries on from the chain that's there
+    overlay.take_snapshot("fourth").unwrap();
+    overlay.write_at(&[6], 0).unwrap();
+    overlay.flush().unwrap();
+    drop(overlay);
+
+    let mut overlay = OverlayBackend::new(base_path.to_str().unwrap(), directory.to_str().unwrap()).unwrap();
+    assert_eq!(overlay.list_snapshots().iter().map(|info| info.name.as_str()).collect::<Vec<_>>(), ["fourth"]);
+    assert_eq!(read(&mut overlay, 0), 6);
+
+    overlay.revert_to_snapshot("fourth").unwrap();
+    assert_eq!(read(&mut overlay, 0), 3);
+
+    fs::remove_dir_all(directory).unwrap();
+    fs::remove_file(base_path).unwrap();
+}
@@ -0,0 +1,756 @@
+// A qcow2 image as a block backend. Guest offsets go through a two level table, the L1 table
+// points at L2 tables and L2 entries point at the host clusters holding the data. Every host
+// cluster is reference counted so snapshots can share them, a cluster with a refcount above one
+// is copied before it's written. Clusters the image doesn't have come from the backing file.
+//
+// Encrypted images, compressed clusters and external data files are refused.
+
+use std::{collections::HashMap, fs::{File, OpenOptions}, io::{Result, Error, ErrorKind}, os::unix::fs::FileExt, path::Path};
+
+use super::backend::{punch_hole, BlockBackend, RawFileBackend, ReadOnlyBackend};
+
+pub const QCOW2_MAGIC: u32 = 0x514649fb;
+
+pub const QCOW2_HEADER_V2_LENGTH: u32 = 72;
+pub const QCOW2_HEADER_V3_LENGTH: u32 = 104;
+
+pub const DEFAULT_CLUSTER_BITS: u32 = 16;
+pub const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+const QCOW_OFLAG_ZERO: u64 = 1;
+
+// The only incompatible feature we can cope with is the image not having been closed cleanly
+const INCOMPAT_DIRTY: u64 = 1;
+
+const MAX_BACKING_CHAIN: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Qcow2Header {
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u32,
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub crypt_method: u32,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub snapshots_offset: u64,
+
+    // Version 3 only, version 2 images get the defaults
+    pub incompatible_features: u64,
+    pub compatible_features: u64,
+    pub autoclear_features: u64,
+    pub refcount_order: u32,
+    pub header_length: u32,
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+impl Qcow2Header {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < QCOW2_HEADER_V2_LENGTH as usize || be_u32(bytes, 0) != QCOW2_MAGIC {
+            return Err(invalid("not a qcow2 image"));
+        }
+
+        let version = be_u32(bytes, 4);
+
+        let mut header = Self {
+            version,
+            backing_file_offset: be_u64(bytes, 8),
+            backing_file_size: be_u32(bytes, 16),
+            cluster_bits: be_u32(bytes, 20),
+            size: be_u64(bytes, 24),
+            crypt_method: be_u32(bytes, 32),
+            l1_size: be_u32(bytes, 36),
+            l1_table_offset: be_u64(bytes, 40),
+            refcount_table_offset: be_u64(bytes, 48),
+            refcount_table_clusters: be_u32(bytes, 56),
+            nb_snapshots: be_u32(bytes, 60),
+            snapshots_offset: be_u64(bytes, 64),
+
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            header_length: QCOW2_HEADER_V2_LENGTH,
+        };
+
+        match version {
+            2 => {},
+            3 if bytes.len() >= QCOW2_HEADER_V3_LENGTH as usize => {
+                header.incompatible_features = be_u64(bytes, 72);
+                header.compatible_features = be_u64(bytes, 80);
+                header.autoclear_features = be_u64(bytes, 88);
+                header.refcount_order = be_u32(bytes, 96);
+                header.header_length = be_u32(bytes, 100);
+            },
+            _ => return Err(Error::new(ErrorKind::Unsupported, format!("qcow2 version {version} is not supported"))),
+        }
+
+        Ok(header)
+    }
+
+    /// Always writes a version 3 header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(QCOW2_HEADER_V3_LENGTH as usize);
+
+        bytes.extend_from_slice(&QCOW2_MAGIC.to_be_bytes());
+        bytes.extend_from_slice(&3u32.to_be_bytes());
+        bytes.extend_from_slice(&self.backing_file_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.backing_file_size.to_be_bytes());
+        bytes.extend_from_slice(&self.cluster_bits.to_be_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&self.crypt_method.to_be_bytes());
+        bytes.extend_from_slice(&self.l1_size.to_be_bytes());
+        bytes.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.refcount_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        bytes.extend_from_slice(&self.nb_snapshots.to_be_bytes());
+        bytes.extend_from_slice(&self.snapshots_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.incompatible_features.to_be_bytes());
+        bytes.extend_from_slice(&self.compatible_features.to_be_bytes());
+        bytes.extend_from_slice(&self.autoclear_features.to_be_bytes());
+        bytes.extend_from_slice(&self.refcount_order.to_be_bytes());
+        bytes.extend_from_slice(&QCOW2_HEADER_V3_LENGTH.to_be_bytes());
+
+        bytes
+    }
+}
+
+/// Checks the magic without trying to make sense of the rest of the image
+pub fn is_qcow2(path: &str) -> Result<bool> {
+    let mut magic = [0u8; 4];
+
+    match File::open(path)?.read_exact_at(&mut magic, 0) {
+        Ok(_) => Ok(u32::from_be_bytes(magic) == QCOW2_MAGIC),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Backing files are never written through, whatever format they turn out to be
+fn open_backing(path: &str, depth: usize) -> Result<Box<dyn BlockBackend>> {
+    if depth >= MAX_BACKING_CHAIN {
+        return Err(invalid("qcow2 backing chain is too long"));
+    }
+
+    if is_qcow2(path)? {
+        Ok(Box::new(ReadOnlyBackend::new(Qcow2Backend::open_file(File::open(path)?, path, depth + 1)?)))
+    } else {
+        Ok(Box::new(ReadOnlyBackend::new(RawFileBackend::open_read_only(path)?)))
+    }
+}
+
+/// Opens any image we understand without ever writing to it
+pub fn open_read_only_image(path: &str) -> Result<Box<dyn BlockBackend>> {
+    open_backing(path, 0)
+}
+
+/// Relative backing file names are relative to the image that names them
+fn resolve_backing(image_path: &str, backing: &str) -> String {
+    match Path::new(image_path).parent() {
+        Some(parent) if !Path::new(backing).is_absolute() => parent.join(backing).to_string_lossy().to_string(),
+        _ => backing.to_string(),
+    }
+}
+
+pub struct Qcow2Backend {
+    file: File,
+    header: Qcow2Header,
+
+    cluster_size: u64,
+    l2_entries: u64,
+    refcount_bytes: u64,
+
+    l1_table: Vec<u64>,
+    refcount_table: Vec<u64>,
+    l2_cache: HashMap<u64, Vec<u64>>,
+
+    // New clusters always go on the end of the file
+    next_free: u64,
+
+    backing: Option<Box<dyn BlockBackend>>,
+    backing_path: Option<String>,
+}
+
+impl Qcow2Backend {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_file(OpenOptions::new().read(true).write(true).open(path)?, path, 0)
+    }
+
+    /// Use with a `ReadOnlyBackend` around it, nothing stops writes reaching the file otherwise
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        Self::open_file(File::open(path)?, path, 0)
+    }
+
+    fn open_file(file: File, path: &str, depth: usize) -> Result<Self> {
+        let mut bytes = vec![0u8; QCOW2_HEADER_V3_LENGTH as usize];
+        let length = file.read_at(&mut bytes, 0)?;
+        let header = Qcow2Header::parse(&bytes[..length])?;
+
+        if header.crypt_method != 0 {
+            return Err(Error::new(ErrorKind::Unsupported, "encrypted qcow2 images are not supported"));
+        }
+
+        if header.incompatible_features & !INCOMPAT_DIRTY != 0 {
+            return Err(Error::new(ErrorKind::Unsupported, format!("qcow2 incompatible features {:x} are not supported", header.incompatible_features)));
+        }
+
+        if !(9..=21).contains(&header.cluster_bits) {
+            return Err(invalid("qcow2 cluster size is out of range"));
+        }
+
+        // Sub-byte refcounts would need bit twiddling nobody has asked for
+        if !(3..=6).contains(&header.refcount_order) {
+            return Err(Error::new(ErrorKind::Unsupported, "qcow2 refcounts narrower than a byte are not supported"));
+        }
+
+        let cluster_size = 1u64 << header.cluster_bits;
+
+        let l1_table = read_table(&file, header.l1_table_offset, header.l1_size as u64)?;
+        let refcount_table = read_table(&file, header.refcount_table_offset, header.refcount_table_clusters as u64 * cluster_size / 8)?;
+
+        let next_free = file.metadata()?.len().div_ceil(cluster_size) * cluster_size;
+
+        let (backing, backing_path) = match header.backing_file_offset {
+            0 => (None, None),
+            offset => {
+                let mut name = vec![0u8; header.backing_file_size as usize];
+                file.read_exact_at(&mut name, offset)?;
+
+                let name = String::from_utf8(name).map_err(|_| invalid("qcow2 backing file name is not utf-8"))?;
+                let resolved = resolve_backing(path, &name);
+
+                (Some(open_backing(&resolved, depth)?), Some(resolved))
+            },
+        };
+
+        Ok(Self {
+            file,
+            cluster_size,
+            l2_entries: cluster_size / 8,
+            refcount_bytes: 1 << (header.refcount_order - 3),
+            header,
+
+            l1_table,
+            refcount_table,
+            l2_cache: HashMap::new(),
+
+            next_free,
+
+            backing,
+            backing_path,
+        })
+    }
+
+    /// Lays out a fresh version 3 image: header, refcount table, one refcount block then the
+    /// L1 table. `backing` is stored as given so relative names stay relative.
+    pub fn create(path: &str, size: u64, backing: Option<&str>) -> Result<Self> {
+        let cluster_size = 1u64 << DEFAULT_CLUSTER_BITS;
+        let l2_coverage = cluster_size * (cluster_size / 8);
+
+        let l1_size = size.div_ceil(l2_coverage);
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+
+        let refcount_table_offset = cluster_size;
+        let refcount_block_offset = cluster_size * 2;
+        let l1_table_offset = cluster_size * 3;
+
+        let metadata_clusters = 3 + l1_clusters;
+
+        // Header, then the extension list terminator, then the backing name
+        let backing_file_offset = QCOW2_HEADER_V3_LENGTH as u64 + 8;
+        let backing = backing.unwrap_or("");
+
+        if backing_file_offset + backing.len() as u64 > cluster_size {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+
+        let header = Qcow2Header {
+            version: 3,
+            backing_file_offset: if backing.is_empty() { 0 } else { backing_file_offset },
+            backing_file_size: backing.len() as u32,
+            cluster_bits: DEFAULT_CLUSTER_BITS,
+            size,
+            crypt_method: 0,
+            l1_size: l1_size as u32,
+            l1_table_offset,
+            refcount_table_offset,
+            refcount_table_clusters: 1,
+            nb_snapshots: 0,
+            snapshots_offset: 0,
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            header_length: QCOW2_HEADER_V3_LENGTH,
+        };
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(metadata_clusters * cluster_size)?;
+
+        let mut first_cluster = header.to_bytes();
+        first_cluster.extend_from_slice(&[0; 8]);
+        first_cluster.extend_from_slice(backing.as_bytes());
+        file.write_all_at(&first_cluster, 0)?;
+
+        file.write_all_at(&refcount_block_offset.to_be_bytes(), refcount_table_offset)?;
+
+        let refcounts: Vec<u8> = (0..metadata_clusters).flat_map(|_| 1u16.to_be_bytes()).collect();
+        file.write_all_at(&refcounts, refcount_block_offset)?;
+
+        file.sync_all()?;
+        drop(file);
+
+        Self::open(path)
+    }
+
+    pub fn backing_path(&self) -> Option<&str> {
+        self.backing_path.as_deref()
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    /// Whether this image, rather than its backing file, decides what the cluster reads as
+    pub fn is_allocated(&mut self, offset: u64) -> Result<bool> {
+        Ok(self.lookup(offset)? != 0)
+    }
+
+    /// Guest offsets of every cluster this image has an entry for
+    pub fn allocated_clusters(&mut self) -> Result<Vec<u64>> {
+        let mut clusters = Vec::new();
+
+        for l1_index in 0..self.l1_table.len() {
+            let l2_offset = self.l1_table[l1_index] & OFFSET_MASK;
+
+            if l2_offset == 0 {
+                continue;
+            }
+
+            let first = l1_index as u64 * self.l2_entries * self.cluster_size;
+            let l2_table = self.load_l2(l2_offset)?.clone();
+
+            clusters.extend(l2_table.iter().enumerate()
+                .filter(|(_, entry)| **entry != 0)
+                .map(|(l2_index, _)| first + l2_index as u64 * self.cluster_size)
+                .filter(|offset| *offset < self.header.size));
+        }
+
+        Ok(clusters)
+    }
+
+    /// Points the image at a different backing file. The name has to fit in the first cluster
+    /// alongside the header, which it always does for images made by `create`.
+    pub fn set_backing(&mut self, backing: &str) -> Result<()> {
+        let offset = match self.header.backing_file_offset {
+            0 => QCOW2_HEADER_V3_LENGTH as u64 + 8,
+            offset => offset,
+        };
+
+        if self.header.version != 3 || offset + backing.len() as u64 > self.cluster_size {
+            return Err(Error::new(ErrorKind::Unsupported, "no room for the backing file name"));
+        }
+
+        let backing_image = open_backing(backing, 0)?;
+
+        self.file.write_all_at(backing.as_bytes(), offset)?;
+        self.header.backing_file_offset = offset;
+        self.header.backing_file_size = backing.len() as u32;
+        self.file.write_all_at(&self.header.to_bytes()[8..20], 8)?;
+        self.file.sync_data()?;
+
+        self.backing = Some(backing_image);
+        self.backing_path = Some(backing.to_string());
+
+        Ok(())
+    }
+
+    fn l1_index(&self, offset: u64) -> usize {
+        (offset / self.cluster_size / self.l2_entries) as usize
+    }
+
+    fn l2_index(&self, offset: u64) -> usize {
+        (offset / self.cluster_size % self.l2_entries) as usize
+    }
+
+    fn load_l2(&mut self, l2_offset: u64) -> Result<&mut Vec<u64>> {
+        if !self.l2_cache.contains_key(&l2_offset) {
+            let table = read_table(&self.file, l2_offset, self.l2_entries)?;
+            self.l2_cache.insert(l2_offset, table);
+        }
+
+        Ok(self.l2_cache.get_mut(&l2_offset).unwrap())
+    }
+
+    /// The L2 entry for a guest offset, zero when the image has nothing for it
+    fn lookup(&mut self, offset: u64) -> Result<u64> {
+        let l1_entry = self.l1_table.get(self.l1_index(offset)).copied().unwrap_or(0);
+        let l2_offset = l1_entry & OFFSET_MASK;
+
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+
+        let l2_index = self.l2_index(offset);
+        Ok(self.load_l2(l2_offset)?[l2_index])
+    }
+
+    fn refcount_location(&self, host_offset: u64) -> (usize, u64) {
+        let cluster = host_offset / self.cluster_size;
+        let per_block = self.cluster_size / self.refcount_bytes;
+
+        ((cluster / per_block) as usize, cluster % per_block * self.refcount_bytes)
+    }
+
+    fn refcount(&self, host_offset: u64) -> Result<u64> {
+        let (block_index, entry_offset) = self.refcount_location(host_offset);
+
+        let block = match self.refcount_table.get(block_index) {
+            Some(block) if *block & OFFSET_MASK != 0 => *block & OFFSET_MASK,
+            _ => return Ok(0),
+        };
+
+        let mut bytes = [0u8; 8];
+        let width = self.refcount_bytes as usize;
+        self.file.read_exact_at(&mut bytes[8 - width..], block + entry_offset)?;
+
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn set_refcount(&mut self, host_offset: u64, value: u64) -> Result<()> {
+        let (block_index, entry_offset) = self.refcount_location(host_offset);
+
+        if block_index >= self.refcount_table.len() {
+            return Err(Error::other("qcow2 refcount table is full"));
+        }
+
+        if self.refcount_table[block_index] & OFFSET_MASK == 0 {
+            let block = self.next_free;
+            self.next_free += self.cluster_size;
+
+            self.file.write_all_at(&vec![0u8; self.cluster_size as usize], block)?;
+            self.refcount_table[block_index] = block;
+            self.file.write_all_at(&block.to_be_bytes(), self.header.refcount_table_offset + block_index as u64 * 8)?;
+
+            self.set_refcount(block, 1)?;
+        }
+
+        let block = self.refcount_table[block_index] & OFFSET_MASK;
+        let width = self.refcount_bytes as usize;
+        self.file.write_all_at(&value.to_be_bytes()[8 - width..], block + entry_offset)
+    }
+
+    fn allocate_cluster(&mut self, contents: &[u8]) -> Result<u64> {
+        let offset = self.next_free;
+        self.next_free += self.cluster_size;
+
+        self.file.write_all_at(contents, offset)?;
+        self.set_refcount(offset, 1)?;
+
+        Ok(offset)
+    }
+
+    /// Drops one reference, a cluster nothing points at any more has its space given back
+    fn release_cluster(&mut self, host_offset: u64) -> Result<()> {
+        let refcount = self.refcount(host_offset)?.saturating_sub(1);
+        self.set_refcount(host_offset, refcount)?;
+
+        if refcount == 0 {
+            let _ = punch_hole(&self.file, host_offset, self.cluster_size);
+        }
+
+        Ok(())
+    }
+
+    /// Gets an L2 table we're allowed to write for the guest offset, allocating one or copying
+    /// one a snapshot still shares
+    fn writable_l2(&mut self, offset: u64) -> Result<u64> {
+        let l1_index = self.l1_index(offset);
+
+        if l1_index >= self.l1_table.len() {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+
+        let l1_entry = self.l1_table[l1_index];
+        let l2_offset = l1_entry & OFFSET_MASK;
+
+        if l2_offset != 0 && l1_entry & QCOW_OFLAG_COPIED > 0 {
+            return Ok(l2_offset);
+        }
+
+        // Nothing shares it after all, the flag was just missing
+        if l2_offset != 0 && self.refcount(l2_offset)? == 1 {
+            self.set_l1_entry(l1_index, l2_offset | QCOW_OFLAG_COPIED)?;
+            return Ok(l2_offset);
+        }
+
+        let table = match l2_offset {
+            0 => vec![0u64; self.l2_entries as usize],
+            _ => self.load_l2(l2_offset)?.clone(),
+        };
+
+        // Every data cluster the old table pointed at gains a reference from the copy
+        for entry in table.iter().filter(|entry| *entry & OFFSET_MASK != 0) {
+            let host = *entry & OFFSET_MASK;
+            let refcount = self.refcount(host)?;
+            self.set_refcount(host, refcount + 1)?;
+        }
+
+        let table: Vec<u64> = table.into_iter().map(|entry| entry & !QCOW_OFLAG_COPIED).collect();
+        let new_offset = self.allocate_cluster(&encode_table(&table))?;
+
+        if l2_offset != 0 {
+            self.release_cluster(l2_offset)?;
+        }
+
+        self.l2_cache.insert(new_offset, table);
+        self.set_l1_entry(l1_index, new_offset | QCOW_OFLAG_COPIED)?;
+
+        Ok(new_offset)
+    }
+
+    fn set_l1_entry(&mut self, l1_index: usize, entry: u64) -> Result<()> {
+        self.l1_table[l1_index] = entry;
+        self.file.write_all_at(&entry.to_be_bytes(), self.header.l1_table_offset + l1_index as u64 * 8)
+    }
+
+    fn set_l2_entry(&mut self, offset: u64, entry: u64) -> Result<()> {
+        let l2_offset = self.writable_l2(offset)?;
+        let l2_index = self.l2_index(offset);
+
+        self.load_l2(l2_offset)?[l2_index] = entry;
+        self.file.write_all_at(&entry.to_be_bytes(), l2_offset + l2_index as u64 * 8)
+    }
+
+    /// What the guest would read for a whole cluster if we didn't have it
+    fn underlying_cluster(&mut self, cluster_offset: u64, entry: u64) -> Result<Vec<u8>> {
+        let mut contents = vec![0u8; self.cluster_size as usize];
+        let host = entry & OFFSET_MASK;
+
+        if entry & QCOW_OFLAG_ZERO > 0 {
+            return Ok(contents);
+        }
+
+        if host != 0 {
+            self.file.read_exact_at(&mut contents, host)?;
+        } else if let Some(backing) = self.backing.as_mut() {
+            read_clamped(backing.as_mut(), &mut contents, cluster_offset)?;
+        }
+
+        Ok(contents)
+    }
+
+    fn read_piece(&mut self, buffer: &mut [u8], offset: u64) -> Result<()> {
+        let entry = self.lookup(offset)?;
+        let host = entry & OFFSET_MASK;
+
+        if entry & QCOW_OFLAG_COMPRESSED > 0 {
+            return Err(Error::new(ErrorKind::Unsupported, "compressed qcow2 clusters are not supported"));
+        }
+
+        if entry & QCOW_OFLAG_ZERO > 0 {
+            buffer.fill(0);
+            Ok(())
+        } else if host != 0 {
+            self.file.read_exact_at(buffer, host + offset % self.cluster_size)
+        } else if let Some(backing) = self.backing.as_mut() {
+            read_clamped(backing.as_mut(), buffer, offset)
+        } else {
+            buffer.fill(0);
+            Ok(())
+        }
+    }
+
+    fn write_piece(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        let entry = self.lookup(offset)?;
+        let host = entry & OFFSET_MASK;
+        let in_cluster = offset % self.cluster_size;
+
+        if entry & QCOW_OFLAG_COMPRESSED > 0 {
+            return Err(Error::new(ErrorKind::Unsupported, "compressed qcow2 clusters are not supported"));
+        }
+
+        // Ours alone and holding real data, so it can be written where it is
+        if host != 0 && entry & QCOW_OFLAG_COPIED > 0 && entry & QCOW_OFLAG_ZERO == 0 && self.l1_is_copied(offset) {
+            return self.file.write_all_at(data, host + in_cluster);
+        }
+
+        let cluster_offset = offset - in_cluster;
+        let mut contents = self.underlying_cluster(cluster_offset, entry)?;
+        contents[in_cluster as usize..in_cluster as usize + data.len()].copy_from_slice(data);
+
+        let new_host = self.allocate_cluster(&contents)?;
+        self.set_l2_entry(offset, new_host | QCOW_OFLAG_COPIED)?;
+
+        if host != 0 {
+            self.release_cluster(host)?;
+        }
+
+        Ok(())
+    }
+
+    fn l1_is_copied(&self, offset: u64) -> bool {
+        self.l1_table[self.l1_index(offset)] & QCOW_OFLAG_COPIED > 0
+    }
+
+    fn discard_cluster(&mut self, offset: u64) -> Result<()> {
+        let entry = self.lookup(offset)?;
+        let host = entry & OFFSET_MASK;
+
+        let replacement = match (&self.backing, self.header.version) {
+            (None, _) => 0,
+            // Without the zero flag the backing file would show through again
+            (Some(_), 3) => QCOW_OFLAG_ZERO,
+            (Some(_), _) => return self.write_piece(&vec![0u8; self.cluster_size as usize], offset),
+        };
+
+        if entry == replacement {
+            return Ok(());
+        }
+
+        self.set_l2_entry(offset, replacement)?;
+
+        if host != 0 {
+            self.release_cluster(host)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: u64) -> Result<Vec<u64>> {
+    let mut bytes = vec![0u8; entries as usize * 8];
+    file.read_exact_at(&mut bytes, offset)?;
+
+    Ok(bytes.chunks_exact(8).map(|entry| u64::from_be_bytes(entry.try_into().unwrap())).collect())
+}
+
+fn encode_table(table: &[u64]) -> Vec<u8> {
+    table.iter().flat_map(|entry| entry.to_be_bytes()).collect()
+}
+
+/// Backing files can be smaller than the image on top of them, past their end reads as zero
+fn read_clamped(backing: &mut dyn BlockBackend, buffer: &mut [u8], offset: u64) -> Result<()> {
+    let available = backing.capacity().saturating_sub(offset).min(buffer.len() as u64) as usize;
+
+    buffer[available..].fill(0);
+
+    match available {
+        0 => Ok(()),
+        _ => backing.read_at(&mut buffer[..available], offset),
+    }
+}
+
+/// Splits a guest range at cluster boundaries, yielding (offset, start in the range, length)
+fn cluster_pieces(cluster_size: u64, offset: u64, length: usize) -> impl Iterator<Item = (u64, usize, usize)> {
+    let mut done = 0;
+
+    std::iter::from_fn(move || {
+        if done >= length {
+            return None;
+        }
+
+        let position = offset + done as u64;
+        let piece = ((cluster_size - position % cluster_size) as usize).min(length - done);
+        let start = done;
+        done += piece;
+
+        Some((position, start, piece))
+    })
+}
+
+impl BlockBackend for Qcow2Backend {
+    fn capacity(&self) -> u64 {
+        self.header.size
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8], offset: u64) -> Result<()> {
+        for (position, start, length) in cluster_pieces(self.cluster_size, offset, buffer.len()) {
+            self.read_piece(&mut buffer[start..start + length], position)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        for (position, start, length) in cluster_pieces(self.cluster_size, offset, data.len()) {
+            self.write_piece(&data[start..start + length], position)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> Result<()> {
+        for (position, _, piece) in cluster_pieces(self.cluster_size, offset, length as usize) {
+            match piece as u64 == self.cluster_size {
+                true => self.discard_cluster(position)?,
+                false => self.write_piece(&vec![0u8; piece], position)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_qcow2_backing_chain() {
+    let directory = std::env::temp_dir();
+    let base_path = directory.join(format!("virtio-qcow2-base-{}.qcow2", std::process::id()));
+    let overlay_path = directory.join(format!("virtio-qcow2-overlay-{}.qcow2", std::process::id()));
+
+    let base_name = base_path.file_name().unwrap().to_str().unwrap();
+    let cluster = 1u64 << DEFAULT_CLUSTER_BITS;
+
+    let mut base = Qcow2Backend::create(base_path.to_str().unwrap(), 4 << 20, None).unwrap();
+    base.write_at(&[0xaa; 1024], cluster - 512).unwrap();
+    base.flush().unwrap();
+    drop(base);
+
+    let mut overlay = Qcow2Backend::create(overlay_path.to_str().unwrap(), 4 << 20, Some(base_name)).unwrap();
+    assert_eq!(overlay.backing_path(), Some(base_path.to_str().unwrap()));
+
+    let mut contents = [0u8; 1024];
+    overlay.read_at(&mut contents, cluster - 512).unwrap();
+    assert_eq!(contents, [0xaa; 1024]);
+
+    // Only half the second cluster is written, the rest has to come up from the base
+    overlay.write_at(&[0x55; 256], cluster).unwrap();
+    overlay.discard(0, cluster).unwrap();
+    drop(overlay);
+
+    let mut overlay = Qcow2Backend::open(overlay_path.to_str().unwrap()).unwrap();
+    overlay.read_at(&mut contents, cluster - 512).unwrap();
+    assert_eq!(&contents[..512], &[0; 512]);
+    assert_eq!(&contents[512..768], &[0x55; 256]);
+    assert_eq!(&contents[768..], &[0xaa; 256]);
+
+    let mut base = Qcow2Backend::open_read_only(base_path.to_str().unwrap()).unwrap();
+    base.read_at(&mut contents, cluster - 512).unwrap();
+    assert_eq!(contents, [0xaa; 1024]);
+    assert_eq!(base.refcount(0).unwrap(), 1);
+
+    std::fs::remove_file(base_path).unwrap();
+    std::fs::remove_file(overlay_path).unwrap();
+}
@@ -0,0 +1,650 @@
+// A virtio-console. Port 0 is the console shown in the TUI pane: the driver keeps its receive
+// queue stocked with empty buffers that get filled with whatever is typed into the pane, and
+// everything it puts on the transmit queue is shown there.
+//
+// With VIRTIO_CONSOLE_F_MULTIPORT there are more ports, each with its own pair of queues and a
+// name, and a control queue pair the device and driver use to add, remove and open ports. The
+// named ports are backed by a Unix socket, a PTY or a file on the host.
+
+pub mod port;
+
+use std::{collections::VecDeque, io::Result, sync::mpsc::Receiver};
+
+use packed_struct::prelude::*;
+
+use crate::{comms::Messages, virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain}};
+
+use self::port::{BackendSpec, HostEvent, PortBackend, PortEvents, PortInput};
+
+pub const VIRTIO_CONSOLE_DEVICE_ID: u32 = 3;
+
+pub const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+pub const RECEIVEQ: u16 = 0;
+pub const TRANSMITQ: u16 = 1;
+pub const CONTROL_RECEIVEQ: u16 = 2;
+pub const CONTROL_TRANSMITQ: u16 = 3;
+
+pub const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+pub const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+pub const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+pub const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+pub const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+pub const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+pub const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+pub const CONTROL_MESSAGE_SIZE: usize = 8;
+
+pub const DEFAULT_COLS: u16 = 80;
+pub const DEFAULT_ROWS: u16 = 24;
+
+pub const DEFAULT_MAX_PORTS: u32 = 4;
+
+#[derive(PackedStruct)]
+#[packed_struct(endian="lsb", bit_numbering="msb0")]
+pub struct ConsoleConfig {
+    #[packed_field(bytes="0x00..=0x01")]
+    cols: Integer<u16, packed_bits::Bits::<16>>,
+
+    #[packed_field(bytes="0x02..=0x03")]
+    rows: Integer<u16, packed_bits::Bits::<16>>,
+
+    #[packed_field(bytes="0x04..=0x07")]
+    max_nr_ports: Integer<u32, packed_bits::Bits::<32>>,
+
+    #[packed_field(bytes="0x08..=0x0b")]
+    emerg_wr: Integer<u32, packed_bits::Bits::<32>>,
+}
+
+/// Port 0 keeps the first queue pair, the control queues come next and then a pair per port
+pub fn receive_queue(port: u32) -> u16 {
+    match port {
+        0 => RECEIVEQ,
+        port => 2 + 2 * port as u16,
+    }
+}
+
+pub fn transmit_queue(port: u32) -> u16 {
+    receive_queue(port) + 1
+}
+
+/// The port a data queue belongs to, None for the control queues
+pub fn queue_port(queue: u16) -> Option<u32> {
+    match queue {
+        RECEIVEQ | TRANSMITQ => Some(0),
+        CONTROL_RECEIVEQ | CONTROL_TRANSMITQ => None,
+        queue => Some(queue as u32 / 2 - 1),
+    }
+}
+
+/// A `virtio_console_control`, PORT_NAME messages carry the name straight after it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControlMessage {
+    pub id: u32,
+    pub event: u16,
+    pub value: u16,
+}
+
+impl ControlMessage {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < CONTROL_MESSAGE_SIZE {
+            return None;
+        }
+
+        Some(Self {
+            id: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            event: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+            value: u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; CONTROL_MESSAGE_SIZE] {
+        let mut bytes = [0; CONTROL_MESSAGE_SIZE];
+        bytes[0..4].copy_from_slice(&self.id.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.event.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.value.to_le_bytes());
+
+        bytes
+    }
+}
+
+struct Port {
+    name: String,
+    // None is the TUI pane
+    backend: Option<Box<dyn PortBackend>>,
+
+    guest_ready: bool,
+    guest_open: bool,
+
+    // Bytes from the host wait here until the driver gives us somewhere to put them
+    pending_input: VecDeque<u8>,
+    receive_buffers: VecDeque<DescriptorChain>,
+}
+
+impl Port {
+    fn new(name: &str, backend: Option<Box<dyn PortBackend>>) -> Self {
+        Self {
+            name: name.to_string(),
+            backend,
+
+            guest_ready: false,
+            guest_open: false,
+
+            pending_input: VecDeque::new(),
+            receive_buffers: VecDeque::new(),
+        }
+    }
+
+    fn host_connected(&self) -> bool {
+        self.backend.as_ref().map(|backend| backend.connected()).unwrap_or(true)
+    }
+
+    fn describe(&self) -> String {
+        self.backend.as_ref().map(|backend| backend.describe()).unwrap_or("the console pane".to_string())
+    }
+
+    unsafe fn fill_receive_buffers(&mut self, ctx: &mut DeviceContext, queue: u16) {
+        while !self.pending_input.is_empty() {
+            let Some(chain) = self.receive_buffers.pop_front() else {
+                return;
+            };
+
+            let count = chain.writable_len().min(self.pending_input.len());
+            let data: Vec<u8> = self.pending_input.drain(..count).collect();
+
+            let written = chain.write_at(0, &data);
+            ctx.complete(queue, chain, written as u32);
+        }
+    }
+}
+
+pub struct VirtioConsole {
+    max_ports: u32,
+    multiport: bool,
+    driver_ready: bool,
+
+    // Indexed by port id, port 0 is always there
+    ports: Vec<Option<Port>>,
+
+    input: Option<Receiver<Vec<u8>>>,
+    host_events: Option<(PortEvents, Receiver<PortInput>)>,
+
+    control_buffers: VecDeque<DescriptorChain>,
+    control_messages: VecDeque<Vec<u8>>,
+
+    cols: u16,
+    rows: u16,
+}
+
+impl VirtioConsole {
+    /// A single port console without the control queues
+    pub fn new() -> Self {
+        Self::with_ports(1)
+    }
+
+    pub fn with_ports(max_ports: u32) -> Self {
+        let mut ports: Vec<Option<Port>> = (0..max_ports.max(1)).map(|_| None).collect();
+        ports[0] = Some(Port::new("", None));
+
+        Self {
+            max_ports: max_ports.max(1),
+            multiport: false,
+            driver_ready: false,
+
+            ports,
+
+            input: None,
+            host_events: None,
+
+            control_buffers: VecDeque::new(),
+            control_messages: VecDeque::new(),
+
+            cols: DEFAULT_COLS,
+            rows: DEFAULT_ROWS,
+        }
+    }
+
+    /// Where keystrokes for the guest come from, usually the TUI's console pane
+    pub fn set_input(&mut self, input: Receiver<Vec<u8>>) {
+        self.input = Some(input);
+    }
+
+    /// How port backends reach the device thread, needed before any port can be added
+    pub fn set_host_events(&mut self, events: PortEvents, receiver: Receiver<PortInput>) {
+        self.host_events = Some((events, receiver));
+    }
+
+    fn find_port(&self, name: &str) -> Option<u32> {
+        self.ports.iter().position(|port| port.as_ref().map(|port| port.name == name).unwrap_or(false)).map(|id| id as u32)
+    }
+
+    fn port_mut(&mut self, id: u32) -> Option<&mut Port> {
+        self.ports.get_mut(id as usize).and_then(|port| port.as_mut())
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16, payload: &[u8]) {
+        let mut message = ControlMessage { id, event, value }.to_bytes().to_vec();
+        message.extend_from_slice(payload);
+
+        self.control_messages.push_back(message);
+    }
+
+    /// Opens a named port, the driver hears about it straight away if it's already running
+    pub fn add_port(&mut self, name: &str, spec: &str) -> std::result::Result<u32, String> {
+        if name.is_empty() || self.find_port(name).is_some() {
+            return Err(format!("There's already a port called {name}"));
+        }
+
+        let spec = BackendSpec::parse(spec)?;
+        let events = self.host_events.as_ref().ok_or("The console can't take named ports")?.0.clone();
+
+        let id = self.ports.iter().position(|port| port.is_none()).ok_or(format!("All {} ports are in use", self.max_ports))? as u32;
+        let backend = spec.open(id, events).map_err(|err| format!("Couldn't open port {name}: {err}"))?;
+
+        self.ports[id as usize] = Some(Port::new(name, Some(backend)));
+
+        if self.driver_ready {
+            self.send_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]);
+        }
+
+        Ok(id)
+    }
+
+    /// Buffers the driver gave the port go back empty before it hears the port is gone
+    pub fn remove_port(&mut self, ctx: &mut DeviceContext, name: &str) -> std::result::Result<u32, String> {
+        let id = self.find_port(name).filter(|id| *id != 0).ok_or(format!("No port called {name}"))?;
+        let port = self.ports[id as usize].take().unwrap();
+
+        for chain in port.receive_buffers {
+            ctx.complete(receive_queue(id), chain, 0);
+        }
+
+        if self.driver_ready {
+            self.send_control(id, VIRTIO_CONSOLE_DEVICE_REMOVE, 1, &[]);
+        }
+
+        Ok(id)
+    }
+
+    fn handle_control(&mut self, ctx: &mut DeviceContext, message: ControlMessage) {
+        match message.event {
+            VIRTIO_CONSOLE_DEVICE_READY if message.value == 1 => {
+                self.driver_ready = true;
+
+                for id in 0..self.max_ports {
+                    if self.ports[id as usize].is_some() {
+                        self.send_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]);
+                    }
+                }
+            },
+            VIRTIO_CONSOLE_DEVICE_READY => ctx.send_message("The console driver failed to start".to_string()),
+            VIRTIO_CONSOLE_PORT_READY => {
+                let Some(port) = self.port_mut(message.id) else {
+                    return;
+                };
+
+                if message.value != 1 {
+                    let name = port.name.clone();
+                    ctx.send_message(format!("The driver couldn't set up console port {name}"));
+                    return;
+                }
+
+                port.guest_ready = true;
+                let (name, connected) = (port.name.clone(), port.host_connected());
+
+                if message.id == 0 {
+                    self.send_control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                } else {
+                    self.send_control(message.id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                }
+
+                if connected {
+                    self.send_control(message.id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                }
+            },
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                let Some(port) = self.port_mut(message.id) else {
+                    return;
+                };
+
+                port.guest_open = message.value == 1;
+                let state = if port.guest_open { "opened" } else { "closed" };
+
+                ctx.send_message(format!("The guest {state} console port {}", port.name));
+            },
+            event => ctx.send_message(format!("Unexpected console control event {event}")),
+        }
+    }
+
+    fn handle_host_event(&mut self, ctx: &mut DeviceContext, input: PortInput) {
+        let driver_ready = self.driver_ready;
+
+        let Some(port) = self.port_mut(input.port) else {
+            return;
+        };
+
+        let open = match input.event {
+            HostEvent::Data(bytes) => {
+                port.pending_input.extend(bytes);
+                return;
+            },
+            HostEvent::Connected => 1,
+            HostEvent::Disconnected => 0,
+        };
+
+        let (name, notify) = (port.name.clone(), driver_ready && port.guest_ready);
+        ctx.send_message(format!("Host side of console port {name} {}", if open == 1 { "connected" } else { "disconnected" }));
+
+        if notify {
+            self.send_control(input.port, VIRTIO_CONSOLE_PORT_OPEN, open, &[]);
+        }
+    }
+
+    unsafe fn flush_control(&mut self, ctx: &mut DeviceContext) {
+        while !self.control_messages.is_empty() {
+            let Some(chain) = self.control_buffers.pop_front() else {
+                return;
+            };
+
+            let message = self.control_messages.pop_front().unwrap();
+            let written = chain.write_at(0, &message);
+
+            ctx.complete(CONTROL_RECEIVEQ, chain, written as u32);
+        }
+    }
+
+    fn list_ports(&self) -> String {
+        let ports: Vec<String> = self.ports.iter().enumerate().filter_map(|(id, port)| {
+            let port = port.as_ref()?;
+            let name = if id == 0 { "console" } else { &port.name };
+            let open = if port.guest_open { ", open in the guest" } else { "" };
+
+            Some(format!("{id}: {name} on {}{open}", port.describe()))
+        }).collect();
+
+        ports.join("; ")
+    }
+}
+
+impl Default for VirtioConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_CONSOLE_DEVICE_ID
+    }
+
+    fn device_features(&self) -> u64 {
+        match self.max_ports {
+            1 => VIRTIO_CONSOLE_F_SIZE,
+            _ => VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_MULTIPORT,
+        }
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        let config = ConsoleConfig {
+            cols: self.cols.into(),
+            rows: self.rows.into(),
+            max_nr_ports: self.max_ports.into(),
+            emerg_wr: 0.into(),
+        };
+
+        config.pack().unwrap().to_vec()
+    }
+
+    fn queue_count(&self) -> usize {
+        match self.max_ports {
+            1 => 2,
+            ports => 2 * ports as usize + 2,
+        }
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, queue: u16, chain: DescriptorChain) -> Option<u32> {
+        match queue {
+            CONTROL_RECEIVEQ if self.multiport => {
+                self.control_buffers.push_back(chain);
+                None
+            },
+            CONTROL_TRANSMITQ if self.multiport => {
+                if let Some(message) = ControlMessage::parse(&unsafe { chain.read_all() }) {
+                    self.handle_control(ctx, message);
+                }
+
+                Some(0)
+            },
+            queue => {
+                let Some(port) = queue_port(queue).and_then(|id| self.port_mut(id)) else {
+                    // A port that was removed, or the control queues without MULTIPORT
+                    return Some(0);
+                };
+
+                if queue == receive_queue(queue_port(queue).unwrap()) {
+                    port.receive_buffers.push_back(chain);
+                    return None;
+                }
+
+                let output = unsafe { chain.read_all() };
+
+                match port.backend.as_mut() {
+                    None => ctx.send(Messages::ConsoleOutput(String::from_utf8_lossy(&output).into_owned())),
+                    Some(backend) => {
+                        if let Err(err) = backend.write(&output) {
+                            ctx.send_message(format!("Writing to console port {} failed: {err}", port.name));
+                        }
+                    },
+                }
+
+                Some(0)
+            },
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut DeviceContext) {
+        if let Some(input) = self.input.as_ref() {
+            let console = self.ports[0].as_mut().unwrap();
+
+            while let Ok(bytes) = input.try_recv() {
+                console.pending_input.extend(bytes);
+            }
+        }
+
+        let host_input: Vec<PortInput> = match self.host_events.as_ref() {
+            Some((_, receiver)) => receiver.try_iter().collect(),
+            None => Vec::new(),
+        };
+
+        for input in host_input {
+            self.handle_host_event(ctx, input);
+        }
+
+        unsafe {
+            for (id, port) in self.ports.iter_mut().enumerate() {
+                if let Some(port) = port.as_mut() {
+                    port.fill_receive_buffers(ctx, receive_queue(id as u32));
+                }
+            }
+
+            self.flush_control(ctx);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.driver_ready = false;
+        self.multiport = false;
+
+        self.control_buffers.clear();
+        self.control_messages.clear();
+
+        for port in self.ports.iter_mut().flatten() {
+            port.guest_ready = false;
+            port.guest_open = false;
+            port.receive_buffers.clear();
+        }
+    }
+
+    fn command(&mut self, ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["resize", cols, rows] => {
+                self.cols = cols.parse().map_err(|_| format!("{cols} isn't a column count"))?;
+                self.rows = rows.parse().map_err(|_| format!("{rows} isn't a row count"))?;
+                ctx.config_changed();
+
+                Ok(format!("Console resized to {}x{}", self.cols, self.rows))
+            },
+            ["port", "add", name, spec] => {
+                let id = self.add_port(name, spec)?;
+                let port = self.ports[id as usize].as_ref().unwrap();
+
+                Ok(format!("Added console port {id} ({name}) on {}", port.describe()))
+            },
+            ["port", "remove", name] => {
+                let id = self.remove_port(ctx, name)?;
+                Ok(format!("Removed console port {id} ({name})"))
+            },
+            ["port", "list"] => Ok(self.list_ports()),
+            _ => Err(format!("The console doesn't understand {command}, try resize, port add <name> <socket:path|pty|file:path>, port remove <name> or port list")),
+        }
+    }
+
+    // Input the driver hasn't taken yet is the only state worth keeping
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(self.ports[0].as_ref().unwrap().pending_input.iter().copied().collect())
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        let console = self.ports[0].as_mut().unwrap();
+
+        console.pending_input = snapshot.iter().copied().collect();
+        console.receive_buffers.clear();
+
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_console_input_waits_for_buffers() {
+    use std::sync::mpsc::channel;
+
+    use tokio::sync::mpsc::channel as message_channel;
+
+    use crate::virtio::{virtqueue::DescriptorCell, vring::VIRTQ_DESC_F_WRITE, transport::{MmioTransport, TransportMode}};
+
+    let (tx, mut rx) = message_channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, VIRTIO_CONSOLE_DEVICE_ID, 0, &[4, 4]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport);
+
+    let (input, receiver) = channel();
+    let mut console = VirtioConsole::new();
+    console.set_input(receiver);
+
+    let mut receive = [0u8; 4];
+    let mut transmit = *b"$ ";
+
+    let mut table = [
+        DescriptorCell { addr: receive.as_mut_ptr() as u64, length: 4, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        DescriptorCell { addr: transmit.as_mut_ptr() as u64, length: 2, flags: 0, next: 0 },
+        DescriptorCell::default(),
+        DescriptorCell::default(),
+    ];
+
+    input.send(b"ls -l".to_vec()).unwrap();
+    console.poll(&mut ctx);
+    assert!(ctx.completions.is_empty());
+
+    unsafe {
+        assert_eq!(console.process_request(&mut ctx, RECEIVEQ, DescriptorChain::new(table.as_mut_ptr(), 4, 0)), None);
+        assert_eq!(console.process_request(&mut ctx, TRANSMITQ, DescriptorChain::new(table.as_mut_ptr(), 4, 1)), Some(0));
+    }
+
+    console.poll(&mut ctx);
+    assert_eq!(ctx.completions.len(), 1);
+    assert_eq!(ctx.completions[0].length, 4);
+    assert_eq!(&receive, b"ls -");
+    assert_eq!(console.snapshot().unwrap(), b"l");
+
+    match rx.try_recv() {
+        Ok(Messages::ConsoleOutput(output)) => assert_eq!(output, "$ "),
+        _ => panic!("the transmitted bytes never reached the pane"),
+    }
+}
+
+#[test]
+pub fn test_multiport_handshake() {
+    use tokio::sync::mpsc::channel as message_channel;
+
+    use crate::{poller::PollableQueue, device_thread::DeviceControl, virtio::{virtqueue::DescriptorCell, vring::VIRTQ_DESC_F_WRITE, transport::{MmioTransport, TransportMode}}};
+
+    struct NoWaker;
+
+    impl PollableQueue for NoWaker {
+        fn wait_for_event(&self) {}
+        fn submit_event(&self) {}
+    }
+
+    let path = std::env::temp_dir().join(format!("virtio-console-port-{}.log", std::process::id()));
+
+    let (tx, _rx) = message_channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, VIRTIO_CONSOLE_DEVICE_ID, 0, &[8; 6]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport);
+
+    let mut console = VirtioConsole::with_ports(2);
+    let (events, receiver) = DeviceControl::new(NoWaker);
+    console.set_host_events(events, receiver);
+
+    assert_eq!(console.add_port("agent", &format!("file:{}", path.display())), Ok(1));
+    assert_eq!(console.queue_count(), 6);
+    console.activate(VIRTIO_CONSOLE_F_MULTIPORT);
+
+    let mut control = [[0u8; 32]; 4];
+    let mut request = [0u8; CONTROL_MESSAGE_SIZE];
+    let mut output = *b"hello";
+
+    let mut table: [DescriptorCell; 8] = Default::default();
+
+    for (idx, buffer) in control.iter_mut().enumerate() {
+        table[idx] = DescriptorCell { addr: buffer.as_mut_ptr() as u64, length: 32, flags: VIRTQ_DESC_F_WRITE, next: 0 };
+    }
+
+    table[4] = DescriptorCell { addr: request.as_mut_ptr() as u64, length: CONTROL_MESSAGE_SIZE as u32, flags: 0, next: 0 };
+    table[5] = DescriptorCell { addr: output.as_mut_ptr() as u64, length: 5, flags: 0, next: 0 };
+
+    let table = table.as_mut_ptr();
+    let chain = |head| unsafe { DescriptorChain::new(table, 8, head) };
+
+    for head in 0..4 {
+        assert_eq!(console.process_request(&mut ctx, CONTROL_RECEIVEQ, chain(head)), None);
+    }
+
+    let mut send = |console: &mut VirtioConsole, ctx: &mut DeviceContext, message: ControlMessage| {
+        request = message.to_bytes();
+        assert_eq!(console.process_request(ctx, CONTROL_TRANSMITQ, chain(4)), Some(0));
+        console.poll(ctx);
+    };
+
+    send(&mut console, &mut ctx, ControlMessage { id: 0, event: VIRTIO_CONSOLE_DEVICE_READY, value: 1 });
+    send(&mut console, &mut ctx, ControlMessage { id: 1, event: VIRTIO_CONSOLE_PORT_READY, value: 1 });
+
+    let received: Vec<ControlMessage> = ctx.completions.iter().map(|completion| ControlMessage::parse(&control[completion.head as usize]).unwrap()).collect();
+
+    assert_eq!(received, [
+        ControlMessage { id: 0, event: VIRTIO_CONSOLE_DEVICE_ADD, value: 1 },
+        ControlMessage { id: 1, event: VIRTIO_CONSOLE_DEVICE_ADD, value: 1 },
+        ControlMessage { id: 1, event: VIRTIO_CONSOLE_PORT_NAME, value: 1 },
+        ControlMessage { id: 1, event: VIRTIO_CONSOLE_PORT_OPEN, value: 1 },
+    ]);
+    assert_eq!(&control[2][CONTROL_MESSAGE_SIZE..CONTROL_MESSAGE_SIZE + 5], b"agent");
+    assert_eq!(ctx.completions[2].length, CONTROL_MESSAGE_SIZE as u32 + 5);
+
+    assert_eq!(console.process_request(&mut ctx, transmit_queue(1), chain(5)), Some(0));
+    assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+
+    std::fs::remove_file(path).unwrap();
+}
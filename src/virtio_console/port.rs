@@ -0,0 +1,314 @@
+// Where a named port's bytes end up on the host. Output from the guest is written straight to
+// the backend from the device thread, input is read by a thread per backend and handed to the
+// device as `PortInput`, which wakes the device thread like a queue notification would.
+
+use std::{fs::{self, File, OpenOptions}, io::{Read, Write, Result, Error}, os::unix::{io::{AsRawFd, FromRawFd}, net::{UnixListener, UnixStream}}, net::Shutdown, ffi::CStr, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread, time::Duration};
+
+use libc::{c_char, grantpt, poll, pollfd, posix_openpt, ptsname_r, unlockpt, cfmakeraw, tcgetattr, tcsetattr, termios, O_NOCTTY, O_RDWR, POLLIN, TCSANOW};
+
+use crate::device_thread::DeviceControl;
+
+const READ_SIZE: usize = 4096;
+// How often an idle PTY reader looks to see if its port has gone
+const PTY_POLL_MS: i32 = 100;
+
+pub enum HostEvent {
+    Connected,
+    Data(Vec<u8>),
+    Disconnected,
+}
+
+pub struct PortInput {
+    pub port: u32,
+    pub event: HostEvent,
+}
+
+pub type PortEvents = DeviceControl<PortInput>;
+
+pub trait PortBackend: Send {
+    /// Something the guest wrote to the port
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Whether anyone is on the host end to read it
+    fn connected(&self) -> bool;
+
+    /// Where the port can be found on the host, shown to the user
+    fn describe(&self) -> String;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendSpec {
+    Socket(String),
+    Pty,
+    File(String),
+}
+
+impl BackendSpec {
+    /// `socket:<path>`, `pty` or `file:<path>`
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        match spec.split_once(':').unwrap_or((spec, "")) {
+            ("socket", path) if !path.is_empty() => Ok(Self::Socket(path.to_string())),
+            ("file", path) if !path.is_empty() => Ok(Self::File(path.to_string())),
+            ("pty", "") => Ok(Self::Pty),
+            _ => Err(format!("{spec} isn't socket:<path>, pty or file:<path>")),
+        }
+    }
+
+    pub fn open(&self, port: u32, events: PortEvents) -> Result<Box<dyn PortBackend>> {
+        Ok(match self {
+            Self::Socket(path) => Box::new(SocketBackend::listen(path, port, events)?),
+            Self::Pty => Box::new(PtyBackend::open(port, events)?),
+            Self::File(path) => Box::new(FileBackend::open(path)?),
+        })
+    }
+}
+
+/// Listens on a Unix socket and serves one connection at a time
+pub struct SocketBackend {
+    path: String,
+    stream: Arc<Mutex<Option<UnixStream>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl SocketBackend {
+    pub fn listen(path: &str, port: u32, events: PortEvents) -> Result<Self> {
+        // A socket left behind by an earlier run would make the bind fail
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+
+        let stream = Arc::new(Mutex::new(None));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let (thread_stream, thread_closed) = (stream.clone(), closed.clone());
+
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                if thread_closed.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                let Ok(mut connection) = connection else {
+                    continue;
+                };
+
+                let Ok(writer) = connection.try_clone() else {
+                    continue;
+                };
+
+                *thread_stream.lock().unwrap() = Some(writer);
+                events.send(PortInput { port, event: HostEvent::Connected });
+
+                let mut buffer = [0u8; READ_SIZE];
+
+                loop {
+                    match connection.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(count) => events.send(PortInput { port, event: HostEvent::Data(buffer[..count].to_vec()) }),
+                    }
+                }
+
+                *thread_stream.lock().unwrap() = None;
+
+                if thread_closed.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                events.send(PortInput { port, event: HostEvent::Disconnected });
+            }
+        });
+
+        Ok(Self { path: path.to_string(), stream, closed })
+    }
+}
+
+impl PortBackend for SocketBackend {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        match self.stream.lock().unwrap().as_mut() {
+            Some(stream) => stream.write_all(data),
+            // Nobody listening, the bytes go nowhere like on a real serial line
+            None => Ok(()),
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.stream.lock().unwrap().is_some()
+    }
+
+    fn describe(&self) -> String {
+        format!("socket {}", self.path)
+    }
+}
+
+impl Drop for SocketBackend {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        // Knocks the listener thread out of accept so it sees the port is gone
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A pseudo terminal, anything can open the other end by its path. There's no telling whether
+/// anyone has, so it always counts as connected.
+pub struct PtyBackend {
+    master: File,
+    path: String,
+    closed: Arc<AtomicBool>,
+}
+
+impl PtyBackend {
+    pub fn open(port: u32, events: PortEvents) -> Result<Self> {
+        let (master, path) = unsafe {
+            let fd = posix_openpt(O_RDWR | O_NOCTTY);
+
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            let master = File::from_raw_fd(fd);
+
+            if grantpt(fd) != 0 || unlockpt(fd) != 0 {
+                return Err(Error::last_os_error());
+            }
+
+            let mut name = [0 as c_char; 128];
+
+            if ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(Error::last_os_error());
+            }
+
+            // No echo or line editing, the guest sees exactly what was typed
+            let mut attributes: termios = std::mem::zeroed();
+            tcgetattr(fd, &mut attributes);
+            cfmakeraw(&mut attributes);
+            tcsetattr(fd, TCSANOW, &attributes);
+
+            (master, CStr::from_ptr(name.as_ptr()).to_string_lossy().to_string())
+        };
+
+        let mut reader = master.try_clone()?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread_closed = closed.clone();
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; READ_SIZE];
+
+            while !thread_closed.load(Ordering::SeqCst) {
+                let mut ready = pollfd { fd: reader.as_raw_fd(), events: POLLIN, revents: 0 };
+
+                if unsafe { poll(&mut ready, 1, PTY_POLL_MS) } <= 0 {
+                    continue;
+                }
+
+                // With nothing open on the other end the master reads as hung up, wait for someone
+                if ready.revents & POLLIN == 0 {
+                    thread::sleep(Duration::from_millis(PTY_POLL_MS as u64));
+                    continue;
+                }
+
+                match reader.read(&mut buffer) {
+                    Ok(0) => return,
+                    Ok(count) => events.send(PortInput { port, event: HostEvent::Data(buffer[..count].to_vec()) }),
+                    Err(_) => thread::sleep(Duration::from_millis(PTY_POLL_MS as u64)),
+                }
+            }
+        });
+
+        Ok(Self { master, path, closed })
+    }
+}
+
+impl PortBackend for PtyBackend {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        match self.master.write_all(data) {
+            // Nothing has the slave open yet
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn connected(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        format!("pty {}", self.path)
+    }
+}
+
+impl Drop for PtyBackend {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Appends whatever the guest writes to a file, there's never any input
+pub struct FileBackend {
+    file: File,
+    path: String,
+}
+
+impl FileBackend {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self { file, path: path.to_string() })
+    }
+}
+
+impl PortBackend for FileBackend {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data)
+    }
+
+    fn connected(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        format!("file {}", self.path)
+    }
+}
+
+#[test]
+pub fn test_socket_port_round_trip() {
+    use crate::poller::PollableQueue;
+
+    struct NoWaker;
+
+    impl PollableQueue for NoWaker {
+        fn wait_for_event(&self) {}
+        fn submit_event(&self) {}
+    }
+
+    let path = std::env::temp_dir().join(format!("virtio-console-port-{}.sock", std::process::id()));
+    let (events, receiver) = DeviceControl::new(NoWaker);
+
+    let mut backend = BackendSpec::parse(&format!("socket:{}", path.display())).unwrap().open(2, events).unwrap();
+    assert!(!backend.connected());
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    let next = || receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    assert!(matches!(next(), PortInput { port: 2, event: HostEvent::Connected }));
+    assert!(backend.connected());
+
+    client.write_all(b"ping").unwrap();
+    assert!(matches!(next(), PortInput { event: HostEvent::Data(data), .. } if data == b"ping"));
+
+    backend.write(b"pong").unwrap();
+    let mut reply = [0u8; 4];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"pong");
+
+    drop(client);
+    assert!(matches!(next(), PortInput { event: HostEvent::Disconnected, .. }));
+
+    drop(backend);
+    assert!(!path.exists());
+}
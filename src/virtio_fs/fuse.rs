@@ -0,0 +1,246 @@
+// The FUSE wire format as virtio-fs carries it. Every request starts with a `fuse_in_header` and
+// the opcode's own struct, names follow as NUL terminated strings. Every reply starts with a
+// `fuse_out_header`, an error reply is nothing but the header with a negative errno in it.
+// Everything is little endian.
+
+use std::{fs::Metadata, os::unix::fs::MetadataExt};
+
+pub const FUSE_KERNEL_VERSION: u32 = 7;
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+pub const IN_HEADER_SIZE: usize = 40;
+pub const OUT_HEADER_SIZE: usize = 16;
+
+pub const FUSE_ROOT_ID: u64 = 1;
+
+pub const FUSE_LOOKUP: u32 = 1;
+pub const FUSE_FORGET: u32 = 2;
+pub const FUSE_GETATTR: u32 = 3;
+pub const FUSE_SETATTR: u32 = 4;
+pub const FUSE_READLINK: u32 = 5;
+pub const FUSE_SYMLINK: u32 = 6;
+pub const FUSE_MKDIR: u32 = 9;
+pub const FUSE_UNLINK: u32 = 10;
+pub const FUSE_RMDIR: u32 = 11;
+pub const FUSE_RENAME: u32 = 12;
+pub const FUSE_OPEN: u32 = 14;
+pub const FUSE_READ: u32 = 15;
+pub const FUSE_WRITE: u32 = 16;
+pub const FUSE_STATFS: u32 = 17;
+pub const FUSE_RELEASE: u32 = 18;
+pub const FUSE_FSYNC: u32 = 20;
+pub const FUSE_FLUSH: u32 = 25;
+pub const FUSE_INIT: u32 = 26;
+pub const FUSE_OPENDIR: u32 = 27;
+pub const FUSE_READDIR: u32 = 28;
+pub const FUSE_RELEASEDIR: u32 = 29;
+pub const FUSE_FSYNCDIR: u32 = 30;
+pub const FUSE_ACCESS: u32 = 34;
+pub const FUSE_CREATE: u32 = 35;
+pub const FUSE_INTERRUPT: u32 = 36;
+pub const FUSE_DESTROY: u32 = 38;
+pub const FUSE_BATCH_FORGET: u32 = 42;
+pub const FUSE_RENAME2: u32 = 45;
+pub const FUSE_SETUPMAPPING: u32 = 48;
+pub const FUSE_REMOVEMAPPING: u32 = 49;
+
+pub const FUSE_ASYNC_READ: u32 = 1 << 0;
+pub const FUSE_ATOMIC_O_TRUNC: u32 = 1 << 3;
+pub const FUSE_BIG_WRITES: u32 = 1 << 5;
+pub const FUSE_MAP_ALIGNMENT: u32 = 1 << 26;
+
+pub const FUSE_SETUPMAPPING_FLAG_WRITE: u64 = 1 << 0;
+
+pub const FATTR_MODE: u32 = 1 << 0;
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_ATIME: u32 = 1 << 4;
+pub const FATTR_MTIME: u32 = 1 << 5;
+pub const FATTR_FH: u32 = 1 << 6;
+pub const FATTR_ATIME_NOW: u32 = 1 << 7;
+pub const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+pub const RENAME_NOREPLACE: u32 = 1 << 0;
+
+pub const ATTR_SIZE: usize = 88;
+pub const ENTRY_OUT_SIZE: usize = 40 + ATTR_SIZE;
+pub const ATTR_OUT_SIZE: usize = 16 + ATTR_SIZE;
+pub const OPEN_OUT_SIZE: usize = 16;
+pub const INIT_OUT_SIZE: usize = 64;
+pub const READ_IN_SIZE: usize = 40;
+pub const WRITE_IN_SIZE: usize = 40;
+
+// How long the guest may cache entries and attributes, in seconds
+const VALID_SECONDS: u64 = 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InHeader {
+    pub len: u32,
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl InHeader {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+
+        Some(Self {
+            len: reader.u32()?,
+            opcode: reader.u32()?,
+            unique: reader.u64()?,
+            nodeid: reader.u64()?,
+            uid: reader.u32()?,
+            gid: reader.u32()?,
+            pid: reader.u32()?,
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; IN_HEADER_SIZE] {
+        let mut bytes = [0; IN_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.len.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.opcode.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.unique.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.nodeid.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.uid.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.gid.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.pid.to_le_bytes());
+
+        bytes
+    }
+}
+
+/// A whole reply, `error` is a negative errno or zero
+pub fn reply(unique: u64, error: i32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(OUT_HEADER_SIZE + body.len());
+    bytes.extend_from_slice(&((OUT_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+    bytes.extend_from_slice(&error.to_le_bytes());
+    bytes.extend_from_slice(&unique.to_le_bytes());
+    bytes.extend_from_slice(body);
+
+    bytes
+}
+
+/// Splits a reply into its error and body
+pub fn parse_reply(bytes: &[u8]) -> Option<(u64, i32, &[u8])> {
+    let mut reader = Reader::new(bytes);
+    let len = reader.u32()? as usize;
+    let error = reader.u32()? as i32;
+    let unique = reader.u64()?;
+
+    Some((unique, error, bytes.get(OUT_HEADER_SIZE..len.max(OUT_HEADER_SIZE))?))
+}
+
+/// Reads the fields of a request body in order
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.offset..self.offset + count)?;
+        self.offset += count;
+
+        Some(bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A NUL terminated name
+    pub fn name(&mut self) -> Option<&'a str> {
+        let rest = self.bytes.get(self.offset..)?;
+        let end = rest.iter().position(|byte| *byte == 0)?;
+        self.offset += end + 1;
+
+        std::str::from_utf8(&rest[..end]).ok()
+    }
+}
+
+/// A `fuse_attr` straight from the host file's metadata
+pub fn attr(metadata: &Metadata) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ATTR_SIZE);
+
+    for value in [metadata.ino(), metadata.size(), metadata.blocks(), metadata.atime() as u64, metadata.mtime() as u64, metadata.ctime() as u64] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    for value in [metadata.atime_nsec() as u32, metadata.mtime_nsec() as u32, metadata.ctime_nsec() as u32, metadata.mode(), metadata.nlink() as u32, metadata.uid(), metadata.gid(), metadata.rdev() as u32, metadata.blksize() as u32, 0] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    bytes
+}
+
+pub fn entry_out(nodeid: u64, metadata: &Metadata) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ENTRY_OUT_SIZE);
+    bytes.extend_from_slice(&nodeid.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&VALID_SECONDS.to_le_bytes());
+    bytes.extend_from_slice(&VALID_SECONDS.to_le_bytes());
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&attr(metadata));
+
+    bytes
+}
+
+pub fn attr_out(metadata: &Metadata) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ATTR_OUT_SIZE);
+    bytes.extend_from_slice(&VALID_SECONDS.to_le_bytes());
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&attr(metadata));
+
+    bytes
+}
+
+pub fn open_out(fh: u64) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(OPEN_OUT_SIZE);
+    bytes.extend_from_slice(&fh.to_le_bytes());
+    bytes.extend_from_slice(&[0; 8]);
+
+    bytes
+}
+
+/// One `fuse_dirent`, padded to 8 bytes. `offset` is where the next READDIR carries on from
+pub fn dirent(ino: u64, offset: u64, kind: u32, name: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(24 + name.len() + 8);
+    bytes.extend_from_slice(&ino.to_le_bytes());
+    bytes.extend_from_slice(&offset.to_le_bytes());
+    bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&kind.to_le_bytes());
+    bytes.extend_from_slice(name);
+    bytes.resize(bytes.len().next_multiple_of(8), 0);
+
+    bytes
+}
+
+/// Walks the dirents in a READDIR reply, as (offset, kind, name)
+pub fn parse_dirents(mut bytes: &[u8]) -> Vec<(u64, u32, String)> {
+    let mut entries = Vec::new();
+
+    while bytes.len() >= 24 {
+        let mut reader = Reader::new(bytes);
+        let (_, offset, length, kind) = (reader.u64().unwrap(), reader.u64().unwrap(), reader.u32().unwrap() as usize, reader.u32().unwrap());
+
+        let Some(name) = reader.bytes(length) else {
+            break;
+        };
+
+        entries.push((offset, kind, String::from_utf8_lossy(name).into_owned()));
+        bytes = &bytes[(24 + length).next_multiple_of(8).min(bytes.len())..];
+    }
+
+    entries
+}
@@ -0,0 +1,232 @@
+// A virtio-fs device, a host directory shared with the guest over FUSE. The driver puts each
+// request on a request queue as a readable FUSE request followed by writable room for the reply,
+// FORGETs go on the hiprio queue and never get one. The config space names the share with a
+// tag, which is what the guest mounts it by.
+//
+// With a DAX window the device exposes shared memory region 0 and the guest can ask for file
+// ranges to be mapped straight into it with SETUPMAPPING instead of reading them through the
+// queues. See `passthrough` for how everything else is served from the directory.
+
+pub mod fuse;
+pub mod passthrough;
+
+use std::{io::{Error, Result}, path::Path};
+
+use crate::virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain, shared_memory::PAGE_SIZE};
+
+use self::{fuse::{reply, InHeader, Reader, IN_HEADER_SIZE, FUSE_SETUPMAPPING, FUSE_REMOVEMAPPING, FUSE_SETUPMAPPING_FLAG_WRITE}, passthrough::PassthroughFs};
+
+pub const VIRTIO_FS_DEVICE_ID: u32 = 26;
+
+pub const HIPRIO_QUEUE: u16 = 0;
+pub const REQUEST_QUEUE: u16 = 1;
+
+pub const TAG_SIZE: usize = 36;
+
+pub const DAX_WINDOW: u8 = 0;
+
+pub struct VirtioFs {
+    tag: String,
+    fs: PassthroughFs,
+    // Bytes of DAX window, none at all when 0
+    dax_window: u64,
+
+    requests: u64,
+    errors: u64,
+}
+
+impl VirtioFs {
+    pub fn new(tag: &str, root: &Path, dax_window: u64) -> std::result::Result<Self, String> {
+        if tag.is_empty() || tag.len() > TAG_SIZE {
+            return Err(format!("The virtio-fs tag has to be 1 to {TAG_SIZE} bytes"));
+        }
+
+        if !dax_window.is_multiple_of(PAGE_SIZE) {
+            return Err(format!("The DAX window has to be a whole number of {PAGE_SIZE} byte pages"));
+        }
+
+        Ok(Self { tag: tag.to_string(), fs: PassthroughFs::new(root), dax_window, requests: 0, errors: 0 })
+    }
+
+    /// SETUPMAPPING and REMOVEMAPPING, the only requests that need the window the transport holds
+    fn map(&self, ctx: &DeviceContext, opcode: u32, body: &[u8]) -> Result<()> {
+        let mut reader = Reader::new(body);
+        let invalid = || Error::from_raw_os_error(libc::EINVAL);
+        let no_window = || Error::from_raw_os_error(libc::ENODEV);
+
+        if !self.fs.initialised() {
+            return Err(Error::from_raw_os_error(libc::EIO));
+        }
+
+        if opcode == FUSE_SETUPMAPPING {
+            let (fh, file_offset, length, flags, offset) = (reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?);
+            let file = self.fs.file(fh)?;
+
+            return ctx.shared_memory(DAX_WINDOW, |window| window.map_file(offset, file, file_offset, length, flags & FUSE_SETUPMAPPING_FLAG_WRITE > 0))
+                .ok_or(no_window())?
+                .map(|_| ());
+        }
+
+        let count = reader.u32().ok_or(invalid())?;
+
+        for _ in 0..count {
+            let (offset, length) = (reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?);
+            ctx.shared_memory(DAX_WINDOW, |window| window.unmap(offset, length)).ok_or(no_window())??;
+        }
+
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioFs {
+    fn device_id(&self) -> u32 {
+        VIRTIO_FS_DEVICE_ID
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    // The tag, NUL padded, then how many request queues there are
+    fn config_space(&self) -> Vec<u8> {
+        let mut config = self.tag.as_bytes().to_vec();
+        config.resize(TAG_SIZE, 0);
+        config.extend_from_slice(&1u32.to_le_bytes());
+
+        config
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    fn shared_memory_regions(&self) -> Vec<(u8, u64)> {
+        match self.dax_window {
+            0 => Vec::new(),
+            length => vec![(DAX_WINDOW, length)],
+        }
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, _queue: u16, chain: DescriptorChain) -> Option<u32> {
+        let request = unsafe { chain.read_all() };
+        self.requests += 1;
+
+        let header = InHeader::parse(&request);
+
+        let out = match header {
+            Some(header) if header.opcode == FUSE_SETUPMAPPING || header.opcode == FUSE_REMOVEMAPPING => {
+                let body = request.get(IN_HEADER_SIZE..).unwrap_or(&[]);
+
+                match self.map(ctx, header.opcode, body) {
+                    Ok(()) => Some(reply(header.unique, 0, &[])),
+                    Err(err) => Some(reply(header.unique, -err.raw_os_error().unwrap_or(libc::EIO), &[])),
+                }
+            },
+            _ => self.fs.handle(&request),
+        };
+
+        let Some(mut out) = out else {
+            return Some(0);
+        };
+
+        if out.len() > unsafe { chain.writable_len() } {
+            let unique = header.map_or(0, |header| header.unique);
+            ctx.send_message(format!("A {} byte FUSE reply didn't fit the driver's buffer", out.len()));
+            out = reply(unique, -libc::EIO, &[]);
+        }
+
+        if i32::from_le_bytes(out[4..8].try_into().unwrap()) != 0 {
+            self.errors += 1;
+        }
+
+        Some(unsafe { chain.write_at(0, &out) } as u32)
+    }
+
+    fn reset(&mut self) {
+        self.fs.reset();
+    }
+
+    fn command(&mut self, _ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command {
+            "stats" => Ok(format!("Sharing {} as {} with a {} KiB DAX window: {} requests, {} errors, {}", self.fs.root().display(), self.tag, self.dax_window / 1024, self.requests, self.errors, self.fs.describe())),
+            _ => Err(format!("The fs device doesn't understand {command}, try stats")),
+        }
+    }
+}
+
+#[test]
+pub fn test_dax_window_maps_files() {
+    use std::fs;
+
+    use tokio::sync::mpsc::channel;
+
+    use crate::virtio::{device_register::{SHM_SEL, SHM_BASE_LOW, SHM_BASE_HIGH}, shared_memory::SharedMemoryRegion, virtqueue::DescriptorCell, vring::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE}, transport::{MmioTransport, TransportMode}};
+
+    use self::fuse::*;
+
+    let root = std::env::temp_dir().join(format!("virtio-fs-dax-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("paged.txt"), [b"through the window".as_slice(), &[0; 4078]].concat()).unwrap();
+
+    let mut device = VirtioFs::new("dax", &root, 1 << 20).unwrap();
+    let mut transport = MmioTransport::new(TransportMode::Modern, VIRTIO_FS_DEVICE_ID, 0, &[16, 16]);
+
+    for (id, length) in device.shared_memory_regions() {
+        transport.add_shared_memory_region(SharedMemoryRegion::new(id, length).unwrap());
+    }
+
+    transport.write(SHM_SEL, DAX_WINDOW as u32);
+    let window = (transport.read(SHM_BASE_HIGH) as u64) << 32 | transport.read(SHM_BASE_LOW) as u64;
+
+    let (tx, _rx) = channel(16);
+    let mut ctx = DeviceContext::new(&tx, transport.into_shared());
+    let mut unique = 0;
+
+    let mut call = |opcode, nodeid, body: &[u8]| {
+        unique += 1;
+
+        let header = InHeader { len: (IN_HEADER_SIZE + body.len()) as u32, opcode, unique, nodeid, ..InHeader::default() };
+        let mut request = [header.to_bytes().as_slice(), body].concat();
+        let mut out = [0u8; 256];
+
+        let mut table = [
+            DescriptorCell { addr: request.as_mut_ptr() as u64, length: request.len() as u32, flags: VIRTQ_DESC_F_NEXT, next: 1 },
+            DescriptorCell { addr: out.as_mut_ptr() as u64, length: out.len() as u32, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        ];
+
+        let length = device.process_request(&mut ctx, REQUEST_QUEUE, unsafe { DescriptorChain::new(table.as_mut_ptr(), 2, 0) }).unwrap();
+        let (_, error, body) = parse_reply(&out[..length as usize]).unwrap();
+
+        (error, body.to_vec())
+    };
+
+    call(FUSE_INIT, 0, &[[7u32.to_le_bytes(), 31u32.to_le_bytes()].concat(), vec![0; 56]].concat());
+
+    let (_, entry) = call(FUSE_LOOKUP, FUSE_ROOT_ID, b"paged.txt\0");
+    let nodeid = Reader::new(&entry).u64().unwrap();
+    let (_, opened) = call(FUSE_OPEN, nodeid, &[(libc::O_RDWR as u32).to_le_bytes(), [0; 4]].concat());
+    let fh = Reader::new(&opened).u64().unwrap();
+
+    let setup = |file_offset: u64, length: u64, offset: u64| [fh, file_offset, length, FUSE_SETUPMAPPING_FLAG_WRITE, offset].map(u64::to_le_bytes).concat();
+
+    // The file's first page shows up in the window and the guest's stores land in the file
+    assert_eq!(call(FUSE_SETUPMAPPING, nodeid, &setup(0, 4096, 8192)).0, 0);
+
+    unsafe {
+        let page = (window + 8192) as *mut u8;
+        assert_eq!(std::slice::from_raw_parts(page, 7), b"through");
+        page.copy_from_nonoverlapping(b"THROUGH".as_ptr(), 7);
+    }
+
+    assert!(fs::read(root.join("paged.txt")).unwrap().starts_with(b"THROUGH the window"));
+
+    let remove = [1u32.to_le_bytes().as_slice(), &8192u64.to_le_bytes(), &4096u64.to_le_bytes()].concat();
+    assert_eq!(call(FUSE_REMOVEMAPPING, nodeid, &remove).0, 0);
+
+    // Ranges have to be whole pages inside the window
+    assert_eq!(call(FUSE_SETUPMAPPING, nodeid, &setup(0, 100, 0)).0, -libc::EINVAL);
+    assert_eq!(call(FUSE_SETUPMAPPING, nodeid, &setup(0, 4096, 1 << 20)).0, -libc::EINVAL);
+    assert_eq!(call(FUSE_SETUPMAPPING, nodeid, &[9u64.to_le_bytes(), [0; 8], 4096u64.to_le_bytes(), [0; 8], [0; 8]].concat()).0, -libc::EBADF);
+
+    fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,566 @@
+// Serves FUSE requests from a directory on the host, like virtiofsd does. The guest knows files
+// by node ids we hand out on LOOKUP and keeps them until it FORGETs them as often as it looked
+// them up. Each node remembers its path under the shared directory, renames update the paths
+// of everything below. Names with a slash or that climb out with `..` are refused, every directory
+// on the way to a node has to be a real one rather than a symlink, and the last component is never
+// followed either, so the guest can't reach outside the directory.
+
+use std::{collections::HashMap, ffi::CString, fs::{self, DirBuilder, File, Metadata, OpenOptions}, io::{Error, Result}, os::unix::{ffi::OsStrExt, fs::{symlink, DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt}}, path::{Path, PathBuf}};
+
+use crate::virtio::shared_memory::PAGE_SIZE;
+
+use super::fuse::*;
+
+// Requests and replies are never bigger than this, the driver's buffers are sized by it
+pub const MAX_WRITE: u32 = 128 * 1024;
+
+struct Node {
+    // Relative to the shared directory, empty for the root
+    path: PathBuf,
+    lookups: u64,
+}
+
+enum Handle {
+    File(File),
+    // A listing taken at OPENDIR, READDIR offsets index into it
+    Directory(Vec<(u64, u32, Vec<u8>)>),
+}
+
+fn errno(err: Error) -> i32 {
+    err.raw_os_error().unwrap_or(libc::EIO)
+}
+
+fn invalid() -> Error {
+    Error::from_raw_os_error(libc::EINVAL)
+}
+
+/// The `d_type` of a dirent is the file type bits of the mode
+fn dirent_kind(metadata: &Metadata) -> u32 {
+    (metadata.mode() & libc::S_IFMT) >> 12
+}
+
+/// One name in a directory, nothing that could go anywhere else
+fn check_name(name: &str) -> Result<&str> {
+    match name {
+        "" | "." | ".." => Err(invalid()),
+        name if name.contains('/') => Err(invalid()),
+        name => Ok(name),
+    }
+}
+
+pub struct PassthroughFs {
+    root: PathBuf,
+    initialised: bool,
+
+    nodes: HashMap<u64, Node>,
+    // Host (device, inode) to node id, so hard links and repeat lookups share a node
+    inodes: HashMap<(u64, u64), u64>,
+    next_node: u64,
+
+    handles: HashMap<u64, Handle>,
+    next_handle: u64,
+}
+
+impl PassthroughFs {
+    pub fn new(root: &Path) -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert(FUSE_ROOT_ID, Node { path: PathBuf::new(), lookups: 1 });
+
+        Self {
+            root: root.to_path_buf(),
+            initialised: false,
+            nodes,
+            inodes: HashMap::new(),
+            next_node: FUSE_ROOT_ID + 1,
+            handles: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    pub fn initialised(&self) -> bool {
+        self.initialised
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} nodes, {} open handles", self.nodes.len(), self.handles.len())
+    }
+
+    /// Back to how it was before INIT, every node and handle forgotten
+    pub fn reset(&mut self) {
+        self.initialised = false;
+        self.nodes.retain(|nodeid, _| *nodeid == FUSE_ROOT_ID);
+        self.inodes.clear();
+        self.handles.clear();
+    }
+
+    /// `relative` under the shared directory, as long as everything before its last component is
+    /// a directory. A symlink the guest made on the way could point anywhere
+    fn beneath(&self, relative: &Path) -> Result<PathBuf> {
+        let mut path = self.root.clone();
+        let mut components = relative.components().peekable();
+
+        while let Some(component) = components.next() {
+            path.push(component);
+
+            if components.peek().is_some() && !fs::symlink_metadata(&path)?.is_dir() {
+                return Err(Error::from_raw_os_error(libc::ENOTDIR));
+            }
+        }
+
+        Ok(path)
+    }
+
+    fn host_path(&self, nodeid: u64) -> Result<PathBuf> {
+        let node = self.nodes.get(&nodeid).ok_or(Error::from_raw_os_error(libc::ESTALE))?;
+        self.beneath(&node.path)
+    }
+
+    fn child_path(&self, parent: u64, name: &str) -> Result<(PathBuf, PathBuf)> {
+        let node = self.nodes.get(&parent).ok_or(Error::from_raw_os_error(libc::ESTALE))?;
+        let relative = node.path.join(check_name(name)?);
+
+        Ok((self.beneath(&relative)?, relative))
+    }
+
+    /// Looks a name up and counts it against its node, the reply is a `fuse_entry_out`
+    fn lookup(&mut self, parent: u64, name: &str) -> Result<Vec<u8>> {
+        let (path, relative) = self.child_path(parent, name)?;
+        let metadata = fs::symlink_metadata(&path)?;
+
+        let nodeid = match self.inodes.get(&(metadata.dev(), metadata.ino())) {
+            Some(nodeid) => *nodeid,
+            None => {
+                let nodeid = self.next_node;
+                self.next_node += 1;
+                self.inodes.insert((metadata.dev(), metadata.ino()), nodeid);
+                self.nodes.insert(nodeid, Node { path: PathBuf::new(), lookups: 0 });
+
+                nodeid
+            },
+        };
+
+        let node = self.nodes.get_mut(&nodeid).unwrap();
+        node.path = relative;
+        node.lookups += 1;
+
+        Ok(entry_out(nodeid, &metadata))
+    }
+
+    fn forget(&mut self, nodeid: u64, count: u64) {
+        if nodeid == FUSE_ROOT_ID {
+            return;
+        }
+
+        let Some(node) = self.nodes.get_mut(&nodeid) else {
+            return;
+        };
+
+        node.lookups = node.lookups.saturating_sub(count);
+
+        if node.lookups == 0 {
+            self.nodes.remove(&nodeid);
+            self.inodes.retain(|_, node| *node != nodeid);
+        }
+    }
+
+    fn add_handle(&mut self, handle: Handle) -> u64 {
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(fh, handle);
+
+        fh
+    }
+
+    pub fn file(&self, fh: u64) -> Result<&File> {
+        match self.handles.get(&fh) {
+            Some(Handle::File(file)) => Ok(file),
+            _ => Err(Error::from_raw_os_error(libc::EBADF)),
+        }
+    }
+
+    /// Opens with the guest's flags, never following a symlink and never creating anything
+    /// unless asked to
+    fn open_options(flags: u32) -> OpenOptions {
+        let flags = flags as i32;
+        let mut options = OpenOptions::new();
+
+        match flags & libc::O_ACCMODE {
+            libc::O_WRONLY => options.write(true),
+            libc::O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+
+        options.custom_flags(flags & (libc::O_APPEND | libc::O_TRUNC | libc::O_EXCL) | libc::O_NOFOLLOW);
+        options
+    }
+
+    fn init(&mut self, body: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(body);
+        let (major, _minor, max_readahead, flags) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+
+        // A guest speaking a different major version can't work with us
+        if major != FUSE_KERNEL_VERSION {
+            return Err(Error::from_raw_os_error(libc::EPROTO));
+        }
+
+        self.initialised = true;
+
+        let mut out = Vec::with_capacity(INIT_OUT_SIZE);
+        out.extend_from_slice(&FUSE_KERNEL_VERSION.to_le_bytes());
+        out.extend_from_slice(&FUSE_KERNEL_MINOR_VERSION.to_le_bytes());
+        out.extend_from_slice(&max_readahead.to_le_bytes());
+        out.extend_from_slice(&(flags & (FUSE_ASYNC_READ | FUSE_ATOMIC_O_TRUNC | FUSE_BIG_WRITES | FUSE_MAP_ALIGNMENT)).to_le_bytes());
+        // max_background and congestion_threshold
+        out.extend_from_slice(&16u16.to_le_bytes());
+        out.extend_from_slice(&12u16.to_le_bytes());
+        out.extend_from_slice(&MAX_WRITE.to_le_bytes());
+        // Timestamps are to the nanosecond
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&((MAX_WRITE / 4096) as u16).to_le_bytes());
+        // DAX mappings go in whole pages, as a power of two
+        out.extend_from_slice(&(PAGE_SIZE.trailing_zeros() as u16).to_le_bytes());
+        out.resize(INIT_OUT_SIZE, 0);
+
+        Ok(out)
+    }
+
+    fn setattr(&mut self, nodeid: u64, body: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(body);
+        let valid = reader.u32().ok_or(invalid())?;
+        reader.u32();
+        let (fh, size, _lock_owner, atime, mtime) = (reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u64(), reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?);
+        reader.u64();
+        let (atimensec, mtimensec, _ctimensec, mode) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.u32(), reader.u32().ok_or(invalid())?);
+
+        let path = self.host_path(nodeid)?;
+
+        // chmod follows a symlink and Linux can't change a symlink's own mode anyway
+        if valid & FATTR_MODE != 0 {
+            if fs::symlink_metadata(&path)?.is_symlink() {
+                return Err(Error::from_raw_os_error(libc::EOPNOTSUPP));
+            }
+
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+
+        if valid & FATTR_SIZE != 0 {
+            match valid & FATTR_FH != 0 {
+                true => self.file(fh)?.set_len(size)?,
+                false => OpenOptions::new().write(true).custom_flags(libc::O_NOFOLLOW).open(&path)?.set_len(size)?,
+            }
+        }
+
+        if valid & (FATTR_ATIME | FATTR_MTIME) != 0 {
+            let time = |set: u32, now: u32, seconds: u64, nanoseconds: u32| match (valid & set != 0, valid & now != 0) {
+                (false, _) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+                (true, true) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+                (true, false) => libc::timespec { tv_sec: seconds as i64, tv_nsec: nanoseconds as i64 },
+            };
+
+            let times = [time(FATTR_ATIME, FATTR_ATIME_NOW, atime, atimensec), time(FATTR_MTIME, FATTR_MTIME_NOW, mtime, mtimensec)];
+            let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| invalid())?;
+
+            if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        Ok(attr_out(&fs::symlink_metadata(&path)?))
+    }
+
+    fn rename(&mut self, parent: u64, body: &[u8], with_flags: bool) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(body);
+        let new_parent = reader.u64().ok_or(invalid())?;
+        let flags = match with_flags {
+            true => reader.u64().ok_or(invalid())? as u32,
+            false => 0,
+        };
+
+        let (old_name, new_name) = (reader.name().ok_or(invalid())?, reader.name().ok_or(invalid())?);
+        let (old_path, old_relative) = self.child_path(parent, old_name)?;
+        let (new_path, new_relative) = self.child_path(new_parent, new_name)?;
+
+        // RENAME_EXCHANGE and whiteouts aren't supported
+        if flags & !RENAME_NOREPLACE != 0 {
+            return Err(invalid());
+        }
+
+        if flags & RENAME_NOREPLACE != 0 && fs::symlink_metadata(&new_path).is_ok() {
+            return Err(Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        fs::rename(&old_path, &new_path)?;
+
+        for node in self.nodes.values_mut() {
+            // Joining an empty path would leave a trailing slash on the renamed node itself
+            match node.path.strip_prefix(&old_relative) {
+                Ok(below) if below.as_os_str().is_empty() => node.path = new_relative.clone(),
+                Ok(below) => node.path = new_relative.join(below),
+                Err(_) => {},
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn opendir(&mut self, nodeid: u64) -> Result<Vec<u8>> {
+        let path = self.host_path(nodeid)?;
+        let metadata = fs::symlink_metadata(&path)?;
+
+        if !metadata.is_dir() {
+            return Err(Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        let mut entries = vec![(metadata.ino(), libc::DT_DIR as u32, b".".to_vec()), (metadata.ino(), libc::DT_DIR as u32, b"..".to_vec())];
+
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            entries.push((metadata.ino(), dirent_kind(&metadata), entry.file_name().as_bytes().to_vec()));
+        }
+
+        Ok(open_out(self.add_handle(Handle::Directory(entries))))
+    }
+
+    fn readdir(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(body);
+        let (fh, offset, size) = (reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u32().ok_or(invalid())? as usize);
+
+        let Some(Handle::Directory(entries)) = self.handles.get(&fh) else {
+            return Err(Error::from_raw_os_error(libc::EBADF));
+        };
+
+        let mut out = Vec::new();
+
+        for (index, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let entry = dirent(*ino, index as u64 + 1, *kind, name);
+
+            if out.len() + entry.len() > size {
+                break;
+            }
+
+            out.extend_from_slice(&entry);
+        }
+
+        Ok(out)
+    }
+
+    fn statfs(&self) -> Result<Vec<u8>> {
+        let path = CString::new(self.root.as_os_str().as_bytes()).map_err(|_| invalid())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut out = Vec::with_capacity(80);
+
+        for value in [stat.f_blocks, stat.f_bfree, stat.f_bavail, stat.f_files, stat.f_ffree] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+
+        for value in [stat.f_bsize, stat.f_namemax, stat.f_frsize] {
+            out.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+
+        out.resize(80, 0);
+        Ok(out)
+    }
+
+    /// Handles one request, None for the ones FUSE never replies to
+    pub fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let header = InHeader::parse(request)?;
+
+        let end = (header.len as usize).clamp(IN_HEADER_SIZE, request.len().max(IN_HEADER_SIZE));
+        let body = request.get(IN_HEADER_SIZE..end).unwrap_or(&[]);
+
+        match header.opcode {
+            FUSE_FORGET => {
+                self.forget(header.nodeid, Reader::new(body).u64().unwrap_or(0));
+                return None;
+            },
+            FUSE_BATCH_FORGET => {
+                let mut reader = Reader::new(body);
+                let count = reader.u32().unwrap_or(0);
+                reader.u32();
+
+                for _ in 0..count {
+                    let (Some(nodeid), Some(lookups)) = (reader.u64(), reader.u64()) else {
+                        break;
+                    };
+
+                    self.forget(nodeid, lookups);
+                }
+
+                return None;
+            },
+            // Everything is answered as it arrives, there's never anything to interrupt
+            FUSE_INTERRUPT => return None,
+            _ => {},
+        }
+
+        let result = match header.opcode {
+            FUSE_INIT => self.init(body),
+            _ if !self.initialised => Err(Error::from_raw_os_error(libc::EIO)),
+            opcode => self.dispatch(opcode, header.nodeid, body),
+        };
+
+        Some(match result {
+            Ok(out) => reply(header.unique, 0, &out),
+            Err(err) => reply(header.unique, -errno(err), &[]),
+        })
+    }
+
+    fn dispatch(&mut self, opcode: u32, nodeid: u64, body: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = Reader::new(body);
+
+        match opcode {
+            FUSE_LOOKUP => self.lookup(nodeid, reader.name().ok_or(invalid())?),
+            FUSE_GETATTR => Ok(attr_out(&fs::symlink_metadata(self.host_path(nodeid)?)?)),
+            FUSE_SETATTR => self.setattr(nodeid, body),
+            FUSE_READLINK => Ok(fs::read_link(self.host_path(nodeid)?)?.as_os_str().as_bytes().to_vec()),
+            FUSE_SYMLINK => {
+                let (name, target) = (reader.name().ok_or(invalid())?, reader.name().ok_or(invalid())?);
+                symlink(target, self.child_path(nodeid, name)?.0)?;
+
+                self.lookup(nodeid, name)
+            },
+            FUSE_MKDIR => {
+                let (mode, umask) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                let name = reader.name().ok_or(invalid())?;
+                DirBuilder::new().mode(mode & !umask & 0o7777).create(self.child_path(nodeid, name)?.0)?;
+
+                self.lookup(nodeid, name)
+            },
+            FUSE_UNLINK => {
+                fs::remove_file(self.child_path(nodeid, reader.name().ok_or(invalid())?)?.0)?;
+                Ok(Vec::new())
+            },
+            FUSE_RMDIR => {
+                fs::remove_dir(self.child_path(nodeid, reader.name().ok_or(invalid())?)?.0)?;
+                Ok(Vec::new())
+            },
+            FUSE_RENAME => self.rename(nodeid, body, false),
+            FUSE_RENAME2 => self.rename(nodeid, body, true),
+            FUSE_OPEN => {
+                let file = Self::open_options(reader.u32().ok_or(invalid())?).open(self.host_path(nodeid)?)?;
+                Ok(open_out(self.add_handle(Handle::File(file))))
+            },
+            FUSE_CREATE => {
+                let (flags, mode, umask) = (reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                reader.u32();
+                let name = reader.name().ok_or(invalid())?;
+
+                let file = Self::open_options(flags).create(true).mode(mode & !umask & 0o7777).open(self.child_path(nodeid, name)?.0)?;
+                let mut out = self.lookup(nodeid, name)?;
+                out.extend_from_slice(&open_out(self.add_handle(Handle::File(file))));
+
+                Ok(out)
+            },
+            FUSE_READ => {
+                let (fh, offset, size) = (reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u32().ok_or(invalid())?);
+                let mut data = vec![0u8; size.min(MAX_WRITE) as usize];
+                let count = self.file(fh)?.read_at(&mut data, offset)?;
+                data.truncate(count);
+
+                Ok(data)
+            },
+            FUSE_WRITE => {
+                let (fh, offset, size) = (reader.u64().ok_or(invalid())?, reader.u64().ok_or(invalid())?, reader.u32().ok_or(invalid())? as usize);
+                let data = body.get(WRITE_IN_SIZE..WRITE_IN_SIZE + size).ok_or(invalid())?;
+                let written = self.file(fh)?.write_at(data, offset)?;
+
+                let mut out = (written as u32).to_le_bytes().to_vec();
+                out.extend_from_slice(&[0; 4]);
+                Ok(out)
+            },
+            FUSE_STATFS => self.statfs(),
+            FUSE_RELEASE | FUSE_RELEASEDIR => {
+                self.handles.remove(&reader.u64().ok_or(invalid())?);
+                Ok(Vec::new())
+            },
+            FUSE_FSYNC => {
+                self.file(reader.u64().ok_or(invalid())?)?.sync_all()?;
+                Ok(Vec::new())
+            },
+            FUSE_FLUSH | FUSE_FSYNCDIR | FUSE_ACCESS => Ok(Vec::new()),
+            FUSE_OPENDIR => self.opendir(nodeid),
+            FUSE_READDIR => self.readdir(body),
+            FUSE_DESTROY => {
+                self.reset();
+                Ok(Vec::new())
+            },
+            _ => Err(Error::from_raw_os_error(libc::ENOSYS)),
+        }
+    }
+}
+
+#[test]
+pub fn test_files_through_fuse() {
+    let root = std::env::temp_dir().join(format!("virtio-fs-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+
+    let mut passthrough = PassthroughFs::new(&root);
+    let mut unique = 0;
+
+    let mut call = |opcode, nodeid, body: &[u8]| {
+        unique += 1;
+
+        let header = InHeader { len: (IN_HEADER_SIZE + body.len()) as u32, opcode, unique, nodeid, ..InHeader::default() };
+        let out = passthrough.handle(&[header.to_bytes().as_slice(), body].concat()).unwrap();
+        let (replied, error, body) = parse_reply(&out).unwrap();
+        assert_eq!(replied, unique);
+
+        (error, body.to_vec())
+    };
+
+    // Nothing is served before INIT
+    assert_eq!(call(FUSE_GETATTR, FUSE_ROOT_ID, &[0; 16]).0, -libc::EIO);
+
+    let (error, init) = call(FUSE_INIT, 0, &[[7u32.to_le_bytes(), 31u32.to_le_bytes()].concat(), vec![0; 56]].concat());
+    assert_eq!((error, Reader::new(&init).u32()), (0, Some(7)));
+
+    let create = [[(libc::O_RDWR as u32).to_le_bytes(), 0o100644u32.to_le_bytes(), 0u32.to_le_bytes(), 0u32.to_le_bytes()].concat(), b"hello.txt\0".to_vec()].concat();
+    let (error, created) = call(FUSE_CREATE, FUSE_ROOT_ID, &create);
+    assert_eq!(error, 0);
+
+    let nodeid = Reader::new(&created).u64().unwrap();
+    let fh = Reader::new(&created[ENTRY_OUT_SIZE..]).u64().unwrap();
+    let io = |offset: u64, size: u32| [fh.to_le_bytes().as_slice(), &offset.to_le_bytes(), &size.to_le_bytes(), &[0; 20]].concat();
+
+    assert_eq!(call(FUSE_WRITE, nodeid, &[io(0, 5), b"hello".to_vec()].concat()), (0, vec![5, 0, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(call(FUSE_READ, nodeid, &io(1, 100)), (0, b"ello".to_vec()));
+
+    let (_, opened) = call(FUSE_OPENDIR, FUSE_ROOT_ID, &[0; 8]);
+    let dir = Reader::new(&opened).u64().unwrap();
+    let (_, listing) = call(FUSE_READDIR, FUSE_ROOT_ID, &[dir.to_le_bytes().as_slice(), &[0; 8], &4096u32.to_le_bytes(), &[0; 20]].concat());
+    assert!(parse_dirents(&listing).iter().any(|(_, _, name)| name == "hello.txt"));
+
+    // Renamed on the host and still the same node for the guest
+    assert_eq!(call(FUSE_RENAME, FUSE_ROOT_ID, b"\x01\0\0\0\0\0\0\0hello.txt\0moved.txt\0").0, 0);
+    assert_eq!(fs::read(root.join("moved.txt")).unwrap(), b"hello");
+    assert_eq!(call(FUSE_GETATTR, nodeid, &[0; 16]).0, 0);
+
+    // No climbing out of the shared directory
+    assert_eq!(call(FUSE_LOOKUP, FUSE_ROOT_ID, b"..\0").0, -libc::EINVAL);
+
+    // Nor through a symlink the guest made, which is only ever seen as a link
+    let (error, link) = call(FUSE_SYMLINK, FUSE_ROOT_ID, b"escape\0/etc\0");
+    assert_eq!(error, 0);
+
+    let link = Reader::new(&link).u64().unwrap();
+    assert_eq!(call(FUSE_LOOKUP, link, b"passwd\0").0, -libc::ENOTDIR);
+    assert_eq!(call(FUSE_CREATE, link, &create).0, -libc::ENOTDIR);
+    assert_eq!(call(FUSE_OPEN, link, &[0; 8]).0, -libc::ELOOP);
+    assert_eq!(call(FUSE_OPENDIR, link, &[0; 8]).0, -libc::ENOTDIR);
+
+    assert_eq!(call(FUSE_UNLINK, FUSE_ROOT_ID, b"moved.txt\0").0, 0);
+    assert_eq!(call(FUSE_LOOKUP, FUSE_ROOT_ID, b"moved.txt\0").0, -libc::ENOENT);
+
+    fs::remove_dir_all(&root).unwrap();
+}
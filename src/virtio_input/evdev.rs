@@ -0,0 +1,286 @@
+// Linux evdev events as they cross the event queue, and how terminal events turn into them.
+// Keys are mapped for a US layout, a character that needs shift gets the shift key wrapped
+// around it. Terminals only report key presses, so every key goes down and straight back up.
+// The mouse is an absolute pointer in terminal cells with three buttons and a wheel.
+
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_LED: u16 = 0x11;
+
+pub const SYN_REPORT: u16 = 0;
+
+pub const INPUT_PROP_POINTER: u16 = 0x00;
+
+pub const REL_HWHEEL: u16 = 0x06;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+
+pub const LED_NUML: u16 = 0x00;
+pub const LED_CAPSL: u16 = 0x01;
+pub const LED_SCROLLL: u16 = 0x02;
+
+pub const KEY_ESC: u16 = 1;
+pub const KEY_MINUS: u16 = 12;
+pub const KEY_EQUAL: u16 = 13;
+pub const KEY_BACKSPACE: u16 = 14;
+pub const KEY_TAB: u16 = 15;
+pub const KEY_LEFTBRACE: u16 = 26;
+pub const KEY_RIGHTBRACE: u16 = 27;
+pub const KEY_ENTER: u16 = 28;
+pub const KEY_LEFTCTRL: u16 = 29;
+pub const KEY_SEMICOLON: u16 = 39;
+pub const KEY_APOSTROPHE: u16 = 40;
+pub const KEY_GRAVE: u16 = 41;
+pub const KEY_LEFTSHIFT: u16 = 42;
+pub const KEY_BACKSLASH: u16 = 43;
+pub const KEY_COMMA: u16 = 51;
+pub const KEY_DOT: u16 = 52;
+pub const KEY_SLASH: u16 = 53;
+pub const KEY_LEFTALT: u16 = 56;
+pub const KEY_SPACE: u16 = 57;
+pub const KEY_CAPSLOCK: u16 = 58;
+pub const KEY_F1: u16 = 59;
+pub const KEY_NUMLOCK: u16 = 69;
+pub const KEY_SCROLLLOCK: u16 = 70;
+pub const KEY_F11: u16 = 87;
+pub const KEY_F12: u16 = 88;
+pub const KEY_HOME: u16 = 102;
+pub const KEY_UP: u16 = 103;
+pub const KEY_PAGEUP: u16 = 104;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_END: u16 = 107;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_PAGEDOWN: u16 = 109;
+pub const KEY_INSERT: u16 = 110;
+pub const KEY_DELETE: u16 = 111;
+pub const KEY_LEFTMETA: u16 = 125;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+pub const EVENT_SIZE: usize = 8;
+
+// The top row and the letters, in keyboard order, their codes run on from these
+const NUMBER_ROW: &[u8] = b"1234567890";
+const LETTER_ROWS: [(&[u8], u16); 3] = [(b"qwertyuiop", 16), (b"asdfghjkl", 30), (b"zxcvbnm", 44)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    pub kind: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl InputEvent {
+    pub fn new(kind: u16, code: u16, value: u32) -> Self {
+        Self { kind, code, value }
+    }
+
+    pub fn syn() -> Self {
+        Self::new(EV_SYN, SYN_REPORT, 0)
+    }
+
+    pub fn to_bytes(self) -> [u8; EVENT_SIZE] {
+        let mut bytes = [0u8; EVENT_SIZE];
+        bytes[0..2].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.code.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.value.to_le_bytes());
+
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..EVENT_SIZE)?;
+
+        Some(Self {
+            kind: u16::from_le_bytes([bytes[0], bytes[1]]),
+            code: u16::from_le_bytes([bytes[2], bytes[3]]),
+            value: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        })
+    }
+}
+
+/// The key a character is typed with and whether it needs shift
+pub fn char_key(c: char) -> Option<(u16, bool)> {
+    let byte = u8::try_from(c).ok()?;
+    let lower = byte.to_ascii_lowercase();
+
+    if let Some(idx) = NUMBER_ROW.iter().position(|digit| *digit == byte) {
+        return Some((2 + idx as u16, false));
+    }
+
+    if let Some((row, first)) = LETTER_ROWS.iter().find(|(row, _)| row.contains(&lower)) {
+        let idx = row.iter().position(|letter| *letter == lower).unwrap();
+        return Some((first + idx as u16, byte.is_ascii_uppercase()));
+    }
+
+    let key = match byte {
+        b' ' => (KEY_SPACE, false),
+        b'-' => (KEY_MINUS, false), b'_' => (KEY_MINUS, true),
+        b'=' => (KEY_EQUAL, false), b'+' => (KEY_EQUAL, true),
+        b'[' => (KEY_LEFTBRACE, false), b'{' => (KEY_LEFTBRACE, true),
+        b']' => (KEY_RIGHTBRACE, false), b'}' => (KEY_RIGHTBRACE, true),
+        b';' => (KEY_SEMICOLON, false), b':' => (KEY_SEMICOLON, true),
+        b'\'' => (KEY_APOSTROPHE, false), b'"' => (KEY_APOSTROPHE, true),
+        b'`' => (KEY_GRAVE, false), b'~' => (KEY_GRAVE, true),
+        b'\\' => (KEY_BACKSLASH, false), b'|' => (KEY_BACKSLASH, true),
+        b',' => (KEY_COMMA, false), b'<' => (KEY_COMMA, true),
+        b'.' => (KEY_DOT, false), b'>' => (KEY_DOT, true),
+        b'/' => (KEY_SLASH, false), b'?' => (KEY_SLASH, true),
+        b'!' | b'@' | b'#' | b'$' | b'%' | b'^' | b'&' | b'*' | b'(' | b')' => {
+            let idx = b"!@#$%^&*()".iter().position(|shifted| *shifted == byte).unwrap();
+            (2 + idx as u16, true)
+        },
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+fn key_code(code: KeyCode) -> Option<(u16, bool)> {
+    let key = match code {
+        KeyCode::Char(c) => return char_key(c),
+        KeyCode::F(n @ 1..=10) => KEY_F1 + n as u16 - 1,
+        KeyCode::F(11) => KEY_F11,
+        KeyCode::F(12) => KEY_F12,
+        KeyCode::BackTab => return Some((KEY_TAB, true)),
+        KeyCode::Backspace => KEY_BACKSPACE,
+        KeyCode::Enter => KEY_ENTER,
+        KeyCode::Tab => KEY_TAB,
+        KeyCode::Esc => KEY_ESC,
+        KeyCode::Left => KEY_LEFT,
+        KeyCode::Right => KEY_RIGHT,
+        KeyCode::Up => KEY_UP,
+        KeyCode::Down => KEY_DOWN,
+        KeyCode::Home => KEY_HOME,
+        KeyCode::End => KEY_END,
+        KeyCode::PageUp => KEY_PAGEUP,
+        KeyCode::PageDown => KEY_PAGEDOWN,
+        KeyCode::Insert => KEY_INSERT,
+        KeyCode::Delete => KEY_DELETE,
+        KeyCode::CapsLock => KEY_CAPSLOCK,
+        KeyCode::ScrollLock => KEY_SCROLLLOCK,
+        KeyCode::NumLock => KEY_NUMLOCK,
+        _ => return None,
+    };
+
+    Some((key, false))
+}
+
+/// Every key and button the device reports
+pub fn supported_keys() -> Vec<u16> {
+    let mut keys: Vec<u16> = (0..=0x7fu8).filter_map(|byte| char_key(byte as char)).map(|(key, _)| key).collect();
+
+    keys.extend([
+        KEY_ESC, KEY_BACKSPACE, KEY_TAB, KEY_ENTER, KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_LEFTALT, KEY_LEFTMETA,
+        KEY_CAPSLOCK, KEY_NUMLOCK, KEY_SCROLLLOCK, KEY_F11, KEY_F12, KEY_HOME, KEY_UP, KEY_PAGEUP, KEY_LEFT,
+        KEY_RIGHT, KEY_END, KEY_DOWN, KEY_PAGEDOWN, KEY_INSERT, KEY_DELETE, BTN_LEFT, BTN_RIGHT, BTN_MIDDLE,
+    ]);
+    keys.extend(KEY_F1..KEY_F1 + 10);
+
+    keys.sort();
+    keys.dedup();
+
+    keys
+}
+
+/// A terminal key press, with the modifiers it came with and any shift the character needs
+pub fn key_events(key: &KeyEvent) -> Vec<InputEvent> {
+    let Some((code, shift)) = key_code(key.code).filter(|_| key.kind != KeyEventKind::Release) else {
+        return Vec::new();
+    };
+
+    let mut modifiers = Vec::new();
+
+    for (modifier, modifier_key) in [(KeyModifiers::CONTROL, KEY_LEFTCTRL), (KeyModifiers::ALT, KEY_LEFTALT), (KeyModifiers::SUPER, KEY_LEFTMETA)] {
+        if key.modifiers.contains(modifier) {
+            modifiers.push(modifier_key);
+        }
+    }
+
+    if shift || key.modifiers.contains(KeyModifiers::SHIFT) {
+        modifiers.push(KEY_LEFTSHIFT);
+    }
+
+    press(code, &modifiers)
+}
+
+/// One report with the key and its modifiers down, then one with them all up
+pub fn press(code: u16, modifiers: &[u16]) -> Vec<InputEvent> {
+    let mut events: Vec<InputEvent> = modifiers.iter().map(|modifier| InputEvent::new(EV_KEY, *modifier, 1)).collect();
+    events.extend([InputEvent::new(EV_KEY, code, 1), InputEvent::syn(), InputEvent::new(EV_KEY, code, 0)]);
+    events.extend(modifiers.iter().rev().map(|modifier| InputEvent::new(EV_KEY, *modifier, 0)));
+    events.push(InputEvent::syn());
+
+    events
+}
+
+fn button(button: MouseButton) -> u16 {
+    match button {
+        MouseButton::Left => BTN_LEFT,
+        MouseButton::Right => BTN_RIGHT,
+        MouseButton::Middle => BTN_MIDDLE,
+    }
+}
+
+/// Every mouse event says where the pointer is, then what happened there
+pub fn mouse_events(mouse: &MouseEvent) -> Vec<InputEvent> {
+    let mut events = vec![InputEvent::new(EV_ABS, ABS_X, mouse.column as u32), InputEvent::new(EV_ABS, ABS_Y, mouse.row as u32)];
+
+    match mouse.kind {
+        MouseEventKind::Down(pressed) => events.push(InputEvent::new(EV_KEY, button(pressed), 1)),
+        MouseEventKind::Up(released) => events.push(InputEvent::new(EV_KEY, button(released), 0)),
+        MouseEventKind::Drag(_) | MouseEventKind::Moved => {},
+        MouseEventKind::ScrollUp => events.push(InputEvent::new(EV_REL, REL_WHEEL, 1)),
+        MouseEventKind::ScrollDown => events.push(InputEvent::new(EV_REL, REL_WHEEL, -1i32 as u32)),
+        MouseEventKind::ScrollLeft => events.push(InputEvent::new(EV_REL, REL_HWHEEL, -1i32 as u32)),
+        MouseEventKind::ScrollRight => events.push(InputEvent::new(EV_REL, REL_HWHEEL, 1)),
+    }
+
+    events.push(InputEvent::syn());
+    events
+}
+
+pub fn events_for(event: &Event) -> Vec<InputEvent> {
+    match event {
+        Event::Key(key) => key_events(key),
+        Event::Mouse(mouse) => mouse_events(mouse),
+        _ => Vec::new(),
+    }
+}
+
+/// A name for a key or button code, for the guest's event log
+pub fn key_name(code: u16) -> String {
+    let named = match code {
+        KEY_ESC => "ESC", KEY_BACKSPACE => "BACKSPACE", KEY_TAB => "TAB", KEY_ENTER => "ENTER",
+        KEY_LEFTCTRL => "LEFTCTRL", KEY_LEFTSHIFT => "LEFTSHIFT", KEY_LEFTALT => "LEFTALT", KEY_LEFTMETA => "LEFTMETA",
+        KEY_SPACE => "SPACE", KEY_CAPSLOCK => "CAPSLOCK", KEY_NUMLOCK => "NUMLOCK", KEY_SCROLLLOCK => "SCROLLLOCK",
+        KEY_UP => "UP", KEY_DOWN => "DOWN", KEY_LEFT => "LEFT", KEY_RIGHT => "RIGHT",
+        KEY_HOME => "HOME", KEY_END => "END", KEY_PAGEUP => "PAGEUP", KEY_PAGEDOWN => "PAGEDOWN",
+        KEY_INSERT => "INSERT", KEY_DELETE => "DELETE", KEY_F11 => "F11", KEY_F12 => "F12",
+        BTN_LEFT => "BTN_LEFT", BTN_RIGHT => "BTN_RIGHT", BTN_MIDDLE => "BTN_MIDDLE",
+        code if (KEY_F1..KEY_F1 + 10).contains(&code) => return format!("KEY_F{}", code - KEY_F1 + 1),
+        code => {
+            // Whatever character an unshifted press types
+            let typed = (0..=0x7fu8).map(|byte| byte as char).find(|c| char_key(*c) == Some((code, false)));
+
+            return match typed {
+                Some(c) => format!("KEY_{}", c.to_ascii_uppercase()),
+                None => format!("key {code}"),
+            };
+        },
+    };
+
+    match code {
+        BTN_LEFT | BTN_RIGHT | BTN_MIDDLE => named.to_string(),
+        _ => format!("KEY_{named}"),
+    }
+}
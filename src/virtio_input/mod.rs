@@ -0,0 +1,329 @@
+// A virtio-input device, a keyboard and a tablet in one fed by the TUI's key and mouse events.
+// The driver keeps the event queue stocked with buffers, one evdev event each, and the device
+// fills them as input comes in. The status queue goes the other way, the driver sets keyboard
+// LEDs through it.
+//
+// The config space is paged: the driver writes a select and subsel byte and reads back the
+// size and the answer, the device name, its ids, or a bitmap of the codes it has for one event
+// type. Those answers never change, so the transport gets all of them up front.
+
+pub mod evdev;
+
+use std::{collections::{HashMap, VecDeque}, io::{Error, Result}, sync::mpsc::Receiver};
+
+use crossterm::event::Event;
+
+use crate::virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain, transport::ConfigSelections};
+
+use self::evdev::*;
+
+pub const VIRTIO_INPUT_DEVICE_ID: u32 = 18;
+
+pub const EVENTQ: u16 = 0;
+pub const STATUSQ: u16 = 1;
+
+pub const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+pub const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+pub const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+pub const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+pub const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+pub const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+// select, subsel, size and 5 reserved bytes come before the answer
+pub const CONFIG_SIZE_OFFSET: usize = 2;
+pub const CONFIG_DATA_OFFSET: usize = 8;
+pub const CONFIG_DATA_SIZE: usize = 128;
+
+pub const DEFAULT_NAME: &str = "virtio-playground keyboard and tablet";
+const SERIAL: &str = "playground-input-0";
+const BUS_VIRTUAL: u16 = 0x06;
+
+// Pointer positions are terminal cells, no terminal is this many wide
+pub const ABS_MAX: u32 = 1023;
+
+// Input that arrives faster than the driver hands over buffers waits here, past this the
+// oldest goes
+const MAX_PENDING_EVENTS: usize = 1024;
+
+fn bitmap(codes: &[u16]) -> Vec<u8> {
+    let mut bits = vec![0u8; codes.iter().max().map_or(0, |max| *max as usize / 8 + 1)];
+
+    for code in codes {
+        bits[*code as usize / 8] |= 1 << (code % 8);
+    }
+
+    bits
+}
+
+fn led_name(led: u16) -> String {
+    match led {
+        LED_NUML => "num lock".to_string(),
+        LED_CAPSL => "caps lock".to_string(),
+        LED_SCROLLL => "scroll lock".to_string(),
+        led => format!("LED {led}"),
+    }
+}
+
+pub struct VirtioInput {
+    name: String,
+    input: Option<Receiver<Event>>,
+
+    buffers: VecDeque<DescriptorChain>,
+    pending: VecDeque<InputEvent>,
+
+    // Bit per LED as the driver last set them
+    leds: u32,
+
+    events_sent: u64,
+    events_dropped: u64,
+}
+
+impl VirtioInput {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            input: None,
+            buffers: VecDeque::new(),
+            pending: VecDeque::new(),
+            leds: 0,
+            events_sent: 0,
+            events_dropped: 0,
+        }
+    }
+
+    /// Where key and mouse events come from, the TUI while it's in input mode
+    pub fn set_input(&mut self, input: Receiver<Event>) {
+        self.input = Some(input);
+    }
+
+    fn queue_events(&mut self, events: Vec<InputEvent>) {
+        self.pending.extend(events);
+
+        while self.pending.len() > MAX_PENDING_EVENTS {
+            self.pending.pop_front();
+            self.events_dropped += 1;
+        }
+    }
+
+    fn deliver(&mut self, ctx: &mut DeviceContext) {
+        while !self.pending.is_empty() && !self.buffers.is_empty() {
+            let chain = self.buffers.pop_front().unwrap();
+            let event = self.pending.pop_front().unwrap();
+
+            // A buffer too small for an event is handed back empty
+            let written = match unsafe { chain.writable_len() } >= EVENT_SIZE {
+                true => unsafe { chain.write_at(0, &event.to_bytes()) },
+                false => 0,
+            };
+
+            self.events_sent += (written > 0) as u64;
+            ctx.complete(EVENTQ, chain, written as u32);
+        }
+    }
+
+    fn status(&mut self, ctx: &DeviceContext, bytes: &[u8]) {
+        for event in bytes.chunks_exact(EVENT_SIZE).filter_map(InputEvent::parse) {
+            match event.kind {
+                EV_SYN => {},
+                EV_LED if event.code < 32 => {
+                    let on = event.value != 0;
+
+                    if (self.leds & 1 << event.code != 0) != on {
+                        self.leds ^= 1 << event.code;
+                        ctx.send_message(format!("The guest turned the {} LED {}", led_name(event.code), if on { "on" } else { "off" }));
+                    }
+                },
+                kind => ctx.send_message(format!("The guest sent status event {kind}:{} {}, which a keyboard ignores", event.code, event.value)),
+            }
+        }
+    }
+
+    fn lit_leds(&self) -> String {
+        let lit: Vec<String> = (0..32).filter(|led| self.leds & 1 << led != 0).map(led_name).collect();
+
+        match lit.is_empty() {
+            true => "no LEDs lit".to_string(),
+            false => format!("{} lit", lit.join(", ")),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioInput {
+    fn device_id(&self) -> u32 {
+        VIRTIO_INPUT_DEVICE_ID
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        vec![0; CONFIG_DATA_OFFSET + CONFIG_DATA_SIZE]
+    }
+
+    fn config_selections(&self) -> Option<ConfigSelections> {
+        let mut devids = BUS_VIRTUAL.to_le_bytes().to_vec();
+        devids.extend_from_slice(&[0, 0, 0, 0, 1, 0]);
+
+        let abs_info: Vec<u8> = [0, ABS_MAX, 0, 0, 0].iter().flat_map(|value| value.to_le_bytes()).collect();
+
+        let answers = HashMap::from([
+            ((VIRTIO_INPUT_CFG_ID_NAME, 0), self.name.as_bytes().to_vec()),
+            ((VIRTIO_INPUT_CFG_ID_SERIAL, 0), SERIAL.as_bytes().to_vec()),
+            ((VIRTIO_INPUT_CFG_ID_DEVIDS, 0), devids),
+            // The tablet moves a pointer around rather than touching the screen where it points
+            ((VIRTIO_INPUT_CFG_PROP_BITS, 0), bitmap(&[INPUT_PROP_POINTER])),
+            ((VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8), bitmap(&supported_keys())),
+            ((VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8), bitmap(&[REL_HWHEEL, REL_WHEEL])),
+            ((VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8), bitmap(&[ABS_X, ABS_Y])),
+            ((VIRTIO_INPUT_CFG_EV_BITS, EV_LED as u8), bitmap(&[LED_NUML, LED_CAPSL, LED_SCROLLL])),
+            ((VIRTIO_INPUT_CFG_ABS_INFO, ABS_X as u8), abs_info.clone()),
+            ((VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8), abs_info),
+        ]);
+
+        Some(ConfigSelections { size_offset: CONFIG_SIZE_OFFSET, data_offset: CONFIG_DATA_OFFSET, answers })
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, queue: u16, chain: DescriptorChain) -> Option<u32> {
+        match queue {
+            EVENTQ => {
+                // Held until there's input to put in it
+                self.buffers.push_back(chain);
+                self.deliver(ctx);
+
+                None
+            },
+            _ => {
+                self.status(ctx, &unsafe { chain.read_all() });
+                Some(0)
+            },
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut DeviceContext) {
+        let events: Vec<InputEvent> = match self.input.as_ref() {
+            Some(input) => input.try_iter().flat_map(|event| events_for(&event)).collect(),
+            None => Vec::new(),
+        };
+
+        self.queue_events(events);
+        self.deliver(ctx);
+    }
+
+    fn reset(&mut self) {
+        self.buffers.clear();
+        self.pending.clear();
+        self.leds = 0;
+    }
+
+    fn command(&mut self, ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command.split_once(' ').unwrap_or((command, "")) {
+            ("stats", _) => Ok(format!(
+                "{} events sent, {} dropped, {} waiting for {} buffers, {}",
+                self.events_sent, self.events_dropped, self.pending.len(), self.buffers.len(), self.lit_leds(),
+            )),
+            ("type", text) if !text.is_empty() => {
+                let keys: Option<Vec<(u16, bool)>> = text.chars().map(char_key).collect();
+                let keys = keys.ok_or(format!("The keyboard can only type ASCII, not {text}"))?;
+
+                for (code, shift) in keys {
+                    self.queue_events(press(code, if shift { &[KEY_LEFTSHIFT] } else { &[] }));
+                }
+
+                self.deliver(ctx);
+                Ok(format!("Typed {} keys", text.chars().count()))
+            },
+            _ => Err(format!("The input device doesn't understand {command}, try stats or type <text>")),
+        }
+    }
+
+    // Just the LEDs, the guest gets its buffers back from the driver on restore
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(self.leds.to_le_bytes().to_vec())
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        self.leds = u32::from_le_bytes(snapshot.try_into().map_err(|_| Error::from_raw_os_error(libc::EINVAL))?);
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_config_pages_and_key_events() {
+    use std::sync::mpsc;
+
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MouseEvent, MouseEventKind, MouseButton};
+    use tokio::sync::mpsc::channel;
+
+    use crate::virtio::{virtqueue::DescriptorCell, vring::VIRTQ_DESC_F_WRITE, transport::{MmioTransport, TransportMode}};
+
+    let (tx, _rx) = channel(16);
+    let mut device = VirtioInput::new(DEFAULT_NAME);
+
+    let mut transport = MmioTransport::new(TransportMode::Modern, VIRTIO_INPUT_DEVICE_ID, 0, &[8; 2]);
+    transport.set_config_space(device.config_space());
+    transport.set_config_selections(device.config_selections());
+    let transport = transport.into_shared();
+
+    let select = |select: u8, subsel: u8| {
+        let mut transport = transport.lock().unwrap();
+        transport.write_config(0, &[select, subsel]);
+
+        let mut size = [0u8];
+        transport.read_config(CONFIG_SIZE_OFFSET as u32, &mut size);
+
+        let mut answer = vec![0u8; size[0] as usize];
+        transport.read_config(CONFIG_DATA_OFFSET as u32, &mut answer);
+
+        answer
+    };
+
+    // The driver pages through names and bitmaps, anything the device doesn't have is empty
+    assert_eq!(select(VIRTIO_INPUT_CFG_ID_NAME, 0), DEFAULT_NAME.as_bytes());
+    assert_eq!(select(VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8), [0b11]);
+    assert_ne!(select(VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8)[BTN_LEFT as usize / 8] & 1 << (BTN_LEFT % 8), 0);
+    assert_eq!(select(VIRTIO_INPUT_CFG_PROP_BITS, 0), [1 << INPUT_PROP_POINTER]);
+    assert!(select(VIRTIO_INPUT_CFG_EV_BITS, EV_SYN as u8).is_empty());
+
+    let (input, receiver) = mpsc::channel();
+    device.set_input(receiver);
+
+    let mut ctx = DeviceContext::new(&tx, transport.clone());
+    let mut buffers = [[0u8; EVENT_SIZE]; 8];
+    let mut led = [InputEvent::new(EV_LED, LED_CAPSL, 1).to_bytes(), InputEvent::syn().to_bytes()].concat();
+
+    let mut table: [DescriptorCell; 9] = Default::default();
+
+    for (idx, buffer) in buffers.iter_mut().enumerate() {
+        table[idx] = DescriptorCell { addr: buffer.as_mut_ptr() as u64, length: EVENT_SIZE as u32, flags: VIRTQ_DESC_F_WRITE, next: 0 };
+    }
+
+    table[8] = DescriptorCell { addr: led.as_mut_ptr() as u64, length: led.len() as u32, flags: 0, next: 0 };
+
+    let table = table.as_mut_ptr();
+    let chain = |head| unsafe { DescriptorChain::new(table, 9, head) };
+
+    for head in 0..8 {
+        assert_eq!(device.process_request(&mut ctx, EVENTQ, chain(head)), None);
+    }
+
+    // A capital letter is shift, the key, then both up again, with a report after each half
+    input.send(Event::Key(KeyEvent::new(KeyCode::Char('Q'), KeyModifiers::SHIFT))).unwrap();
+    input.send(Event::Mouse(MouseEvent { kind: MouseEventKind::Down(MouseButton::Left), column: 3, row: 4, modifiers: KeyModifiers::NONE })).unwrap();
+    device.poll(&mut ctx);
+
+    let events: Vec<InputEvent> = buffers.iter().filter_map(|buffer| InputEvent::parse(buffer)).collect();
+    assert_eq!(&events[..6], &[
+        InputEvent::new(EV_KEY, KEY_LEFTSHIFT, 1),
+        InputEvent::new(EV_KEY, 16, 1),
+        InputEvent::syn(),
+        InputEvent::new(EV_KEY, 16, 0),
+        InputEvent::new(EV_KEY, KEY_LEFTSHIFT, 0),
+        InputEvent::syn(),
+    ]);
+    assert_eq!(&events[6..], &[InputEvent::new(EV_ABS, ABS_X, 3), InputEvent::new(EV_ABS, ABS_Y, 4)]);
+    assert_eq!((ctx.completions.len(), device.pending.len()), (8, 2));
+
+    assert_eq!(device.process_request(&mut ctx, STATUSQ, chain(8)), Some(0));
+    assert_eq!(device.leds, 1 << LED_CAPSL);
+}
@@ -0,0 +1,21 @@
+// Where the frames a virtio-net device sends go, and where the ones it receives come from.
+// Backends that get frames on their own schedule wake the device thread through a
+// `DeviceControl` when one arrives, the device then picks it up with `receive`.
+
+use std::io::Result;
+
+use super::control::RxFilter;
+
+pub trait NetBackend: Send {
+    /// A frame from the guest, without its virtio_net_hdr
+    fn transmit(&mut self, frame: &[u8]) -> Result<()>;
+
+    /// The next frame waiting for the guest, if there is one
+    fn receive(&mut self) -> Option<Vec<u8>>;
+
+    fn describe(&self) -> String;
+
+    /// What the guest wants to receive, for backends that can drop frames before they get to
+    /// the device. The device checks every frame against the filter itself either way.
+    fn set_filter(&mut self, _filter: &RxFilter) {}
+}
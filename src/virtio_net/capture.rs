@@ -0,0 +1,165 @@
+// Packet capture in pcapng, which Wireshark and tcpdump open as is. Frames are recorded as they
+// cross the device's queues, so a transmitted GSO packet shows up whole rather than as the
+// segments that went on the wire. The virtio_net_hdr that came with each frame goes in the
+// packet's comment and the direction in its flags, both from the guest's point of view.
+
+use std::{fs::File, io::{Result, Write}, time::{SystemTime, UNIX_EPOCH}};
+
+use super::NetHeader;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+const EPB_INBOUND: u32 = 1;
+const EPB_OUTBOUND: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the device to the guest
+    Received,
+    /// From the guest to the device
+    Sent,
+}
+
+fn option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend_from_slice(&code.to_le_bytes());
+    block.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    block.extend_from_slice(value);
+    block.resize(block.len().next_multiple_of(4), 0);
+}
+
+/// Wraps a block body in its type and the length it has at both ends
+fn block(kind: u32, mut body: Vec<u8>) -> Vec<u8> {
+    option(&mut body, OPT_END, &[]);
+
+    let length = (body.len() + 12) as u32;
+
+    let mut block = Vec::with_capacity(length as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&length.to_le_bytes());
+    block.extend_from_slice(&body);
+    block.extend_from_slice(&length.to_le_bytes());
+
+    block
+}
+
+pub fn describe_header(header: &NetHeader) -> String {
+    format!("virtio_net_hdr flags {:#04x} gso_type {} hdr_len {} gso_size {} csum_start {} csum_offset {} num_buffers {}",
+        header.flags, header.gso_type, header.hdr_len, header.gso_size, header.csum_start, header.csum_offset, header.num_buffers)
+}
+
+pub struct Capture {
+    file: File,
+    path: String,
+    frames: u64,
+}
+
+impl Capture {
+    /// Starts a new capture file, `interface` is what analyzers show as the interface's name
+    pub fn create(path: &str, interface: &str) -> Result<Self> {
+        let mut file = File::create(path)?;
+
+        let mut section = Vec::new();
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        // The section's length isn't known up front
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        option(&mut section, SHB_USERAPPL, b"virtio-playground");
+
+        let mut interface_description = Vec::new();
+        interface_description.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        interface_description.extend_from_slice(&0u16.to_le_bytes());
+        // No limit on how much of a frame is kept
+        interface_description.extend_from_slice(&0u32.to_le_bytes());
+        option(&mut interface_description, IF_NAME, interface.as_bytes());
+
+        file.write_all(&block(SECTION_HEADER_BLOCK, section))?;
+        file.write_all(&block(INTERFACE_DESCRIPTION_BLOCK, interface_description))?;
+
+        Ok(Self { file, path: path.to_string(), frames: 0 })
+    }
+
+    pub fn record(&mut self, direction: Direction, header: &NetHeader, frame: &[u8]) -> Result<()> {
+        // Microseconds since the epoch, the default resolution
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_micros() as u64);
+
+        let mut packet = Vec::with_capacity(frame.len() + 160);
+        packet.extend_from_slice(&0u32.to_le_bytes());
+        packet.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        packet.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        packet.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        packet.extend_from_slice(frame);
+        packet.resize(packet.len().next_multiple_of(4), 0);
+
+        let flags = match direction {
+            Direction::Received => EPB_INBOUND,
+            Direction::Sent => EPB_OUTBOUND,
+        };
+
+        option(&mut packet, OPT_COMMENT, describe_header(header).as_bytes());
+        option(&mut packet, EPB_FLAGS, &flags.to_le_bytes());
+
+        // A whole block per write, so a capture read while it's still going is never torn
+        self.file.write_all(&block(ENHANCED_PACKET_BLOCK, packet))?;
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    pub fn describe(&self) -> String {
+        format!("capturing to {}, {} frames so far", self.path, self.frames)
+    }
+}
+
+#[test]
+pub fn test_capture_is_pcapng() {
+    let path = std::env::temp_dir().join(format!("virtio-net-capture-{}.pcapng", std::process::id()));
+    let path = path.to_string_lossy().to_string();
+
+    let mut capture = Capture::create(&path, "net0").unwrap();
+    let header = NetHeader { flags: 1, gso_type: 3, ..NetHeader::default() };
+    capture.record(Direction::Sent, &header, &[0xaa; 61]).unwrap();
+    capture.record(Direction::Received, &NetHeader::default(), &[0xbb; 60]).unwrap();
+    drop(capture);
+
+    let bytes = std::fs::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    // Walk the blocks by their lengths, each has the same length at both ends
+    let word = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let length = word(offset + 4) as usize;
+        assert_eq!(length % 4, 0);
+        assert_eq!(word(offset + length - 4) as usize, length);
+
+        blocks.push((word(offset), offset));
+        offset += length;
+    }
+
+    assert_eq!(blocks.iter().map(|(kind, _)| *kind).collect::<Vec<_>>(), [SECTION_HEADER_BLOCK, INTERFACE_DESCRIPTION_BLOCK, ENHANCED_PACKET_BLOCK, ENHANCED_PACKET_BLOCK]);
+    assert_eq!(word(8), BYTE_ORDER_MAGIC);
+
+    // The first packet keeps its odd length, with the header in the comment after the padding
+    let packet = blocks[2].1;
+    assert_eq!((word(packet + 20), word(packet + 24)), (61, 61));
+    assert_eq!(&bytes[packet + 28..packet + 89], &[0xaa; 61]);
+
+    let comment = packet + 28 + 64;
+    assert_eq!(u16::from_le_bytes([bytes[comment], bytes[comment + 1]]), OPT_COMMENT);
+    assert!(String::from_utf8_lossy(&bytes[comment + 4..]).starts_with("virtio_net_hdr flags 0x01 gso_type 3"));
+}
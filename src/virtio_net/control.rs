@@ -0,0 +1,241 @@
+// The control virtqueue. Every command is a class and a command byte followed by the command's
+// data, all device readable, then a single device writable byte the device acks with OK or ERR.
+// Most of the commands program the receive filter, which decides what frames the guest gets.
+
+use std::collections::BTreeSet;
+
+use super::{format_mac, switch::{is_multicast, MacAddress}, ETHERNET_HEADER_SIZE};
+
+pub const VIRTIO_NET_OK: u8 = 0;
+pub const VIRTIO_NET_ERR: u8 = 1;
+
+pub const VIRTIO_NET_CTRL_RX: u8 = 0;
+pub const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+pub const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+pub const VIRTIO_NET_CTRL_RX_ALLUNI: u8 = 2;
+pub const VIRTIO_NET_CTRL_RX_NOMULTI: u8 = 3;
+pub const VIRTIO_NET_CTRL_RX_NOUNI: u8 = 4;
+pub const VIRTIO_NET_CTRL_RX_NOBCAST: u8 = 5;
+
+pub const VIRTIO_NET_CTRL_MAC: u8 = 1;
+pub const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+pub const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+
+pub const VIRTIO_NET_CTRL_VLAN: u8 = 2;
+pub const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
+pub const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
+
+pub const VIRTIO_NET_CTRL_MQ: u8 = 4;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+
+pub const MAX_VLAN_ID: u16 = 4095;
+
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const BROADCAST: MacAddress = [0xff; 6];
+
+pub enum ControlCommand {
+    RxMode(u8, bool),
+    MacTable { unicast: Vec<MacAddress>, multicast: Vec<MacAddress> },
+    MacAddress(MacAddress),
+    VlanAdd(u16),
+    VlanDel(u16),
+    QueuePairs(u16),
+}
+
+/// Reads `count` followed by that many MACs, returns them and what's left
+fn parse_mac_table(bytes: &[u8]) -> Result<(Vec<MacAddress>, &[u8]), String> {
+    let count = bytes.get(0..4).map(|count| u32::from_le_bytes(count.try_into().unwrap()) as usize).ok_or("a MAC table is missing its entry count")?;
+    let end = count.checked_mul(6).map(|length| length + 4).filter(|end| *end <= bytes.len()).ok_or(format!("a MAC table says it has {count} entries but doesn't"))?;
+
+    let macs = bytes[4..end].chunks(6).map(|mac| mac.try_into().unwrap()).collect();
+    Ok((macs, &bytes[end..]))
+}
+
+impl ControlCommand {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let [class, command, data @ ..] = bytes else {
+            return Err("the command is too short to have a header".to_string());
+        };
+
+        let flag = || data.first().map(|on| *on != 0).ok_or(format!("rx mode command {command} is missing its on or off byte"));
+        let word = || data.get(0..2).map(|word| u16::from_le_bytes([word[0], word[1]])).ok_or(format!("class {class} command {command} is missing its value"));
+
+        match (*class, *command) {
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC..=VIRTIO_NET_CTRL_RX_NOBCAST) => Ok(ControlCommand::RxMode(*command, flag()?)),
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET) => {
+                let (unicast, rest) = parse_mac_table(data)?;
+                let (multicast, _) = parse_mac_table(rest)?;
+
+                Ok(ControlCommand::MacTable { unicast, multicast })
+            },
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET) => {
+                let mac = data.get(0..6).ok_or("the new MAC address is too short")?;
+                Ok(ControlCommand::MacAddress(mac.try_into().unwrap()))
+            },
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD) => Ok(ControlCommand::VlanAdd(word()?)),
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_DEL) => Ok(ControlCommand::VlanDel(word()?)),
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => Ok(ControlCommand::QueuePairs(word()?)),
+            _ => Err(format!("class {class} command {command} isn't a command")),
+        }
+    }
+}
+
+/// What the guest has asked to receive. Until the driver says otherwise that's frames for its
+/// own MAC, broadcasts and every multicast group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RxFilter {
+    pub mac: MacAddress,
+
+    pub promiscuous: bool,
+    pub all_multicast: bool,
+    pub all_unicast: bool,
+    pub no_multicast: bool,
+    pub no_unicast: bool,
+    pub no_broadcast: bool,
+
+    pub unicast: Vec<MacAddress>,
+    pub multicast: Vec<MacAddress>,
+
+    // Only applies once the driver has negotiated VLAN filtering, then every VLAN starts out blocked
+    pub vlan_filtering: bool,
+    pub vlans: BTreeSet<u16>,
+}
+
+impl RxFilter {
+    pub fn new(mac: MacAddress) -> Self {
+        Self {
+            mac,
+
+            promiscuous: false,
+            all_multicast: true,
+            all_unicast: false,
+            no_multicast: false,
+            no_unicast: false,
+            no_broadcast: false,
+
+            unicast: Vec::new(),
+            multicast: Vec::new(),
+
+            vlan_filtering: false,
+            vlans: BTreeSet::new(),
+        }
+    }
+
+    pub fn set_mode(&mut self, command: u8, on: bool) {
+        let mode = match command {
+            VIRTIO_NET_CTRL_RX_PROMISC => &mut self.promiscuous,
+            VIRTIO_NET_CTRL_RX_ALLMULTI => &mut self.all_multicast,
+            VIRTIO_NET_CTRL_RX_ALLUNI => &mut self.all_unicast,
+            VIRTIO_NET_CTRL_RX_NOMULTI => &mut self.no_multicast,
+            VIRTIO_NET_CTRL_RX_NOUNI => &mut self.no_unicast,
+            _ => &mut self.no_broadcast,
+        };
+
+        *mode = on;
+    }
+
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            return false;
+        }
+
+        if self.promiscuous {
+            return true;
+        }
+
+        // Untagged frames always get through, tagged ones only for VLANs the driver added
+        if self.vlan_filtering && u16::from_be_bytes([frame[12], frame[13]]) == ETHERTYPE_VLAN {
+            let vlan = frame.get(14..16).map(|tag| u16::from_be_bytes([tag[0], tag[1]]) & MAX_VLAN_ID);
+
+            if !vlan.is_some_and(|vlan| self.vlans.contains(&vlan)) {
+                return false;
+            }
+        }
+
+        let destination: MacAddress = frame[0..6].try_into().unwrap();
+
+        if destination == BROADCAST {
+            !self.no_broadcast
+        } else if is_multicast(&destination) {
+            !self.no_multicast && (self.all_multicast || self.multicast.contains(&destination))
+        } else {
+            !self.no_unicast && (destination == self.mac || self.all_unicast || self.unicast.contains(&destination))
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let modes = [
+            (self.promiscuous, "promisc"),
+            (self.all_multicast, "allmulti"),
+            (self.all_unicast, "alluni"),
+            (self.no_multicast, "nomulti"),
+            (self.no_unicast, "nouni"),
+            (self.no_broadcast, "nobcast"),
+        ];
+
+        let modes: Vec<&str> = modes.iter().filter(|(on, _)| *on).map(|(_, name)| *name).collect();
+        let table = |macs: &[MacAddress]| macs.iter().map(format_mac).collect::<Vec<_>>().join(" ");
+
+        let vlans = match self.vlan_filtering {
+            true => self.vlans.iter().map(|vlan| vlan.to_string()).collect::<Vec<_>>().join(" "),
+            false => "not filtered".to_string(),
+        };
+
+        format!("{} modes [{}] unicast [{}] multicast [{}] vlans [{}]", format_mac(&self.mac), modes.join(" "), table(&self.unicast), table(&self.multicast), vlans)
+    }
+}
+
+#[test]
+pub fn test_filter_follows_control_commands() {
+    let frame = |destination: MacAddress, vlan: Option<u16>| {
+        let mut frame = vec![0u8; 60];
+        frame[0..6].copy_from_slice(&destination);
+        frame[6..12].copy_from_slice(&[2, 0, 0, 0, 0, 9]);
+
+        if let Some(vlan) = vlan {
+            frame[12..14].copy_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+            frame[14..16].copy_from_slice(&vlan.to_be_bytes());
+        }
+
+        frame
+    };
+
+    let ours = [2, 0, 0, 0, 0, 1];
+    let theirs = [2, 0, 0, 0, 0, 2];
+    let group = [0x01, 0x00, 0x5e, 0, 0, 1];
+
+    let mut filter = RxFilter::new(ours);
+    assert!(filter.accepts(&frame(ours, None)));
+    assert!(filter.accepts(&frame(BROADCAST, None)));
+    assert!(filter.accepts(&frame(group, None)));
+    assert!(!filter.accepts(&frame(theirs, None)));
+
+    // A table with one unicast and one multicast entry, and all-multicast turned off
+    let mut table = vec![VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET, 1, 0, 0, 0];
+    table.extend_from_slice(&theirs);
+    table.extend_from_slice(&[1, 0, 0, 0]);
+    table.extend_from_slice(&group);
+
+    let Ok(ControlCommand::MacTable { unicast, multicast }) = ControlCommand::parse(&table) else {
+        panic!("the MAC table didn't parse");
+    };
+
+    filter.unicast = unicast;
+    filter.multicast = multicast;
+    filter.set_mode(VIRTIO_NET_CTRL_RX_ALLMULTI, false);
+
+    assert!(filter.accepts(&frame(theirs, None)));
+    assert!(filter.accepts(&frame(group, None)));
+    assert!(!filter.accepts(&frame([0x01, 0x00, 0x5e, 0, 0, 2], None)));
+
+    // A table that claims more entries than it has is refused
+    assert!(ControlCommand::parse(&table[..12]).is_err());
+
+    filter.vlan_filtering = true;
+    filter.vlans.insert(5);
+    assert!(filter.accepts(&frame(ours, Some(5))));
+    assert!(!filter.accepts(&frame(ours, Some(6))));
+
+    filter.set_mode(VIRTIO_NET_CTRL_RX_PROMISC, true);
+    assert!(filter.accepts(&frame([2, 0, 0, 0, 0, 7], Some(6))));
+}
@@ -0,0 +1,674 @@
+// A virtio-net device. Every frame on the queues is prefixed with a `virtio_net_hdr`, the driver
+// keeps the receive queue stocked with buffers big enough for a header and a full frame and
+// puts frames to send on the transmit queue. What's on the other end is a `NetBackend`.
+//
+// With multiqueue there's a receive and transmit queue per pair and the control queue comes
+// after all of them, otherwise it's queue 2. The driver uses the control queue to program the
+// receive filter and to say how many pairs it's using.
+//
+// The offload features let the driver leave checksums and segmentation to the device on
+// transmit, and get checked and coalesced packets on receive. Everything crossing the queues
+// can be recorded to a pcapng file.
+
+pub mod backend;
+pub mod capture;
+pub mod control;
+pub mod offload;
+pub mod steering;
+pub mod switch;
+pub mod unix_socket;
+
+use std::collections::VecDeque;
+
+use packed_struct::prelude::*;
+
+use crate::virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain};
+
+use self::{backend::NetBackend, capture::{Capture, Direction}, control::{ControlCommand, RxFilter, MAX_VLAN_ID, VIRTIO_NET_CTRL_RX_ALLMULTI, VIRTIO_NET_ERR, VIRTIO_NET_OK}, offload::{Coalescer, GuestOffloads, VIRTIO_NET_HDR_F_NEEDS_CSUM, VIRTIO_NET_HDR_GSO_ECN, VIRTIO_NET_HDR_GSO_NONE, VIRTIO_NET_HDR_GSO_TCPV4, VIRTIO_NET_HDR_GSO_TCPV6, VIRTIO_NET_HDR_GSO_UDP}, steering::FlowTable, switch::MacAddress};
+
+pub const VIRTIO_NET_DEVICE_ID: u32 = 1;
+
+pub const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+pub const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+pub const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+pub const VIRTIO_NET_F_GUEST_TSO4: u64 = 1 << 7;
+pub const VIRTIO_NET_F_GUEST_TSO6: u64 = 1 << 8;
+pub const VIRTIO_NET_F_GUEST_UFO: u64 = 1 << 10;
+pub const VIRTIO_NET_F_HOST_TSO4: u64 = 1 << 11;
+pub const VIRTIO_NET_F_HOST_TSO6: u64 = 1 << 12;
+pub const VIRTIO_NET_F_HOST_UFO: u64 = 1 << 14;
+pub const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+pub const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+pub const VIRTIO_NET_F_CTRL_RX: u64 = 1 << 18;
+pub const VIRTIO_NET_F_CTRL_VLAN: u64 = 1 << 19;
+pub const VIRTIO_NET_F_CTRL_RX_EXTRA: u64 = 1 << 20;
+pub const VIRTIO_NET_F_MQ: u64 = 1 << 22;
+pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u64 = 1 << 23;
+
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+pub const RECEIVEQ: u16 = 0;
+pub const TRANSMITQ: u16 = 1;
+
+pub const DEFAULT_QUEUE_PAIRS: u16 = 2;
+pub const MAX_QUEUE_PAIRS: u16 = 0x8000;
+
+// With VIRTIO_F_VERSION_1 the header always has num_buffers, so it's always this long
+pub const NET_HEADER_SIZE: usize = 12;
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+pub const DEFAULT_MTU: u16 = 1500;
+pub const MAX_FRAME_SIZE: usize = ETHERNET_HEADER_SIZE + DEFAULT_MTU as usize;
+
+// The biggest packet GSO can hand over in either direction, a driver that takes the guest
+// offloads posts receive buffers this big after the header
+pub const MAX_GSO_FRAME_SIZE: usize = 65550;
+
+// Frames from the backend waiting for a receive buffer, past this they're dropped
+const MAX_PENDING_FRAMES: usize = 256;
+
+#[derive(PackedStruct)]
+#[packed_struct(endian="lsb", bit_numbering="msb0")]
+pub struct NetConfig {
+    #[packed_field(bytes="0x00..=0x05")]
+    mac: [u8; 6],
+
+    #[packed_field(bytes="0x06..=0x07")]
+    status: Integer<u16, packed_bits::Bits::<16>>,
+
+    #[packed_field(bytes="0x08..=0x09")]
+    max_virtqueue_pairs: Integer<u16, packed_bits::Bits::<16>>,
+
+    #[packed_field(bytes="0x0a..=0x0b")]
+    mtu: Integer<u16, packed_bits::Bits::<16>>,
+}
+
+/// A `virtio_net_hdr`, all zeroes for a frame that needs nothing done to it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetHeader {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+    pub num_buffers: u16,
+}
+
+impl NetHeader {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < NET_HEADER_SIZE {
+            return None;
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+        Some(Self {
+            flags: bytes[0],
+            gso_type: bytes[1],
+            hdr_len: word(2),
+            gso_size: word(4),
+            csum_start: word(6),
+            csum_offset: word(8),
+            num_buffers: word(10),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; NET_HEADER_SIZE] {
+        let mut bytes = [0; NET_HEADER_SIZE];
+        bytes[0] = self.flags;
+        bytes[1] = self.gso_type;
+        bytes[2..4].copy_from_slice(&self.hdr_len.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.gso_size.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.csum_start.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.csum_offset.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.num_buffers.to_le_bytes());
+
+        bytes
+    }
+}
+
+/// The ones' complement sum IP, ICMP, TCP and UDP all use, zero when `data` checks out
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32).sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+pub fn format_mac(mac: &MacAddress) -> String {
+    mac.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>().join(":")
+}
+
+pub fn receive_queue(pair: u16) -> u16 {
+    pair * 2
+}
+
+pub fn transmit_queue(pair: u16) -> u16 {
+    pair * 2 + 1
+}
+
+/// A capture that can't be written to any more is stopped rather than failing every frame
+fn record(capture: &mut Option<Capture>, ctx: &mut DeviceContext, direction: Direction, header: &NetHeader, frame: &[u8]) {
+    if let Some(Err(err)) = capture.as_mut().map(|capture| capture.record(direction, header, frame)) {
+        *capture = None;
+        ctx.send_message(format!("Stopped capturing, writing a frame failed: {err}"));
+    }
+}
+
+pub struct VirtioNet {
+    mac: MacAddress,
+    link_up: bool,
+
+    // Plugged in once the device's queues exist, the backend wakes them
+    backend: Option<Box<dyn NetBackend>>,
+
+    features: u64,
+    queue_pairs: u16,
+    active_pairs: u16,
+
+    filter: RxFilter,
+    flows: FlowTable,
+
+    // Both indexed by queue pair
+    pending_frames: Vec<VecDeque<Vec<u8>>>,
+    receive_buffers: Vec<VecDeque<DescriptorChain>>,
+
+    tx_frames: Vec<u64>,
+    rx_frames: Vec<u64>,
+    dropped_frames: u64,
+    filtered_frames: u64,
+    segmented_frames: u64,
+    coalesced_frames: u64,
+
+    // IPv6 fragments of one datagram share an id
+    fragment_id: u32,
+
+    capture: Option<Capture>,
+}
+
+impl VirtioNet {
+    pub fn new(mac: MacAddress) -> Self {
+        Self {
+            mac,
+            link_up: true,
+
+            backend: None,
+
+            features: 0,
+            queue_pairs: 1,
+            active_pairs: 1,
+
+            filter: RxFilter::new(mac),
+            flows: FlowTable::default(),
+
+            pending_frames: vec![VecDeque::new()],
+            receive_buffers: vec![VecDeque::new()],
+
+            tx_frames: vec![0],
+            rx_frames: vec![0],
+            dropped_frames: 0,
+            filtered_frames: 0,
+            segmented_frames: 0,
+            coalesced_frames: 0,
+
+            fragment_id: 0,
+
+            capture: None,
+        }
+    }
+
+    /// Offers multiqueue with up to `pairs` receive and transmit queue pairs
+    pub fn with_queue_pairs(mut self, pairs: u16) -> Self {
+        let pairs = pairs.clamp(1, MAX_QUEUE_PAIRS);
+
+        self.queue_pairs = pairs;
+        self.pending_frames = (0..pairs).map(|_| VecDeque::new()).collect();
+        self.receive_buffers = (0..pairs).map(|_| VecDeque::new()).collect();
+        self.tx_frames = vec![0; pairs as usize];
+        self.rx_frames = vec![0; pairs as usize];
+
+        self
+    }
+
+    pub fn set_backend(&mut self, mut backend: Box<dyn NetBackend>) {
+        backend.set_filter(&self.filter);
+        self.backend = Some(backend);
+    }
+
+    /// Records every frame crossing the queues to `path` from now on
+    pub fn start_capture(&mut self, path: &str) -> std::result::Result<String, String> {
+        let capture = Capture::create(path, &format!("virtio-net {}", format_mac(&self.mac))).map_err(|err| format!("Couldn't start capturing to {path}: {err}"))?;
+        self.capture = Some(capture);
+
+        Ok(format!("Capturing to {path}"))
+    }
+
+    pub fn stop_capture(&mut self) -> String {
+        match self.capture.take() {
+            Some(capture) => format!("Stopped {}", capture.describe()),
+            None => "There's no capture running".to_string(),
+        }
+    }
+
+    /// Where `capture` with no path records to
+    fn default_capture_path(&self) -> String {
+        let name = format!("virtio-net-{}.pcapng", format_mac(&self.mac).replace(':', "-"));
+        std::env::temp_dir().join(name).to_string_lossy().to_string()
+    }
+
+    fn control_queue(&self) -> u16 {
+        match self.features & VIRTIO_NET_F_MQ {
+            0 => 2,
+            _ => self.queue_pairs * 2,
+        }
+    }
+
+    fn require(&self, feature: u64, name: &str) -> std::result::Result<(), String> {
+        match self.features & feature {
+            0 => Err(format!("{name} wasn't negotiated")),
+            _ => Ok(()),
+        }
+    }
+
+    fn apply_control(&mut self, ctx: &mut DeviceContext, command: ControlCommand) -> std::result::Result<String, String> {
+        let message = match command {
+            ControlCommand::RxMode(mode, on) => {
+                match mode {
+                    0..=VIRTIO_NET_CTRL_RX_ALLMULTI => self.require(VIRTIO_NET_F_CTRL_RX, "VIRTIO_NET_F_CTRL_RX")?,
+                    _ => self.require(VIRTIO_NET_F_CTRL_RX_EXTRA, "VIRTIO_NET_F_CTRL_RX_EXTRA")?,
+                }
+
+                self.filter.set_mode(mode, on);
+                format!("Receive filter is now {}", self.filter.describe())
+            },
+            ControlCommand::MacTable { unicast, multicast } => {
+                self.require(VIRTIO_NET_F_CTRL_RX, "VIRTIO_NET_F_CTRL_RX")?;
+
+                self.filter.unicast = unicast;
+                self.filter.multicast = multicast;
+                format!("Receive filter is now {}", self.filter.describe())
+            },
+            ControlCommand::MacAddress(mac) => {
+                self.require(VIRTIO_NET_F_CTRL_MAC_ADDR, "VIRTIO_NET_F_CTRL_MAC_ADDR")?;
+
+                self.mac = mac;
+                self.filter.mac = mac;
+                ctx.config_changed();
+
+                format!("The driver set the MAC address to {}", format_mac(&mac))
+            },
+            ControlCommand::VlanAdd(vlan) | ControlCommand::VlanDel(vlan) if vlan > MAX_VLAN_ID => return Err(format!("{vlan} isn't a VLAN id")),
+            ControlCommand::VlanAdd(vlan) => {
+                self.require(VIRTIO_NET_F_CTRL_VLAN, "VIRTIO_NET_F_CTRL_VLAN")?;
+
+                self.filter.vlans.insert(vlan);
+                format!("Receive filter is now {}", self.filter.describe())
+            },
+            ControlCommand::VlanDel(vlan) => {
+                self.require(VIRTIO_NET_F_CTRL_VLAN, "VIRTIO_NET_F_CTRL_VLAN")?;
+
+                self.filter.vlans.remove(&vlan);
+                format!("Receive filter is now {}", self.filter.describe())
+            },
+            ControlCommand::QueuePairs(pairs) => {
+                self.require(VIRTIO_NET_F_MQ, "VIRTIO_NET_F_MQ")?;
+
+                if pairs == 0 || pairs > self.queue_pairs {
+                    return Err(format!("{pairs} queue pairs asked for, the device has 1 to {}", self.queue_pairs));
+                }
+
+                self.set_active_pairs(pairs);
+                return Ok(format!("The driver is using {pairs} queue pairs"));
+            },
+        };
+
+        if let Some(backend) = self.backend.as_mut() {
+            backend.set_filter(&self.filter);
+        }
+
+        Ok(message)
+    }
+
+    fn set_active_pairs(&mut self, pairs: u16) {
+        // Frames already steered to a pair that's going away go to the first one instead
+        for pair in pairs..self.active_pairs {
+            let frames: Vec<_> = self.pending_frames[pair as usize].drain(..).collect();
+            self.pending_frames[0].extend(frames);
+        }
+
+        self.active_pairs = pairs;
+    }
+
+    fn control(&mut self, ctx: &mut DeviceContext, chain: &DescriptorChain) -> u32 {
+        let command = ControlCommand::parse(&unsafe { chain.read_all() });
+
+        let ack = match command.and_then(|command| self.apply_control(ctx, command)) {
+            Ok(message) => {
+                ctx.send_message(message);
+                VIRTIO_NET_OK
+            },
+            Err(reason) => {
+                ctx.send_message(format!("Refused a control command: {reason}"));
+                VIRTIO_NET_ERR
+            },
+        };
+
+        unsafe { chain.write_at(0, &[ack]) as u32 }
+    }
+
+    fn guest_offloads(&self) -> GuestOffloads {
+        let negotiated = |feature| self.features & feature != 0;
+
+        GuestOffloads {
+            csum: negotiated(VIRTIO_NET_F_GUEST_CSUM),
+            tso4: negotiated(VIRTIO_NET_F_GUEST_TSO4),
+            tso6: negotiated(VIRTIO_NET_F_GUEST_TSO6),
+            ufo: negotiated(VIRTIO_NET_F_GUEST_UFO),
+        }
+    }
+
+    /// Does what the header asks for and returns the frames that go on the wire
+    fn offload_transmit(&mut self, header: &NetHeader, mut frame: Vec<u8>) -> std::result::Result<Vec<Vec<u8>>, String> {
+        if header.gso_type & VIRTIO_NET_HDR_GSO_ECN != 0 {
+            return Err("the packet asked for ECN, which isn't negotiable".to_string());
+        }
+
+        let feature = match header.gso_type {
+            VIRTIO_NET_HDR_GSO_NONE => None,
+            VIRTIO_NET_HDR_GSO_TCPV4 => Some((VIRTIO_NET_F_HOST_TSO4, "VIRTIO_NET_F_HOST_TSO4")),
+            VIRTIO_NET_HDR_GSO_TCPV6 => Some((VIRTIO_NET_F_HOST_TSO6, "VIRTIO_NET_F_HOST_TSO6")),
+            VIRTIO_NET_HDR_GSO_UDP => Some((VIRTIO_NET_F_HOST_UFO, "VIRTIO_NET_F_HOST_UFO")),
+            gso_type => return Err(format!("gso_type {gso_type} isn't a GSO type")),
+        };
+
+        // Segmenting fills in every checksum, so NEEDS_CSUM comes for free
+        if let Some((feature, name)) = feature {
+            self.require(feature, name)?;
+            self.fragment_id = self.fragment_id.wrapping_add(1);
+
+            let frames = offload::segment(&frame, header, self.fragment_id)?;
+            self.segmented_frames += frames.len() as u64;
+
+            return Ok(frames);
+        }
+
+        // With room for a VLAN tag
+        if frame.len() > MAX_FRAME_SIZE + 4 {
+            return Err(format!("a {} byte frame is too big to send without GSO", frame.len()));
+        }
+
+        if header.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+            self.require(VIRTIO_NET_F_CSUM, "VIRTIO_NET_F_CSUM")?;
+            offload::complete_checksum(&mut frame, header.csum_start as usize, header.csum_offset as usize)?;
+        }
+
+        Ok(vec![frame])
+    }
+
+    fn transmit(&mut self, ctx: &mut DeviceContext, pair: u16, packet: &[u8]) {
+        let Some(header) = NetHeader::parse(packet) else {
+            ctx.send_message("Dropped a transmit buffer without a net header".to_string());
+            return;
+        };
+
+        record(&mut self.capture, ctx, Direction::Sent, &header, &packet[NET_HEADER_SIZE..]);
+
+        let frames = match self.offload_transmit(&header, packet[NET_HEADER_SIZE..].to_vec()) {
+            Ok(frames) => frames,
+            Err(reason) => {
+                self.dropped_frames += 1;
+                ctx.send_message(format!("Dropped a frame to send: {reason}"));
+                return;
+            },
+        };
+
+        for frame in frames {
+            // Nothing gets out over a cable that isn't plugged in
+            let Some(backend) = self.backend.as_mut().filter(|_| self.link_up) else {
+                self.dropped_frames += 1;
+                return;
+            };
+
+            match backend.transmit(&frame) {
+                Ok(()) => {
+                    self.tx_frames[pair as usize] += 1;
+                    self.flows.record_transmit(&frame, pair);
+                },
+                Err(err) => {
+                    self.dropped_frames += 1;
+                    ctx.send_message(format!("Sending a frame failed: {err}"));
+                },
+            }
+        }
+    }
+
+    unsafe fn fill_receive_buffers(&mut self, ctx: &mut DeviceContext, pair: u16) {
+        let offloads = self.guest_offloads();
+        let (pending, buffers) = (&mut self.pending_frames[pair as usize], &mut self.receive_buffers[pair as usize]);
+
+        while !pending.is_empty() {
+            let Some(chain) = buffers.pop_front() else {
+                return;
+            };
+
+            // Whatever follows on from the first frame and still fits the buffer goes up with it
+            let room = chain.writable_len().saturating_sub(NET_HEADER_SIZE);
+            let mut coalescer = Coalescer::new(pending.pop_front().unwrap(), offloads);
+
+            while pending.front().is_some_and(|next| coalescer.append(next, room)) {
+                pending.pop_front();
+            }
+
+            if coalescer.segments > 1 {
+                self.coalesced_frames += coalescer.segments as u64;
+            }
+
+            let (mut header, frame) = coalescer.finish();
+
+            if chain.writable_len() < NET_HEADER_SIZE + frame.len() {
+                // The buffer goes back empty rather than with half a frame in it
+                self.dropped_frames += 1;
+                ctx.complete(receive_queue(pair), chain, 0);
+                continue;
+            }
+
+            header.num_buffers = 1;
+            record(&mut self.capture, ctx, Direction::Received, &header, &frame);
+
+            chain.write_at(0, &header.to_bytes());
+            chain.write_at(NET_HEADER_SIZE, &frame);
+
+            self.rx_frames[pair as usize] += 1;
+            ctx.complete(receive_queue(pair), chain, (NET_HEADER_SIZE + frame.len()) as u32);
+        }
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_NET_DEVICE_ID
+    }
+
+    fn device_features(&self) -> u64 {
+        let features = VIRTIO_NET_F_MTU | VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS | VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_CTRL_RX
+            | VIRTIO_NET_F_CTRL_RX_EXTRA | VIRTIO_NET_F_CTRL_VLAN | VIRTIO_NET_F_CTRL_MAC_ADDR
+            | VIRTIO_NET_F_CSUM | VIRTIO_NET_F_HOST_TSO4 | VIRTIO_NET_F_HOST_TSO6 | VIRTIO_NET_F_HOST_UFO
+            | VIRTIO_NET_F_GUEST_CSUM | VIRTIO_NET_F_GUEST_TSO4 | VIRTIO_NET_F_GUEST_TSO6 | VIRTIO_NET_F_GUEST_UFO;
+
+        match self.queue_pairs {
+            1 => features,
+            _ => features | VIRTIO_NET_F_MQ,
+        }
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        let config = NetConfig {
+            mac: self.mac,
+            status: (if self.link_up { VIRTIO_NET_S_LINK_UP } else { 0 }).into(),
+            max_virtqueue_pairs: self.queue_pairs.into(),
+            mtu: DEFAULT_MTU.into(),
+        };
+
+        config.pack().unwrap().to_vec()
+    }
+
+    fn queue_count(&self) -> usize {
+        self.queue_pairs as usize * 2 + 1
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.features = features;
+        self.filter.vlan_filtering = features & VIRTIO_NET_F_CTRL_VLAN != 0;
+
+        if let Some(backend) = self.backend.as_mut() {
+            backend.set_filter(&self.filter);
+        }
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, queue: u16, chain: DescriptorChain) -> Option<u32> {
+        if queue == self.control_queue() {
+            return Some(self.control(ctx, &chain));
+        }
+
+        let pair = queue / 2;
+
+        // Queues past the pairs the driver asked for aren't in use
+        if pair >= self.active_pairs {
+            ctx.send_message(format!("Queue {queue} was used but only {} queue pairs are active", self.active_pairs));
+            return Some(0);
+        }
+
+        match queue % 2 {
+            0 => {
+                self.receive_buffers[pair as usize].push_back(chain);
+                None
+            },
+            _ => {
+                let packet = unsafe { chain.read_all() };
+                self.transmit(ctx, pair, &packet);
+
+                Some(0)
+            },
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut DeviceContext) {
+        while let Some(frame) = self.backend.as_mut().and_then(|backend| backend.receive()) {
+            if !self.filter.accepts(&frame) {
+                self.filtered_frames += 1;
+                continue;
+            }
+
+            let pair = self.flows.steer(&frame, self.active_pairs) as usize;
+
+            if !self.link_up || self.pending_frames[pair].len() >= MAX_PENDING_FRAMES {
+                self.dropped_frames += 1;
+                continue;
+            }
+
+            self.pending_frames[pair].push_back(frame);
+        }
+
+        for pair in 0..self.active_pairs {
+            unsafe { self.fill_receive_buffers(ctx, pair) };
+        }
+    }
+
+    fn reset(&mut self) {
+        self.receive_buffers.iter_mut().for_each(VecDeque::clear);
+        self.pending_frames.iter_mut().for_each(VecDeque::clear);
+
+        self.features = 0;
+        self.active_pairs = 1;
+        self.filter = RxFilter::new(self.mac);
+        self.flows.clear();
+
+        if let Some(backend) = self.backend.as_mut() {
+            backend.set_filter(&self.filter);
+        }
+    }
+
+    fn command(&mut self, ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["link", state @ ("up" | "down")] => {
+                self.link_up = *state == "up";
+                ctx.config_changed();
+
+                Ok(format!("Link is {state}"))
+            },
+            ["stats"] => {
+                let per_pair = |frames: &[u64]| frames[..self.active_pairs as usize].iter().map(|count| count.to_string()).collect::<Vec<_>>().join("/");
+                let backend = self.backend.as_ref().map_or("nothing".to_string(), |backend| backend.describe());
+
+                Ok(format!("{} on {backend}: {} frames sent, {} received per queue pair, {} dropped, {} filtered, {} segmented, {} coalesced", format_mac(&self.mac), per_pair(&self.tx_frames), per_pair(&self.rx_frames), self.dropped_frames, self.filtered_frames, self.segmented_frames, self.coalesced_frames))
+            },
+            ["capture"] if self.capture.is_some() => Ok(self.stop_capture()),
+            ["capture"] => self.start_capture(&self.default_capture_path()),
+            ["capture", "off"] => Ok(self.stop_capture()),
+            ["capture", "status"] => Ok(self.capture.as_ref().map_or("Not capturing".to_string(), |capture| format!("{} {}", format_mac(&self.mac), capture.describe()))),
+            ["capture", path] => self.start_capture(path),
+            ["filters"] => Ok(format!("{}, {} of {} queue pairs in use", self.filter.describe(), self.active_pairs, self.queue_pairs)),
+            _ => Err(format!("The net device doesn't understand {command}, try link up, link down, stats, filters or capture [off|status|<path>]")),
+        }
+    }
+}
+
+#[test]
+pub fn test_frames_cross_the_switch() {
+    use tokio::sync::mpsc::channel;
+
+    use crate::{poller::PollableQueue, virtio::{virtqueue::DescriptorCell, vring::VIRTQ_DESC_F_WRITE, transport::{MmioTransport, TransportMode}}};
+
+    use self::switch::VirtualSwitch;
+
+    struct NoWaker;
+
+    impl PollableQueue for NoWaker {
+        fn wait_for_event(&self) {}
+        fn submit_event(&self) {}
+    }
+
+    let (tx, _rx) = channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, VIRTIO_NET_DEVICE_ID, 0, &[4, 4]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport);
+
+    let switch = VirtualSwitch::new();
+    let mut first = VirtioNet::new([2, 0, 0, 0, 0, 1]);
+    let mut second = VirtioNet::new([2, 0, 0, 0, 0, 2]);
+    first.set_backend(Box::new(switch.connect(NoWaker)));
+    second.set_backend(Box::new(switch.connect(NoWaker)));
+
+    let mut packet = vec![0u8; NET_HEADER_SIZE + 60];
+    packet[NET_HEADER_SIZE..NET_HEADER_SIZE + 6].copy_from_slice(&[0xff; 6]);
+    packet[NET_HEADER_SIZE + 6..NET_HEADER_SIZE + 12].copy_from_slice(&[2, 0, 0, 0, 0, 1]);
+
+    let mut small = [0u8; 32];
+    let mut large = [0xffu8; NET_HEADER_SIZE + MAX_FRAME_SIZE];
+
+    let mut table = [
+        DescriptorCell { addr: packet.as_mut_ptr() as u64, length: packet.len() as u32, flags: 0, next: 0 },
+        DescriptorCell { addr: small.as_mut_ptr() as u64, length: 32, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        DescriptorCell { addr: large.as_mut_ptr() as u64, length: large.len() as u32, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        DescriptorCell::default(),
+    ];
+
+    let table = table.as_mut_ptr();
+    let chain = |head| unsafe { DescriptorChain::new(table, 4, head) };
+
+    assert_eq!(first.process_request(&mut ctx, TRANSMITQ, chain(0)), Some(0));
+    assert_eq!(first.process_request(&mut ctx, TRANSMITQ, chain(0)), Some(0));
+
+    // The first frame doesn't fit the small buffer and is dropped, the second lands whole
+    assert_eq!(second.process_request(&mut ctx, RECEIVEQ, chain(1)), None);
+    assert_eq!(second.process_request(&mut ctx, RECEIVEQ, chain(2)), None);
+    second.poll(&mut ctx);
+
+    assert_eq!(ctx.completions.iter().map(|completion| (completion.head, completion.length)).collect::<Vec<_>>(), [(1, 0), (2, 72)]);
+    assert_eq!(NetHeader::parse(&large).unwrap(), NetHeader { num_buffers: 1, ..NetHeader::default() });
+    assert_eq!(&large[NET_HEADER_SIZE..NET_HEADER_SIZE + 60], &packet[NET_HEADER_SIZE..]);
+}
@@ -0,0 +1,617 @@
+// Checksum and segmentation offloads. On transmit a driver can leave the TCP or UDP checksum to
+// the device (NEEDS_CSUM) and hand over packets far bigger than the MTU for the device to cut
+// into segments or IP fragments (gso_type). On receive a driver that negotiated the guest
+// offloads gets frames whose checksums were already checked (DATA_VALID), and runs of segments
+// from one flow glued back into a single big packet the way GRO would.
+
+use super::{NetHeader, ETHERNET_HEADER_SIZE};
+
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+pub const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+pub const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+pub const IP_PROTOCOL_TCP: u8 = 6;
+pub const IP_PROTOCOL_UDP: u8 = 17;
+const IPV6_FRAGMENT_HEADER: u8 = 44;
+
+const IPV6_HEADER_SIZE: usize = 40;
+const IPV6_FRAGMENT_HEADER_SIZE: usize = 8;
+pub const UDP_HEADER_SIZE: usize = 8;
+const TCP_HEADER_SIZE: usize = 20;
+
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_OFFSET_MASK: u16 = 0x1fff;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_CWR: u8 = 0x80;
+
+fn word(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn set_word(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn long(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Ones' complement sum without the final fold, so sums over pieces can be chained
+fn add(sum: u32, data: &[u8]) -> u32 {
+    data.chunks(2).fold(sum, |sum, pair| {
+        let sum = sum + u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32;
+        (sum & 0xffff) + (sum >> 16)
+    })
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+/// Where the headers of an IP packet in a frame are
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    network: usize,
+    ipv6: bool,
+    protocol: u8,
+    transport: usize,
+    payload: usize,
+}
+
+impl Layout {
+    fn parse(frame: &[u8]) -> Result<Self, String> {
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            return Err("the frame is a runt".to_string());
+        }
+
+        let (mut network, mut ethertype) = (ETHERNET_HEADER_SIZE, word(frame, 12));
+
+        if ethertype == ETHERTYPE_VLAN && frame.len() >= ETHERNET_HEADER_SIZE + 4 {
+            network += 4;
+            ethertype = word(frame, 16);
+        }
+
+        let (ipv6, protocol, transport) = match ethertype {
+            ETHERTYPE_IPV4 if frame.len() >= network + 20 => (false, frame[network + 9], network + (frame[network] & 0xf) as usize * 4),
+            ETHERTYPE_IPV6 if frame.len() >= network + IPV6_HEADER_SIZE => (true, frame[network + 6], network + IPV6_HEADER_SIZE),
+            _ => return Err("it isn't an IP packet".to_string()),
+        };
+
+        let payload = match protocol {
+            // Segmenting reads the sequence number, flags and checksum, so all 20 bytes have to be there
+            IP_PROTOCOL_TCP => match frame.get(transport + 12).map(|offset| (offset >> 4) as usize * 4) {
+                Some(length) if length >= TCP_HEADER_SIZE => transport + length,
+                Some(_) => return Err("its TCP header is shorter than 20 bytes".to_string()),
+                None => return Err("its headers run past the end of the frame".to_string()),
+            },
+            IP_PROTOCOL_UDP => transport + UDP_HEADER_SIZE,
+            _ => transport,
+        };
+
+        if transport < network + 20 || payload > frame.len() {
+            return Err("its headers run past the end of the frame".to_string());
+        }
+
+        Ok(Self { network, ipv6, protocol, transport, payload })
+    }
+
+    /// Where the IP packet ends, Ethernet padding isn't part of it
+    fn end(&self, frame: &[u8]) -> usize {
+        let length = match self.ipv6 {
+            true => self.network + IPV6_HEADER_SIZE + word(frame, self.network + 4) as usize,
+            false => self.network + word(frame, self.network + 2) as usize,
+        };
+
+        length.clamp(self.payload, frame.len())
+    }
+
+    fn checksum_offset(&self) -> Option<usize> {
+        match self.protocol {
+            IP_PROTOCOL_TCP => Some(16),
+            IP_PROTOCOL_UDP => Some(6),
+            _ => None,
+        }
+    }
+
+    fn is_fragment(&self, frame: &[u8]) -> bool {
+        !self.ipv6 && word(frame, self.network + 6) & (IPV4_MORE_FRAGMENTS | IPV4_OFFSET_MASK) != 0
+    }
+
+    fn addresses<'a>(&self, frame: &'a [u8]) -> &'a [u8] {
+        match self.ipv6 {
+            true => &frame[self.network + 8..self.network + 40],
+            false => &frame[self.network + 12..self.network + 20],
+        }
+    }
+
+    fn pseudo_header(&self, frame: &[u8], length: usize) -> u32 {
+        let sum = add(0, self.addresses(frame)) + self.protocol as u32 + (length as u32 & 0xffff) + (length as u32 >> 16);
+        fold(sum) as u32
+    }
+
+    fn set_length(&self, frame: &mut [u8]) {
+        match self.ipv6 {
+            true => set_word(frame, self.network + 4, (frame.len() - self.network - IPV6_HEADER_SIZE) as u16),
+            false => {
+                set_word(frame, self.network + 2, (frame.len() - self.network) as u16);
+                set_ipv4_checksum(frame, self.network, self.transport);
+            },
+        }
+    }
+}
+
+fn set_ipv4_checksum(frame: &mut [u8], network: usize, transport: usize) {
+    set_word(frame, network + 10, 0);
+    let checksum = !fold(add(0, &frame[network..transport]));
+    set_word(frame, network + 10, checksum);
+}
+
+/// Fills in the TCP or UDP checksum from scratch, `frame` ends where the IP packet does
+fn set_transport_checksum(frame: &mut [u8], layout: &Layout) {
+    let Some(offset) = layout.checksum_offset() else {
+        return;
+    };
+
+    set_word(frame, layout.transport + offset, 0);
+
+    let length = frame.len() - layout.transport;
+    let mut checksum = !fold(add(layout.pseudo_header(frame, length), &frame[layout.transport..]));
+
+    // Zero means no checksum at all to UDP
+    if layout.protocol == IP_PROTOCOL_UDP && checksum == 0 {
+        checksum = 0xffff;
+    }
+
+    set_word(frame, layout.transport + offset, checksum);
+}
+
+/// Whether the TCP or UDP checksum of an unfragmented packet checks out
+pub fn verify_checksum(frame: &[u8]) -> bool {
+    let Ok(layout) = Layout::parse(frame) else {
+        return false;
+    };
+
+    let Some(offset) = layout.checksum_offset().filter(|_| !layout.is_fragment(frame)) else {
+        return false;
+    };
+
+    let end = layout.end(frame);
+
+    if layout.protocol == IP_PROTOCOL_UDP && !layout.ipv6 && word(frame, layout.transport + offset) == 0 {
+        return true;
+    }
+
+    fold(add(layout.pseudo_header(frame, end - layout.transport), &frame[layout.transport..end])) == 0xffff
+}
+
+/// NEEDS_CSUM, the field at `start + offset` already holds the pseudo header sum
+pub fn complete_checksum(frame: &mut [u8], start: usize, offset: usize) -> Result<(), String> {
+    if start + offset + 2 > frame.len() {
+        return Err(format!("csum_start {start} and csum_offset {offset} point past the end of a {} byte frame", frame.len()));
+    }
+
+    let checksum = !fold(add(0, &frame[start..]));
+    set_word(frame, start + offset, checksum);
+
+    Ok(())
+}
+
+/// What a driver does to hand a TCP or UDP packet's checksum, and its segmentation when it's
+/// bigger than `mtu`, to the device. Anything else goes with an empty header.
+pub fn prepare_transmit(frame: &mut [u8], mtu: usize) -> NetHeader {
+    let Ok(layout) = Layout::parse(frame) else {
+        return NetHeader::default();
+    };
+
+    let Some(offset) = layout.checksum_offset().filter(|_| !layout.is_fragment(frame)) else {
+        return NetHeader::default();
+    };
+
+    let end = layout.end(frame);
+    let partial = layout.pseudo_header(frame, end - layout.transport) as u16;
+    set_word(frame, layout.transport + offset, partial);
+
+    let ip_payload = mtu - (layout.transport - layout.network);
+
+    let (gso_type, gso_size) = match (layout.protocol, layout.ipv6) {
+        _ if end - layout.network <= mtu => (VIRTIO_NET_HDR_GSO_NONE, 0),
+        (IP_PROTOCOL_TCP, false) => (VIRTIO_NET_HDR_GSO_TCPV4, ip_payload - (layout.payload - layout.transport)),
+        (IP_PROTOCOL_TCP, true) => (VIRTIO_NET_HDR_GSO_TCPV6, ip_payload - (layout.payload - layout.transport)),
+        _ => (VIRTIO_NET_HDR_GSO_UDP, ip_payload),
+    };
+
+    NetHeader {
+        flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+        gso_type,
+        hdr_len: layout.payload as u16,
+        gso_size: gso_size as u16,
+        csum_start: layout.transport as u16,
+        csum_offset: offset as u16,
+        num_buffers: 0,
+    }
+}
+
+/// Cuts a GSO packet from the driver into frames that fit on the wire, checksums filled in.
+/// For TCP `gso_size` is the MSS, for UDP it's how much IP payload each fragment carries.
+pub fn segment(frame: &[u8], header: &NetHeader, fragment_id: u32) -> Result<Vec<Vec<u8>>, String> {
+    let gso_size = header.gso_size as usize;
+
+    if gso_size == 0 {
+        return Err("gso_type is set but gso_size is 0".to_string());
+    }
+
+    let layout = Layout::parse(frame)?;
+    let frame = &frame[..layout.end(frame)];
+
+    match (header.gso_type & !VIRTIO_NET_HDR_GSO_ECN, layout.protocol, layout.ipv6) {
+        (VIRTIO_NET_HDR_GSO_TCPV4, IP_PROTOCOL_TCP, false) | (VIRTIO_NET_HDR_GSO_TCPV6, IP_PROTOCOL_TCP, true) => Ok(segment_tcp(frame, &layout, gso_size)),
+        (VIRTIO_NET_HDR_GSO_UDP, IP_PROTOCOL_UDP, false) => Ok(fragment_ipv4(frame, &layout, gso_size)),
+        (VIRTIO_NET_HDR_GSO_UDP, IP_PROTOCOL_UDP, true) => Ok(fragment_ipv6(frame, &layout, gso_size, fragment_id)),
+        (gso_type, protocol, _) => Err(format!("gso_type {gso_type} doesn't match a packet with IP protocol {protocol}")),
+    }
+}
+
+fn segment_tcp(frame: &[u8], layout: &Layout, mss: usize) -> Vec<Vec<u8>> {
+    let (headers, data) = frame.split_at(layout.payload);
+
+    let sequence = long(frame, layout.transport + 4);
+    let flags = frame[layout.transport + 13];
+    let id = word(frame, layout.network + 4);
+
+    let chunks: Vec<&[u8]> = match data.is_empty() {
+        true => vec![data],
+        false => data.chunks(mss).collect(),
+    };
+
+    let count = chunks.len();
+
+    chunks.into_iter().enumerate().map(|(index, chunk)| {
+        let mut segment = headers.to_vec();
+        segment.extend_from_slice(chunk);
+
+        // FIN and PSH belong to the last segment, CWR to the first
+        let mut segment_flags = flags;
+
+        if index + 1 < count {
+            segment_flags &= !(TCP_FIN | TCP_PSH);
+        }
+
+        if index > 0 {
+            segment_flags &= !TCP_CWR;
+        }
+
+        segment[layout.transport + 13] = segment_flags;
+        segment[layout.transport + 4..layout.transport + 8].copy_from_slice(&sequence.wrapping_add((index * mss) as u32).to_be_bytes());
+
+        if !layout.ipv6 {
+            set_word(&mut segment, layout.network + 4, id.wrapping_add(index as u16));
+        }
+
+        layout.set_length(&mut segment);
+        set_transport_checksum(&mut segment, layout);
+
+        segment
+    }).collect()
+}
+
+fn fragment_ipv4(frame: &[u8], layout: &Layout, gso_size: usize) -> Vec<Vec<u8>> {
+    let mut datagram = frame.to_vec();
+    set_transport_checksum(&mut datagram, layout);
+
+    // Every fragment but the last has to carry a multiple of 8 bytes
+    let step = (gso_size & !7).max(8);
+    let (headers, body) = datagram.split_at(layout.transport);
+
+    if body.len() <= step {
+        layout.set_length(&mut datagram);
+        return vec![datagram];
+    }
+
+    let chunks: Vec<&[u8]> = body.chunks(step).collect();
+    let count = chunks.len();
+
+    chunks.into_iter().enumerate().map(|(index, chunk)| {
+        let mut fragment = headers.to_vec();
+        fragment.extend_from_slice(chunk);
+
+        let more = if index + 1 < count { IPV4_MORE_FRAGMENTS } else { 0 };
+        let flags = word(headers, layout.network + 6) & !(IPV4_DONT_FRAGMENT | IPV4_MORE_FRAGMENTS | IPV4_OFFSET_MASK);
+        set_word(&mut fragment, layout.network + 6, flags | more | (index * step / 8) as u16);
+
+        layout.set_length(&mut fragment);
+        fragment
+    }).collect()
+}
+
+fn fragment_ipv6(frame: &[u8], layout: &Layout, gso_size: usize, id: u32) -> Vec<Vec<u8>> {
+    let mut datagram = frame.to_vec();
+    set_transport_checksum(&mut datagram, layout);
+
+    let step = (gso_size.saturating_sub(IPV6_FRAGMENT_HEADER_SIZE) & !7).max(8);
+    let (headers, body) = datagram.split_at(layout.transport);
+
+    if body.len() <= step {
+        return vec![datagram];
+    }
+
+    let chunks: Vec<&[u8]> = body.chunks(step).collect();
+    let count = chunks.len();
+
+    chunks.into_iter().enumerate().map(|(index, chunk)| {
+        let more = (index + 1 < count) as u16;
+
+        let mut fragment = headers.to_vec();
+        fragment[layout.network + 6] = IPV6_FRAGMENT_HEADER;
+        fragment.extend_from_slice(&[layout.protocol, 0]);
+        fragment.extend_from_slice(&((index * step) as u16 | more).to_be_bytes());
+        fragment.extend_from_slice(&id.to_be_bytes());
+        fragment.extend_from_slice(chunk);
+
+        layout.set_length(&mut fragment);
+        fragment
+    }).collect()
+}
+
+/// The receive offloads the driver negotiated, GSO on receive needs GUEST_CSUM as well
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GuestOffloads {
+    pub csum: bool,
+    pub tso4: bool,
+    pub tso6: bool,
+    pub ufo: bool,
+}
+
+/// Glues frames from one flow into a packet for the receiving guest: consecutive TCP segments
+/// when it does TSO and consecutive IPv4 fragments of a datagram when it does UFO
+pub struct Coalescer {
+    frame: Vec<u8>,
+    layout: Option<Layout>,
+    gso_type: u8,
+    gso_size: usize,
+    offloads: GuestOffloads,
+
+    pub segments: usize,
+}
+
+impl Coalescer {
+    pub fn new(mut frame: Vec<u8>, offloads: GuestOffloads) -> Self {
+        let layout = Layout::parse(&frame).ok().filter(|_| offloads.csum);
+
+        let gso_type = match layout {
+            Some(layout) if layout.protocol == IP_PROTOCOL_TCP && !layout.is_fragment(&frame) => match layout.ipv6 {
+                false if offloads.tso4 => VIRTIO_NET_HDR_GSO_TCPV4,
+                true if offloads.tso6 => VIRTIO_NET_HDR_GSO_TCPV6,
+                _ => VIRTIO_NET_HDR_GSO_NONE,
+            },
+            // Reassembly starts from the first fragment
+            Some(layout) if layout.protocol == IP_PROTOCOL_UDP && offloads.ufo && !layout.ipv6 && word(&frame, layout.network + 6) & (IPV4_MORE_FRAGMENTS | IPV4_OFFSET_MASK) == IPV4_MORE_FRAGMENTS => VIRTIO_NET_HDR_GSO_UDP,
+            _ => VIRTIO_NET_HDR_GSO_NONE,
+        };
+
+        let mut gso_size = 0;
+
+        if let Some(layout) = layout.filter(|_| gso_type != VIRTIO_NET_HDR_GSO_NONE) {
+            frame.truncate(layout.end(&frame));
+
+            gso_size = match gso_type {
+                VIRTIO_NET_HDR_GSO_UDP => frame.len() - layout.transport,
+                _ => frame.len() - layout.payload,
+            };
+        }
+
+        Self { frame, layout, gso_type, gso_size, offloads, segments: 1 }
+    }
+
+    /// Adds `next` if it carries on where the packet so far ends and the result stays under
+    /// `limit` bytes, false when it doesn't
+    pub fn append(&mut self, next: &[u8], limit: usize) -> bool {
+        let Some(layout) = self.layout.filter(|_| self.gso_type != VIRTIO_NET_HDR_GSO_NONE) else {
+            return false;
+        };
+
+        let Ok(other) = Layout::parse(next) else {
+            return false;
+        };
+
+        let next = &next[..other.end(next)];
+
+        let same_packet = other.ipv6 == layout.ipv6 && other.protocol == layout.protocol && other.transport == layout.transport
+            && layout.addresses(&self.frame) == other.addresses(next);
+
+        if !same_packet {
+            return false;
+        }
+
+        let appended = match self.gso_type {
+            VIRTIO_NET_HDR_GSO_UDP => self.next_fragment(&layout, next),
+            _ => self.next_segment(&layout, &other, next),
+        };
+
+        let Some(data) = appended.filter(|data| self.frame.len() + data.len() <= limit) else {
+            return false;
+        };
+
+        let data = data.to_vec();
+
+        match self.gso_type {
+            VIRTIO_NET_HDR_GSO_UDP => {
+                // The fragment flags are the last fragment's, the offset stays the first's
+                let flags = word(next, layout.network + 6) & IPV4_MORE_FRAGMENTS;
+                let first = word(&self.frame, layout.network + 6) & !IPV4_MORE_FRAGMENTS;
+                set_word(&mut self.frame, layout.network + 6, first | flags);
+            },
+            _ => self.frame[layout.transport + 13] |= next[layout.transport + 13] & TCP_PSH,
+        }
+
+        self.frame.extend_from_slice(&data);
+        self.segments += 1;
+
+        true
+    }
+
+    fn next_segment<'a>(&self, layout: &Layout, other: &Layout, next: &'a [u8]) -> Option<&'a [u8]> {
+        let tcp = layout.transport;
+        let received = self.frame.len() - layout.payload;
+
+        // A short segment or a PSH ends a run, and only plain ACKs of the same data join one
+        let continues = received.is_multiple_of(self.gso_size)
+            && self.frame[tcp + 13] == TCP_ACK
+            && next[tcp + 13] & !(TCP_ACK | TCP_PSH) == 0
+            && !other.is_fragment(next)
+            && self.frame[tcp..tcp + 4] == next[tcp..tcp + 4]
+            && other.payload == layout.payload
+            && self.frame[tcp + 8..tcp + 13] == next[tcp + 8..tcp + 13]
+            && self.frame[tcp + 14..tcp + 16] == next[tcp + 14..tcp + 16]
+            && self.frame[tcp + 20..layout.payload] == next[tcp + 20..other.payload]
+            && long(next, tcp + 4) == long(&self.frame, tcp + 4).wrapping_add(received as u32);
+
+        let data = &next[other.payload..];
+        (continues && !data.is_empty() && data.len() <= self.gso_size).then_some(data)
+    }
+
+    fn next_fragment<'a>(&self, layout: &Layout, next: &'a [u8]) -> Option<&'a [u8]> {
+        let network = layout.network;
+        let fragment = word(&self.frame, network + 6);
+        let received = self.frame.len() - layout.transport;
+
+        let continues = fragment & IPV4_MORE_FRAGMENTS != 0
+            && self.frame[network + 4..network + 6] == next[network + 4..network + 6]
+            && (word(next, network + 6) & IPV4_OFFSET_MASK) as usize * 8 == (fragment & IPV4_OFFSET_MASK) as usize * 8 + received;
+
+        continues.then_some(&next[layout.transport..])
+    }
+
+    /// The packet and the header that goes with it
+    pub fn finish(mut self) -> (NetHeader, Vec<u8>) {
+        let Some(layout) = self.layout.filter(|_| self.segments > 1) else {
+            let flags = if self.offloads.csum && verify_checksum(&self.frame) { VIRTIO_NET_HDR_F_DATA_VALID } else { 0 };
+            return (NetHeader { flags, ..NetHeader::default() }, self.frame);
+        };
+
+        layout.set_length(&mut self.frame);
+
+        // Fragments that still don't make a whole datagram go up as one bigger fragment
+        if self.gso_type == VIRTIO_NET_HDR_GSO_UDP && word(&self.frame, layout.network + 6) & IPV4_MORE_FRAGMENTS != 0 {
+            return (NetHeader::default(), self.frame);
+        }
+
+        // The driver finishes the checksum off, as if it came from a driver with NEEDS_CSUM
+        let offset = layout.checksum_offset().unwrap();
+        let partial = layout.pseudo_header(&self.frame, self.frame.len() - layout.transport) as u16;
+        set_word(&mut self.frame, layout.transport + offset, partial);
+
+        let header = NetHeader {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: self.gso_type,
+            hdr_len: layout.payload as u16,
+            gso_size: self.gso_size as u16,
+            csum_start: layout.transport as u16,
+            csum_offset: offset as u16,
+            num_buffers: 1,
+        };
+
+        (header, self.frame)
+    }
+}
+
+#[test]
+pub fn test_segments_coalesce_back() {
+    let tcp = |data_len: usize| {
+        let mut frame = vec![0u8; ETHERNET_HEADER_SIZE];
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0, 0, 0, 0, 7, 0x40, 0, 64, IP_PROTOCOL_TCP, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend_from_slice(&[0x9c, 0x40, 0, 80, 0, 0, 1, 0, 0, 0, 0, 9, 0x50, TCP_ACK | TCP_PSH, 0xff, 0xff, 0, 0, 0, 0]);
+        frame.extend((0..data_len).map(|byte| byte as u8));
+
+        let length = frame.len() - ETHERNET_HEADER_SIZE;
+        set_word(&mut frame, 16, length as u16);
+        frame
+    };
+
+    // The driver hands over 4000 bytes with a 1460 byte MSS and leaves the checksum to us
+    let mut packet = tcp(4000);
+    let header = prepare_transmit(&mut packet, 1500);
+    assert_eq!((header.gso_type, header.gso_size, header.csum_start, header.csum_offset), (VIRTIO_NET_HDR_GSO_TCPV4, 1460, 34, 16));
+
+    let segments = segment(&packet, &header, 0).unwrap();
+    assert_eq!(segments.iter().map(|segment| segment.len() - 54).collect::<Vec<_>>(), [1460, 1460, 1080]);
+    assert!(segments.iter().all(|segment| verify_checksum(segment)));
+    assert_eq!(segments.iter().map(|segment| segment[47] & TCP_PSH).collect::<Vec<_>>(), [0, 0, TCP_PSH]);
+
+    // Put back together for a guest that does TSO, then finished off like its driver would
+    let offloads = GuestOffloads { csum: true, tso4: true, tso6: false, ufo: false };
+    let mut coalescer = Coalescer::new(segments[0].clone(), offloads);
+    assert!(segments[1..].iter().all(|segment| coalescer.append(segment, 65535)));
+
+    let (header, mut merged) = coalescer.finish();
+    assert_eq!((header.gso_type, header.gso_size, header.flags), (VIRTIO_NET_HDR_GSO_TCPV4, 1460, VIRTIO_NET_HDR_F_NEEDS_CSUM));
+
+    complete_checksum(&mut merged, header.csum_start as usize, header.csum_offset as usize).unwrap();
+    assert!(verify_checksum(&merged));
+    assert_eq!(merged[54..], tcp(4000)[54..]);
+
+    // Without TSO the segments stay as they are, just marked as checked
+    let (header, _) = Coalescer::new(segments[0].clone(), GuestOffloads { tso4: false, ..offloads }).finish();
+    assert_eq!((header.gso_type, header.flags), (VIRTIO_NET_HDR_GSO_NONE, VIRTIO_NET_HDR_F_DATA_VALID));
+
+    // A UDP datagram goes out as IP fragments and comes back whole
+    let mut datagram = tcp(0)[..34].to_vec();
+    datagram[23] = IP_PROTOCOL_UDP;
+    datagram.extend_from_slice(&[0x9c, 0x40, 0, 7, 0x0f, 0xa8, 0, 0]);
+    datagram.extend((0..4000).map(|byte| (byte * 7) as u8));
+    let length = datagram.len() - 14;
+    set_word(&mut datagram, 16, length as u16);
+
+    let header = prepare_transmit(&mut datagram, 1500);
+    assert_eq!((header.gso_type, header.gso_size), (VIRTIO_NET_HDR_GSO_UDP, 1480));
+
+    let fragments = segment(&datagram, &header, 0).unwrap();
+    assert_eq!(fragments.len(), 3);
+
+    let mut coalescer = Coalescer::new(fragments[0].clone(), GuestOffloads { ufo: true, ..offloads });
+    assert!(fragments[1..].iter().all(|fragment| coalescer.append(fragment, 65535)));
+
+    let (header, mut whole) = coalescer.finish();
+    assert_eq!(header.gso_type, VIRTIO_NET_HDR_GSO_UDP);
+
+    complete_checksum(&mut whole, header.csum_start as usize, header.csum_offset as usize).unwrap();
+    assert!(verify_checksum(&whole));
+    assert_eq!(whole[42..], datagram[42..]);
+}
+
+#[test]
+pub fn test_truncated_tcp_header_is_refused() {
+    let mut frame = vec![0u8; ETHERNET_HEADER_SIZE];
+    frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    frame.extend_from_slice(&[0x45, 0, 0, 33, 0, 7, 0x40, 0, 64, IP_PROTOCOL_TCP, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+    frame.extend_from_slice(&[0x9c, 0x40, 0, 80, 0, 0, 1, 0, 0, 0, 0, 9, 0x00]);
+    assert_eq!(frame.len(), 47);
+
+    let header = NetHeader { gso_type: VIRTIO_NET_HDR_GSO_TCPV4, gso_size: 1460, ..Default::default() };
+    assert!(segment(&frame, &header, 0).is_err());
+
+    // Long enough, but the data offset says the header is only 16 bytes
+    frame.extend_from_slice(&[0; 7]);
+    frame[46] = 0x40;
+    assert!(segment(&frame, &header, 0).is_err());
+
+    frame[46] = 0x50;
+    assert!(segment(&frame, &header, 0).is_ok());
+}
@@ -0,0 +1,140 @@
+// Automatic receive steering for multiqueue. The device remembers which transmit queue each
+// flow last went out on and delivers the other direction of that flow to the receive queue of
+// the same pair, so a connection stays on one CPU in the guest. Flows it hasn't seen yet are
+// spread across the active pairs by a hash.
+
+use std::{collections::HashMap, hash::{DefaultHasher, Hash, Hasher}};
+
+use super::ETHERNET_HEADER_SIZE;
+
+// Past this the table starts again rather than tracking every flow the guest ever had
+const MAX_FLOWS: usize = 1024;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_OFFSET_MASK: u16 = 0x1fff;
+
+/// Two endpoints, IP and port for IPv4 and the MAC for everything else
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Flow {
+    protocol: u16,
+    source: [u8; 6],
+    destination: [u8; 6],
+}
+
+impl Flow {
+    fn parse(frame: &[u8]) -> Option<Self> {
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            return None;
+        }
+
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let packet = &frame[ETHERNET_HEADER_SIZE..];
+
+        if ethertype != ETHERTYPE_IPV4 || packet.len() < 20 {
+            return Some(Self {
+                protocol: ethertype,
+                source: frame[6..12].try_into().unwrap(),
+                destination: frame[0..6].try_into().unwrap(),
+            });
+        }
+
+        let protocol = packet[9];
+        let header_len = (packet[0] & 0xf) as usize * 4;
+
+        let mut source = [0u8; 6];
+        let mut destination = [0u8; 6];
+        source[..4].copy_from_slice(&packet[12..16]);
+        destination[..4].copy_from_slice(&packet[16..20]);
+
+        // Only the first fragment has the ports, all of a datagram's fragments go by address alone
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]) & (IPV4_MORE_FRAGMENTS | IPV4_OFFSET_MASK) != 0;
+
+        if matches!(protocol, IP_PROTOCOL_TCP | IP_PROTOCOL_UDP) && !fragment && packet.len() >= header_len + 4 {
+            source[4..].copy_from_slice(&packet[header_len..header_len + 2]);
+            destination[4..].copy_from_slice(&packet[header_len + 2..header_len + 4]);
+        }
+
+        Some(Self { protocol: protocol as u16, source, destination })
+    }
+
+    fn reversed(&self) -> Self {
+        Self { protocol: self.protocol, source: self.destination, destination: self.source }
+    }
+}
+
+#[derive(Default)]
+pub struct FlowTable {
+    flows: HashMap<Flow, u16>,
+}
+
+impl FlowTable {
+    pub fn record_transmit(&mut self, frame: &[u8], pair: u16) {
+        let Some(flow) = Flow::parse(frame) else {
+            return;
+        };
+
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&flow) {
+            self.flows.clear();
+        }
+
+        self.flows.insert(flow, pair);
+    }
+
+    /// The pair whose receive queue `frame` goes to, always below `active_pairs`
+    pub fn steer(&self, frame: &[u8], active_pairs: u16) -> u16 {
+        let Some(flow) = Flow::parse(frame).map(|flow| flow.reversed()) else {
+            return 0;
+        };
+
+        match self.flows.get(&flow) {
+            Some(pair) if *pair < active_pairs => *pair,
+            _ => {
+                let mut hasher = DefaultHasher::new();
+                flow.hash(&mut hasher);
+
+                (hasher.finish() % active_pairs.max(1) as u64) as u16
+            },
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.flows.clear();
+    }
+}
+
+#[test]
+pub fn test_replies_follow_their_flow() {
+    let udp = |source: [u8; 4], destination: [u8; 4], source_port: u16, destination_port: u16| {
+        let mut frame = vec![0u8; ETHERNET_HEADER_SIZE + 28];
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+
+        let packet = &mut frame[ETHERNET_HEADER_SIZE..];
+        packet[0] = 0x45;
+        packet[9] = IP_PROTOCOL_UDP;
+        packet[12..16].copy_from_slice(&source);
+        packet[16..20].copy_from_slice(&destination);
+        packet[20..22].copy_from_slice(&source_port.to_be_bytes());
+        packet[22..24].copy_from_slice(&destination_port.to_be_bytes());
+
+        frame
+    };
+
+    let (guest, peer) = ([10, 0, 0, 1], [10, 0, 0, 2]);
+    let mut table = FlowTable::default();
+
+    // Each of the guest's connections answers on the pair it was sent from
+    for port in 0..8u16 {
+        table.record_transmit(&udp(guest, peer, 1000 + port, 53), port % 4);
+    }
+
+    for port in 0..8u16 {
+        assert_eq!(table.steer(&udp(peer, guest, 53, 1000 + port), 4), port % 4);
+    }
+
+    // With fewer pairs active the remembered queue might not exist any more
+    assert!(table.steer(&udp(peer, guest, 53, 1003), 2) < 2);
+    assert!(table.steer(&udp(peer, guest, 53, 2000), 4) < 4);
+}
@@ -0,0 +1,248 @@
+// An in-process learning switch. Every virtio-net device plugged into it gets a `SwitchPort`,
+// a frame sent on one port is delivered to the port its destination MAC was last seen on, or
+// flooded to every other port when that isn't known yet or it's broadcast or multicast. Ports
+// whose guest has programmed a receive filter only get the frames it lets through.
+//
+// Delivery happens on the sending device's thread, the frame is queued on the receiving port
+// and its device thread is woken to pick it up. A backend that isn't a device, like a socket to
+// another simulator, is bridged onto a port of its own by a thread that moves frames both ways.
+
+use std::{collections::HashMap, io::Result, sync::{Arc, Condvar, Mutex, mpsc::Receiver}, thread};
+
+use crate::{device_thread::DeviceControl, poller::PollableQueue};
+
+use super::{backend::NetBackend, control::RxFilter};
+
+pub type MacAddress = [u8; 6];
+
+// Frames a slow guest hasn't taken yet, anything past this is dropped like on a real switch
+const MAX_QUEUED_FRAMES: usize = 256;
+
+struct SwitchPortState {
+    inbox: DeviceControl<Vec<u8>>,
+    queued: Arc<Mutex<usize>>,
+
+    // None until the device tells us, then everything goes
+    filter: Option<RxFilter>,
+}
+
+#[derive(Default)]
+struct SwitchState {
+    ports: HashMap<usize, SwitchPortState>,
+    next_port: usize,
+
+    // Where each source MAC was last seen
+    table: HashMap<MacAddress, usize>,
+}
+
+impl SwitchState {
+    fn deliver(&self, port: usize, frame: &[u8]) {
+        let Some(state) = self.ports.get(&port) else {
+            return;
+        };
+
+        if state.filter.as_ref().is_some_and(|filter| !filter.accepts(frame)) {
+            return;
+        }
+
+        let mut queued = state.queued.lock().unwrap();
+
+        if *queued < MAX_QUEUED_FRAMES {
+            *queued += 1;
+            state.inbox.send(frame.to_vec());
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct VirtualSwitch {
+    state: Arc<Mutex<SwitchState>>,
+}
+
+pub fn is_multicast(mac: &MacAddress) -> bool {
+    mac[0] & 1 != 0
+}
+
+impl VirtualSwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Plugs `backend` into a port of its own, `open` gets the waker the backend has to wake
+    /// when a frame comes in
+    pub fn bridge<B: NetBackend + 'static>(&self, open: impl FnOnce(BridgeWaker) -> Result<B>) -> Result<()> {
+        let waker = BridgeWaker::default();
+        let mut backend = open(waker.clone())?;
+        let mut port = self.connect(waker.clone());
+
+        thread::spawn(move || loop {
+            waker.wait_for_event();
+
+            while let Some(frame) = port.receive() {
+                let _ = backend.transmit(&frame);
+            }
+
+            while let Some(frame) = backend.receive() {
+                let _ = port.transmit(&frame);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// `waker` is the guest side poller of the device the port is for
+    pub fn connect<P: PollableQueue + Send + 'static>(&self, waker: P) -> SwitchPort {
+        let (inbox, receiver) = DeviceControl::new(waker);
+        let queued = Arc::new(Mutex::new(0));
+
+        let mut state = self.state.lock().unwrap();
+
+        let id = state.next_port;
+        state.next_port += 1;
+        state.ports.insert(id, SwitchPortState { inbox, queued: queued.clone(), filter: None });
+
+        SwitchPort {
+            id,
+            switch: self.state.clone(),
+            receiver,
+            queued,
+        }
+    }
+}
+
+/// Wakes a bridge thread, which has no queues of its own to be woken through
+#[derive(Clone, Default)]
+pub struct BridgeWaker {
+    pending: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PollableQueue for BridgeWaker {
+    fn wait_for_event(&self) {
+        let (pending, condvar) = &*self.pending;
+        let mut pending = condvar.wait_while(pending.lock().unwrap(), |pending| !*pending).unwrap();
+
+        *pending = false;
+    }
+
+    fn submit_event(&self) {
+        let (pending, condvar) = &*self.pending;
+
+        *pending.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+}
+
+pub struct SwitchPort {
+    id: usize,
+    switch: Arc<Mutex<SwitchState>>,
+
+    receiver: Receiver<Vec<u8>>,
+    queued: Arc<Mutex<usize>>,
+}
+
+impl NetBackend for SwitchPort {
+    fn transmit(&mut self, frame: &[u8]) -> Result<()> {
+        // Runts don't even have the addresses
+        if frame.len() < 14 {
+            return Ok(());
+        }
+
+        let destination: MacAddress = frame[0..6].try_into().unwrap();
+        let source: MacAddress = frame[6..12].try_into().unwrap();
+
+        let mut switch = self.switch.lock().unwrap();
+
+        if !is_multicast(&source) {
+            switch.table.insert(source, self.id);
+        }
+
+        match switch.table.get(&destination).copied() {
+            Some(port) if !is_multicast(&destination) => {
+                if port != self.id {
+                    switch.deliver(port, frame);
+                }
+            },
+            _ => {
+                let others: Vec<usize> = switch.ports.keys().copied().filter(|port| *port != self.id).collect();
+
+                for port in others {
+                    switch.deliver(port, frame);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let frame = self.receiver.try_recv().ok()?;
+        *self.queued.lock().unwrap() -= 1;
+
+        Some(frame)
+    }
+
+    fn describe(&self) -> String {
+        format!("virtual switch port {}", self.id)
+    }
+
+    fn set_filter(&mut self, filter: &RxFilter) {
+        if let Some(port) = self.switch.lock().unwrap().ports.get_mut(&self.id) {
+            port.filter = Some(filter.clone());
+        }
+    }
+}
+
+impl Drop for SwitchPort {
+    fn drop(&mut self) {
+        let mut switch = self.switch.lock().unwrap();
+
+        switch.ports.remove(&self.id);
+        switch.table.retain(|_, port| *port != self.id);
+    }
+}
+
+#[test]
+pub fn test_switch_learns_and_floods() {
+    struct NoWaker;
+
+    impl PollableQueue for NoWaker {
+        fn wait_for_event(&self) {}
+        fn submit_event(&self) {}
+    }
+
+    let frame = |destination: u8, source: u8| {
+        let mut frame = vec![0u8; 60];
+        frame[0..6].copy_from_slice(&[2, 0, 0, 0, 0, destination]);
+        frame[6..12].copy_from_slice(&[2, 0, 0, 0, 0, source]);
+        frame
+    };
+
+    let switch = VirtualSwitch::new();
+    let mut ports: Vec<SwitchPort> = (0..3).map(|_| switch.connect(NoWaker)).collect();
+
+    // Nobody knows where 2 is yet, everyone but the sender hears it
+    ports[0].transmit(&frame(2, 1)).unwrap();
+    assert!(ports[0].receive().is_none());
+    assert_eq!(ports[1].receive(), Some(frame(2, 1)));
+    assert_eq!(ports[2].receive(), Some(frame(2, 1)));
+
+    // The reply teaches the switch where 2 is, from then on it's unicast
+    ports[2].transmit(&frame(1, 2)).unwrap();
+    ports[0].transmit(&frame(2, 1)).unwrap();
+
+    assert_eq!(ports[0].receive(), Some(frame(1, 2)));
+    assert!(ports[1].receive().is_none());
+    assert_eq!(ports[2].receive(), Some(frame(2, 1)));
+
+    // Once 2 unplugs the switch forgets it and floods again
+    drop(ports.pop());
+    ports[0].transmit(&frame(2, 1)).unwrap();
+    assert_eq!(ports[1].receive(), Some(frame(2, 1)));
+
+    // A port whose guest only wants its own frames doesn't hear the flood
+    ports[1].set_filter(&RxFilter::new([2, 0, 0, 0, 0, 3]));
+    ports[0].transmit(&frame(2, 1)).unwrap();
+    ports[0].transmit(&frame(3, 1)).unwrap();
+    assert_eq!(ports[1].receive(), Some(frame(3, 1)));
+    assert!(ports[1].receive().is_none());
+}
@@ -0,0 +1,298 @@
+// A net backend over a Unix socket, so separate simulators, or a simulator and a test harness,
+// can share a network. Every datagram or packet on the socket is one Ethernet frame with no
+// header of any kind in front of it.
+//
+// A seqpacket socket is connection oriented: one end listens and takes one peer at a time, the
+// other connects and keeps trying again until the listener is there. A datagram socket binds
+// its own path and sends to the peer's, frames sent before the peer is up are lost.
+
+use std::{fs, io::{Error, Result}, mem, os::unix::{io::{FromRawFd, IntoRawFd}, net::{UnixDatagram, UnixListener}}, net::Shutdown, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc::Receiver}, thread, time::Duration};
+
+use libc::{c_int, sockaddr, sockaddr_un, socklen_t, AF_UNIX, SOCK_CLOEXEC, SOCK_SEQPACKET};
+
+use crate::{device_thread::DeviceControl, poller::PollableQueue};
+
+use super::{backend::NetBackend, MAX_GSO_FRAME_SIZE};
+
+// How long a connecting end waits before trying the listener again
+const RECONNECT_MS: u64 = 500;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocketSpec {
+    Listen(String),
+    Connect(String),
+    Datagram { local: String, peer: String },
+}
+
+impl SocketSpec {
+    /// `listen:<path>`, `connect:<path>` or `dgram:<local path>,<peer path>`
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        match spec.split_once(':').unwrap_or((spec, "")) {
+            ("listen", path) if !path.is_empty() => Ok(Self::Listen(path.to_string())),
+            ("connect", path) if !path.is_empty() => Ok(Self::Connect(path.to_string())),
+            ("dgram", paths) => match paths.split_once(',') {
+                Some((local, peer)) if !local.is_empty() && !peer.is_empty() => Ok(Self::Datagram { local: local.to_string(), peer: peer.to_string() }),
+                _ => Err(format!("{spec} needs both paths, as dgram:<local path>,<peer path>")),
+            },
+            _ => Err(format!("{spec} isn't listen:<path>, connect:<path> or dgram:<local path>,<peer path>")),
+        }
+    }
+}
+
+fn socket_address(path: &str) -> Result<(sockaddr_un, socklen_t)> {
+    let mut address: sockaddr_un = unsafe { mem::zeroed() };
+    address.sun_family = AF_UNIX as u16;
+
+    if path.len() >= address.sun_path.len() {
+        return Err(Error::other(format!("{path} is too long for a Unix socket")));
+    }
+
+    for (to, from) in address.sun_path.iter_mut().zip(path.bytes()) {
+        *to = from as _;
+    }
+
+    Ok((address, mem::size_of::<sockaddr_un>() as socklen_t))
+}
+
+/// std has no seqpacket sockets, so they're made here and handed to std once connected
+fn seqpacket(path: &str, connect: bool) -> Result<c_int> {
+    let (address, length) = socket_address(path)?;
+
+    unsafe {
+        let fd = libc::socket(AF_UNIX, SOCK_SEQPACKET | SOCK_CLOEXEC, 0);
+
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let address = &address as *const sockaddr_un as *const sockaddr;
+        let result = match connect {
+            true => libc::connect(fd, address, length),
+            false => match libc::bind(fd, address, length) {
+                0 => libc::listen(fd, 1),
+                failed => failed,
+            },
+        };
+
+        if result != 0 {
+            let err = Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+
+        Ok(fd)
+    }
+}
+
+pub struct UnixSocketBackend {
+    spec: SocketSpec,
+
+    // The connected peer for seqpacket, our own bound socket for datagrams
+    socket: Arc<Mutex<Option<UnixDatagram>>>,
+    closed: Arc<AtomicBool>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl UnixSocketBackend {
+    /// `waker` is woken whenever a frame comes in, like a switch port's
+    pub fn open<P: PollableQueue + Send + 'static>(spec: SocketSpec, waker: P) -> Result<Self> {
+        let (inbox, receiver) = DeviceControl::new(waker);
+
+        let socket = Arc::new(Mutex::new(None));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (thread_socket, thread_closed) = (socket.clone(), closed.clone());
+
+        match &spec {
+            SocketSpec::Listen(path) => {
+                // A socket left behind by an earlier run would make the bind fail
+                let _ = fs::remove_file(path);
+                let listener = unsafe { UnixListener::from_raw_fd(seqpacket(path, false)?) };
+
+                thread::spawn(move || {
+                    for connection in listener.incoming() {
+                        if thread_closed.load(Ordering::SeqCst) {
+                            return;
+                        }
+
+                        if let Ok(connection) = connection {
+                            let peer = unsafe { UnixDatagram::from_raw_fd(connection.into_raw_fd()) };
+                            Self::serve(peer, &thread_socket, &inbox);
+                        }
+                    }
+                });
+            },
+            SocketSpec::Connect(path) => {
+                let path = path.clone();
+
+                thread::spawn(move || {
+                    while !thread_closed.load(Ordering::SeqCst) {
+                        match seqpacket(&path, true) {
+                            Ok(fd) => Self::serve(unsafe { UnixDatagram::from_raw_fd(fd) }, &thread_socket, &inbox),
+                            Err(_) => thread::sleep(Duration::from_millis(RECONNECT_MS)),
+                        }
+                    }
+                });
+            },
+            SocketSpec::Datagram { local, .. } => {
+                let _ = fs::remove_file(local);
+                let bound = UnixDatagram::bind(local)?;
+                let reader = bound.try_clone()?;
+
+                *socket.lock().unwrap() = Some(bound);
+
+                thread::spawn(move || {
+                    let mut buffer = vec![0u8; MAX_GSO_FRAME_SIZE];
+
+                    while let Ok(length) = reader.recv(&mut buffer) {
+                        if thread_closed.load(Ordering::SeqCst) {
+                            return;
+                        }
+
+                        inbox.send(buffer[..length].to_vec());
+                    }
+                });
+            },
+        }
+
+        Ok(Self { spec, socket, closed, receiver })
+    }
+
+    /// Passes frames from a connected peer on until it goes away
+    fn serve(peer: UnixDatagram, socket: &Mutex<Option<UnixDatagram>>, inbox: &DeviceControl<Vec<u8>>) {
+        let Ok(writer) = peer.try_clone() else {
+            return;
+        };
+
+        *socket.lock().unwrap() = Some(writer);
+
+        let mut buffer = vec![0u8; MAX_GSO_FRAME_SIZE];
+
+        loop {
+            match peer.recv(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(length) => inbox.send(buffer[..length].to_vec()),
+            }
+        }
+
+        *socket.lock().unwrap() = None;
+    }
+}
+
+impl NetBackend for UnixSocketBackend {
+    fn transmit(&mut self, frame: &[u8]) -> Result<()> {
+        let mut socket = self.socket.lock().unwrap();
+
+        // Nobody on the other end, the frame is lost like on an unplugged cable
+        let Some(connected) = socket.as_ref() else {
+            return Ok(());
+        };
+
+        let sent = match &self.spec {
+            SocketSpec::Datagram { peer, .. } => connected.send_to(frame, peer),
+            _ => connected.send(frame),
+        };
+
+        match sent {
+            Ok(_) => Ok(()),
+            // The datagram peer isn't up yet
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOENT | libc::ECONNREFUSED)) => Ok(()),
+            // The seqpacket peer hung up, the reading side notices too and waits for the next
+            Err(err) if matches!(err.raw_os_error(), Some(libc::EPIPE | libc::ECONNRESET)) => {
+                *socket = None;
+                Ok(())
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.receiver.try_recv().ok()
+    }
+
+    fn describe(&self) -> String {
+        let connected = self.socket.lock().unwrap().is_some();
+
+        match &self.spec {
+            SocketSpec::Listen(path) => format!("seqpacket socket listening on {path}, {}", if connected { "connected" } else { "waiting" }),
+            SocketSpec::Connect(path) => format!("seqpacket socket to {path}, {}", if connected { "connected" } else { "connecting" }),
+            SocketSpec::Datagram { local, peer } => format!("datagram socket {local} to {peer}"),
+        }
+    }
+}
+
+impl Drop for UnixSocketBackend {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        if let Some(socket) = self.socket.lock().unwrap().take() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+
+        match &self.spec {
+            SocketSpec::Listen(path) => {
+                // Knocks the listener thread out of accept so it sees the backend is gone
+                if let Ok(fd) = seqpacket(path, true) {
+                    unsafe { libc::close(fd) };
+                }
+
+                let _ = fs::remove_file(path);
+            },
+            SocketSpec::Datagram { local, .. } => {
+                let _ = UnixDatagram::unbound().and_then(|socket| socket.send_to(&[], local));
+                let _ = fs::remove_file(local);
+            },
+            SocketSpec::Connect(_) => {},
+        }
+    }
+}
+
+#[test]
+pub fn test_frames_cross_the_socket() {
+    struct NoWaker;
+
+    impl PollableQueue for NoWaker {
+        fn wait_for_event(&self) {}
+        fn submit_event(&self) {}
+    }
+
+    let next = |backend: &mut UnixSocketBackend| {
+        (0..200).find_map(|_| backend.receive().or_else(|| { thread::sleep(Duration::from_millis(10)); None }))
+    };
+
+    let directory = std::env::temp_dir().join(format!("virtio-net-socket-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = |name: &str| directory.join(name).to_string_lossy().to_string();
+
+    assert!(SocketSpec::parse("dgram:/tmp/a").is_err());
+    assert_eq!(SocketSpec::parse("listen:/tmp/a"), Ok(SocketSpec::Listen("/tmp/a".to_string())));
+
+    // Each seqpacket frame arrives whole and on its own
+    let mut listener = UnixSocketBackend::open(SocketSpec::Listen(path("link.sock")), NoWaker).unwrap();
+    let mut connector = UnixSocketBackend::open(SocketSpec::Connect(path("link.sock")), NoWaker).unwrap();
+
+    while !listener.describe().ends_with("connected") || !connector.describe().ends_with("connected") {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    connector.transmit(&[1; 60]).unwrap();
+    connector.transmit(&[2; 1514]).unwrap();
+    assert_eq!(next(&mut listener), Some(vec![1; 60]));
+    assert_eq!(next(&mut listener), Some(vec![2; 1514]));
+
+    listener.transmit(&[3; 64]).unwrap();
+    assert_eq!(next(&mut connector), Some(vec![3; 64]));
+
+    // Datagrams sent before the peer is there go nowhere
+    let mut first = UnixSocketBackend::open(SocketSpec::Datagram { local: path("first.sock"), peer: path("second.sock") }, NoWaker).unwrap();
+    first.transmit(&[4; 60]).unwrap();
+
+    let mut second = UnixSocketBackend::open(SocketSpec::Datagram { local: path("second.sock"), peer: path("first.sock") }, NoWaker).unwrap();
+    second.transmit(&[5; 60]).unwrap();
+    first.transmit(&[6; 60]).unwrap();
+
+    assert_eq!(next(&mut first), Some(vec![5; 60]));
+    assert_eq!(next(&mut second), Some(vec![6; 60]));
+
+    drop((listener, connector, first, second));
+    let _ = fs::remove_dir_all(&directory);
+}
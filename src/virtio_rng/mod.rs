@@ -0,0 +1,326 @@
+// A virtio-rng entropy device. The driver posts device writable buffers on the one queue and
+// gets them back full of random bytes, from getrandom or from a seeded generator when runs have
+// to be reproducible. An optional rate limit hands out bytes from a bucket that refills over
+// time, requests that find it empty wait until it has some again.
+
+use std::{collections::VecDeque, io::{Result, Error, ErrorKind}, thread, time::{Duration, Instant}};
+
+use crate::virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain};
+
+pub const VIRTIO_RNG_DEVICE_ID: u32 = 4;
+
+pub const REQUESTQ: u16 = 0;
+
+// Nobody waits longer than this for the bucket, the driver's own requests get a look in
+const MAX_RATE_WAIT: Duration = Duration::from_millis(100);
+
+/// splitmix64, plenty for reproducible tests and nothing else
+struct SeededSource {
+    state: u64,
+}
+
+impl SeededSource {
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+enum EntropySource {
+    Getrandom,
+    Seeded(SeededSource),
+}
+
+impl EntropySource {
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<()> {
+        match self {
+            EntropySource::Seeded(source) => {
+                source.fill(buffer);
+                Ok(())
+            },
+            EntropySource::Getrandom => {
+                let mut filled = 0;
+
+                while filled < buffer.len() {
+                    let count = unsafe { libc::getrandom(buffer[filled..].as_mut_ptr() as *mut libc::c_void, buffer.len() - filled, 0) };
+
+                    if count < 0 {
+                        let err = Error::last_os_error();
+
+                        if err.kind() == ErrorKind::Interrupted {
+                            continue;
+                        }
+
+                        return Err(err);
+                    }
+
+                    filled += count as usize;
+                }
+
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Bytes per second, with up to a second's worth saved up
+struct RateLimit {
+    bytes_per_second: u64,
+    available: u64,
+    last_refill: Instant,
+}
+
+impl RateLimit {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            available: bytes_per_second,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.last_refill.elapsed();
+        let earned = (elapsed.as_nanos() * self.bytes_per_second as u128 / 1_000_000_000) as u64;
+
+        // Only move the clock on by what was paid out so fractions of a byte aren't lost
+        if earned > 0 {
+            self.available = (self.available + earned).min(self.bytes_per_second);
+            self.last_refill += Duration::from_nanos((earned as u128 * 1_000_000_000 / self.bytes_per_second as u128) as u64);
+        }
+    }
+
+    fn take(&mut self, wanted: usize) -> usize {
+        self.refill();
+
+        let granted = self.available.min(wanted as u64);
+        self.available -= granted;
+
+        granted as usize
+    }
+
+    /// How long until at least one byte can be handed out
+    fn wait_time(&mut self) -> Duration {
+        self.refill();
+
+        match self.available {
+            0 => Duration::from_nanos(1_000_000_000 / self.bytes_per_second.max(1)).min(MAX_RATE_WAIT),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+pub struct VirtioRng {
+    source: EntropySource,
+    rate_limit: Option<RateLimit>,
+
+    // Requests that found the bucket empty, oldest first
+    waiting: VecDeque<DescriptorChain>,
+}
+
+impl VirtioRng {
+    pub fn new() -> Self {
+        Self {
+            source: EntropySource::Getrandom,
+            rate_limit: None,
+
+            waiting: VecDeque::new(),
+        }
+    }
+
+    /// The same seed always hands the driver the same bytes
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            source: EntropySource::Seeded(SeededSource { state: seed }),
+            ..Self::new()
+        }
+    }
+
+    pub fn with_rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.rate_limit = Some(RateLimit::new(bytes_per_second));
+        self
+    }
+
+    /// Fills as much of the chain as the rate limit allows, None when it allows nothing
+    unsafe fn fill_chain(&mut self, ctx: &mut DeviceContext, chain: &DescriptorChain) -> Option<u32> {
+        let wanted = chain.writable_len();
+
+        let granted = match self.rate_limit.as_mut() {
+            Some(limit) => limit.take(wanted),
+            None => wanted,
+        };
+
+        if granted == 0 && wanted > 0 {
+            return None;
+        }
+
+        let mut bytes = vec![0u8; granted];
+
+        if let Err(err) = self.source.fill(&mut bytes) {
+            ctx.send_message(format!("Couldn't get entropy: {err}"));
+            return Some(0);
+        }
+
+        Some(chain.write_at(0, &bytes) as u32)
+    }
+}
+
+impl Default for VirtioRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_RNG_DEVICE_ID
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, _queue: u16, chain: DescriptorChain) -> Option<u32> {
+        // The device only ever writes, a readable buffer means the driver is confused
+        if unsafe { chain.readable_len() } > 0 {
+            ctx.send_message(format!("Entropy request {} has device readable buffers", chain.head()));
+            return Some(0);
+        }
+
+        // Later requests can't jump the ones already waiting for the bucket
+        if !self.waiting.is_empty() {
+            self.waiting.push_back(chain);
+            return None;
+        }
+
+        let written = unsafe { self.fill_chain(ctx, &chain) };
+
+        if written.is_none() {
+            self.waiting.push_back(chain);
+        }
+
+        written
+    }
+
+    fn poll(&mut self, ctx: &mut DeviceContext) {
+        while let Some(chain) = self.waiting.front().copied() {
+            let Some(written) = (unsafe { self.fill_chain(ctx, &chain) }) else {
+                return;
+            };
+
+            self.waiting.pop_front();
+            ctx.complete(REQUESTQ, chain, written);
+        }
+    }
+
+    fn wait_for_completions(&mut self) -> bool {
+        let Some(limit) = self.rate_limit.as_mut() else {
+            return false;
+        };
+
+        if self.waiting.is_empty() {
+            return false;
+        }
+
+        thread::sleep(limit.wait_time());
+        true
+    }
+
+    fn reset(&mut self) {
+        self.waiting.clear();
+    }
+
+    fn command(&mut self, _ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["rate", "off"] => {
+                self.rate_limit = None;
+                Ok("Entropy is no longer rate limited".to_string())
+            },
+            ["rate", rate] => {
+                let rate: u64 = rate.parse().ok().filter(|rate| *rate > 0).ok_or(format!("{rate} isn't a rate in bytes per second"))?;
+                self.rate_limit = Some(RateLimit::new(rate));
+
+                Ok(format!("Entropy limited to {rate} bytes a second"))
+            },
+            _ => Err(format!("The rng doesn't understand {command}, try rate <bytes per second> or rate off")),
+        }
+    }
+
+    // A seeded generator picks up where it left off, getrandom has nothing to keep
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        match &self.source {
+            EntropySource::Seeded(source) => Ok(source.state.to_le_bytes().to_vec()),
+            EntropySource::Getrandom => Ok(Vec::new()),
+        }
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        self.waiting.clear();
+
+        match (&mut self.source, snapshot.len()) {
+            (EntropySource::Seeded(source), 8) => source.state = u64::from_le_bytes(snapshot.try_into().unwrap()),
+            (EntropySource::Getrandom, 0) => {},
+            _ => return Err(Error::new(ErrorKind::InvalidData, "rng snapshot doesn't match the entropy source")),
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_seeded_rng_is_reproducible() {
+    use tokio::sync::mpsc::channel;
+
+    use crate::virtio::{virtqueue::DescriptorCell, vring::VIRTQ_DESC_F_WRITE, transport::{MmioTransport, TransportMode}};
+
+    let (tx, _rx) = channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, VIRTIO_RNG_DEVICE_ID, 0, &[4]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport);
+
+    let mut first = [0u8; 13];
+    let mut second = [0u8; 13];
+    let mut readable = [0u8; 4];
+
+    let mut table = [
+        DescriptorCell { addr: first.as_mut_ptr() as u64, length: 13, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        DescriptorCell { addr: second.as_mut_ptr() as u64, length: 13, flags: VIRTQ_DESC_F_WRITE, next: 0 },
+        DescriptorCell { addr: readable.as_mut_ptr() as u64, length: 4, flags: 0, next: 0 },
+        DescriptorCell::default(),
+    ];
+
+    let table = table.as_mut_ptr();
+    let chain = |head| unsafe { DescriptorChain::new(table, 4, head) };
+
+    assert_eq!(VirtioRng::seeded(7).process_request(&mut ctx, REQUESTQ, chain(0)), Some(13));
+    assert_eq!(VirtioRng::seeded(7).process_request(&mut ctx, REQUESTQ, chain(1)), Some(13));
+    assert_eq!(first, second);
+    assert_ne!(first, [0u8; 13]);
+
+    // Buffers the device could only read are refused
+    assert_eq!(VirtioRng::seeded(7).process_request(&mut ctx, REQUESTQ, chain(2)), Some(0));
+
+    // Ten bytes a second leaves the second request waiting on the bucket
+    let mut limited = VirtioRng::seeded(7).with_rate_limit(10);
+    assert_eq!(limited.process_request(&mut ctx, REQUESTQ, chain(0)), Some(10));
+    assert_eq!(limited.process_request(&mut ctx, REQUESTQ, chain(1)), None);
+
+    while ctx.completions.is_empty() {
+        assert!(limited.wait_for_completions());
+        limited.poll(&mut ctx);
+    }
+
+    assert_eq!(ctx.completions[0].head, 1);
+    assert!(ctx.completions[0].length >= 1);
+    assert!(!limited.wait_for_completions());
+}
@@ -0,0 +1,151 @@
+// One stream between a host program and the guest. Neither side may send more than the other
+// has room for: each header says how big the sender's buffer is (buf_alloc) and how much of
+// what it was sent it has passed on (fwd_cnt), so the difference between what we've sent and
+// the peer's fwd_cnt is what's still sitting in its buffer.
+
+use std::{collections::VecDeque, io::{Error, Result}};
+
+use super::{host::HostStream, packet::{PacketHeader, VIRTIO_VSOCK_TYPE_STREAM}};
+
+// What we tell the guest we can hold, its data waits here until the host program takes it
+pub const BUFFER_SIZE: u32 = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// A host program asked for a guest port, the guest hasn't answered yet
+    Connecting,
+    Established,
+    /// The host program went away, we've told the guest and wait for its RST
+    Closing,
+}
+
+pub struct Connection {
+    /// Tags the events from this connection's host stream
+    pub id: u64,
+    pub host_port: u32,
+    pub guest_port: u32,
+    pub state: State,
+    pub host: HostStream,
+
+    /// Host data the guest doesn't have room for yet
+    pub pending: VecDeque<u8>,
+    /// The host stream has ended, the guest hears once `pending` is empty
+    pub host_closed: bool,
+
+    // Guest data queued for the host program, and bytes of ours ahead of it that aren't guest data
+    unwritten: u32,
+    unwritten_own: usize,
+
+    fwd_cnt: u32,
+    reported_fwd_cnt: u32,
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    pub fn new(id: u64, host_port: u32, guest_port: u32, state: State, host: HostStream) -> Self {
+        Self {
+            id,
+            host_port,
+            guest_port,
+            state,
+            host,
+            pending: VecDeque::new(),
+            host_closed: false,
+            unwritten: 0,
+            unwritten_own: 0,
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// Every packet from the guest carries its latest credit
+    pub fn update_credit(&mut self, header: &PacketHeader) {
+        self.peer_buf_alloc = header.buf_alloc;
+        self.peer_fwd_cnt = header.fwd_cnt;
+    }
+
+    /// How much more the guest can take right now
+    pub fn peer_free(&self) -> u32 {
+        self.peer_buf_alloc.saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    /// A header from the host end to the guest, with our credit in it
+    pub fn header(&mut self, guest_cid: u64, op: u16) -> PacketHeader {
+        self.reported_fwd_cnt = self.fwd_cnt;
+
+        PacketHeader {
+            src_cid: super::VMADDR_CID_HOST,
+            dst_cid: guest_cid,
+            src_port: self.host_port,
+            dst_port: self.guest_port,
+            kind: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: BUFFER_SIZE,
+            fwd_cnt: self.fwd_cnt,
+            ..PacketHeader::default()
+        }
+    }
+
+    /// Takes as much pending host data as the guest has credit for, up to `room`
+    pub fn take_data(&mut self, room: usize) -> Option<Vec<u8>> {
+        let count = self.pending.len().min(room).min(self.peer_free() as usize);
+
+        if count == 0 {
+            return None;
+        }
+
+        let data: Vec<u8> = self.pending.drain(..count).collect();
+        self.tx_cnt = self.tx_cnt.wrapping_add(count as u32);
+        self.host.consumed(count);
+
+        Some(data)
+    }
+
+    /// Data from the guest, queued for the host program. It's only forwarded once `written` says so
+    pub fn received(&mut self, data: &[u8]) -> Result<()> {
+        if self.unwritten as usize + data.len() > BUFFER_SIZE as usize {
+            return Err(Error::other("the guest sent more than it had credit for"));
+        }
+
+        self.host.write(data);
+        self.unwritten += data.len() as u32;
+
+        Ok(())
+    }
+
+    /// Queues something of our own for the host program, it doesn't count against the guest's credit
+    pub fn send_own(&mut self, data: &[u8]) {
+        self.host.write(data);
+        self.unwritten_own += data.len();
+    }
+
+    /// The host program took `count` queued bytes, which frees that much of the guest's credit
+    pub fn written(&mut self, count: usize) {
+        // The host stream keeps the order, so our own bytes ahead of the guest's go first
+        let own = count.min(self.unwritten_own);
+        self.unwritten_own -= own;
+
+        let forwarded = ((count - own) as u32).min(self.unwritten);
+        self.unwritten -= forwarded;
+        self.fwd_cnt = self.fwd_cnt.wrapping_add(forwarded);
+    }
+
+    /// How much guest data the host program has taken, wrapping like fwd_cnt does
+    pub fn forwarded(&self) -> u32 {
+        self.fwd_cnt
+    }
+
+    /// Whether the guest should hear about its freed buffer space before it runs short
+    pub fn needs_credit_update(&self) -> bool {
+        self.fwd_cnt.wrapping_sub(self.reported_fwd_cnt) >= BUFFER_SIZE / 2
+    }
+
+    pub fn describe(&self) -> String {
+        format!("host port {} to guest port {}, {:?}, {} bytes waiting for {} bytes of guest credit, {} waiting for the host program and {} taken by it", self.host_port, self.guest_port, self.state, self.pending.len(), self.peer_free(), self.unwritten, self.forwarded())
+    }
+}
@@ -0,0 +1,250 @@
+// The host end of vsock, the way Firecracker does it. The device listens on one Unix socket and
+// a host program that wants a guest port connects to it and sends `CONNECT <port>\n`, once the
+// guest accepts it gets `OK <host port>\n` back and the socket carries the stream from then on.
+// When the guest connects to port P on the host the device connects to `<path>_P` instead.
+//
+// Every stream has a thread reading it, which stops reading while the device holds more of its
+// data than the guest has taken, so a slow guest pushes back on the host program. It has a thread
+// writing it too, the device queues guest data for it and hears back once the host program has
+// taken it, so a slow host program never holds up the device thread.
+
+use std::{collections::VecDeque, fs, io::{Read, Result, Write}, net::Shutdown, os::unix::net::{UnixListener, UnixStream}, sync::{Arc, Condvar, Mutex, atomic::{AtomicBool, Ordering}}, thread};
+
+use crate::device_thread::DeviceControl;
+
+const READ_SIZE: usize = 4096;
+
+// How much host data a connection holds for the guest before its reader waits
+const MAX_BUFFERED: usize = 256 * 1024;
+
+// Longer than any CONNECT line, anything past this isn't one
+const MAX_CONNECT_LINE: usize = 32;
+
+pub enum HostEvent {
+    /// A host program on the listening socket wants to reach `guest_port`
+    Connect { stream: UnixStream, guest_port: u32 },
+    Data { id: u64, bytes: Vec<u8> },
+    /// The host program has taken `count` more of the queued bytes
+    Written { id: u64, count: usize },
+    Closed { id: u64 },
+    /// Writing to the host program failed, what was queued for it is lost
+    WriteFailed { id: u64 },
+}
+
+pub type HostEvents = DeviceControl<HostEvent>;
+
+/// The path a guest connection to host `port` goes to
+pub fn port_path(path: &str, port: u32) -> String {
+    format!("{path}_{port}")
+}
+
+/// Reads a `CONNECT <port>` line a byte at a time, so nothing after it is taken off the stream
+fn read_connect(stream: &mut UnixStream) -> Option<u32> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+
+    while line.len() < MAX_CONNECT_LINE {
+        stream.read_exact(&mut byte).ok()?;
+
+        if byte[0] == b'\n' {
+            let line = String::from_utf8(line).ok()?;
+            return line.trim().strip_prefix("CONNECT ")?.trim().parse().ok();
+        }
+
+        line.push(byte[0]);
+    }
+
+    None
+}
+
+pub struct Listener {
+    path: String,
+    closed: Arc<AtomicBool>,
+}
+
+impl Listener {
+    pub fn listen(path: &str, events: HostEvents) -> Result<Self> {
+        // A socket left behind by an earlier run would make the bind fail
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread_closed = closed.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_closed.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+
+                // The CONNECT line is short, but it gets its own thread so a slow writer can't
+                // hold up everyone else
+                let events = events.clone();
+
+                thread::spawn(move || match read_connect(&mut stream) {
+                    Some(guest_port) => events.send(HostEvent::Connect { stream, guest_port }),
+                    None => {
+                        let _ = stream.write_all(b"ERR expected CONNECT <port>\n");
+                    },
+                });
+            }
+        });
+
+        Ok(Self { path: path.to_string(), closed })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        // Knocks the listener thread out of accept so it sees it's gone
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Default)]
+struct Buffered {
+    bytes: Mutex<usize>,
+    drained: Condvar,
+    closed: AtomicBool,
+}
+
+impl Buffered {
+    /// Waits until there's room for more, false once the stream has been dropped
+    fn wait_for_room(&self) -> bool {
+        let _bytes = self.drained.wait_while(self.bytes.lock().unwrap(), |bytes| *bytes >= MAX_BUFFERED && !self.closed.load(Ordering::SeqCst)).unwrap();
+        !self.closed.load(Ordering::SeqCst)
+    }
+
+    fn change(&self, change: impl FnOnce(&mut usize)) {
+        change(&mut self.bytes.lock().unwrap());
+        self.drained.notify_all();
+    }
+}
+
+#[derive(Default)]
+struct Unsent {
+    bytes: VecDeque<u8>,
+    shutdown: bool,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Outgoing {
+    unsent: Mutex<Unsent>,
+    queued: Condvar,
+}
+
+impl Outgoing {
+    fn change(&self, change: impl FnOnce(&mut Unsent)) {
+        change(&mut self.unsent.lock().unwrap());
+        self.queued.notify_all();
+    }
+
+    /// Everything queued so far and whether to shut the stream after it, None once it's dropped
+    fn wait_for_bytes(&self) -> Option<(Vec<u8>, bool)> {
+        let mut unsent = self.queued.wait_while(self.unsent.lock().unwrap(), |unsent| unsent.bytes.is_empty() && !unsent.shutdown && !unsent.closed).unwrap();
+
+        match unsent.closed {
+            true => None,
+            false => Some((unsent.bytes.drain(..).collect(), unsent.shutdown)),
+        }
+    }
+}
+
+/// The host end of one connection
+pub struct HostStream {
+    stream: UnixStream,
+    buffered: Arc<Buffered>,
+    outgoing: Arc<Outgoing>,
+}
+
+impl HostStream {
+    /// Starts reading `stream`, what it reads goes to the device tagged with `id`
+    pub fn open(stream: UnixStream, id: u64, events: HostEvents) -> Result<Self> {
+        let mut reader = stream.try_clone()?;
+        let mut writer = stream.try_clone()?;
+        let buffered = Arc::new(Buffered::default());
+        let thread_buffered = buffered.clone();
+        let outgoing = Arc::new(Outgoing::default());
+        let thread_outgoing = outgoing.clone();
+        let writer_events = events.clone();
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; READ_SIZE];
+
+            while thread_buffered.wait_for_room() {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(count) => {
+                        thread_buffered.change(|bytes| *bytes += count);
+                        events.send(HostEvent::Data { id, bytes: buffer[..count].to_vec() });
+                    },
+                }
+            }
+
+            events.send(HostEvent::Closed { id });
+        });
+
+        thread::spawn(move || {
+            while let Some((bytes, shutdown)) = thread_outgoing.wait_for_bytes() {
+                if writer.write_all(&bytes).is_err() {
+                    writer_events.send(HostEvent::WriteFailed { id });
+                    return;
+                }
+
+                if !bytes.is_empty() {
+                    writer_events.send(HostEvent::Written { id, count: bytes.len() });
+                }
+
+                // Only once everything queued before it has gone out
+                if shutdown {
+                    let _ = writer.shutdown(Shutdown::Write);
+                    return;
+                }
+            }
+        });
+
+        Ok(Self { stream, buffered, outgoing })
+    }
+
+    /// Guest initiated, connects to the host program listening for `port`
+    pub fn connect(path: &str, port: u32, id: u64, events: HostEvents) -> Result<Self> {
+        Self::open(UnixStream::connect(port_path(path, port))?, id, events)
+    }
+
+    /// Queues `data` for the writer, it reports with `Written` as the host program takes it
+    pub fn write(&self, data: &[u8]) {
+        self.outgoing.change(|unsent| unsent.bytes.extend(data));
+    }
+
+    /// The guest took `count` bytes of what was read, the reader can have that much more
+    pub fn consumed(&self, count: usize) {
+        self.buffered.change(|bytes| *bytes = bytes.saturating_sub(count));
+    }
+
+    /// The guest won't send any more, the host program hears once it has had what's queued
+    pub fn shutdown_write(&self) {
+        self.outgoing.change(|unsent| unsent.shutdown = true);
+    }
+}
+
+impl Drop for HostStream {
+    fn drop(&mut self) {
+        self.buffered.closed.store(true, Ordering::SeqCst);
+        self.buffered.change(|_| {});
+        self.outgoing.change(|unsent| unsent.closed = true);
+
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
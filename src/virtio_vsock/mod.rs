@@ -0,0 +1,546 @@
+// A virtio-vsock device, sockets between the guest and the host with no network in between. The
+// guest has a context id (CID) from the config space and the host is always CID 2. The driver
+// keeps the receive queue stocked with buffers for packets to the guest, puts its own packets on
+// the transmit queue and leaves a buffer on the event queue for transport resets.
+//
+// Only stream sockets are supported. The host end of each connection is a Unix socket, see
+// `host` for how they're set up.
+
+pub mod connection;
+pub mod host;
+pub mod packet;
+
+use std::{collections::{HashMap, VecDeque}, io::Result, sync::mpsc::Receiver};
+
+use packed_struct::prelude::*;
+
+use crate::virtio::{device::{VirtioDevice, DeviceContext}, descriptor_chain::DescriptorChain};
+
+use self::{
+    connection::{Connection, State},
+    host::{HostEvent, HostEvents, HostStream, Listener},
+    packet::{Packet, PacketHeader, HEADER_SIZE, VIRTIO_VSOCK_OP_CREDIT_REQUEST, VIRTIO_VSOCK_OP_CREDIT_UPDATE, VIRTIO_VSOCK_OP_REQUEST, VIRTIO_VSOCK_OP_RESPONSE, VIRTIO_VSOCK_OP_RST, VIRTIO_VSOCK_OP_RW, VIRTIO_VSOCK_OP_SHUTDOWN, VIRTIO_VSOCK_SHUTDOWN_RCV, VIRTIO_VSOCK_SHUTDOWN_SEND, VIRTIO_VSOCK_TYPE_STREAM},
+};
+
+pub const VIRTIO_VSOCK_DEVICE_ID: u32 = 19;
+
+pub const RECEIVEQ: u16 = 0;
+pub const TRANSMITQ: u16 = 1;
+pub const EVENTQ: u16 = 2;
+
+pub const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+pub const VMADDR_CID_HOST: u64 = 2;
+
+pub const DEFAULT_GUEST_CID: u64 = 3;
+
+// Ports the host end of host initiated connections gets, well clear of anything a host program
+// would ask the guest to connect to
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+#[derive(PackedStruct)]
+#[packed_struct(endian="lsb", bit_numbering="msb0")]
+pub struct VsockConfig {
+    #[packed_field(bytes="0x00..=0x07")]
+    guest_cid: Integer<u64, packed_bits::Bits::<64>>,
+}
+
+// Connections are known by their host port and guest port
+type ConnectionKey = (u32, u32);
+
+pub struct VirtioVsock {
+    guest_cid: u64,
+    uds_path: String,
+
+    listener: Option<Listener>,
+    host_events: Option<(HostEvents, Receiver<HostEvent>)>,
+
+    connections: HashMap<ConnectionKey, Connection>,
+    next_id: u64,
+    next_host_port: u32,
+
+    // Packets that aren't connection data go to the guest first
+    replies: VecDeque<Packet>,
+    receive_buffers: VecDeque<DescriptorChain>,
+    event_buffers: VecDeque<DescriptorChain>,
+    transport_reset: bool,
+
+    tx_packets: u64,
+    rx_packets: u64,
+}
+
+impl VirtioVsock {
+    pub fn new(guest_cid: u64, uds_path: &str) -> Self {
+        Self {
+            guest_cid,
+            uds_path: uds_path.to_string(),
+            listener: None,
+            host_events: None,
+            connections: HashMap::new(),
+            next_id: 0,
+            next_host_port: FIRST_HOST_PORT,
+            replies: VecDeque::new(),
+            receive_buffers: VecDeque::new(),
+            event_buffers: VecDeque::new(),
+            transport_reset: false,
+            tx_packets: 0,
+            rx_packets: 0,
+        }
+    }
+
+    /// Starts listening on the Unix socket for host programs, the host streams report to `events`
+    pub fn listen(&mut self, events: HostEvents, receiver: Receiver<HostEvent>) -> Result<()> {
+        self.listener = Some(Listener::listen(&self.uds_path, events.clone())?);
+        self.host_events = Some((events, receiver));
+
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn find(&mut self, id: u64) -> Option<&mut Connection> {
+        self.connections.values_mut().find(|connection| connection.id == id)
+    }
+
+    fn reply(&mut self, header: PacketHeader) {
+        self.replies.push_back(Packet { header, data: Vec::new() });
+    }
+
+    fn reset_connection(&mut self, key: ConnectionKey) {
+        if let Some(mut connection) = self.connections.remove(&key) {
+            let header = connection.header(self.guest_cid, VIRTIO_VSOCK_OP_RST);
+            self.reply(header);
+        }
+    }
+
+    /// The guest wants a host port, which means connecting to whatever listens for it
+    fn guest_connect(&mut self, ctx: &mut DeviceContext, header: &PacketHeader) {
+        let key = (header.dst_port, header.src_port);
+        let id = self.next_id();
+
+        let Some((events, _)) = self.host_events.as_ref() else {
+            self.reply(header.reply(VIRTIO_VSOCK_OP_RST));
+            return;
+        };
+
+        match HostStream::connect(&self.uds_path, header.dst_port, id, events.clone()) {
+            Ok(stream) => {
+                let mut connection = Connection::new(id, header.dst_port, header.src_port, State::Established, stream);
+                connection.update_credit(header);
+
+                let response = connection.header(self.guest_cid, VIRTIO_VSOCK_OP_RESPONSE);
+                self.connections.insert(key, connection);
+                self.reply(response);
+            },
+            Err(err) => {
+                ctx.send_message(format!("The guest's connection to host port {} was refused: {err}", header.dst_port));
+                self.reply(header.reply(VIRTIO_VSOCK_OP_RST));
+            },
+        }
+    }
+
+    fn transmit(&mut self, ctx: &mut DeviceContext, packet: &[u8]) {
+        let Some(header) = PacketHeader::parse(packet) else {
+            ctx.send_message("Dropped a transmit buffer without a vsock header".to_string());
+            return;
+        };
+
+        let data = &packet[HEADER_SIZE..(HEADER_SIZE + header.len as usize).min(packet.len())];
+        self.tx_packets += 1;
+
+        // Packets that claim to come from someone else, or go to anyone but the host, are dropped
+        if header.src_cid != self.guest_cid || header.dst_cid != VMADDR_CID_HOST {
+            ctx.send_message(format!("Dropped a vsock packet from CID {} to CID {}", header.src_cid, header.dst_cid));
+            return;
+        }
+
+        if header.kind != VIRTIO_VSOCK_TYPE_STREAM {
+            self.reply(header.reply(VIRTIO_VSOCK_OP_RST));
+            return;
+        }
+
+        let key = (header.dst_port, header.src_port);
+
+        if header.op == VIRTIO_VSOCK_OP_REQUEST {
+            match self.connections.contains_key(&key) {
+                true => self.reset_connection(key),
+                false => self.guest_connect(ctx, &header),
+            }
+
+            return;
+        }
+
+        let Some(connection) = self.connections.get_mut(&key) else {
+            // A reset for something we've forgotten needs nothing back
+            if header.op != VIRTIO_VSOCK_OP_RST {
+                self.reply(header.reply(VIRTIO_VSOCK_OP_RST));
+            }
+
+            return;
+        };
+
+        connection.update_credit(&header);
+
+        match header.op {
+            VIRTIO_VSOCK_OP_RESPONSE if connection.state == State::Connecting => {
+                connection.state = State::Established;
+
+                let greeting = format!("OK {}\n", connection.host_port);
+                connection.send_own(greeting.as_bytes());
+            },
+            VIRTIO_VSOCK_OP_RW => {
+                if let Err(err) = connection.received(data) {
+                    ctx.send_message(format!("Reset the connection to host port {}: {err}", header.dst_port));
+                    self.reset_connection(key);
+                }
+            },
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => {},
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                let update = connection.header(self.guest_cid, VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+                self.reply(update);
+            },
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                if header.flags & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+                    connection.host.shutdown_write();
+                }
+
+                if header.flags & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 {
+                    connection.pending.clear();
+                }
+
+                // Both ways shut is a close, which we finish with a reset
+                if header.flags & (VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND) == VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND {
+                    self.reset_connection(key);
+                }
+            },
+            VIRTIO_VSOCK_OP_RST => {
+                self.connections.remove(&key);
+            },
+            _ => self.reset_connection(key),
+        }
+    }
+
+    fn handle_host_event(&mut self, ctx: &mut DeviceContext, event: HostEvent) {
+        match event {
+            HostEvent::Connect { stream, guest_port } => {
+                let id = self.next_id();
+                let host_port = self.next_host_port;
+                self.next_host_port = self.next_host_port.checked_add(1).unwrap_or(FIRST_HOST_PORT);
+
+                let Some((events, _)) = self.host_events.as_ref() else {
+                    return;
+                };
+
+                match HostStream::open(stream, id, events.clone()) {
+                    Ok(stream) => {
+                        let mut connection = Connection::new(id, host_port, guest_port, State::Connecting, stream);
+                        let request = connection.header(self.guest_cid, VIRTIO_VSOCK_OP_REQUEST);
+
+                        self.connections.insert((host_port, guest_port), connection);
+                        self.reply(request);
+                    },
+                    Err(err) => ctx.send_message(format!("Couldn't take a host connection to guest port {guest_port}: {err}")),
+                }
+            },
+            HostEvent::Data { id, bytes } => {
+                if let Some(connection) = self.find(id) {
+                    connection.pending.extend(bytes);
+                }
+            },
+            HostEvent::Written { id, count } => {
+                let guest_cid = self.guest_cid;
+
+                let Some(connection) = self.find(id) else {
+                    return;
+                };
+
+                connection.written(count);
+
+                if connection.needs_credit_update() {
+                    let update = connection.header(guest_cid, VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+                    self.reply(update);
+                }
+            },
+            HostEvent::Closed { id } => {
+                if let Some(connection) = self.find(id) {
+                    connection.host_closed = true;
+                }
+            },
+            HostEvent::WriteFailed { id } => {
+                let key = self.connections.iter().find(|(_, connection)| connection.id == id).map(|(key, _)| *key);
+
+                if let Some(key) = key {
+                    ctx.send_message(format!("Lost what the guest sent to host port {}, the host program stopped taking it", key.0));
+                    self.reset_connection(key);
+                }
+            },
+        }
+    }
+
+    /// The next packet for the guest: replies first, then data and shutdowns as credit allows
+    fn next_packet(&mut self, room: usize) -> Option<Packet> {
+        if let Some(packet) = self.replies.pop_front() {
+            return Some(packet);
+        }
+
+        let guest_cid = self.guest_cid;
+
+        for connection in self.connections.values_mut() {
+            if connection.state != State::Established {
+                continue;
+            }
+
+            if let Some(data) = connection.take_data(room) {
+                let mut header = connection.header(guest_cid, VIRTIO_VSOCK_OP_RW);
+                header.len = data.len() as u32;
+
+                return Some(Packet { header, data });
+            }
+
+            if connection.host_closed && connection.pending.is_empty() {
+                connection.state = State::Closing;
+
+                let mut header = connection.header(guest_cid, VIRTIO_VSOCK_OP_SHUTDOWN);
+                header.flags = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+
+                return Some(Packet { header, data: Vec::new() });
+            }
+        }
+
+        None
+    }
+
+    unsafe fn fill_receive_buffers(&mut self, ctx: &mut DeviceContext) {
+        while let Some(chain) = self.receive_buffers.pop_front() {
+            if chain.writable_len() < HEADER_SIZE {
+                ctx.send_message("A vsock receive buffer is too small for a header".to_string());
+                ctx.complete(RECEIVEQ, chain, 0);
+                continue;
+            }
+
+            let Some(packet) = self.next_packet(chain.writable_len() - HEADER_SIZE) else {
+                self.receive_buffers.push_front(chain);
+                return;
+            };
+
+            let bytes = packet.to_bytes();
+            chain.write_at(0, &bytes);
+
+            self.rx_packets += 1;
+            ctx.complete(RECEIVEQ, chain, bytes.len() as u32);
+        }
+    }
+
+    unsafe fn send_transport_reset(&mut self, ctx: &mut DeviceContext) {
+        if !self.transport_reset {
+            return;
+        }
+
+        if let Some(chain) = self.event_buffers.pop_front() {
+            let length = chain.write_at(0, &VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.to_le_bytes());
+            ctx.complete(EVENTQ, chain, length as u32);
+
+            self.transport_reset = false;
+        }
+    }
+
+    /// Drops every connection and tells the driver to forget them too
+    fn reset_transport(&mut self) {
+        self.connections.clear();
+        self.replies.clear();
+        self.transport_reset = true;
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_VSOCK_DEVICE_ID
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        let config = VsockConfig {
+            guest_cid: self.guest_cid.into(),
+        };
+
+        config.pack().unwrap().to_vec()
+    }
+
+    fn queue_count(&self) -> usize {
+        3
+    }
+
+    fn process_request(&mut self, ctx: &mut DeviceContext, queue: u16, chain: DescriptorChain) -> Option<u32> {
+        match queue {
+            RECEIVEQ => {
+                self.receive_buffers.push_back(chain);
+                unsafe { self.fill_receive_buffers(ctx) };
+                None
+            },
+            TRANSMITQ => {
+                let packet = unsafe { chain.read_all() };
+                self.transmit(ctx, &packet);
+
+                // Replies to what was sent can go straight out
+                unsafe { self.fill_receive_buffers(ctx) };
+                Some(0)
+            },
+            _ => {
+                self.event_buffers.push_back(chain);
+                unsafe { self.send_transport_reset(ctx) };
+                None
+            },
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut DeviceContext) {
+        let events: Vec<HostEvent> = match self.host_events.as_ref() {
+            Some((_, receiver)) => receiver.try_iter().collect(),
+            None => Vec::new(),
+        };
+
+        for event in events {
+            self.handle_host_event(ctx, event);
+        }
+
+        unsafe {
+            self.fill_receive_buffers(ctx);
+            self.send_transport_reset(ctx);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.connections.clear();
+        self.replies.clear();
+        self.receive_buffers.clear();
+        self.event_buffers.clear();
+        self.transport_reset = false;
+    }
+
+    fn command(&mut self, ctx: &mut DeviceContext, command: &str) -> std::result::Result<String, String> {
+        match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["connections"] if self.connections.is_empty() => Ok("No connections".to_string()),
+            ["connections"] => {
+                let mut connections: Vec<&Connection> = self.connections.values().collect();
+                connections.sort_by_key(|connection| connection.id);
+
+                Ok(connections.iter().map(|connection| connection.describe()).collect::<Vec<_>>().join("; "))
+            },
+            ["stats"] => {
+                let listening = self.listener.as_ref().map_or("not listening".to_string(), |listener| format!("listening on {}", listener.path()));
+                Ok(format!("CID {}, {listening}: {} connections, {} packets sent, {} received", self.guest_cid, self.connections.len(), self.tx_packets, self.rx_packets))
+            },
+            ["transport", "reset"] => {
+                let dropped = self.connections.len();
+                self.reset_transport();
+                unsafe { self.send_transport_reset(ctx) };
+
+                Ok(format!("Reset the transport, {dropped} connections dropped"))
+            },
+            _ => Err(format!("The vsock device doesn't understand {command}, try connections, stats or transport reset")),
+        }
+    }
+}
+
+#[test]
+pub fn test_stream_with_credit() {
+    use std::{io::{Read, Write}, os::unix::net::UnixListener, thread, time::Duration};
+
+    use tokio::sync::mpsc::channel;
+
+    use crate::{poller::PollableQueue, device_thread::DeviceControl, virtio::{virtqueue::DescriptorCell, vring::VIRTQ_DESC_F_WRITE, transport::{MmioTransport, TransportMode}}};
+
+    struct NoWaker;
+
+    impl PollableQueue for NoWaker {
+        fn wait_for_event(&self) {}
+        fn submit_event(&self) {}
+    }
+
+    let path = std::env::temp_dir().join(format!("virtio-vsock-{}.sock", std::process::id())).to_string_lossy().to_string();
+    let agent = UnixListener::bind(host::port_path(&path, 1234)).unwrap();
+
+    let (tx, _rx) = channel(16);
+    let transport = MmioTransport::new(TransportMode::Modern, VIRTIO_VSOCK_DEVICE_ID, 0, &[8; 3]).into_shared();
+    let mut ctx = DeviceContext::new(&tx, transport);
+
+    let mut vsock = VirtioVsock::new(DEFAULT_GUEST_CID, &path);
+    let (events, receiver) = DeviceControl::new(NoWaker);
+    vsock.listen(events, receiver).unwrap();
+
+    let guest = |op, buf_alloc, data: &[u8]| {
+        let header = PacketHeader { src_cid: DEFAULT_GUEST_CID, dst_cid: VMADDR_CID_HOST, src_port: 5000, dst_port: 1234, len: data.len() as u32, kind: VIRTIO_VSOCK_TYPE_STREAM, op, buf_alloc, ..PacketHeader::default() };
+        Packet { header, data: data.to_vec() }.to_bytes()
+    };
+
+    let mut request = guest(VIRTIO_VSOCK_OP_REQUEST, 8, &[]);
+    let mut hello = guest(VIRTIO_VSOCK_OP_RW, 8, b"hello");
+    let mut receive = [[0u8; 128]; 3];
+
+    let mut table: [DescriptorCell; 5] = Default::default();
+    table[0] = DescriptorCell { addr: request.as_mut_ptr() as u64, length: request.len() as u32, flags: 0, next: 0 };
+    table[1] = DescriptorCell { addr: hello.as_mut_ptr() as u64, length: hello.len() as u32, flags: 0, next: 0 };
+
+    for (idx, buffer) in receive.iter_mut().enumerate() {
+        table[2 + idx] = DescriptorCell { addr: buffer.as_mut_ptr() as u64, length: 128, flags: VIRTQ_DESC_F_WRITE, next: 0 };
+    }
+
+    let table = table.as_mut_ptr();
+    let chain = |head| unsafe { DescriptorChain::new(table, 5, head) };
+
+    // The guest connects, offering only 8 bytes of buffer, and the host program is there
+    assert_eq!(vsock.process_request(&mut ctx, RECEIVEQ, chain(2)), None);
+    assert_eq!(vsock.process_request(&mut ctx, TRANSMITQ, chain(0)), Some(0));
+    assert_eq!(PacketHeader::parse(&receive[0]).unwrap().op, VIRTIO_VSOCK_OP_RESPONSE);
+
+    let (mut stream, _) = agent.accept().unwrap();
+    assert_eq!(vsock.process_request(&mut ctx, TRANSMITQ, chain(1)), Some(0));
+
+    let mut greeting = [0u8; 5];
+    stream.read_exact(&mut greeting).unwrap();
+    assert_eq!(&greeting, b"hello");
+
+    // The guest's credit only comes back once the writer says the host program has the bytes
+    let forwarded = |vsock: &VirtioVsock| vsock.connections.values().next().unwrap().forwarded();
+
+    for _ in 0..200 {
+        vsock.poll(&mut ctx);
+
+        if forwarded(&vsock) == 5 {
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(forwarded(&vsock), 5);
+
+    // The reply is held to the guest's credit
+    stream.write_all(b"0123456789").unwrap();
+    assert_eq!(vsock.process_request(&mut ctx, RECEIVEQ, chain(3)), None);
+    assert_eq!(vsock.process_request(&mut ctx, RECEIVEQ, chain(4)), None);
+
+    for _ in 0..200 {
+        vsock.poll(&mut ctx);
+
+        if ctx.completions.len() == 2 {
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let data = PacketHeader::parse(&receive[1]).unwrap();
+    assert_eq!((data.op, data.len), (VIRTIO_VSOCK_OP_RW, 8));
+    assert_eq!(&receive[1][HEADER_SIZE..HEADER_SIZE + 8], b"01234567");
+    assert_eq!(vsock.connections.values().next().unwrap().pending.len(), 2);
+
+    drop(vsock);
+    let _ = std::fs::remove_file(host::port_path(&path, 1234));
+}
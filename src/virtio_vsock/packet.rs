@@ -0,0 +1,102 @@
+// Every vsock packet starts with a `virtio_vsock_hdr`, RW packets carry their data after it.
+// Each header also tells the other side how much buffer space the sender has for the
+// connection and how much of what it was sent it has used up, that's the credit.
+
+pub const HEADER_SIZE: usize = 44;
+
+pub const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+pub const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+pub const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+pub const VIRTIO_VSOCK_OP_RST: u16 = 3;
+pub const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VIRTIO_VSOCK_OP_RW: u16 = 5;
+pub const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+pub const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+pub const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub len: u32,
+    pub kind: u16,
+    pub op: u16,
+    pub flags: u32,
+    pub buf_alloc: u32,
+    pub fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let long = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let word = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let short = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+        Some(Self {
+            src_cid: long(0),
+            dst_cid: long(8),
+            src_port: word(16),
+            dst_port: word(20),
+            len: word(24),
+            kind: short(28),
+            op: short(30),
+            flags: word(32),
+            buf_alloc: word(36),
+            fwd_cnt: word(40),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.len.to_le_bytes());
+        bytes[28..30].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[30..32].copy_from_slice(&self.op.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+
+        bytes
+    }
+
+    /// The header a reply to this packet starts from, addressed back to where it came from
+    pub fn reply(&self, op: u16) -> Self {
+        Self {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            kind: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            ..Self::default()
+        }
+    }
+}
+
+/// A packet for the guest, waiting for a receive buffer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub header: PacketHeader,
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.to_bytes().to_vec();
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+}